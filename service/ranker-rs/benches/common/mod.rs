@@ -0,0 +1,28 @@
+//! Timing helpers shared by the benchmarks
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const WARMUP: Duration = Duration::from_millis(200);
+const MEASURE: Duration = Duration::from_secs(1);
+
+/// Mean time per call after a warmup period
+pub fn bench<T>(name: &str, mut f: impl FnMut() -> T) -> Duration {
+    let start = Instant::now();
+    while start.elapsed() < WARMUP {
+        black_box(f());
+    }
+    let (start, mut iters) = (Instant::now(), 0u32);
+    while start.elapsed() < MEASURE {
+        black_box(f());
+        iters += 1;
+    }
+    let per_iter = start.elapsed() / iters;
+    println!("{:<24} {:>10.2?}/iter ({} iters)", name, per_iter, iters);
+    per_iter
+}
+
+/// Print how much faster `after` ran than `before`
+pub fn print_speedup(before: Duration, after: Duration) {
+    println!("speedup: {:.2}x", before.as_secs_f64() / after.as_secs_f64());
+}
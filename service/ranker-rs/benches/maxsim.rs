@@ -0,0 +1,51 @@
+//! MaxSim inner loop: per-token row copies vs contiguous token slices
+//!
+//! Run with `cargo bench --bench maxsim`.
+
+mod common;
+
+use common::{bench, print_speedup};
+use nalgebra::DMatrix;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ranker_rs::kernels::dot_kernel;
+use ranker_rs::scoring::{maxsim_score, normalized_matrix, DEFAULT_NORM_EPS};
+use std::hint::black_box;
+
+const DIM: usize = 128;
+const Q_TOKENS: usize = 32;
+const D_TOKENS: usize = 80;
+
+/// The previous implementation, which copied both rows for every dot
+fn maxsim_copying_rows(q: &DMatrix<f32>, d: &DMatrix<f32>) -> f32 {
+    let dot = dot_kernel();
+    let mut total_score = 0.0;
+    for q_row in q.row_iter() {
+        let mut max_dot = f32::NEG_INFINITY;
+        for d_row in d.row_iter() {
+            let q_vec: Vec<f32> = q_row.iter().cloned().collect();
+            let d_vec: Vec<f32> = d_row.iter().cloned().collect();
+            max_dot = max_dot.max(dot(&q_vec, &d_vec));
+        }
+        total_score += max_dot;
+    }
+    total_score
+}
+
+fn random_matrix(rng: &mut StdRng, rows: usize) -> DMatrix<f32> {
+    let tokens: Vec<Vec<f32>> = (0..rows).map(|_| (0..DIM).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect();
+    normalized_matrix(&tokens, DEFAULT_NORM_EPS)
+}
+
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let q = random_matrix(&mut rng, Q_TOKENS);
+    let d = random_matrix(&mut rng, D_TOKENS);
+    assert!((maxsim_score(&q, &d) - maxsim_copying_rows(&q, &d)).abs() < 1e-4);
+
+    println!("maxsim {}x{} query vs {}-token document", Q_TOKENS, DIM, D_TOKENS);
+    let before = bench("copying rows", || maxsim_copying_rows(black_box(&q), black_box(&d)));
+    let after = bench("token slices", || maxsim_score(black_box(&q), black_box(&d)));
+    print_speedup(before, after);
+}
@@ -0,0 +1,47 @@
+//! Token pruning: full salience sort vs partial selection of the top N
+//!
+//! Run with `cargo bench --bench prune`.
+
+mod common;
+
+use common::{bench, print_speedup};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ranker_rs::scoring::{prune_token_indices, token_salience};
+use std::hint::black_box;
+
+const DIM: usize = 128;
+const Q_TOKENS: usize = 500;
+const Q_MAX: usize = 32;
+const D_TOKENS: usize = 200;
+const D_MAX: usize = 64;
+const N_DOCS: usize = 100;
+
+/// The previous implementation: score and sort every token, then take N
+fn full_sort(tokens: &[Vec<f32>], n: usize) -> Vec<usize> {
+    token_salience(tokens, "idf_norm").into_iter().take(n).map(|(idx, _)| idx).collect()
+}
+
+fn random_tokens(rng: &mut StdRng, n: usize) -> Vec<Vec<f32>> {
+    (0..n).map(|_| (0..DIM).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()
+}
+
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let query = random_tokens(&mut rng, Q_TOKENS);
+    let docs: Vec<Vec<Vec<f32>>> = (0..N_DOCS).map(|_| random_tokens(&mut rng, D_TOKENS)).collect();
+    assert_eq!(full_sort(&query, Q_MAX), prune_token_indices(&query, Q_MAX, "idf_norm", None, None));
+
+    println!("query: top {} of {} tokens", Q_MAX, Q_TOKENS);
+    let before = bench("full sort", || full_sort(black_box(&query), Q_MAX));
+    let after = bench("partial select", || prune_token_indices(black_box(&query), Q_MAX, "idf_norm", None, None));
+    print_speedup(before, after);
+
+    println!("documents: top {} of {} tokens, {} documents", D_MAX, D_TOKENS, N_DOCS);
+    let before = bench("full sort", || docs.iter().flat_map(|doc| full_sort(black_box(doc), D_MAX)).collect::<Vec<_>>());
+    let after = bench("partial select", || {
+        docs.iter().flat_map(|doc| prune_token_indices(black_box(doc), D_MAX, "idf_norm", None, None)).collect::<Vec<_>>()
+    });
+    print_speedup(before, after);
+}
@@ -0,0 +1,41 @@
+//! Top-K selection: full sort vs partial selection of the best K
+//!
+//! Run with `cargo bench --bench topk`.
+
+mod common;
+
+use common::{bench, print_speedup};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ranker_rs::topk::select_top;
+use std::cmp::Ordering;
+use std::hint::black_box;
+
+const N_DOCS: usize = 50_000;
+const K: usize = 10;
+
+/// The previous implementation: sort every document, then truncate
+fn full_sort(mut ranked: Vec<(usize, f32)>, k: usize) -> Vec<(usize, f32)> {
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    ranked.truncate(k);
+    ranked
+}
+
+fn partial_select(mut ranked: Vec<(usize, f32)>, k: usize) -> Vec<(usize, f32)> {
+    select_top(&mut ranked, k);
+    ranked.truncate(k);
+    ranked
+}
+
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let ranked: Vec<(usize, f32)> = (0..N_DOCS).map(|idx| (idx, rng.gen_range(0.0..32.0))).collect();
+    assert_eq!(full_sort(ranked.clone(), K), partial_select(ranked.clone(), K));
+
+    println!("top-{} of {} documents", K, N_DOCS);
+    // Each call clones the input, which both sides pay equally
+    let before = bench("full sort", || full_sort(black_box(ranked.clone()), K));
+    let after = bench("partial select", || partial_select(black_box(ranked.clone()), K));
+    print_speedup(before, after);
+}
@@ -0,0 +1,226 @@
+//! Coalescing of identical in-flight requests
+//!
+//! Concurrent identical requests share one computation: the
+//! first caller runs it and the others wait for its result. Nothing is cached
+//! once the computation finishes, so memory only grows with the number of
+//! distinct requests in flight. Followers pay the leader's full latency even
+//! if they arrived just before it finished, and each receives its own clone
+//! of the result.
+//!
+//! Requests are matched by a 64-bit hash (token values are hashed by bit
+//! pattern, see `KeyHasher`), and a shared result is only handed out once
+//! the request itself compares equal to the running one, so a hash
+//! collision costs a separate computation rather than a wrong answer. Each
+//! in-flight slot keeps an `Arc` of its request rather than a copy.
+//!
+//! The in-flight map is a `Mutex<HashMap>` rather than a concurrent map:
+//! the lock is only held for the entry lookup, never across an await, and
+//! the tokio `OnceCell` in each slot provides the async wait and lets a
+//! waiter take over if the leader is cancelled.
+
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Write};
+use std::future::Future;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+/// A request and its hash, as matched by `Coalescer::run`
+#[derive(Debug)]
+pub struct Fingerprint<K> {
+    hash: u64,
+    key: Arc<K>,
+}
+
+impl<K> Fingerprint<K> {
+    pub fn new(hash: u64, key: Arc<K>) -> Self {
+        Self { hash, key }
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl<K> Clone for Fingerprint<K> {
+    fn clone(&self) -> Self {
+        Self { hash: self.hash, key: self.key.clone() }
+    }
+}
+
+/// Running computation and the request it answers
+type Slot<K, T> = (Arc<K>, Arc<OnceCell<T>>);
+
+/// Shared slots for computations currently running, keyed by request hash
+#[derive(Debug)]
+pub struct Coalescer<K, T> {
+    inflight: Arc<Mutex<HashMap<u64, Slot<K, T>>>>,
+    computations: Arc<AtomicUsize>,
+}
+
+impl<K, T> Default for Coalescer<K, T> {
+    fn default() -> Self {
+        Self { inflight: Arc::default(), computations: Arc::default() }
+    }
+}
+
+impl<K, T> Clone for Coalescer<K, T> {
+    fn clone(&self) -> Self {
+        Self { inflight: self.inflight.clone(), computations: self.computations.clone() }
+    }
+}
+
+impl<K: PartialEq, T: Clone> Coalescer<K, T> {
+    /// Run `compute`, or wait for an identical in-flight run and share its
+    /// result. If the running caller is cancelled, a waiter takes over.
+    pub async fn run<F, Fut>(&self, key: Fingerprint<K>, compute: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let cell = match self.inflight.lock().unwrap().entry(key.hash) {
+            Entry::Occupied(slot) if *slot.get().0 == *key.key => Some(slot.get().1.clone()),
+            // A different request with the same hash is running; don't share
+            Entry::Occupied(_) => None,
+            Entry::Vacant(slot) => Some(slot.insert((key.key, Arc::default())).1.clone()),
+        };
+        let Some(cell) = cell else {
+            self.computations.fetch_add(1, Ordering::Relaxed);
+            return compute().await;
+        };
+        let value = cell
+            .get_or_init(|| {
+                self.computations.fetch_add(1, Ordering::Relaxed);
+                compute()
+            })
+            .await
+            .clone();
+
+        // The first caller back retires the slot, unless a newer run took it
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(&key.hash).is_some_and(|(_, current)| Arc::ptr_eq(current, &cell)) {
+            inflight.remove(&key.hash);
+        }
+        value
+    }
+
+    /// Callers currently waiting on or running an in-flight computation
+    pub fn waiting(&self) -> usize {
+        // Each slot's cell is also held once by the map itself
+        self.inflight.lock().unwrap().values().map(|(_, cell)| Arc::strong_count(cell) - 1).sum()
+    }
+
+    /// Computations actually started, as opposed to requests served
+    pub fn computations(&self) -> usize {
+        self.computations.load(Ordering::Relaxed)
+    }
+}
+
+/// Streaming 64-bit hash of a request, built without copying it
+#[derive(Default)]
+pub struct KeyHasher(DefaultHasher);
+
+impl KeyHasher {
+    /// Hash floats by bit pattern
+    pub fn floats(&mut self, values: &[f32]) {
+        for value in values {
+            self.0.write_u32(value.to_bits());
+        }
+    }
+
+    /// Hash a value's `Debug` form, formatted straight into the hasher
+    pub fn debug(&mut self, value: &impl Debug) {
+        write!(self, "{:?}", value).expect("hashing never fails");
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+impl Write for KeyHasher {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Hash of a value's `Debug` form, which spells out every f32 exactly
+pub fn debug_hash(value: &impl Debug) -> u64 {
+    let mut hasher = KeyHasher::default();
+    hasher.debug(value);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn test_identical_requests_share_one_computation() {
+        let coalescer: Coalescer<Vec<f32>, Vec<usize>> = Coalescer::default();
+        let release = Arc::new(Notify::new());
+        let request = vec![1.0f32, 2.0];
+        let mut hasher = KeyHasher::default();
+        hasher.floats(&request);
+        let key = Fingerprint::new(hasher.finish(), Arc::new(request));
+
+        let waiters: Vec<_> = (0..16)
+            .map(|_| {
+                let (coalescer, release, key) = (coalescer.clone(), release.clone(), key.clone());
+                tokio::spawn(async move {
+                    coalescer
+                        .run(key, || async move {
+                            release.notified().await;
+                            vec![3, 1, 2]
+                        })
+                        .await
+                })
+            })
+            .collect();
+        // Hold the leader until every request has joined its computation
+        while coalescer.waiting() < 16 {
+            tokio::task::yield_now().await;
+        }
+        release.notify_one();
+
+        for waiter in waiters {
+            assert_eq!(waiter.await.unwrap(), vec![3, 1, 2]);
+        }
+        assert_eq!(coalescer.computations(), 1);
+        let mut other = KeyHasher::default();
+        other.floats(&[1.0, 2.5]);
+        assert_ne!(key.hash(), other.finish());
+    }
+
+    #[tokio::test]
+    async fn test_hash_collision_runs_separately() {
+        let coalescer: Coalescer<u32, u32> = Coalescer::default();
+        let release = Arc::new(Notify::new());
+        let first = Fingerprint::new(debug_hash(&1), Arc::new(1));
+        let colliding = Fingerprint::new(first.hash(), Arc::new(2));
+
+        let leader = {
+            let (coalescer, release) = (coalescer.clone(), release.clone());
+            tokio::spawn(async move {
+                coalescer
+                    .run(first, || async move {
+                        release.notified().await;
+                        1
+                    })
+                    .await
+            })
+        };
+        while coalescer.waiting() < 1 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(coalescer.run(colliding, || async { 2 }).await, 2);
+        release.notify_one();
+        assert_eq!(leader.await.unwrap(), 1);
+        assert_eq!(coalescer.computations(), 2);
+    }
+}
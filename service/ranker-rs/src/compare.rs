@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::scoring::{score_docs_with_options, NoopPostScorer, PruneConfig, ScoreError, ScoreOptions, ScoreOutput};
+
+/// One complete scoring configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScoringConfig {
+    pub prune: PruneConfig,
+    #[serde(flatten)]
+    pub options: ScoreOptions,
+}
+
+/// Request to score the same input under two configurations
+#[derive(Debug, Deserialize)]
+pub struct CompareRequest {
+    pub q_tokens: Vec<Vec<f32>>,
+    pub d_tokens: Vec<Vec<Vec<f32>>>,
+    pub topk: usize,
+    pub a: ScoringConfig,
+    pub b: ScoringConfig,
+}
+
+/// Ranking produced by one side of a comparison
+#[derive(Debug, Serialize)]
+pub struct CompareSide {
+    pub order: Vec<usize>,
+    pub scores: Vec<f32>,
+    pub latency_ms: f32,
+}
+
+/// Side-by-side result of two scoring configurations
+#[derive(Debug, Serialize)]
+pub struct CompareResponse {
+    pub a: CompareSide,
+    pub b: CompareSide,
+    /// Kendall tau-a between the two full document rankings
+    pub kendall_tau: f32,
+    /// Jaccard overlap of the two top-K document sets
+    pub topk_overlap: f32,
+}
+
+/// Kendall tau-a between two rankings of the same documents
+///
+/// Both slices list document indices best first. Runs in O(n²), which is fine
+/// for rerank-sized candidate sets.
+pub fn kendall_tau(a: &[usize], b: &[usize]) -> f32 {
+    let n = a.len().min(b.len());
+    if n < 2 {
+        return 1.0;
+    }
+
+    let max_idx = a.iter().chain(b.iter()).copied().max().unwrap_or(0);
+    let mut rank_b = vec![usize::MAX; max_idx + 1];
+    for (rank, &doc) in b.iter().enumerate() {
+        rank_b[doc] = rank;
+    }
+
+    let mut concordant = 0i64;
+    let mut discordant = 0i64;
+    for i in 0..n {
+        for j in (i + 1)..n {
+            // a ranks a[i] above a[j]; check whether b agrees
+            if rank_b[a[i]] < rank_b[a[j]] {
+                concordant += 1;
+            } else {
+                discordant += 1;
+            }
+        }
+    }
+
+    let pairs = (n * (n - 1) / 2) as f32;
+    (concordant - discordant) as f32 / pairs
+}
+
+/// Jaccard overlap between two sets of document indices
+pub fn topk_overlap(a: &[usize], b: &[usize]) -> f32 {
+    let set_a: HashSet<usize> = a.iter().copied().collect();
+    let set_b: HashSet<usize> = b.iter().copied().collect();
+    let union = set_a.union(&set_b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    set_a.intersection(&set_b).count() as f32 / union as f32
+}
+
+/// Score the input under both configurations and summarize the difference
+pub fn compare_configs(request: &CompareRequest) -> Result<CompareResponse, ScoreError> {
+    let n_docs = request.d_tokens.len();
+    let run = |config: &ScoringConfig| {
+        let start = std::time::Instant::now();
+        let output = score_docs_with_options(
+            &request.q_tokens,
+            &request.d_tokens,
+            n_docs,
+            &config.prune,
+            &config.options,
+            &NoopPostScorer,
+        )?;
+        Ok((output, start.elapsed().as_secs_f32() * 1000.0))
+    };
+
+    let (out_a, latency_a) = run(&request.a)?;
+    let (out_b, latency_b) = run(&request.b)?;
+
+    let kendall_tau = kendall_tau(&out_a.order, &out_b.order);
+    let topk = request.topk.min(n_docs);
+    let topk_overlap = topk_overlap(&out_a.order[..topk], &out_b.order[..topk]);
+
+    let side = |mut output: ScoreOutput, latency_ms: f32| {
+        output.order.truncate(topk);
+        output.scores.truncate(topk);
+        CompareSide { order: output.order, scores: output.scores, latency_ms }
+    };
+
+    Ok(CompareResponse {
+        a: side(out_a, latency_a),
+        b: side(out_b, latency_b),
+        kendall_tau,
+        topk_overlap,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(a: PruneConfig, b: PruneConfig, topk: usize) -> CompareRequest {
+        CompareRequest {
+            q_tokens: vec![vec![2.0, 0.0], vec![0.0, 1.0]],
+            d_tokens: vec![
+                vec![vec![1.0, 0.0]],
+                vec![vec![0.0, 1.0], vec![0.6, 0.8]],
+                vec![vec![-1.0, 0.0]],
+            ],
+            topk,
+            a: ScoringConfig { prune: a, options: ScoreOptions::default() },
+            b: ScoringConfig { prune: b, options: ScoreOptions::default() },
+        }
+    }
+
+    #[test]
+    fn test_kendall_tau() {
+        assert_eq!(kendall_tau(&[0, 1, 2], &[0, 1, 2]), 1.0);
+        assert_eq!(kendall_tau(&[0, 1, 2], &[2, 1, 0]), -1.0);
+        assert!((kendall_tau(&[1, 0, 2], &[0, 1, 2]) - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_compare_identical_configs() {
+        let response = compare_configs(&request(PruneConfig::default(), PruneConfig::default(), 2)).unwrap();
+        assert_eq!(response.a.order, response.b.order);
+        assert_eq!(response.a.scores, response.b.scores);
+        assert_eq!(response.kendall_tau, 1.0);
+        assert_eq!(response.topk_overlap, 1.0);
+    }
+
+    #[test]
+    fn test_compare_divergent_configs() {
+        // Keeping only the high-norm query token flips docs 0 and 1
+        let pruned = PruneConfig { q_max: 1, ..Default::default() };
+        let response = compare_configs(&request(PruneConfig::default(), pruned, 1)).unwrap();
+        assert_eq!(response.a.order, vec![1]);
+        assert_eq!(response.b.order, vec![0]);
+        assert!((response.kendall_tau - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(response.topk_overlap, 0.0);
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+use crate::scoring::{score_docs_with_options, NoopPostScorer, PruneConfig, ScoreError, ScoreOptions};
+
+fn default_temperature() -> f32 {
+    1.0
+}
+
+/// Batch of (query, positive) pairs scored against shared in-batch negatives
+#[derive(Debug, Deserialize)]
+pub struct ContrastiveRequest {
+    pub queries: Vec<Vec<Vec<f32>>>,
+    /// `positives[i]` is the relevant document for `queries[i]`
+    pub positives: Vec<Vec<Vec<f32>>>,
+    /// Documents every query is scored against alongside its positive
+    pub negatives: Vec<Vec<Vec<f32>>>,
+    #[serde(default)]
+    pub prune: Option<PruneConfig>,
+    /// Softmax temperature applied to the candidate scores
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+}
+
+/// How one query's positive fared against the negatives
+#[derive(Debug, Serialize)]
+pub struct ContrastiveResult {
+    /// 1-based rank of the positive among its candidates
+    pub positive_rank: usize,
+    pub positive_score: f32,
+    /// Softmax probability of the positive over `[positive] + negatives`
+    pub positive_probability: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContrastiveResponse {
+    /// One entry per query, in request order
+    pub results: Vec<ContrastiveResult>,
+    pub mean_reciprocal_rank: f32,
+}
+
+/// Softmax probability of `scores[target]` at the given temperature
+pub fn softmax_probability(scores: &[f32], target: usize, temperature: f32) -> f32 {
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let total: f32 = scores.iter().map(|&s| ((s - max) / temperature).exp()).sum();
+    ((scores[target] - max) / temperature).exp() / total
+}
+
+/// Score each query against its positive followed by the shared negatives
+pub fn score_contrastive(request: &ContrastiveRequest, prune: &PruneConfig) -> Result<ContrastiveResponse, ScoreError> {
+    let options = ScoreOptions::default();
+    let results: Vec<ContrastiveResult> = request
+        .queries
+        .iter()
+        .zip(&request.positives)
+        .map(|(query, positive)| {
+            let mut candidates = Vec::with_capacity(request.negatives.len() + 1);
+            candidates.push(positive.clone());
+            candidates.extend(request.negatives.iter().cloned());
+            let output =
+                score_docs_with_options(query, &candidates, candidates.len(), prune, &options, &NoopPostScorer)?;
+
+            // Map ranked scores back to candidate order; the positive is 0
+            let mut scores = vec![0.0; candidates.len()];
+            for (&idx, &score) in output.order.iter().zip(&output.scores) {
+                scores[idx] = score;
+            }
+            let rank = output.order.iter().position(|&idx| idx == 0).unwrap_or(0) + 1;
+            Ok(ContrastiveResult {
+                positive_rank: rank,
+                positive_score: scores[0],
+                positive_probability: softmax_probability(&scores, 0, request.temperature),
+            })
+        })
+        .collect::<Result<_, ScoreError>>()?;
+
+    let mean_reciprocal_rank = if results.is_empty() {
+        0.0
+    } else {
+        results.iter().map(|r| 1.0 / r.positive_rank as f32).sum::<f32>() / results.len() as f32
+    };
+    Ok(ContrastiveResponse { results, mean_reciprocal_rank })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_positive_ranks_first() {
+        let request = ContrastiveRequest {
+            queries: vec![vec![vec![1.0, 0.0]], vec![vec![0.0, 1.0]]],
+            positives: vec![vec![vec![1.0, 0.0]], vec![vec![0.0, 1.0]]],
+            negatives: vec![vec![vec![0.6, 0.8]], vec![vec![-1.0, 0.0]]],
+            prune: None,
+            temperature: 1.0,
+        };
+        let response = score_contrastive(&request, &PruneConfig::default()).unwrap();
+
+        assert_eq!(response.results.len(), 2);
+        for result in &response.results {
+            assert_eq!(result.positive_rank, 1);
+            assert!((result.positive_score - 1.0).abs() < 1e-6);
+            assert!(result.positive_probability > 0.4 && result.positive_probability < 1.0);
+        }
+        // Query 0 scores the negatives 0.6 and -1.0
+        let e = |x: f32| x.exp();
+        let expected = e(1.0) / (e(1.0) + e(0.6) + e(-1.0));
+        assert!((response.results[0].positive_probability - expected).abs() < 1e-5);
+        assert_eq!(response.mean_reciprocal_rank, 1.0);
+    }
+}
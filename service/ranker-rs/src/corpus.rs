@@ -0,0 +1,323 @@
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::embeddings::Dtype;
+use crate::scoring::{score_docs, Layout, PruneConfig, RerankRequest, RerankResponse, ScoreOptions};
+
+/// One document stored in the server-side corpus
+#[derive(Debug, Deserialize)]
+pub struct CorpusDoc {
+    pub id: u64,
+    pub tokens: Vec<Vec<f32>>,
+}
+
+/// Documents to add to (or replace in) a tenant's corpus
+#[derive(Debug, Deserialize)]
+pub struct UploadRequest {
+    pub docs: Vec<CorpusDoc>,
+    /// Tenant namespace; may also be sent as the `x-tenant-id` header
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    /// Documents written by this upload
+    pub stored: usize,
+    /// Documents in the tenant's corpus afterwards
+    pub total: usize,
+}
+
+fn default_dedup() -> bool {
+    true
+}
+
+/// Rerank documents already uploaded to the corpus, referenced by id
+#[derive(Debug, Deserialize)]
+pub struct RerankByIdRequest {
+    /// Tenant namespace; may also be sent as the `x-tenant-id` header
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    pub q_tokens: Vec<Vec<f32>>,
+    pub candidate_ids: Vec<u64>,
+    pub topk: usize,
+    #[serde(default)]
+    pub prune: Option<PruneConfig>,
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Duplicate `candidate_ids` policy. `true` (the default) keeps the first
+    /// occurrence of each id and logs a warning; `false` rejects the request.
+    #[serde(default = "default_dedup")]
+    pub dedup_candidates: bool,
+    #[serde(flatten)]
+    pub options: ScoreOptions,
+}
+
+impl RerankByIdRequest {
+    /// Build a plain rerank request over the given candidate documents
+    pub fn into_rerank(self, d_tokens: Vec<Vec<Vec<f32>>>) -> RerankRequest {
+        RerankRequest {
+            q_tokens: self.q_tokens,
+            d_tokens,
+            dtype: Dtype::F32,
+            d_ids: None,
+            d_quant: None,
+            d_scales: None,
+            topk: self.topk,
+            prune: self.prune,
+            model: self.model,
+            layout: Layout::RowMajor,
+            snapshot: false,
+            prev_snapshot_id: None,
+            allow_empty_candidates: false,
+            session_id: None,
+            smoothing: None,
+            options: self.options,
+        }
+    }
+}
+
+/// Rerank response with `order` mapped back to corpus ids
+#[derive(Debug, Serialize)]
+pub struct RerankByIdResponse {
+    /// Corpus id of each returned document, best first
+    pub ids: Vec<u64>,
+    #[serde(flatten)]
+    pub response: RerankResponse,
+}
+
+/// Remove repeated ids, keeping each id's first occurrence.
+/// Returns the unique ids and how many duplicates were dropped.
+pub fn dedup_ids(ids: &[u64]) -> (Vec<u64>, usize) {
+    let mut seen = HashSet::with_capacity(ids.len());
+    let unique: Vec<u64> = ids.iter().copied().filter(|id| seen.insert(*id)).collect();
+    let dropped = ids.len() - unique.len();
+    (unique, dropped)
+}
+
+/// Header naming the tenant whose corpus a request addresses
+pub const TENANT_HEADER: &str = "x-tenant-id";
+
+/// Tenant used when a request names none
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Pick the tenant from the header and/or body field, which must agree
+pub fn resolve_tenant(header: Option<&str>, field: Option<&str>) -> Result<String, String> {
+    match (header, field) {
+        (Some(h), Some(f)) if h != f => {
+            Err(format!("tenant header '{}' does not match tenant_id '{}'", h, f))
+        }
+        (Some(tenant), _) | (None, Some(tenant)) => Ok(tenant.to_string()),
+        (None, None) => Ok(DEFAULT_TENANT.to_string()),
+    }
+}
+
+/// Why an upload was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadError {
+    /// The tenant would exceed `max_docs_per_tenant`
+    TenantFull { limit: usize, requested: usize },
+}
+
+type TenantDocs = HashMap<u64, Vec<Vec<f32>>>;
+type Tenants = HashMap<String, TenantDocs>;
+
+/// Checks applied to the standby corpus before it may be promoted
+#[derive(Debug, Deserialize)]
+pub struct ValidateStandbyRequest {
+    /// Embedding dimension every staged token must have
+    pub dim: usize,
+    /// Fewest documents the standby must hold across all tenants
+    #[serde(default)]
+    pub min_docs: usize,
+}
+
+/// Outcome of validating the standby corpus
+#[derive(Debug, Serialize)]
+pub struct StandbyReport {
+    pub valid: bool,
+    pub docs: usize,
+    /// Human-readable reasons the standby was rejected
+    pub problems: Vec<String>,
+}
+
+/// Why the standby corpus could not be promoted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromoteError {
+    NoStandby,
+    NotValidated,
+}
+
+/// Documents sampled per tenant for the standby sanity rerank
+const SANITY_SAMPLE_DOCS: usize = 8;
+
+#[derive(Debug, Default)]
+struct Standby {
+    tenants: Tenants,
+    /// Cleared by every stage, so promotion always sees validated contents
+    validated: bool,
+}
+
+/// Shared in-memory document store, namespaced by tenant
+///
+/// Ids are only resolved within the caller's tenant, so another tenant's
+/// documents are indistinguishable from ids that don't exist.
+///
+/// A replacement corpus can be staged as a standby next to the active one,
+/// validated, and then promoted with a single atomic swap. Requests keep
+/// reading the old corpus until the swap. An upload racing a promotion
+/// either completes before the swap, and is replaced with the rest of the
+/// old corpus, or is written to the promoted one.
+#[derive(Debug, Clone, Default)]
+pub struct Corpus {
+    active: Arc<ArcSwap<RwLock<Tenants>>>,
+    standby: Arc<Mutex<Option<Standby>>>,
+    /// Per-tenant document cap; unlimited when `None`
+    max_docs_per_tenant: Option<usize>,
+}
+
+/// Insert `docs` into `store` unless that would exceed `limit`
+fn insert_docs(store: &mut TenantDocs, docs: Vec<CorpusDoc>, limit: Option<usize>) -> Result<usize, UploadError> {
+    if let Some(limit) = limit {
+        let new_ids: HashSet<u64> = docs
+            .iter()
+            .map(|doc| doc.id)
+            .filter(|id| !store.contains_key(id))
+            .collect();
+        let requested = store.len() + new_ids.len();
+        if requested > limit {
+            return Err(UploadError::TenantFull { limit, requested });
+        }
+    }
+    for doc in docs {
+        store.insert(doc.id, doc.tokens);
+    }
+    Ok(store.len())
+}
+
+/// Problems that make `tenants` unfit to serve `dim`-dimensional queries
+fn standby_problems(tenants: &Tenants, request: &ValidateStandbyRequest) -> Vec<String> {
+    let mut problems = Vec::new();
+    let docs: usize = tenants.values().map(|docs| docs.len()).sum();
+    if docs < request.min_docs {
+        problems.push(format!("standby holds {} documents, expected at least {}", docs, request.min_docs));
+    }
+    for (tenant, store) in tenants {
+        for (id, tokens) in store {
+            if tokens.is_empty() {
+                problems.push(format!("tenant '{}' document {} has no tokens", tenant, id));
+            } else if let Some(token) = tokens.iter().find(|t| t.len() != request.dim) {
+                problems.push(format!(
+                    "tenant '{}' document {} has dimension {}, expected {}",
+                    tenant, id, token.len(), request.dim
+                ));
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    // Rerank a sample of each tenant against its first document's tokens
+    for (tenant, store) in tenants {
+        let sample: Vec<Vec<Vec<f32>>> = store.values().take(SANITY_SAMPLE_DOCS).cloned().collect();
+        let Some(query) = sample.first() else { continue };
+        match score_docs(query, &sample, sample.len(), &PruneConfig::default()) {
+            Ok((_, scores, _)) if scores.iter().any(|s| !s.is_finite()) => {
+                problems.push(format!("tenant '{}' sample rerank produced non-finite scores", tenant));
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(format!("tenant '{}' sample rerank failed: {}", tenant, e)),
+        }
+    }
+    problems
+}
+
+impl Corpus {
+    pub fn with_tenant_limit(max_docs_per_tenant: usize) -> Self {
+        Self { max_docs_per_tenant: Some(max_docs_per_tenant), ..Default::default() }
+    }
+
+    /// Insert documents into `tenant`, replacing any with the same id.
+    /// Returns the tenant's new size; nothing is written if over the limit.
+    pub fn insert(&self, tenant: &str, docs: Vec<CorpusDoc>) -> Result<usize, UploadError> {
+        loop {
+            let active = self.active.load_full();
+            let mut tenants = active.write().unwrap();
+            // `promote` swaps while holding the retired corpus' write lock,
+            // so a corpus still current here stays current until we're done
+            if !Arc::ptr_eq(&active, &self.active.load()) {
+                continue;
+            }
+            return insert_docs(tenants.entry(tenant.to_string()).or_default(), docs, self.max_docs_per_tenant);
+        }
+    }
+
+    /// Copy out `tenant`'s tokens for `ids` in order, or the first unknown id
+    pub fn fetch(&self, tenant: &str, ids: &[u64]) -> Result<Vec<Vec<Vec<f32>>>, u64> {
+        let active = self.active.load();
+        let tenants = active.read().unwrap();
+        let store = tenants.get(tenant);
+        ids.iter()
+            .map(|id| store.and_then(|docs| docs.get(id)).cloned().ok_or(*id))
+            .collect()
+    }
+
+    /// Documents stored for `tenant`
+    pub fn tenant_len(&self, tenant: &str) -> usize {
+        self.active.load().read().unwrap().get(tenant).map_or(0, |docs| docs.len())
+    }
+
+    /// Distinct token dimensions among `tenant`'s documents, ascending.
+    /// Visits every stored token.
+    pub fn tenant_dims(&self, tenant: &str) -> Vec<usize> {
+        let active = self.active.load();
+        let tenants = active.read().unwrap();
+        let dims: BTreeSet<usize> =
+            tenants.get(tenant).into_iter().flat_map(|docs| docs.values().flatten()).map(|token| token.len()).collect();
+        dims.into_iter().collect()
+    }
+
+    /// Add documents to `tenant` in the standby corpus, creating it if needed.
+    /// Returns the tenant's standby size and invalidates earlier validation.
+    pub fn stage(&self, tenant: &str, docs: Vec<CorpusDoc>) -> Result<usize, UploadError> {
+        let mut standby = self.standby.lock().unwrap();
+        let standby = standby.get_or_insert_with(Standby::default);
+        standby.validated = false;
+        insert_docs(standby.tenants.entry(tenant.to_string()).or_default(), docs, self.max_docs_per_tenant)
+    }
+
+    /// Check dimensions, document count and a sample rerank of the standby.
+    /// Returns `None` when nothing is staged.
+    pub fn validate_standby(&self, request: &ValidateStandbyRequest) -> Option<StandbyReport> {
+        let mut standby = self.standby.lock().unwrap();
+        let standby = standby.as_mut()?;
+        let problems = standby_problems(&standby.tenants, request);
+        standby.validated = problems.is_empty();
+        Some(StandbyReport {
+            valid: standby.validated,
+            docs: standby.tenants.values().map(|docs| docs.len()).sum(),
+            problems,
+        })
+    }
+
+    /// Atomically replace the active corpus with the validated standby.
+    /// Returns the number of documents now active.
+    pub fn promote(&self) -> Result<usize, PromoteError> {
+        let mut slot = self.standby.lock().unwrap();
+        match slot.as_ref() {
+            None => return Err(PromoteError::NoStandby),
+            Some(standby) if !standby.validated => return Err(PromoteError::NotValidated),
+            Some(_) => {}
+        }
+        let tenants = slot.take().unwrap().tenants;
+        let docs = tenants.values().map(|docs| docs.len()).sum();
+        // Hold back uploads to the retired corpus until the swap is visible
+        let retired = self.active.load_full();
+        let _writers = retired.write().unwrap();
+        self.active.store(Arc::new(RwLock::new(tenants)));
+        Ok(docs)
+    }
+}
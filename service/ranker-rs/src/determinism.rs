@@ -0,0 +1,114 @@
+//! Reproducibility checks across rayon thread counts
+//!
+//! Scoring is parallel over documents, so a reduction that depended on
+//! scheduling would show up as rankings that change with the thread count.
+//! `rerank_deterministic_check` runs one request in a dedicated pool per
+//! thread count and compares every run bit-for-bit with the first, for CI
+//! jobs that guard determinism.
+
+use serde::Serialize;
+
+use crate::scoring::{score_docs_with_options, NoopPostScorer, RerankRequest, ScoreOutput};
+
+/// First place where a run differs from the reference run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Divergence {
+    /// Thread count of the differing run
+    pub threads: usize,
+    /// Position in the returned top-K (0-based)
+    pub rank: usize,
+    /// `(doc_index, score)` at `rank` in the reference run, if it has one
+    pub expected: Option<(usize, f32)>,
+    /// `(doc_index, score)` at `rank` in the differing run, if it has one
+    pub actual: Option<(usize, f32)>,
+}
+
+/// Outcome of `rerank_deterministic_check`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeterminismReport {
+    /// Thread counts run, in order; the first is the reference
+    pub thread_counts: Vec<usize>,
+    /// True when every run returned the same order and score bits
+    pub identical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub divergence: Option<Divergence>,
+}
+
+/// Compare two `(order, scores)` rankings rank by rank
+fn first_divergence(reference: (&[usize], &[f32]), run: (&[usize], &[f32]), threads: usize) -> Option<Divergence> {
+    let entry = |(order, scores): (&[usize], &[f32]), rank: usize| order.get(rank).map(|&idx| (idx, scores[rank]));
+    let len = reference.0.len().max(run.0.len());
+    (0..len).find_map(|rank| {
+        let (expected, actual) = (entry(reference, rank), entry(run, rank));
+        let same = match (expected, actual) {
+            (Some(a), Some(b)) => a.0 == b.0 && a.1.to_bits() == b.1.to_bits(),
+            _ => false,
+        };
+        (!same).then_some(Divergence { threads, rank, expected, actual })
+    })
+}
+
+/// Score `request` once per entry of `thread_counts`, each in its own rayon
+/// pool, and report whether the top-K orders and scores are bit-identical to
+/// the first run, with the first divergence if not. Uses the exhaustive
+/// path with the request's prune config and options as given.
+pub fn rerank_deterministic_check(request: &RerankRequest, thread_counts: &[usize]) -> Result<DeterminismReport, String> {
+    let prune = request.prune.clone().unwrap_or_default();
+    let mut reference: Option<ScoreOutput> = None;
+    let mut divergence = None;
+    for &threads in thread_counts {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(|e| format!("thread pool of {}: {}", threads, e))?;
+        let out = pool
+            .install(|| {
+                score_docs_with_options(
+                    &request.q_tokens, &request.d_tokens, request.topk, &prune, &request.options, &NoopPostScorer,
+                )
+            })
+            .map_err(|e| e.to_string())?;
+        match &reference {
+            None => reference = Some(out),
+            Some(first) => {
+                divergence = first_divergence((&first.order, &first.scores), (&out.order, &out.scores), threads);
+                if divergence.is_some() {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(DeterminismReport { thread_counts: thread_counts.to_vec(), identical: divergence.is_none(), divergence })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_exhaustive_path_is_identical_across_thread_counts() {
+        let mut rng = StdRng::seed_from_u64(53);
+        let mut random_tokens = |n: usize| -> Vec<Vec<f32>> {
+            (0..n).map(|_| (0..32).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()
+        };
+        let q_tokens = random_tokens(8);
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..64).map(|_| random_tokens(16)).collect();
+        let request: RerankRequest = serde_json::from_value(serde_json::json!({
+            "q_tokens": q_tokens,
+            "d_tokens": d_tokens,
+            "topk": 10
+        }))
+        .unwrap();
+
+        let report = rerank_deterministic_check(&request, &[1, 2, 4]).unwrap();
+        assert!(report.identical, "{:?}", report.divergence);
+        assert_eq!(report.thread_counts, vec![1, 2, 4]);
+
+        // A differing run is located at the first rank that changed
+        let scores = [0.9, 0.5];
+        let divergence = first_divergence((&[3, 1], &scores), (&[3, 2], &scores), 2).unwrap();
+        assert_eq!((divergence.rank, divergence.expected, divergence.actual), (1, Some((1, 0.5)), Some((2, 0.5))));
+    }
+}
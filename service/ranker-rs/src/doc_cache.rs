@@ -0,0 +1,319 @@
+//! Server-side cache of pruned, normalized document matrices
+//!
+//! Documents are pruned and L2-normalized once at upload and stored under
+//! their caller-supplied id together with a fingerprint of the prune config
+//! they were pruned with, so the same id may be cached once per config. A
+//! `/rerank` request sending `d_ids` instead of `d_tokens` scores against
+//! these matrices and skips both steps. When the cache is full, the least
+//! recently used entry (by upload or rerank) is evicted.
+
+use nalgebra::DMatrix;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::coalesce::debug_hash;
+use crate::scoring::{
+    log_score, normalized_matrix, percentile, prune_token_indices, result_hash, score_with_mode, EffectivePrune,
+    MaxSimConfig, PerfStats, PruneConfig, PruneStats, ScoreError, ScoreOptions, ScoreOutput, ScoreStats,
+    DEFAULT_LSE_TEMPERATURE, DEFAULT_NORM_EPS, QUERY_AFFINITY,
+};
+
+/// Per-request options `score_cached_docs` implements, by request field name
+pub const CACHED_OPTIONS: &[&str] = &[
+    "calibration",
+    "direction",
+    "log_scores",
+    "lse_temperature",
+    "q_idf",
+    "q_weights",
+    "relu_sim",
+    "result_hash",
+    "symmetric",
+];
+
+/// Entries kept when `RERANKER_DOC_CACHE_CAPACITY` is unset
+pub const DEFAULT_DOC_CACHE_CAPACITY: usize = 10_000;
+
+/// Documents to prune, normalize and cache under the given ids
+#[derive(Debug, Deserialize)]
+pub struct CacheUploadRequest {
+    pub doc_ids: Vec<String>,
+    pub d_tokens: Vec<Vec<Vec<f32>>>,
+    /// Prune config the documents are cached for; reranks must send the same
+    #[serde(default)]
+    pub prune: Option<PruneConfig>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheUploadResponse {
+    /// Documents written by this upload
+    pub stored: usize,
+    /// Entries evicted to make room for them
+    pub evicted: usize,
+    /// Entries in the cache afterwards
+    pub cached: usize,
+}
+
+/// One cached document, ready to score
+#[derive(Debug)]
+pub struct CachedDoc {
+    /// Token count before pruning
+    pub tokens_in: usize,
+    /// Kept tokens as L2-normalized rows
+    pub matrix: DMatrix<f32>,
+}
+
+type CacheKey = (String, u64);
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<CacheKey, (u64, Arc<CachedDoc>)>,
+    /// Last-use tick of every entry, oldest first
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &CacheKey) -> Option<Arc<CachedDoc>> {
+        self.tick += 1;
+        let (used, doc) = self.entries.get_mut(key)?;
+        self.recency.remove(used);
+        *used = self.tick;
+        self.recency.insert(self.tick, key.clone());
+        Some(doc.clone())
+    }
+}
+
+/// Bounded LRU map from `(doc id, prune fingerprint)` to a cached document
+#[derive(Debug, Clone)]
+pub struct DocCache {
+    capacity: usize,
+    lru: Arc<Mutex<Lru>>,
+}
+
+impl Default for DocCache {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_DOC_CACHE_CAPACITY)
+    }
+}
+
+/// Fingerprint identifying the pruning a cached document went through
+pub fn prune_key(prune: &PruneConfig) -> u64 {
+    debug_hash(prune)
+}
+
+/// Prune a document the way `/rerank` would, without query-dependent steps,
+/// and pack the kept tokens into a normalized matrix
+pub fn prepare_doc(tokens: &[Vec<f32>], prune: &PruneConfig) -> CachedDoc {
+    let capped = match prune.hard_doc_token_cap {
+        Some(cap) if tokens.len() > cap => &tokens[..cap],
+        _ => tokens,
+    };
+    let kept = prune_token_indices(capped, prune.d_budget(tokens.len()), &prune.method, None, None);
+    let kept: Vec<Vec<f32>> = kept.into_iter().map(|i| capped[i].clone()).collect();
+    CachedDoc { tokens_in: tokens.len(), matrix: normalized_matrix(&kept, DEFAULT_NORM_EPS) }
+}
+
+impl DocCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { capacity, lru: Arc::default() }
+    }
+
+    /// Insert documents, replacing any cached under the same id and prune
+    /// key. Returns how many documents were stored and how many older
+    /// entries were evicted; a zero-capacity cache stores nothing.
+    pub fn insert(&self, prune_key: u64, docs: Vec<(String, CachedDoc)>) -> (usize, usize) {
+        let mut lru = self.lru.lock().unwrap();
+        let (mut stored, mut evicted) = (0, 0);
+        for (id, doc) in docs {
+            let key = (id, prune_key);
+            if lru.touch(&key).is_none() {
+                if lru.entries.len() >= self.capacity {
+                    let Some((_, oldest)) = lru.recency.pop_first() else { break };
+                    lru.entries.remove(&oldest);
+                    evicted += 1;
+                }
+                let tick = lru.tick;
+                lru.recency.insert(tick, key.clone());
+                lru.entries.insert(key.clone(), (tick, Arc::new(doc)));
+            } else if let Some(entry) = lru.entries.get_mut(&key) {
+                entry.1 = Arc::new(doc);
+            }
+            stored += 1;
+        }
+        (stored, evicted)
+    }
+
+    /// Look up `ids` in order, marking each as recently used, or return the
+    /// first id not cached for this prune key
+    pub fn get(&self, prune_key: u64, ids: &[String]) -> Result<Vec<Arc<CachedDoc>>, String> {
+        let mut lru = self.lru.lock().unwrap();
+        ids.iter()
+            .map(|id| lru.touch(&(id.clone(), prune_key)).ok_or_else(|| id.clone()))
+            .collect()
+    }
+
+    /// Entries currently cached
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Score cached documents and return the top-K, like `score_docs_with_options`
+///
+/// Documents were pruned and normalized at upload, so only the query is
+/// pruned here (honouring `adapt_q_to_docs`). Supports `score_mode`,
+/// `direction`, `lse_temperature`, `relu_sim`, `q_idf`, `q_weights`,
+/// `calibration`, `log_scores` and `result_hash` (see `CACHED_OPTIONS`);
+/// other per-request options need the raw tokens and are not available on
+/// this path.
+pub fn score_cached_docs(
+    q_tokens: &[Vec<f32>],
+    docs: &[Arc<CachedDoc>],
+    topk: usize,
+    prune: &PruneConfig,
+    options: &ScoreOptions,
+) -> Result<ScoreOutput, ScoreError> {
+    let query_dim = q_tokens.first().map(|t| t.len()).ok_or(ScoreError::EmptyQuery)?;
+    if let Some((doc_index, doc)) = docs.iter().enumerate().find(|(_, doc)| doc.matrix.ncols() != query_dim) {
+        return Err(ScoreError::DimMismatch { query_dim, doc_index, doc_dim: doc.matrix.ncols() });
+    }
+    if let Some(doc_index) = docs.iter().position(|doc| doc.matrix.nrows() == 0) {
+        return Err(ScoreError::EmptyDocument { doc_index });
+    }
+    for (option, values) in [("q_idf", &options.q_idf), ("q_weights", &options.q_weights)] {
+        if let Some(len) = values.as_ref().map(Vec::len).filter(|&len| len != q_tokens.len()) {
+            return Err(ScoreError::MisalignedOption { option, len, expected: q_tokens.len() });
+        }
+    }
+
+    let q_method = if prune.method == QUERY_AFFINITY { "idf_norm" } else { &prune.method };
+    let min_doc_kept = docs.iter().map(|doc| doc.matrix.nrows()).min();
+    let q_budget = prune.q_budget_for_docs(q_tokens.len(), min_doc_kept);
+    let q_kept = prune_token_indices(q_tokens, q_budget, q_method, options.q_idf.as_deref(), None);
+    let pruned_q: Vec<Vec<f32>> = q_kept.iter().map(|&i| q_tokens[i].clone()).collect();
+    let q_matrix = normalized_matrix(&pruned_q, DEFAULT_NORM_EPS);
+    let score_mode = options.score_mode.unwrap_or_default();
+    let kept_weights: Option<Vec<f32>> =
+        options.q_weights.as_ref().map(|weights| q_kept.iter().map(|&i| weights[i]).collect());
+    let config = MaxSimConfig {
+        relu: options.relu_sim,
+        direction: options.effective_direction(),
+        weights: kept_weights.as_deref(),
+        temperature: options.lse_temperature.unwrap_or(DEFAULT_LSE_TEMPERATURE),
+        ..Default::default()
+    };
+
+    let mut results: Vec<(usize, f32, f32)> = docs
+        .par_iter()
+        .enumerate()
+        .map(|(idx, doc)| {
+            let start = std::time::Instant::now();
+            let score = score_with_mode(&q_matrix, &doc.matrix, score_mode, &config);
+            (idx, score, start.elapsed().as_secs_f32() * 1000.0)
+        })
+        .collect();
+
+    let mut times: Vec<f32> = results.iter().map(|r| r.2).collect();
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let perf = PerfStats {
+        per_doc_ms_p50: percentile(&times, 50.0),
+        per_doc_ms_p95: percentile(&times, 95.0),
+        sample_size: times.len(),
+    };
+
+    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    let cutoff_ties = match topk.min(results.len()).checked_sub(1).map(|k| results[k].1) {
+        Some(cutoff) => results.iter().filter(|r| r.1 == cutoff).count(),
+        None => 0,
+    };
+    results.truncate(topk);
+    let prune_stats = PruneStats {
+        q_tokens_in: q_tokens.len(),
+        q_tokens_kept: q_kept.len(),
+        docs: results.iter().map(|r| (docs[r.0].tokens_in, docs[r.0].matrix.nrows())).collect(),
+    };
+    let order: Vec<usize> = results.iter().map(|r| r.0).collect();
+    let scores: Vec<f32> = results.iter().map(|r| r.1).collect();
+    let stats = ScoreStats {
+        score_mode,
+        effective_prune: EffectivePrune::new(prune, q_budget, q_method),
+        cutoff_ties,
+        sanitized_values: options.sanitized_values,
+        result_hash: options.result_hash.then(|| format!("{:016x}", result_hash(&order, &scores))),
+        ..Default::default()
+    };
+    Ok(ScoreOutput {
+        ranks: (1..=results.len()).collect(),
+        perf,
+        stats,
+        probabilities: options.calibration.map(|calibration| scores.iter().map(|&s| calibration.probability(s)).collect()),
+        log_scores: options.log_scores.then(|| scores.iter().map(|&s| log_score(s)).collect()),
+        topks: None,
+        prune_stats,
+        order,
+        scores,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(tokens_in: usize) -> CachedDoc {
+        CachedDoc { tokens_in, matrix: DMatrix::from_element(1, 2, 0.5) }
+    }
+
+    fn ids(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_cache_hit_and_miss_respect_prune_key() {
+        let cache = DocCache::with_capacity(4);
+        cache.insert(1, vec![("a".into(), doc(3)), ("b".into(), doc(5))]);
+        let hits = cache.get(1, &ids(&["b", "a"])).unwrap();
+        assert_eq!(hits.iter().map(|d| d.tokens_in).collect::<Vec<_>>(), vec![5, 3]);
+        assert_eq!(cache.get(1, &ids(&["a", "c"])).unwrap_err(), "c");
+        // Same id, different prune config
+        assert_eq!(cache.get(2, &ids(&["a"])).unwrap_err(), "a");
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let cache = DocCache::with_capacity(2);
+        assert_eq!(cache.insert(1, vec![("a".into(), doc(1)), ("b".into(), doc(2))]), (2, 0));
+        // Reading "a" makes "b" the eviction candidate
+        cache.get(1, &ids(&["a"])).unwrap();
+        assert_eq!(cache.insert(1, vec![("c".into(), doc(3))]), (1, 1));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(1, &ids(&["b"])).is_err());
+        assert!(cache.get(1, &ids(&["a", "c"])).is_ok());
+        // Replacing a cached id evicts nothing
+        assert_eq!(cache.insert(1, vec![("c".into(), doc(4))]), (1, 0));
+        assert_eq!(cache.get(1, &ids(&["c"])).unwrap()[0].tokens_in, 4);
+    }
+
+    #[test]
+    fn test_zero_capacity_cache_stores_nothing() {
+        let cache = DocCache::with_capacity(0);
+        assert_eq!(cache.insert(1, vec![("a".into(), doc(1)), ("b".into(), doc(2))]), (0, 0));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_short_q_weights_are_rejected_not_indexed() {
+        let docs = vec![Arc::new(doc(1))];
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let options = ScoreOptions { q_weights: Some(vec![1.0]), ..Default::default() };
+        let err = score_cached_docs(&q_tokens, &docs, 1, &PruneConfig::default(), &options).unwrap_err();
+        assert_eq!(err, ScoreError::MisalignedOption { option: "q_weights", len: 1, expected: 2 });
+    }
+}
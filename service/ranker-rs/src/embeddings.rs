@@ -0,0 +1,339 @@
+//! Half-precision embedding payloads
+//!
+//! A rerank request may set `dtype` to `f16` or `bf16` and send every token
+//! of `q_tokens` and `d_tokens` as a base64 string of little-endian 16-bit
+//! values instead of an array of JSON numbers. That is about a quarter of
+//! the bytes of decimal f32 text. Each token is read as a `WireToken` in
+//! the same pass as the rest of the body, then widened to f32 once the
+//! request's `dtype` is known, so scoring never sees the wire format.
+
+use serde::de::{self, DeserializeOwned, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+use crate::scoring::RerankRequest;
+
+/// Element type of the token arrays in a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dtype {
+    /// JSON arrays of numbers
+    #[default]
+    F32,
+    /// IEEE 754 half precision, base64-encoded per token
+    F16,
+    /// bfloat16, base64-encoded per token
+    Bf16,
+}
+
+/// Widen IEEE 754 half-precision bits to f32 (exact)
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits & 0x8000) as u32) << 16;
+    let exp = ((bits >> 10) & 0x1f) as u32;
+    let mant = (bits & 0x3ff) as u32;
+    let magnitude = match exp {
+        0 => (mant as f32 * 2f32.powi(-24)).to_bits(),
+        0x1f => 0x7f80_0000 | (mant << 13),
+        _ => ((exp + 112) << 23) | (mant << 13),
+    };
+    f32::from_bits(sign | magnitude)
+}
+
+/// Narrow f32 to IEEE 754 half-precision bits, rounding to nearest even
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7f_ffff;
+    if exp == 0xff {
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+    let round = |value: u32, shift: u32| -> u32 {
+        let (kept, rest, half) = (value >> shift, value & ((1 << shift) - 1), 1 << (shift - 1));
+        if rest > half || (rest == half && kept & 1 == 1) { kept + 1 } else { kept }
+    };
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        sign | 0x7c00
+    } else if e <= 0 {
+        // Subnormal, or too small for even the smallest subnormal
+        if e < -10 {
+            return sign;
+        }
+        sign | round(mant | 0x80_0000, (14 - e) as u32) as u16
+    } else {
+        // A rounding carry into the exponent is still the right encoding
+        sign | round(((e as u32) << 23) | mant, 13) as u16
+    }
+}
+
+/// Widen bfloat16 bits to f32 (exact)
+pub fn bf16_to_f32(bits: u16) -> f32 {
+    f32::from_bits((bits as u32) << 16)
+}
+
+/// Narrow f32 to bfloat16 bits, rounding to nearest even
+pub fn f32_to_bf16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    if value.is_nan() {
+        return ((bits >> 16) | 0x40) as u16;
+    }
+    ((bits + 0x7fff + ((bits >> 16) & 1)) >> 16) as u16
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding
+pub fn encode_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode standard base64; padding is required
+pub fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return Err(format!("base64 length {} is not a multiple of 4", text.len()));
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for (n, chunk) in text.chunks(4).enumerate() {
+        let last = n + 1 == text.len() / 4;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err("misplaced base64 padding".to_string());
+        }
+        let mut group = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let sextet = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return Err(format!("invalid base64 character '{}'", c as char)),
+            };
+            group = group << 6 | sextet as u32;
+        }
+        group <<= 6 * padding;
+        out.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+/// Decode one base64 token of little-endian 16-bit values
+pub fn decode_token(text: &str, dtype: Dtype) -> Result<Vec<f32>, String> {
+    let bytes = decode_base64(text)?;
+    if bytes.is_empty() || !bytes.len().is_multiple_of(2) {
+        return Err(format!("token holds {} bytes, expected a positive even count", bytes.len()));
+    }
+    let widen = match dtype {
+        Dtype::F16 => f16_to_f32,
+        Dtype::Bf16 => bf16_to_f32,
+        Dtype::F32 => return Err("f32 tokens are sent as JSON arrays".to_string()),
+    };
+    Ok(bytes.chunks_exact(2).map(|pair| widen(u16::from_le_bytes([pair[0], pair[1]]))).collect())
+}
+
+/// Encode one token for a request with the given 16-bit `dtype`
+pub fn encode_token(token: &[f32], dtype: Dtype) -> String {
+    let narrow = match dtype {
+        Dtype::Bf16 => f32_to_bf16,
+        _ => f32_to_f16,
+    };
+    let bytes: Vec<u8> = token.iter().flat_map(|&x| narrow(x).to_le_bytes()).collect();
+    encode_base64(&bytes)
+}
+
+/// One token as sent: an array of JSON numbers, or a base64 string of
+/// 16-bit values that `widen` decodes according to the request's `dtype`
+#[derive(Debug, Clone, PartialEq)]
+pub enum WireToken {
+    Values(Vec<f32>),
+    Packed(String),
+}
+
+impl<'de> Deserialize<'de> for WireToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TokenVisitor;
+
+        impl<'de> Visitor<'de> for TokenVisitor {
+            type Value = WireToken;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an array of numbers or a base64 string")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<WireToken, A::Error> {
+                let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(value) = seq.next_element()? {
+                    values.push(value);
+                }
+                Ok(WireToken::Values(values))
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<WireToken, E> {
+                Ok(WireToken::Packed(text.to_string()))
+            }
+
+            fn visit_string<E: de::Error>(self, text: String) -> Result<WireToken, E> {
+                Ok(WireToken::Packed(text))
+            }
+        }
+
+        deserializer.deserialize_any(TokenVisitor)
+    }
+}
+
+impl WireToken {
+    /// The token as f32 values, decoding a packed token as `dtype`
+    pub fn widen(self, dtype: Dtype) -> Result<Vec<f32>, String> {
+        match (self, dtype) {
+            (WireToken::Values(values), Dtype::F32) => Ok(values),
+            (WireToken::Values(_), _) => Err("expected a base64 string".to_string()),
+            (WireToken::Packed(_), Dtype::F32) => Err("base64 tokens require dtype f16 or bf16".to_string()),
+            (WireToken::Packed(text), dtype) => decode_token(&text, dtype),
+        }
+    }
+}
+
+fn widen_tokens(tokens: Vec<WireToken>, dtype: Dtype) -> Result<Vec<Vec<f32>>, String> {
+    tokens.into_iter().map(|token| token.widen(dtype)).collect()
+}
+
+impl RerankRequest<WireToken> {
+    /// Widen every token to f32 according to `dtype`
+    pub fn widen(self) -> Result<RerankRequest, String> {
+        let dtype = self.dtype;
+        let q_tokens = widen_tokens(self.q_tokens, dtype).map_err(|e| format!("q_tokens: {}", e))?;
+        let d_tokens = self
+            .d_tokens
+            .into_iter()
+            .map(|doc| widen_tokens(doc, dtype))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("d_tokens: {}", e))?;
+        Ok(RerankRequest {
+            q_tokens,
+            d_tokens,
+            dtype: Dtype::F32,
+            d_ids: self.d_ids,
+            d_quant: self.d_quant,
+            d_scales: self.d_scales,
+            topk: self.topk,
+            prune: self.prune,
+            model: self.model,
+            layout: self.layout,
+            snapshot: self.snapshot,
+            prev_snapshot_id: self.prev_snapshot_id,
+            allow_empty_candidates: self.allow_empty_candidates,
+            session_id: self.session_id,
+            smoothing: self.smoothing,
+            options: self.options,
+        })
+    }
+}
+
+/// A request body whose tokens may be sent half precision
+pub trait WireRequest: Sized {
+    /// The body as deserialized, before its tokens are widened
+    type Wire: DeserializeOwned;
+
+    fn from_wire(wire: Self::Wire) -> Result<Self, String>;
+}
+
+impl WireRequest for RerankRequest {
+    type Wire = RerankRequest<WireToken>;
+
+    fn from_wire(wire: Self::Wire) -> Result<Self, String> {
+        wire.widen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scoring::{check_finite, score_docs, PruneConfig, ScoreError};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_half_precision_tokens_score_like_f32() {
+        let mut rng = StdRng::seed_from_u64(47);
+        let mut random_tokens = |n: usize| -> Vec<Vec<f32>> {
+            (0..n).map(|_| (0..16).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()
+        };
+        let q_tokens = random_tokens(4);
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..10).map(|_| random_tokens(6)).collect();
+        let (order, scores, _) = score_docs(&q_tokens, &d_tokens, 10, &PruneConfig::default()).unwrap();
+
+        for (dtype, name, tolerance) in [(Dtype::F16, "f16", 5e-3), (Dtype::Bf16, "bf16", 5e-2)] {
+            let encode = |tokens: &[Vec<f32>]| -> Vec<String> { tokens.iter().map(|t| encode_token(t, dtype)).collect() };
+            let body = serde_json::json!({
+                "dtype": name,
+                "q_tokens": encode(&q_tokens),
+                "d_tokens": d_tokens.iter().map(|doc| encode(doc)).collect::<Vec<_>>(),
+                "topk": 10
+            });
+            let wire: RerankRequest<WireToken> = serde_json::from_value(body).unwrap();
+            let request = wire.widen().unwrap();
+            let (half_order, half_scores, _) =
+                score_docs(&request.q_tokens, &request.d_tokens, 10, &PruneConfig::default()).unwrap();
+            for (idx, score) in order.iter().zip(&scores) {
+                let pos = half_order.iter().position(|i| i == idx).unwrap();
+                assert!((half_scores[pos] - score).abs() < tolerance, "{}: {} vs {}", name, half_scores[pos], score);
+            }
+        }
+    }
+
+    #[test]
+    fn test_f16_conversion_and_length_checks() {
+        for value in [0.0f32, -0.0, 1.0, -2.5, 65504.0, 6.1035156e-5, 5.9604645e-8] {
+            assert_eq!(f16_to_f32(f32_to_f16(value)).to_bits(), value.to_bits());
+        }
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert_eq!(decode_base64(&encode_base64(b"ranker")).unwrap(), b"ranker");
+        assert_eq!(decode_base64(&encode_base64(b"rank")).unwrap(), b"rank");
+
+        // Three bytes cannot hold whole 16-bit values
+        let odd = encode_base64(&[1, 2, 3]);
+        assert!(decode_token(&odd, Dtype::F16).is_err());
+        assert!(decode_token("AAA", Dtype::F16).unwrap_err().contains("multiple of 4"));
+        let body = serde_json::json!({ "dtype": "f16", "q_tokens": [odd], "d_tokens": [], "topk": 1 });
+        let wire: RerankRequest<WireToken> = serde_json::from_value(body).unwrap();
+        assert!(wire.widen().unwrap_err().starts_with("q_tokens"));
+
+        // Packed tokens need a 16-bit dtype and vice versa
+        let wire: RerankRequest<WireToken> = serde_json::from_value(serde_json::json!({
+            "q_tokens": [encode_base64(&[0, 60])], "topk": 1
+        }))
+        .unwrap();
+        assert!(wire.widen().is_err());
+    }
+
+    #[test]
+    fn test_non_finite_half_precision_tokens_decode_to_non_finite_f32() {
+        // f16 +Inf, -Inf and a quiet NaN
+        let bytes: Vec<u8> = [0x7c00u16, 0xfc00, 0x7e00].iter().flat_map(|bits| bits.to_le_bytes()).collect();
+        let body = serde_json::json!({ "dtype": "f16", "q_tokens": [encode_base64(&bytes)], "topk": 1 });
+        let wire: RerankRequest<WireToken> = serde_json::from_value(body).unwrap();
+        let request = wire.widen().unwrap();
+
+        let token = &request.q_tokens[0];
+        assert_eq!(token[..2], [f32::INFINITY, f32::NEG_INFINITY]);
+        assert!(token[2].is_nan());
+        assert_eq!(
+            check_finite(&request.q_tokens, &[]),
+            Err(ScoreError::NonFiniteValue { doc_index: None, token_index: 0 })
+        );
+        assert_eq!(bf16_to_f32(0x7f80), f32::INFINITY);
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+use crate::scoring::{
+    normalized_matrix, prune_token_indices, pruned_query_indices, query_row_matches, MaxSimConfig, PruneConfig,
+    ScoreError, ScoreOptions, DEFAULT_NORM_EPS, QUERY_AFFINITY,
+};
+
+/// One query and one document to align token by token
+#[derive(Debug, Deserialize)]
+pub struct ExplainRequest {
+    pub q_tokens: Vec<Vec<f32>>,
+    /// The document's tokens
+    pub d_tokens: Vec<Vec<f32>>,
+    /// Pruning applied before alignment, as on `/rerank`
+    #[serde(default)]
+    pub prune: Option<PruneConfig>,
+}
+
+/// The document token a query token matched in MaxSim
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenMatch {
+    /// Query token index in the request
+    pub q_index: usize,
+    /// Best-matching document token index in the request
+    pub d_index: usize,
+    /// Similarity of the pair, as summed into `score`
+    pub value: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExplainResponse {
+    /// MaxSim score, the sum of `matches[..].value`
+    pub score: f32,
+    /// One entry per query token kept by pruning, in request order
+    pub matches: Vec<TokenMatch>,
+    /// Document tokens kept by pruning
+    pub d_tokens_kept: usize,
+}
+
+/// Why `prune` can't be explained: stages that drop tokens at random or
+/// against the query lose the mapping back to request indices
+pub fn unexplainable_prune(prune: &PruneConfig) -> Option<&'static str> {
+    if prune.method == QUERY_AFFINITY {
+        Some("query_affinity pruning")
+    } else if prune.token_dropout > 0.0 {
+        Some("token_dropout")
+    } else if prune.reservoir_sample.is_some() {
+        Some("reservoir_sample")
+    } else {
+        None
+    }
+}
+
+/// Prune both sides as `/rerank` would and report each kept query token's
+/// best document token. The score matches `/rerank`'s `maxsim` score.
+pub fn explain_pair(request: &ExplainRequest, prune: &PruneConfig) -> Result<ExplainResponse, ScoreError> {
+    if request.q_tokens.is_empty() {
+        return Err(ScoreError::EmptyQuery);
+    }
+    if request.d_tokens.is_empty() {
+        return Err(ScoreError::EmptyDocument { doc_index: 0 });
+    }
+
+    let q_kept = pruned_query_indices(&request.q_tokens, prune, &ScoreOptions::default());
+    let capped = match prune.hard_doc_token_cap {
+        Some(cap) if request.d_tokens.len() > cap => &request.d_tokens[..cap],
+        _ => &request.d_tokens[..],
+    };
+    let d_kept = prune_token_indices(capped, prune.d_budget(request.d_tokens.len()), &prune.method, None, None);
+
+    let pick = |tokens: &[Vec<f32>], kept: &[usize]| -> Vec<Vec<f32>> { kept.iter().map(|&i| tokens[i].clone()).collect() };
+    let q_matrix = normalized_matrix(&pick(&request.q_tokens, &q_kept), DEFAULT_NORM_EPS);
+    let d_matrix = normalized_matrix(&pick(capped, &d_kept), DEFAULT_NORM_EPS);
+
+    let mut matches: Vec<TokenMatch> = query_row_matches(&q_matrix, &d_matrix, &MaxSimConfig::default())
+        .into_iter()
+        .zip(&q_kept)
+        .map(|((row, value), &q_index)| TokenMatch { q_index, d_index: d_kept[row], value })
+        .collect();
+    // Sum in scoring order so the total is bit-identical to `maxsim_score`
+    let score = matches.iter().map(|m| m.value).sum();
+    matches.sort_by_key(|m| m.q_index);
+    Ok(ExplainResponse { score, matches, d_tokens_kept: d_kept.len() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scoring::score_docs;
+
+    #[test]
+    fn test_explain_reports_argmax_document_tokens() {
+        let request = ExplainRequest {
+            q_tokens: vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.6, 0.8]],
+            d_tokens: vec![vec![0.0, 2.0], vec![0.8, 0.6], vec![3.0, 0.0], vec![0.6, 0.8]],
+            prune: None,
+        };
+        let prune = PruneConfig::default();
+        let response = explain_pair(&request, &prune).unwrap();
+
+        let pairs: Vec<(usize, usize)> = response.matches.iter().map(|m| (m.q_index, m.d_index)).collect();
+        assert_eq!(pairs, vec![(0, 2), (1, 0), (2, 3)]);
+        for m in &response.matches {
+            assert!((m.value - 1.0).abs() < 1e-6);
+        }
+        assert_eq!(response.d_tokens_kept, 4);
+
+        let (_, scores, _) = score_docs(&request.q_tokens, std::slice::from_ref(&request.d_tokens), 1, &prune).unwrap();
+        assert_eq!(response.score, scores[0]);
+    }
+}
@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::scoring::{RerankRequest, RerankResponse};
+
+/// Batch of rerank requests processed in the background
+#[derive(Debug, Deserialize)]
+pub struct JobRequest {
+    pub requests: Vec<RerankRequest>,
+}
+
+/// Lifecycle of a batch job
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobState {
+    Pending,
+    Running,
+    /// Results are on disk at `JobStore::result_path`
+    Done,
+    Failed { error: String },
+}
+
+impl JobState {
+    fn is_finished(&self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed { .. })
+    }
+}
+
+/// How long finished jobs and their result files are kept by default
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// In-memory job table with results persisted as JSON files.
+///
+/// Finished jobs are forgotten, and their result files deleted, once they
+/// are older than the retention period; expired jobs are swept whenever a
+/// new job is created. Result files left by an earlier process are still
+/// served until they expire.
+#[derive(Debug, Clone)]
+pub struct JobStore {
+    dir: PathBuf,
+    retention: Duration,
+    next_seq: Arc<AtomicU64>,
+    /// Each job's state and when it last changed
+    states: Arc<Mutex<HashMap<String, (JobState, SystemTime)>>>,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        Self::new(std::env::temp_dir().join("ranker-jobs"))
+    }
+}
+
+impl JobStore {
+    /// Store results under `dir`, created on first write
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self::with_retention(dir, DEFAULT_RETENTION)
+    }
+
+    /// Like `new`, keeping finished jobs for `retention`
+    pub fn with_retention(dir: impl Into<PathBuf>, retention: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            retention,
+            next_seq: Arc::new(AtomicU64::new(0)),
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Register a new pending job and return its id
+    pub fn create(&self) -> String {
+        // The timestamp keeps ids unique across restarts sharing a directory
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+        let id = format!("{}-{}", millis, self.next_seq.fetch_add(1, Ordering::Relaxed));
+        self.set(&id, JobState::Pending);
+        self.prune_expired();
+        id
+    }
+
+    pub fn set(&self, id: &str, state: JobState) {
+        self.states.lock().unwrap().insert(id.to_string(), (state, SystemTime::now()));
+    }
+
+    /// State of a job, falling back to result files written before a restart
+    pub fn get(&self, id: &str) -> Option<JobState> {
+        if let Some((state, _)) = self.states.lock().unwrap().get(id) {
+            return Some(state.clone());
+        }
+        // Ids are `<millis>-<seq>`; anything else cannot name a result file
+        let well_formed = !id.is_empty() && id.chars().all(|c| c.is_ascii_digit() || c == '-');
+        (well_formed && self.result_path(id).exists()).then_some(JobState::Done)
+    }
+
+    /// Forget finished jobs and delete result files older than the retention
+    /// period, returning how many result files were removed
+    pub fn prune_expired(&self) -> usize {
+        let now = SystemTime::now();
+        let expired = |at: SystemTime| now.duration_since(at).is_ok_and(|age| age > self.retention);
+        self.states
+            .lock()
+            .unwrap()
+            .retain(|_, (state, at)| !(state.is_finished() && expired(*at)));
+
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return 0;
+        };
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let modified = entry.metadata().and_then(|m| m.modified());
+            if modified.is_ok_and(expired) && std::fs::remove_file(&path).is_ok() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// File holding a finished job's results
+    pub fn result_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Write a job's results to disk and mark it done
+    pub fn finish(&self, id: &str, results: &[RerankResponse]) {
+        let state = match self.write_results(id, results) {
+            Ok(()) => JobState::Done,
+            Err(e) => JobState::Failed { error: format!("failed to persist results: {}", e) },
+        };
+        self.set(id, state);
+    }
+
+    fn write_results(&self, id: &str, results: &[RerankResponse]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let json = serde_json::to_vec(results)?;
+        std::fs::write(self.result_path(id), json)
+    }
+
+    /// Persisted results of a finished job
+    pub fn read_results(&self, id: &str) -> std::io::Result<serde_json::Value> {
+        let bytes = std::fs::read(self.result_path(id))?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ranker-jobs-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_get_falls_back_to_results_on_disk() {
+        let dir = test_dir("restart");
+        let store = JobStore::new(&dir);
+        let id = store.create();
+        store.finish(&id, &[]);
+
+        let restarted = JobStore::new(&dir);
+        assert_eq!(restarted.get(&id), Some(JobState::Done));
+        assert_eq!(restarted.read_results(&id).unwrap(), serde_json::json!([]));
+        assert_eq!(restarted.get("missing"), None);
+        assert_eq!(restarted.get("../jobs"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_expired_drops_finished_jobs_only() {
+        let dir = test_dir("retention");
+        let store = JobStore::with_retention(&dir, Duration::ZERO);
+        let done = store.create();
+        let running = store.create();
+        store.finish(&done, &[]);
+        store.set(&running, JobState::Running);
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(store.prune_expired(), 1);
+        assert_eq!(store.get(&done), None);
+        assert!(!store.result_path(&done).exists());
+        assert_eq!(store.get(&running), Some(JobState::Running));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Dot-product kernel signature shared by all implementations
+pub type DotKernel = fn(&[f32], &[f32]) -> f32;
+
+/// SIMD features detected on the running CPU
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuCaps {
+    pub avx2: bool,
+    pub avx512f: bool,
+}
+
+impl CpuCaps {
+    /// Detect features at runtime
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            Self {
+                avx2: std::arch::is_x86_feature_detected!("avx2"),
+                avx512f: std::arch::is_x86_feature_detected!("avx512f"),
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            Self::default()
+        }
+    }
+
+    /// Short label for the widest available instruction set
+    pub fn label(&self) -> &'static str {
+        if self.avx512f {
+            "AVX-512"
+        } else if self.avx2 {
+            "AVX2"
+        } else {
+            "SSE"
+        }
+    }
+}
+
+/// Portable dot product
+pub fn dot_scalar(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// AVX2 dot product; only selected by `select_kernel` when AVX2 is present
+#[cfg(target_arch = "x86_64")]
+fn dot_avx2(a: &[f32], b: &[f32]) -> f32 {
+    // SAFETY: `select_kernel` only hands out this kernel when the CPU reports AVX2
+    unsafe { dot_avx2_impl(a, b) }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn dot_avx2_impl(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+
+    let n = a.len().min(b.len());
+    let split = n - n % 8;
+    let mut acc = _mm256_setzero_ps();
+    for i in (0..split).step_by(8) {
+        let va = _mm256_loadu_ps(a.as_ptr().add(i));
+        let vb = _mm256_loadu_ps(b.as_ptr().add(i));
+        acc = _mm256_add_ps(acc, _mm256_mul_ps(va, vb));
+    }
+
+    let mut lanes = [0.0f32; 8];
+    _mm256_storeu_ps(lanes.as_mut_ptr(), acc);
+    lanes.iter().sum::<f32>() + dot_scalar(&a[split..n], &b[split..n])
+}
+
+/// AVX-512 dot product; only selected when AVX-512F is present
+#[cfg(target_arch = "x86_64")]
+fn dot_avx512(a: &[f32], b: &[f32]) -> f32 {
+    // SAFETY: kernels are only activated when the CPU reports AVX-512F
+    unsafe { dot_avx512_impl(a, b) }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn dot_avx512_impl(a: &[f32], b: &[f32]) -> f32 {
+    use std::arch::x86_64::*;
+
+    let n = a.len().min(b.len());
+    let split = n - n % 16;
+    let mut acc = _mm512_setzero_ps();
+    for i in (0..split).step_by(16) {
+        let va = _mm512_loadu_ps(a.as_ptr().add(i));
+        let vb = _mm512_loadu_ps(b.as_ptr().add(i));
+        acc = _mm512_fmadd_ps(va, vb, acc);
+    }
+
+    _mm512_reduce_add_ps(acc) + dot_scalar(&a[split..n], &b[split..n])
+}
+
+/// Dot-product implementations the server can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelKind {
+    Scalar,
+    Avx2,
+    Avx512,
+}
+
+impl KernelKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KernelKind::Scalar => "scalar",
+            KernelKind::Avx2 => "avx2",
+            KernelKind::Avx512 => "avx512",
+        }
+    }
+
+    /// Whether `caps` can run this kernel
+    pub fn supported(&self, caps: CpuCaps) -> bool {
+        match self {
+            KernelKind::Scalar => true,
+            KernelKind::Avx2 => cfg!(target_arch = "x86_64") && caps.avx2,
+            KernelKind::Avx512 => cfg!(target_arch = "x86_64") && caps.avx512f,
+        }
+    }
+
+    fn function(&self) -> DotKernel {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            KernelKind::Avx2 => dot_avx2,
+            #[cfg(target_arch = "x86_64")]
+            KernelKind::Avx512 => dot_avx512,
+            _ => dot_scalar,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(KernelKind::Scalar),
+            1 => Some(KernelKind::Avx2),
+            2 => Some(KernelKind::Avx512),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for KernelKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scalar" => Ok(KernelKind::Scalar),
+            "avx2" => Ok(KernelKind::Avx2),
+            "avx512" => Ok(KernelKind::Avx512),
+            other => Err(format!("unknown kernel '{}', expected scalar, avx2 or avx512", other)),
+        }
+    }
+}
+
+/// Pick the fastest kernel supported by `caps`
+pub fn select_kernel(caps: CpuCaps) -> (&'static str, DotKernel) {
+    let kind = best_kernel(caps);
+    (kind.name(), kind.function())
+}
+
+fn best_kernel(caps: CpuCaps) -> KernelKind {
+    [KernelKind::Avx512, KernelKind::Avx2]
+        .into_iter()
+        .find(|kind| kind.supported(caps))
+        .unwrap_or(KernelKind::Scalar)
+}
+
+const KERNEL_UNSET: u8 = u8::MAX;
+
+/// Active kernel as a `KernelKind` discriminant, resolved on first use
+static ACTIVE: AtomicU8 = AtomicU8::new(KERNEL_UNSET);
+
+/// Kernel currently used for scoring
+pub fn active_kernel() -> KernelKind {
+    if let Some(kind) = KernelKind::from_u8(ACTIVE.load(Ordering::Relaxed)) {
+        return kind;
+    }
+    let kind = best_kernel(CpuCaps::detect());
+    // Lose gracefully to a concurrent `set_kernel`
+    let _ = ACTIVE.compare_exchange(KERNEL_UNSET, kind as u8, Ordering::Relaxed, Ordering::Relaxed);
+    KernelKind::from_u8(ACTIVE.load(Ordering::Relaxed)).unwrap_or(kind)
+}
+
+/// Force a kernel for the rest of the process, overriding auto-selection
+pub fn set_kernel(kind: KernelKind) -> Result<(), String> {
+    if !kind.supported(CpuCaps::detect()) {
+        return Err(format!("kernel '{}' is not supported on this CPU", kind.name()));
+    }
+    ACTIVE.store(kind as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Dot-product kernel currently in effect
+#[inline]
+pub fn dot_kernel() -> DotKernel {
+    active_kernel().function()
+}
+
+/// Dot product on the widest SIMD kernel the CPU supports, falling back to
+/// `dot_scalar`. Detection runs once and is cached by `active_kernel`.
+#[inline]
+pub fn dot_sim_dispatch(a: &[f32], b: &[f32]) -> f32 {
+    dot_kernel()(a, b)
+}
+
+/// Name of the active kernel, e.g. `"avx2"` or `"scalar"`
+pub fn kernel_name() -> &'static str {
+    active_kernel().name()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_resolved_kernel_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let kernel = dot_kernel();
+        for len in [1, 7, 8, 13, 128, 131] {
+            let a: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            let b: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            assert!((kernel(&a, &b) - dot_scalar(&a, &b)).abs() < 1e-4, "len {}", len);
+        }
+        assert_eq!(kernel(&[1.0, 2.0, 3.0], &[4.0, 5.0, 6.0]), 32.0);
+    }
+
+    #[test]
+    fn test_simd_matches_scalar_on_embedding_widths() {
+        let caps = CpuCaps::detect();
+        let mut rng = StdRng::seed_from_u64(11);
+        // Widths that aren't multiples of 8 or 16 exercise the scalar tail
+        for len in [128, 384, 130, 389] {
+            let a: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            let b: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            let expected = dot_scalar(&a, &b);
+            assert!((dot_sim_dispatch(&a, &b) - expected).abs() < 1e-4, "dispatch len {}", len);
+            for kind in [KernelKind::Avx2, KernelKind::Avx512].into_iter().filter(|kind| kind.supported(caps)) {
+                let got = (kind.function())(&a, &b);
+                assert!((got - expected).abs() < 1e-4, "{} len {}", kind.name(), len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_supported_kernel_matches_scalar() {
+        let caps = CpuCaps::detect();
+        let mut rng = StdRng::seed_from_u64(5);
+        for kind in [KernelKind::Scalar, KernelKind::Avx2, KernelKind::Avx512] {
+            if !kind.supported(caps) {
+                continue;
+            }
+            for len in [3, 16, 17, 128, 131] {
+                let a: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+                let b: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+                let got = (kind.function())(&a, &b);
+                assert!((got - dot_scalar(&a, &b)).abs() < 1e-4, "{} len {}", kind.name(), len);
+            }
+        }
+    }
+}
@@ -1 +1,23 @@
+pub mod coalesce;
+pub mod compare;
+pub mod contrastive;
+pub mod corpus;
+pub mod determinism;
+pub mod embeddings;
+pub mod explain;
+pub mod doc_cache;
+pub mod jobs;
+pub mod kernels;
+pub mod metrics;
+pub mod mmap_corpus;
+pub mod mmr;
+pub mod models;
+pub mod packed;
+pub mod proto;
+pub mod quant;
 pub mod scoring;
+pub mod second_stage;
+pub mod server;
+pub mod sessions;
+pub mod snapshots;
+pub mod topk;
@@ -0,0 +1,177 @@
+//! Request metrics exported in the Prometheus text format by `/metrics`
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::scoring::PerfStats;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+/// Reranks usually take from well under a millisecond to tens of
+/// milliseconds, so the buckets are densest there.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// Content type of the Prometheus text exposition format
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Cumulative latency histogram with fixed buckets
+#[derive(Debug)]
+struct Histogram {
+    /// Observations per bucket (not cumulative); the last is `+Inf`
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..=LATENCY_BUCKETS.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|&le| seconds <= le).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters for one endpoint
+#[derive(Debug, Default)]
+struct EndpointMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    docs_scored: AtomicU64,
+    latency: Histogram,
+    /// f32 bits of the most recent per-document p50 and p95, in ms
+    per_doc_ms_p50: AtomicU32,
+    per_doc_ms_p95: AtomicU32,
+}
+
+/// Endpoints whose requests are measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Rerank,
+    Bench,
+}
+
+impl Endpoint {
+    fn label(self) -> &'static str {
+        match self {
+            Endpoint::Rerank => "rerank",
+            Endpoint::Bench => "bench",
+        }
+    }
+}
+
+/// Process-wide request metrics; clones share the same counters
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    rerank: Arc<EndpointMetrics>,
+    bench: Arc<EndpointMetrics>,
+}
+
+impl Metrics {
+    fn endpoint(&self, endpoint: Endpoint) -> &EndpointMetrics {
+        match endpoint {
+            Endpoint::Rerank => &self.rerank,
+            Endpoint::Bench => &self.bench,
+        }
+    }
+
+    /// Count one finished request and its latency
+    pub fn observe_request(&self, endpoint: Endpoint, elapsed: Duration, failed: bool) {
+        let metrics = self.endpoint(endpoint);
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            metrics.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.latency.observe(elapsed);
+    }
+
+    /// Count documents scored and keep the run's per-document percentiles
+    pub fn observe_scoring(&self, endpoint: Endpoint, docs: usize, perf: &PerfStats) {
+        let metrics = self.endpoint(endpoint);
+        metrics.docs_scored.fetch_add(docs as u64, Ordering::Relaxed);
+        if let Some(p50) = perf.per_doc_ms_p50 {
+            metrics.per_doc_ms_p50.store(p50.to_bits(), Ordering::Relaxed);
+        }
+        if let Some(p95) = perf.per_doc_ms_p95 {
+            metrics.per_doc_ms_p95.store(p95.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Render every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let endpoints = [Endpoint::Rerank, Endpoint::Bench];
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: fn(&EndpointMetrics) -> u64| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for endpoint in endpoints {
+                let _ = writeln!(out, "{}{{endpoint=\"{}\"}} {}", name, endpoint.label(), value(self.endpoint(endpoint)));
+            }
+        };
+        counter("reranker_requests_total", "Requests handled.", |m| m.requests.load(Ordering::Relaxed));
+        counter("reranker_request_errors_total", "Requests answered with an error status.", |m| {
+            m.errors.load(Ordering::Relaxed)
+        });
+        counter("reranker_documents_scored_total", "Documents scored by successful requests.", |m| {
+            m.docs_scored.load(Ordering::Relaxed)
+        });
+
+        let name = "reranker_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} End-to-end request latency.\n# TYPE {} histogram", name, name);
+        for endpoint in endpoints {
+            let histogram = &self.endpoint(endpoint).latency;
+            let label = endpoint.label();
+            let mut cumulative = 0;
+            for (i, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count.load(Ordering::Relaxed);
+                let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |le| le.to_string());
+                let _ = writeln!(out, "{}_bucket{{endpoint=\"{}\",le=\"{}\"}} {}", name, label, le, cumulative);
+            }
+            let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let _ = writeln!(out, "{}_sum{{endpoint=\"{}\"}} {}", name, label, sum);
+            let _ = writeln!(out, "{}_count{{endpoint=\"{}\"}} {}", name, label, cumulative);
+        }
+
+        let name = "reranker_per_doc_ms";
+        let _ = writeln!(out, "# HELP {} Per-document scoring time percentiles of the latest request.\n# TYPE {} gauge", name, name);
+        for endpoint in endpoints {
+            let metrics = self.endpoint(endpoint);
+            for (quantile, bits) in [("0.5", &metrics.per_doc_ms_p50), ("0.95", &metrics.per_doc_ms_p95)] {
+                let value = f32::from_bits(bits.load(Ordering::Relaxed));
+                let _ = writeln!(out, "{}{{endpoint=\"{}\",quantile=\"{}\"}} {}", name, endpoint.label(), quantile, value);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.observe_request(Endpoint::Rerank, Duration::from_micros(50), false);
+        metrics.observe_request(Endpoint::Rerank, Duration::from_millis(3), true);
+        metrics.observe_request(Endpoint::Rerank, Duration::from_secs(2), false);
+
+        let text = metrics.render();
+        let line = |prefix: &str| text.lines().find(|l| l.starts_with(prefix)).unwrap().to_string();
+        let prefix = "reranker_request_duration_seconds_bucket{endpoint=\"rerank\"";
+        assert!(line(&format!("{},le=\"0.0001\"}}", prefix)).ends_with(" 1"));
+        assert!(line(&format!("{},le=\"0.005\"}}", prefix)).ends_with(" 2"));
+        assert!(line(&format!("{},le=\"+Inf\"}}", prefix)).ends_with(" 3"));
+        assert_eq!(line("reranker_request_errors_total{endpoint=\"rerank\"}"), "reranker_request_errors_total{endpoint=\"rerank\"} 1");
+    }
+}
@@ -0,0 +1,225 @@
+use memmap2::Mmap;
+use nalgebra::DMatrix;
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::scoring::{
+    l2_normalize_rows, normalized_matrix, percentile, prune_token_indices, score_with_mode, top_salient,
+    MaxSimConfig, PerfStats, PruneConfig, ScoreMode, DEFAULT_NORM_EPS, QUERY_AFFINITY,
+};
+
+/// Read-only document store backed by a memory-mapped f32 file
+///
+/// Two files make up a corpus:
+/// - `<path>`: every token row of every document, back to back, as
+///   native-endian f32;
+/// - `<path>.offsets`: `dim`, the document count `n`, then `n + 1` row
+///   offsets, all as little-endian u64. Document `i` spans rows
+///   `offsets[i]..offsets[i + 1]`.
+///
+/// Only the offsets are read into memory. Embeddings are paged in by the OS
+/// as scoring touches them, so the first pass over a cold corpus pays a page
+/// fault per 4 KiB and runs at disk speed. Repeat queries over a working set
+/// that fits in the page cache approach in-memory speed. Random candidate
+/// sets over a corpus much larger than RAM can thrash.
+pub struct MmapCorpus {
+    dim: usize,
+    map: Mmap,
+    offsets: Vec<usize>,
+}
+
+/// Path of the offsets index for the data file at `path`
+fn offsets_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".offsets");
+    PathBuf::from(name)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl MmapCorpus {
+    /// Write `docs` (all tokens of dimension `dim`) in the on-disk layout
+    pub fn write(path: impl AsRef<Path>, docs: &[Vec<Vec<f32>>], dim: usize) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut data = BufWriter::new(File::create(path)?);
+        let mut index = BufWriter::new(File::create(offsets_path(path))?);
+        index.write_all(&(dim as u64).to_le_bytes())?;
+        index.write_all(&(docs.len() as u64).to_le_bytes())?;
+        index.write_all(&0u64.to_le_bytes())?;
+
+        let mut rows = 0u64;
+        for (i, doc) in docs.iter().enumerate() {
+            for token in doc {
+                if token.len() != dim {
+                    return Err(invalid(format!("doc {} has a {}-dim token, expected {}", i, token.len(), dim)));
+                }
+                for value in token {
+                    data.write_all(&value.to_ne_bytes())?;
+                }
+            }
+            rows += doc.len() as u64;
+            index.write_all(&rows.to_le_bytes())?;
+        }
+        data.flush()?;
+        index.flush()
+    }
+
+    /// Map the corpus at `path`. The files must not change while mapped.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut index = Vec::new();
+        File::open(offsets_path(path))?.read_to_end(&mut index)?;
+        let words: Vec<usize> = index
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()) as usize)
+            .collect();
+        let (&dim, rest) = words.split_first().ok_or_else(|| invalid("empty offsets index".into()))?;
+        let (&n, offsets) = rest.split_first().ok_or_else(|| invalid("missing document count".into()))?;
+        if dim == 0 || n.checked_add(1) != Some(offsets.len()) || offsets.windows(2).any(|w| w[0] > w[1]) {
+            return Err(invalid(format!("malformed offsets index for {} documents", n)));
+        }
+
+        let file = File::open(path)?;
+        // Safety: the corpus files are treated as immutable while mapped
+        let map = unsafe { Mmap::map(&file)? };
+        let rows = offsets[n];
+        let expected = rows.checked_mul(dim).and_then(|values| values.checked_mul(std::mem::size_of::<f32>()));
+        if expected != Some(map.len()) {
+            return Err(invalid(format!("data holds {} bytes, index expects {} rows of {}", map.len(), rows, dim)));
+        }
+        Ok(Self { dim, map, offsets: offsets.to_vec() })
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every value of the mapped file
+    fn values(&self) -> &[f32] {
+        // Safety: any bit pattern is a valid f32, and the map is page aligned
+        let (prefix, values, _) = unsafe { self.map.align_to::<f32>() };
+        debug_assert!(prefix.is_empty());
+        values
+    }
+
+    /// Document `idx`'s token rows, sliced straight from the mapped region
+    pub fn rows(&self, idx: usize) -> Vec<&[f32]> {
+        let values = self.values();
+        (self.offsets[idx]..self.offsets[idx + 1])
+            .map(|row| &values[row * self.dim..(row + 1) * self.dim])
+            .collect()
+    }
+}
+
+/// Score every mapped document and return the top-K, like `score_docs`
+///
+/// Supports salience pruning (`idf_norm`, `norm_only`; `query_affinity`
+/// falls back to norm salience) and score modes. Request options that need
+/// per-document side data are not available on this path. Kept rows are
+/// copied out of the map once, into the matrix that is scored.
+pub fn score_mmap_docs(
+    q_tokens: &[Vec<f32>],
+    corpus: &MmapCorpus,
+    topk: usize,
+    prune_config: &PruneConfig,
+    score_mode: ScoreMode,
+) -> (Vec<usize>, Vec<f32>, PerfStats) {
+    let q_method = if prune_config.method == QUERY_AFFINITY { "idf_norm" } else { &prune_config.method };
+    let q_kept = prune_token_indices(q_tokens, prune_config.q_budget(q_tokens.len()), q_method, None, None);
+    let pruned_q: Vec<Vec<f32>> = q_kept.iter().map(|&i| q_tokens[i].clone()).collect();
+    let q_matrix = normalized_matrix(&pruned_q, DEFAULT_NORM_EPS);
+    let config = MaxSimConfig::default();
+
+    let mut results: Vec<(usize, f32, f32)> = (0..corpus.len())
+        .into_par_iter()
+        .filter_map(|idx| {
+            let start = std::time::Instant::now();
+            let rows = corpus.rows(idx);
+            if rows.is_empty() {
+                return None;
+            }
+            let d_budget = prune_config.d_budget(rows.len());
+            let kept: Vec<usize> =
+                top_salient(&rows, d_budget, &prune_config.method, None).into_iter().map(|(i, _)| i).collect();
+            let mut d_matrix = DMatrix::from_row_iterator(
+                kept.len(),
+                corpus.dim(),
+                kept.iter().flat_map(|&i| rows[i].iter().copied()),
+            );
+            l2_normalize_rows(&mut d_matrix, DEFAULT_NORM_EPS);
+            let score = score_with_mode(&q_matrix, &d_matrix, score_mode, &config);
+            Some((idx, score, start.elapsed().as_secs_f32() * 1000.0))
+        })
+        .collect();
+
+    let mut times: Vec<f32> = results.iter().map(|r| r.2).collect();
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let perf = PerfStats {
+        per_doc_ms_p50: percentile(&times, 50.0),
+        per_doc_ms_p95: percentile(&times, 95.0),
+        sample_size: times.len(),
+    };
+
+    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    results.truncate(topk);
+    (results.iter().map(|r| r.0).collect(), results.iter().map(|r| r.1).collect(), perf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scoring::score_docs;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_mmap_corpus_matches_in_memory_scoring() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut random_tokens = |n: usize| -> Vec<Vec<f32>> {
+            (0..n).map(|_| (0..8).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()
+        };
+        let q_tokens = random_tokens(4);
+        let d_tokens: Vec<Vec<Vec<f32>>> = (1..=20).map(|n| random_tokens(n * 5)).collect();
+        let path = std::env::temp_dir().join(format!("ranker-mmap-{}.f32", std::process::id()));
+        MmapCorpus::write(&path, &d_tokens, 8).unwrap();
+        let corpus = MmapCorpus::open(&path).unwrap();
+
+        assert_eq!((corpus.len(), corpus.dim()), (20, 8));
+        assert_eq!(corpus.rows(3)[2], &d_tokens[3][2][..]);
+        let prune = PruneConfig { d_max: 32, ..Default::default() };
+        let (order, scores, perf) = score_mmap_docs(&q_tokens, &corpus, 5, &prune, ScoreMode::MaxSim);
+        let (expected_order, expected_scores, _) = score_docs(&q_tokens, &d_tokens, 5, &prune).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(offsets_path(&path)).unwrap();
+
+        assert_eq!(order, expected_order);
+        assert_eq!(scores, expected_scores);
+        assert_eq!(perf.sample_size, 20);
+    }
+
+    #[test]
+    fn test_open_rejects_oversized_index() {
+        let path = std::env::temp_dir().join(format!("ranker-mmap-overflow-{}.f32", std::process::id()));
+        std::fs::write(&path, [0u8; 16]).unwrap();
+        // dim 2^62 with 4 rows overflows the expected byte count
+        let index: Vec<u8> = [1u64 << 62, 1, 0, 4].iter().flat_map(|word| word.to_le_bytes()).collect();
+        std::fs::write(offsets_path(&path), index).unwrap();
+        let err = MmapCorpus::open(&path).err().expect("size overflow rejected");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(offsets_path(&path)).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
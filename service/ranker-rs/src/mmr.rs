@@ -0,0 +1,103 @@
+use nalgebra::DMatrix;
+
+use crate::scoring::{score_with_mode, MaxSimConfig, ScoreMode};
+
+/// Candidates considered per requested result when sweeping MMR lambdas
+pub const MMR_POOL_FACTOR: usize = 4;
+
+/// Mean relevance and diversity of the MMR top-K at one lambda
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct MmrPoint {
+    pub lambda: f32,
+    /// Mean ranking score of the selected documents
+    pub mean_relevance: f32,
+    /// Mean `1 - similarity` over pairs of selected documents; 0 below two
+    pub mean_diversity: f32,
+}
+
+/// Symmetric similarity of two documents with L2-normalized rows: the
+/// average of mean MaxSim in each direction
+pub fn doc_similarity(a: &DMatrix<f32>, b: &DMatrix<f32>) -> f32 {
+    let config = MaxSimConfig::default();
+    0.5 * (score_with_mode(a, b, ScoreMode::MeanMaxSim, &config)
+        + score_with_mode(b, a, ScoreMode::MeanMaxSim, &config))
+}
+
+/// Greedy maximal marginal relevance: repeatedly pick the candidate
+/// maximizing `lambda * relevance - (1 - lambda) * max similarity to the
+/// picks so far`. `relevance` should be on the same scale as `similarity`.
+pub fn mmr_select(relevance: &[f32], similarity: &[Vec<f32>], k: usize, lambda: f32) -> Vec<usize> {
+    let mut selected: Vec<usize> = Vec::with_capacity(k.min(relevance.len()));
+    let mut remaining: Vec<usize> = (0..relevance.len()).collect();
+    while selected.len() < k && !remaining.is_empty() {
+        let marginal = |i: usize| {
+            let redundancy = selected.iter().map(|&j| similarity[i][j]).fold(f32::NEG_INFINITY, f32::max);
+            let redundancy = if selected.is_empty() { 0.0 } else { redundancy };
+            lambda * relevance[i] - (1.0 - lambda) * redundancy
+        };
+        // Earliest candidate wins ties, so lambda = 1 keeps the ranking order
+        let (pos, _) = remaining
+            .iter()
+            .enumerate()
+            .fold((0, f32::NEG_INFINITY), |best, (pos, &i)| {
+                let value = marginal(i);
+                if value > best.1 { (pos, value) } else { best }
+            });
+        selected.push(remaining.remove(pos));
+    }
+    selected
+}
+
+/// Run MMR over ranked candidates once per lambda and report the relevance
+/// and diversity of each selected top-K. `scores` are the candidates'
+/// ranking scores and `docs` their normalized token matrices.
+pub fn mmr_sweep(scores: &[f32], docs: &[DMatrix<f32>], k: usize, lambdas: &[f32]) -> Vec<MmrPoint> {
+    let n = scores.len();
+    let similarity: Vec<Vec<f32>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { doc_similarity(&docs[i], &docs[j]) }).collect())
+        .collect();
+
+    // Rescale relevance to [0, 1] so it trades off evenly against similarity
+    let lo = scores.iter().copied().fold(f32::INFINITY, f32::min);
+    let hi = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let relevance: Vec<f32> = scores
+        .iter()
+        .map(|&s| if hi > lo { (s - lo) / (hi - lo) } else { 1.0 })
+        .collect();
+
+    lambdas
+        .iter()
+        .map(|&lambda| {
+            let picks = mmr_select(&relevance, &similarity, k, lambda);
+            let mean_relevance = if picks.is_empty() {
+                0.0
+            } else {
+                picks.iter().map(|&i| scores[i]).sum::<f32>() / picks.len() as f32
+            };
+            let mut pairs = 0;
+            let mut distance = 0.0;
+            for (a, &i) in picks.iter().enumerate() {
+                for &j in &picks[a + 1..] {
+                    distance += 1.0 - similarity[i][j];
+                    pairs += 1;
+                }
+            }
+            let mean_diversity = if pairs > 0 { distance / pairs as f32 } else { 0.0 };
+            MmrPoint { lambda, mean_relevance, mean_diversity }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmr_select_trades_relevance_for_novelty() {
+        // Candidates 0 and 1 are near-duplicates; 2 is less relevant but novel
+        let relevance = [1.0, 0.95, 0.6];
+        let similarity = vec![vec![1.0, 0.99, 0.1], vec![0.99, 1.0, 0.1], vec![0.1, 0.1, 1.0]];
+        assert_eq!(mmr_select(&relevance, &similarity, 2, 1.0), vec![0, 1]);
+        assert_eq!(mmr_select(&relevance, &similarity, 2, 0.5), vec![0, 2]);
+    }
+}
@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::scoring::{Calibration, PruneConfig, ScoreMode, PRUNE_METHODS};
+
+/// Per-model settings applied when a request names the model
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// Expected embedding dimension; requests must match it
+    pub dim: usize,
+    /// Prune config used when the request omits `prune`
+    #[serde(default)]
+    pub prune: Option<PruneConfig>,
+    /// Score mode used when the request omits `score_mode`
+    #[serde(default)]
+    pub score_mode: Option<ScoreMode>,
+    /// Calibration used when the request omits `calibration`
+    #[serde(default)]
+    pub calibration: Option<Calibration>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelsFile {
+    #[serde(default)]
+    models: HashMap<String, ModelConfig>,
+}
+
+/// Named model configurations loaded at startup
+///
+/// The config file is TOML with one table per model:
+///
+/// ```toml
+/// [models.colbert-small]
+/// dim = 128
+/// score_mode = "mean_maxsim"
+/// prune = { q_max = 16, d_max = 64, method = "idf_norm" }
+/// calibration = { scale = 2.0, bias = -1.0 }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ModelRegistry {
+    models: HashMap<String, ModelConfig>,
+}
+
+impl ModelRegistry {
+    /// Load and validate a registry from a TOML file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Self::from_toml_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Parse and validate a registry from TOML text
+    pub fn from_toml_str(text: &str) -> Result<Self, String> {
+        let file: ModelsFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        for (name, model) in &file.models {
+            if model.dim == 0 {
+                return Err(format!("model '{}': dim must be positive", name));
+            }
+            if let Some(prune) = &model.prune {
+                if prune.q_max == 0 || prune.d_max == 0 {
+                    return Err(format!("model '{}': q_max and d_max must be positive", name));
+                }
+                if !PRUNE_METHODS.contains(&prune.method.as_str()) {
+                    return Err(format!("model '{}': unknown prune method '{}'", name, prune.method));
+                }
+            }
+            if let Some(calibration) = &model.calibration {
+                if !calibration.scale.is_finite() || !calibration.bias.is_finite() {
+                    return Err(format!("model '{}': calibration must be finite", name));
+                }
+            }
+        }
+        Ok(Self { models: file.models })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ModelConfig> {
+        self.models.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_MODELS: &str = r#"
+        [models.small]
+        dim = 2
+        score_mode = "mean_maxsim"
+        prune = { q_max = 1, d_max = 8, method = "idf_norm" }
+        calibration = { scale = 2.0, bias = -1.0 }
+
+        [models.large]
+        dim = 4
+    "#;
+
+    #[test]
+    fn test_load_two_model_config() {
+        let path = std::env::temp_dir().join(format!("ranker-models-{}.toml", std::process::id()));
+        std::fs::write(&path, TWO_MODELS).unwrap();
+        let registry = ModelRegistry::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let registry = registry.unwrap();
+
+        assert_eq!(registry.len(), 2);
+        let small = registry.get("small").unwrap();
+        assert_eq!(small.dim, 2);
+        assert_eq!(small.score_mode, Some(ScoreMode::MeanMaxSim));
+        assert_eq!(small.prune.as_ref().unwrap().q_max, 1);
+        assert_eq!(small.calibration.unwrap().scale, 2.0);
+        let large = registry.get("large").unwrap();
+        assert!(large.prune.is_none() && large.score_mode.is_none());
+    }
+
+    #[test]
+    fn test_invalid_config_fails() {
+        assert!(ModelRegistry::from_toml_str("[models.bad]\ndim = 0\n").is_err());
+        assert!(ModelRegistry::from_toml_str("[models.bad]\ndim = 4\nscore_mode = \"nope\"\n").is_err());
+        assert!(ModelRegistry::from_toml_str("[models.bad]\ndimension = 4\n").is_err());
+        let typo = "[models.bad]\ndim = 4\nprune = { q_max = 1, d_max = 1, method = \"idfnorm\" }\n";
+        assert!(ModelRegistry::from_toml_str(typo).is_err());
+    }
+}
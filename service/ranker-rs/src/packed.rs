@@ -0,0 +1,62 @@
+use nalgebra::DMatrix;
+use rayon::prelude::*;
+
+/// All documents' token rows in one contiguous row-major buffer
+#[derive(Debug, Clone)]
+pub struct PackedDocs {
+    dim: usize,
+    data: Vec<f32>,
+    /// Row offset of each document; `offsets[i]..offsets[i + 1]` are doc i's rows
+    offsets: Vec<usize>,
+}
+
+impl PackedDocs {
+    /// Pack documents whose tokens all have `dim` entries
+    pub fn pack(docs: &[Vec<Vec<f32>>], dim: usize) -> Self {
+        let total_rows: usize = docs.iter().map(|doc| doc.len()).sum();
+        let mut data = Vec::with_capacity(total_rows * dim);
+        let mut offsets = Vec::with_capacity(docs.len() + 1);
+        offsets.push(0);
+        for doc in docs {
+            for token in doc {
+                data.extend_from_slice(token);
+            }
+            offsets.push(offsets.last().unwrap() + doc.len());
+        }
+        Self { dim, data, offsets }
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Token rows of document `idx`
+    pub fn rows(&self, idx: usize) -> usize {
+        self.offsets[idx + 1] - self.offsets[idx]
+    }
+
+    /// L2-normalize every row of every document in one parallel pass, with
+    /// the same `eps` rule as `l2_normalize_rows`
+    pub fn normalize_rows(&mut self, eps: f32) {
+        if self.dim == 0 {
+            return;
+        }
+        self.data.par_chunks_mut(self.dim).for_each(|row| {
+            let norm = row.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if norm > eps {
+                row.iter_mut().for_each(|x| *x /= norm);
+            }
+        });
+    }
+
+    /// Document `idx` as a row-per-token matrix
+    pub fn matrix(&self, idx: usize) -> DMatrix<f32> {
+        let start = self.offsets[idx] * self.dim;
+        let end = self.offsets[idx + 1] * self.dim;
+        DMatrix::from_row_slice(self.rows(idx), self.dim, &self.data[start..end])
+    }
+}
@@ -0,0 +1,62 @@
+//! Protobuf encoding of rerank responses, mirroring `proto/ranker.proto`
+
+use crate::scoring::RerankResponse;
+
+/// MIME type clients send in `Accept` to get a protobuf response
+pub const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PerfStatsProto {
+    #[prost(float, optional, tag = "1")]
+    pub per_doc_ms_p50: Option<f32>,
+    #[prost(float, optional, tag = "2")]
+    pub per_doc_ms_p95: Option<f32>,
+    #[prost(uint64, tag = "3")]
+    pub sample_size: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RerankResponseProto {
+    #[prost(uint64, repeated, tag = "1")]
+    pub order: Vec<u64>,
+    #[prost(float, repeated, tag = "2")]
+    pub scores: Vec<f32>,
+    #[prost(uint64, repeated, tag = "3")]
+    pub ranks: Vec<u64>,
+    #[prost(message, optional, tag = "4")]
+    pub perf: Option<PerfStatsProto>,
+    #[prost(string, tag = "5")]
+    pub stats_json: String,
+    #[prost(float, repeated, tag = "6")]
+    pub probabilities: Vec<f32>,
+    #[prost(float, repeated, tag = "7")]
+    pub log_scores: Vec<f32>,
+    #[prost(string, repeated, tag = "8")]
+    pub doc_ids: Vec<String>,
+}
+
+impl From<&RerankResponse> for RerankResponseProto {
+    fn from(response: &RerankResponse) -> Self {
+        Self {
+            order: response.order.iter().map(|&i| i as u64).collect(),
+            scores: response.scores.clone(),
+            ranks: response.ranks.iter().map(|&r| r as u64).collect(),
+            perf: Some(PerfStatsProto {
+                per_doc_ms_p50: response.perf.per_doc_ms_p50,
+                per_doc_ms_p95: response.perf.per_doc_ms_p95,
+                sample_size: response.perf.sample_size as u64,
+            }),
+            stats_json: serde_json::to_string(&response.stats).unwrap_or_default(),
+            probabilities: response.probabilities.clone().unwrap_or_default(),
+            log_scores: response.log_scores.clone().unwrap_or_default(),
+            doc_ids: Vec::new(),
+        }
+    }
+}
+
+/// Encode a rerank response as protobuf bytes, with the returned documents'
+/// ids (best first) when they were named by id
+pub fn encode_response(response: &RerankResponse, doc_ids: Option<&[String]>) -> Vec<u8> {
+    let proto = RerankResponseProto { doc_ids: doc_ids.map(<[String]>::to_vec).unwrap_or_default(), ..response.into() };
+    prost::Message::encode_to_vec(&proto)
+}
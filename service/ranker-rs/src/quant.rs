@@ -0,0 +1,306 @@
+//! Int8 document embeddings for memory-bound corpora
+//!
+//! A `/rerank` request may send `d_quant` instead of `d_tokens`: every
+//! document token as int8 values plus one f32 scale per token in
+//! `d_scales`, so that `token ≈ scale × values` (symmetric per-row
+//! quantization, see `quantize_rows`). That is a quarter of the memory of
+//! f32 rows. The kept query rows are quantized the same way, and MaxSim dot
+//! products are computed in int32 and dequantized by the two row scales.
+//! On L2-normalized embeddings the ranking stays close to f32 scoring.
+
+use rayon::prelude::*;
+use std::cmp::Ordering;
+
+use crate::scoring::{
+    percentile, prepare_query_with_options, select_by_salience, MaxSimConfig, PerfStats, PruneConfig, PruneStats,
+    ScoreError, ScoreMode, ScoreOptions, ScoreOutput, ScoreStats, DEFAULT_NORM_EPS,
+};
+
+/// Per-request options `score_quantized_docs` implements, by request field name
+pub const QUANT_OPTIONS: &[&str] = &["norm_eps", "q_idf", "q_mask", "q_tiebreak_keys", "q_weights", "relu_sim"];
+
+/// Largest int8 magnitude used; -128 is left out so the range is symmetric
+const QUANT_MAX: f32 = 127.0;
+
+/// Token rows stored as int8 values with one dequantization scale per row
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizedRows {
+    dim: usize,
+    /// Row-major values, `dim` per row
+    values: Vec<i8>,
+    scales: Vec<f32>,
+}
+
+impl QuantizedRows {
+    /// Pack rows as sent in a request, one scale per row
+    pub fn from_parts(rows: &[Vec<i8>], scales: &[f32]) -> Result<Self, String> {
+        if rows.len() != scales.len() {
+            return Err(format!("{} tokens but {} scales", rows.len(), scales.len()));
+        }
+        let dim = rows.first().map_or(0, |row| row.len());
+        if let Some(row) = rows.iter().position(|row| row.len() != dim) {
+            return Err(format!("token {} has {} values, expected {}", row, rows[row].len(), dim));
+        }
+        if let Some(row) = scales.iter().position(|scale| !scale.is_finite()) {
+            return Err(format!("token {} has a non-finite scale", row));
+        }
+        Ok(Self { dim, values: rows.concat(), scales: scales.to_vec() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.scales.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scales.is_empty()
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn row(&self, i: usize) -> &[i8] {
+        &self.values[i * self.dim..(i + 1) * self.dim]
+    }
+
+    pub fn scale(&self, i: usize) -> f32 {
+        self.scales[i]
+    }
+
+    /// L2 norm of row `i` once dequantized
+    pub fn row_norm(&self, i: usize) -> f32 {
+        let squares: i32 = self.row(i).iter().map(|&v| v as i32 * v as i32).sum();
+        self.scales[i].abs() * (squares as f32).sqrt()
+    }
+
+    /// The given rows, in that order
+    pub fn select(&self, rows: &[usize]) -> Self {
+        Self {
+            dim: self.dim,
+            values: rows.iter().flat_map(|&i| self.row(i)).copied().collect(),
+            scales: rows.iter().map(|&i| self.scales[i]).collect(),
+        }
+    }
+
+    /// Rescale every row to unit L2 norm, like `l2_normalize_rows`. Only the
+    /// scales change; rows with norm at most `eps` are left as they are.
+    pub fn normalized(mut self, eps: f32) -> Self {
+        for i in 0..self.len() {
+            let norm = self.row_norm(i);
+            if norm > eps {
+                self.scales[i] /= norm;
+            }
+        }
+        self
+    }
+}
+
+/// Symmetric per-row quantization: each row is scaled so its largest
+/// magnitude maps to 127 and rounded. All-zero rows get scale 0.
+pub fn quantize_rows(rows: &[Vec<f32>]) -> QuantizedRows {
+    let dim = rows.first().map_or(0, |row| row.len());
+    let mut values = Vec::with_capacity(rows.len() * dim);
+    let mut scales = Vec::with_capacity(rows.len());
+    for row in rows {
+        let max = row.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        let scale = max / QUANT_MAX;
+        values.extend(row.iter().map(|&x| if scale > 0.0 { (x / scale).round() as i8 } else { 0 }));
+        scales.push(scale);
+    }
+    QuantizedRows { dim, values, scales }
+}
+
+/// Dot product of two int8 rows, accumulated in int32
+#[inline]
+pub fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+    a.iter().zip(b).map(|(&x, &y)| x as i32 * y as i32).sum()
+}
+
+/// MaxSim over quantized rows: `maxsim_score_with` with every dot computed
+/// in int32 and scaled by both row scales. Honours `relu` and `weights`.
+pub fn maxsim_score_i8(q: &QuantizedRows, d: &QuantizedRows, config: &MaxSimConfig) -> f32 {
+    let mut total_score = 0.0;
+
+    for i in 0..q.len() {
+        let q_row = q.row(i);
+        let mut max_dot = f32::NEG_INFINITY;
+
+        for j in 0..d.len() {
+            max_dot = max_dot.max(dot_i8(q_row, d.row(j)) as f32 * d.scale(j));
+        }
+
+        // Query scales are never negative, so they factor out of the max
+        let mut max_dot = max_dot * q.scale(i);
+        if config.relu {
+            max_dot = max_dot.max(0.0);
+        }
+        total_score += max_dot * config.weights.map_or(1.0, |weights| weights[i]);
+    }
+
+    total_score
+}
+
+/// Score int8 documents and return the top-K, like `score_docs_with_options`
+///
+/// Documents are pruned by their dequantized norms (`hard_doc_token_cap`
+/// and the salience budget; without an IDF table every salience method but
+/// `query_affinity` ranks tokens by norm) and normalized through their
+/// scales. Supports the `maxsim` and `mean_maxsim` score modes and the
+/// options in `QUANT_OPTIONS`; the query is prepared by
+/// `prepare_query_with_options`.
+pub fn score_quantized_docs(
+    q_tokens: &[Vec<f32>],
+    docs: &[QuantizedRows],
+    topk: usize,
+    prune: &PruneConfig,
+    options: &ScoreOptions,
+) -> Result<ScoreOutput, ScoreError> {
+    let query_dim = q_tokens.first().map(|t| t.len()).ok_or(ScoreError::EmptyQuery)?;
+    if let Some(doc_index) = docs.iter().position(|doc| doc.is_empty()) {
+        return Err(ScoreError::EmptyDocument { doc_index });
+    }
+    if let Some((doc_index, doc)) = docs.iter().enumerate().find(|(_, doc)| doc.dim() != query_dim) {
+        return Err(ScoreError::DimMismatch { query_dim, doc_index, doc_dim: doc.dim() });
+    }
+
+    let per_token = [
+        ("q_idf", options.q_idf.as_ref().map(Vec::len)),
+        ("q_weights", options.q_weights.as_ref().map(Vec::len)),
+        ("q_mask", options.q_mask.as_ref().map(Vec::len)),
+        ("q_tiebreak_keys", options.q_tiebreak_keys.as_ref().map(Vec::len)),
+    ];
+    for (option, len) in per_token {
+        if let Some(len) = len.filter(|&len| len != q_tokens.len()) {
+            return Err(ScoreError::MisalignedOption { option, len, expected: q_tokens.len() });
+        }
+    }
+
+    let query = prepare_query_with_options(q_tokens, prune, options)?;
+    let q_kept = &query.kept;
+    let eps = options.norm_eps.unwrap_or(DEFAULT_NORM_EPS);
+    let q_rows: Vec<Vec<f32>> = query.matrix.row_iter().map(|row| row.iter().copied().collect()).collect();
+    let q_quant = quantize_rows(&q_rows);
+    let score_mode = options.score_mode.unwrap_or_default();
+    let kept_weights: Option<Vec<f32>> =
+        options.q_weights.as_ref().map(|weights| q_kept.iter().map(|&i| weights[i]).collect());
+    let config = MaxSimConfig { relu: options.relu_sim, weights: kept_weights.as_deref(), ..Default::default() };
+
+    let mut results: Vec<(usize, f32, f32, usize)> = docs
+        .par_iter()
+        .enumerate()
+        .map(|(idx, doc)| {
+            let start = std::time::Instant::now();
+            let capped = prune.hard_doc_token_cap.map_or(doc.len(), |cap| doc.len().min(cap));
+            let mut saliences: Vec<(usize, f32)> = (0..capped).map(|j| (j, doc.row_norm(j))).collect();
+            select_by_salience(&mut saliences, prune.d_budget(doc.len()), None);
+            let kept: Vec<usize> = saliences.iter().map(|(j, _)| *j).collect();
+            let d_quant = doc.select(&kept).normalized(eps);
+            let total = maxsim_score_i8(&q_quant, &d_quant, &config);
+            let score = match score_mode {
+                ScoreMode::MeanMaxSim => total / q_quant.len() as f32,
+                _ => total,
+            };
+            (idx, score, start.elapsed().as_secs_f32() * 1000.0, kept.len())
+        })
+        .collect();
+
+    let mut times: Vec<f32> = results.iter().map(|r| r.2).collect();
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let perf = PerfStats {
+        per_doc_ms_p50: percentile(&times, 50.0),
+        per_doc_ms_p95: percentile(&times, 95.0),
+        sample_size: times.len(),
+    };
+
+    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    let cutoff_ties = match topk.min(results.len()).checked_sub(1).map(|k| results[k].1) {
+        Some(cutoff) => results.iter().filter(|r| r.1 == cutoff).count(),
+        None => 0,
+    };
+    results.truncate(topk);
+    let prune_stats = PruneStats {
+        q_tokens_in: q_tokens.len(),
+        q_tokens_kept: q_kept.len(),
+        docs: results.iter().map(|r| (docs[r.0].len(), r.3)).collect(),
+    };
+    Ok(ScoreOutput {
+        order: results.iter().map(|r| r.0).collect(),
+        scores: results.iter().map(|r| r.1).collect(),
+        ranks: (1..=results.len()).collect(),
+        perf,
+        stats: ScoreStats { score_mode, cutoff_ties, sanitized_values: options.sanitized_values, ..Default::default() },
+        probabilities: None,
+        log_scores: None,
+        topks: None,
+        prune_stats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compare::kendall_tau;
+    use crate::scoring::{score_docs, score_docs_with_options, NoopPostScorer};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_int8_scores_track_f32_on_normalized_data() {
+        let mut rng = StdRng::seed_from_u64(61);
+        let mut normalized_tokens = |n: usize| -> Vec<Vec<f32>> {
+            (0..n)
+                .map(|_| {
+                    let token: Vec<f32> = (0..64).map(|_| rng.gen_range(-1.0..1.0)).collect();
+                    let norm = token.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    token.iter().map(|x| x / norm).collect()
+                })
+                .collect()
+        };
+        let q_tokens = normalized_tokens(8);
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..40).map(|_| normalized_tokens(24)).collect();
+        let prune = PruneConfig::default();
+        let (order, scores, _) = score_docs(&q_tokens, &d_tokens, d_tokens.len(), &prune).unwrap();
+
+        let docs: Vec<QuantizedRows> = d_tokens.iter().map(|doc| quantize_rows(doc)).collect();
+        let quant = score_quantized_docs(&q_tokens, &docs, docs.len(), &prune, &ScoreOptions::default()).unwrap();
+        for (idx, score) in order.iter().zip(&scores) {
+            let pos = quant.order.iter().position(|i| i == idx).unwrap();
+            assert!((quant.scores[pos] - score).abs() < 0.02, "doc {}: {} vs {}", idx, quant.scores[pos], score);
+        }
+        let tau = kendall_tau(&order, &quant.order);
+        assert!(tau > 0.9, "kendall tau {}", tau);
+    }
+
+    #[test]
+    fn test_query_options_match_f32_scoring() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![-30.0, 40.0], vec![0.0, 1.0]];
+        let d_tokens = vec![vec![vec![0.6, 0.8]], vec![vec![-0.6, 0.8]], vec![vec![1.0, 0.0], vec![0.0, 1.0]]];
+        let options = ScoreOptions {
+            q_mask: Some(vec![true, false, true]),
+            q_weights: Some(vec![2.0, 1.0, 0.5]),
+            ..Default::default()
+        };
+        let prune = PruneConfig { q_max: 2, ..Default::default() };
+        let f32_out = score_docs_with_options(&q_tokens, &d_tokens, 3, &prune, &options, &NoopPostScorer).unwrap();
+        let docs: Vec<QuantizedRows> = d_tokens.iter().map(|doc| quantize_rows(doc)).collect();
+        let quant = score_quantized_docs(&q_tokens, &docs, 3, &prune, &options).unwrap();
+        assert_eq!(quant.order, f32_out.order);
+        assert_eq!(quant.prune_stats.q_tokens_kept, 2);
+
+        let short = ScoreOptions { q_weights: Some(vec![1.0]), ..Default::default() };
+        let err = score_quantized_docs(&q_tokens, &docs, 3, &prune, &short).unwrap_err();
+        assert_eq!(err, ScoreError::MisalignedOption { option: "q_weights", len: 1, expected: 3 });
+    }
+
+    #[test]
+    fn test_quantized_rows_round_trip_and_reject_ragged_input() {
+        let rows = quantize_rows(&[vec![0.5, -1.0], vec![0.0, 0.0]]);
+        assert_eq!(rows.row(0), &[64, -127]);
+        assert_eq!(rows.row(1), &[0, 0]);
+        assert!((rows.row_norm(0) - 1.25f32.sqrt()).abs() < 1e-2);
+        assert_eq!(QuantizedRows::from_parts(&[vec![64, -127], vec![0, 0]], &[1.0 / 127.0, 0.0]).unwrap(), rows);
+
+        assert!(QuantizedRows::from_parts(&[vec![1, 2], vec![3]], &[1.0, 1.0]).is_err());
+        assert!(QuantizedRows::from_parts(&[vec![1, 2]], &[]).is_err());
+    }
+}
@@ -1,209 +1,2851 @@
 use nalgebra::DMatrix;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::embeddings::Dtype;
+use crate::kernels::{dot_kernel, dot_sim_dispatch};
+use crate::mmr::{mmr_sweep, MmrPoint, MMR_POOL_FACTOR};
+use crate::packed::PackedDocs;
+use crate::topk::{select_top, AtomicScore, Ranked, Reservoir, TopKHeap};
 
 /// Performance statistics tracking
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct PerfStats {
-    pub per_doc_ms_p50: f32,
-    pub per_doc_ms_p95: f32,
+    /// Per-document scoring time. Outside `low_memory` documents are pruned
+    /// and normalized in a separate pass first, which these timings exclude.
+    /// `None` when fewer than `min_percentile_samples` timings were taken.
+    pub per_doc_ms_p50: Option<f32>,
+    pub per_doc_ms_p95: Option<f32>,
+    /// Per-doc timings the percentiles were taken over. Percentiles of a
+    /// handful of samples (one document gives p50 == p95) mean little.
+    pub sample_size: usize,
+}
+
+/// Fewest per-doc timings for which percentiles are reported by default
+pub const DEFAULT_MIN_PERCENTILE_SAMPLES: usize = 1;
+
+/// Nearest-rank percentile of ascending `sorted` values, `p` in [0, 100]
+///
+/// Picks the value at rank `ceil(p / 100 * len)`, clamped to the valid
+/// range, so p95 of three samples is the largest rather than the middle one.
+pub fn percentile(sorted: &[f32], p: f32) -> Option<f32> {
+    let last = sorted.len().checked_sub(1)?;
+    let rank = (p / 100.0 * sorted.len() as f32).ceil() as usize;
+    Some(sorted[rank.saturating_sub(1).min(last)])
 }
 
 /// Token pruning configuration
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 pub struct PruneConfig {
     pub q_max: usize,
     pub d_max: usize,
     pub method: String,
+    /// Experimental (eval only): fraction of surviving document tokens to
+    /// drop at random after salience pruning, to simulate noisy retrieval
+    #[serde(default)]
+    pub token_dropout: f32,
+    /// Seed for `token_dropout`; each document derives its own stream from it
+    #[serde(default)]
+    pub dropout_seed: u64,
+    /// Minimum fraction of query tokens to keep. The query budget is
+    /// `max(q_max, ceil(q_min_ratio * q_tokens))`, so the ratio wins when
+    /// `q_max` would prune a short query below it.
+    #[serde(default)]
+    pub q_min_ratio: f32,
+    /// Truncate documents to their first N tokens before salience pruning.
+    /// This is a cheap guard for enormous documents and runs ahead of `d_max`,
+    /// so salience is only computed over the surviving prefix.
+    #[serde(default)]
+    pub hard_doc_token_cap: Option<usize>,
+    /// Reservoir-sample documents down to N tokens before salience pruning.
+    /// Two-stage approximation for very long documents: salience is only
+    /// computed over a uniform sample (in original token order), so `d_max`
+    /// picks the most salient tokens of the sample, not of the whole document.
+    /// Each document's sample is seeded from `dropout_seed` and its index.
+    #[serde(default)]
+    pub reservoir_sample: Option<usize>,
+    /// Size each document's budget by its length instead of a flat `d_max`:
+    /// `max(d_min, round(d_max_ratio * tokens))`. `d_max` still caps the
+    /// result, so the ratio only ever shrinks the budget of short documents.
+    #[serde(default)]
+    pub d_max_ratio: Option<f32>,
+    /// Floor for the `d_max_ratio` budget; ignored without a ratio
+    #[serde(default)]
+    pub d_min: usize,
+    /// Cap the query budget at the smallest document's kept token count.
+    /// When the pruned query outnumbers a document's tokens, several query
+    /// tokens must share each document token's max, so short documents (e.g.
+    /// titles or passages pruned to a few tokens) saturate MaxSim and rank
+    /// on how well they match a handful of query tokens many times over.
+    /// Keeping no more query tokens than the shortest document avoids that
+    /// when candidate lengths vary widely. The cap wins over `q_min_ratio`.
+    #[serde(default)]
+    pub adapt_q_to_docs: bool,
+}
+
+impl PruneConfig {
+    /// Number of query tokens to keep for a query of `q_len` tokens
+    pub fn q_budget(&self, q_len: usize) -> usize {
+        let min_keep = (self.q_min_ratio * q_len as f32).ceil() as usize;
+        self.q_max.max(min_keep)
+    }
+
+    /// Query budget capped at `min_doc_kept`, the fewest tokens any document
+    /// keeps after pruning, when `adapt_q_to_docs` is set
+    pub fn q_budget_for_docs(&self, q_len: usize, min_doc_kept: Option<usize>) -> usize {
+        let budget = self.q_budget(q_len);
+        match min_doc_kept {
+            Some(kept) if self.adapt_q_to_docs => budget.min(kept.max(1)),
+            _ => budget,
+        }
+    }
+
+    /// This config with `q_max` fixed to `q_budget_for_docs` for the given
+    /// query length and documents, so every later stage prunes the query to
+    /// the same budget. Unchanged unless `adapt_q_to_docs` is set.
+    pub fn adapted_to_docs(&self, q_len: usize, d_tokens: &[Vec<Vec<f32>>]) -> PruneConfig {
+        if !self.adapt_q_to_docs {
+            return self.clone();
+        }
+        let min_doc_kept = d_tokens.iter().map(|doc| self.doc_kept_len(doc.len())).min();
+        PruneConfig { q_max: self.q_budget_for_docs(q_len, min_doc_kept), q_min_ratio: 0.0, ..self.clone() }
+    }
+
+    /// Tokens left after every document pruning stage for a document of
+    /// `d_len` tokens (`token_dropout` always keeps at least one)
+    pub fn doc_kept_len(&self, d_len: usize) -> usize {
+        let capped = self.hard_doc_token_cap.map_or(d_len, |cap| d_len.min(cap));
+        let sampled = self.reservoir_sample.map_or(capped, |n| capped.min(n));
+        sampled.min(self.d_budget(d_len))
+    }
+
+    /// Number of tokens to keep for a document of `d_len` original tokens
+    pub fn d_budget(&self, d_len: usize) -> usize {
+        match self.d_max_ratio {
+            Some(ratio) => {
+                let proportional = (ratio * d_len as f32).round() as usize;
+                self.d_min.max(proportional).min(self.d_max)
+            }
+            None => self.d_max,
+        }
+    }
+}
+
+impl Default for PruneConfig {
+    fn default() -> Self {
+        Self {
+            q_max: 16,
+            d_max: 64,
+            method: "idf_norm".to_string(),
+            token_dropout: 0.0,
+            dropout_seed: 0,
+            q_min_ratio: 0.0,
+            hard_doc_token_cap: None,
+            reservoir_sample: None,
+            d_max_ratio: None,
+            d_min: 0,
+            adapt_q_to_docs: false,
+        }
+    }
 }
 
 /// Request structure for reranking
-#[derive(Debug, serde::Deserialize)]
-pub struct RerankRequest {
-    pub q_tokens: Vec<Vec<f32>>,
-    pub d_tokens: Vec<Vec<Vec<f32>>>,
+///
+/// `T` is the token type: `Vec<f32>` once decoded, `WireToken` as sent when
+/// tokens may be half precision (see `embeddings`).
+#[derive(Debug, PartialEq, serde::Deserialize)]
+#[serde(bound(deserialize = "T: serde::Deserialize<'de>"))]
+pub struct RerankRequest<T = Vec<f32>> {
+    pub q_tokens: Vec<T>,
+    #[serde(default)]
+    pub d_tokens: Vec<Vec<T>>,
+    /// Element type of the tokens as sent; always `f32` once decoded
+    #[serde(default)]
+    pub dtype: Dtype,
+    /// Score documents from the server's document cache instead of
+    /// `d_tokens`; see `doc_cache`
+    #[serde(default)]
+    pub d_ids: Option<Vec<String>>,
+    /// Int8 document tokens, scored instead of `d_tokens`; see `quant`
+    #[serde(default)]
+    pub d_quant: Option<Vec<Vec<Vec<i8>>>>,
+    /// Dequantization scale of each `d_quant` token, by document
+    #[serde(default)]
+    pub d_scales: Option<Vec<Vec<f32>>>,
     pub topk: usize,
-    pub prune: PruneConfig,
+    /// Falls back to the model's default, then `PruneConfig::default()`
+    #[serde(default)]
+    pub prune: Option<PruneConfig>,
+    /// Registered model whose defaults fill in omitted settings
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Memory order of the token arrays; `col_major` sends each matrix as
+    /// `[dim][tokens]` and is transposed on arrival
+    #[serde(default)]
+    pub layout: Layout,
+    /// Store the returned ranking and report its `snapshot_id`
+    #[serde(default)]
+    pub snapshot: bool,
+    /// Reply with only the rank changes relative to this earlier snapshot
+    /// (implies `snapshot`, so deltas can be chained)
+    #[serde(default)]
+    pub prev_snapshot_id: Option<String>,
+    /// Answer an empty `d_tokens` (or `d_ids`) with an empty ranking instead
+    /// of a 400, e.g. when a filter upstream removed every candidate
+    #[serde(default)]
+    pub allow_empty_candidates: bool,
+    /// Blend the scores with this session's previous request; see `sessions`
+    #[serde(default)]
+    pub session_id: Option<String>,
+    /// Weight of this request's scores in the session blend, in (0, 1]
+    /// (default `DEFAULT_SMOOTHING`)
+    #[serde(default)]
+    pub smoothing: Option<f32>,
+    #[serde(flatten)]
+    pub options: ScoreOptions,
+}
+
+/// Memory order of embedding matrices in a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    /// One inner array per token (the native layout)
+    #[default]
+    RowMajor,
+    /// One inner array per embedding dimension
+    ColMajor,
+}
+
+impl RerankRequest {
+    /// Convert column-major token arrays to row-major in place
+    pub fn normalize_layout(&mut self) -> Result<(), String> {
+        if self.layout == Layout::ColMajor {
+            self.q_tokens = transpose(std::mem::take(&mut self.q_tokens))
+                .map_err(|e| format!("q_tokens: {}", e))?;
+            for (i, doc) in self.d_tokens.iter_mut().enumerate() {
+                *doc = transpose(std::mem::take(doc)).map_err(|e| format!("d_tokens[{}]: {}", i, e))?;
+            }
+            self.layout = Layout::RowMajor;
+        }
+        Ok(())
+    }
+}
+
+/// Transpose a rectangular nested matrix
+pub fn transpose(matrix: Vec<Vec<f32>>) -> Result<Vec<Vec<f32>>, String> {
+    let cols = matrix.first().map_or(0, |row| row.len());
+    if let Some(row) = matrix.iter().position(|row| row.len() != cols) {
+        return Err(format!("row {} has {} entries, expected {}", row, matrix[row].len(), cols));
+    }
+    Ok((0..cols).map(|c| matrix.iter().map(|row| row[c]).collect()).collect())
 }
 
 /// Response structure for reranking
+///
+/// `/rerank` also adds a top-level `serialize_ms` when responding with JSON.
 #[derive(Debug, serde::Serialize)]
 pub struct RerankResponse {
+    pub order: Vec<usize>,
+    /// Raw scores, never altered by `calibration`
+    pub scores: Vec<f32>,
+    /// 1-based rank of each returned document, counting from `offset + 1`
+    pub ranks: Vec<usize>,
+    pub perf: PerfStats,
+    pub stats: ScoreStats,
+    /// `sigmoid(scale * score + bias)` per entry of `scores`, present only
+    /// when `calibration` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probabilities: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_scores: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topks: Option<BTreeMap<usize, RankedPrefix>>,
+    pub prune_stats: PruneStats,
+}
+
+/// Token counts before and after pruning
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct PruneStats {
+    pub q_tokens_in: usize,
+    pub q_tokens_kept: usize,
+    /// `(tokens_in, tokens_kept)` per returned document, parallel to `order`
+    pub docs: Vec<(usize, usize)>,
+}
+
+/// Best K documents of a ranking
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct RankedPrefix {
+    pub order: Vec<usize>,
+    pub scores: Vec<f32>,
+}
+
+/// Logistic mapping from raw scores to relevance probabilities
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Calibration {
+    pub scale: f32,
+    pub bias: f32,
+}
+
+impl Calibration {
+    /// `sigmoid(scale * score + bias)`
+    pub fn probability(&self, score: f32) -> f32 {
+        1.0 / (1.0 + (-(self.scale * score + self.bias)).exp())
+    }
+}
+
+/// How per-query-token maxima are combined into a document score
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ScoreMode {
+    /// Sum of per-query-token max dots (standard ColBERT)
+    #[default]
+    #[serde(rename = "maxsim", alias = "sum")]
+    MaxSim,
+    /// MaxSim divided by the number of scored query tokens, so scores are
+    /// comparable across query lengths
+    #[serde(rename = "mean_maxsim", alias = "mean")]
+    MeanMaxSim,
+    /// Sum over query tokens of a softmax-weighted average of document dots
+    /// instead of the hard max; see `soft_maxsim`
+    #[serde(rename = "logsumexp")]
+    LogSumExp,
+    /// Sum of every query-token × document-token dot, with no max. A
+    /// baseline showing what the max selection contributes; it computes
+    /// the same dots as MaxSim at the same cost. See `sum_all_dots`.
+    #[serde(rename = "sum_all")]
+    SumAll,
+}
+
+impl ScoreMode {
+    pub const ALL: [ScoreMode; 4] = [ScoreMode::MaxSim, ScoreMode::MeanMaxSim, ScoreMode::LogSumExp, ScoreMode::SumAll];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScoreMode::MaxSim => "maxsim",
+            ScoreMode::MeanMaxSim => "mean_maxsim",
+            ScoreMode::LogSumExp => "logsumexp",
+            ScoreMode::SumAll => "sum_all",
+        }
+    }
+}
+
+impl std::str::FromStr for ScoreMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "maxsim" | "sum" => Ok(ScoreMode::MaxSim),
+            "mean_maxsim" | "mean" => Ok(ScoreMode::MeanMaxSim),
+            "logsumexp" => Ok(ScoreMode::LogSumExp),
+            "sum_all" => Ok(ScoreMode::SumAll),
+            other => Err(format!("unknown score mode '{}', expected maxsim, mean_maxsim, logsumexp or sum_all", other)),
+        }
+    }
+}
+
+/// Token similarity used on unnormalized rows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Similarity {
+    /// Raw dot product, so token norms weight the score
+    Dot,
+    /// Dot product divided by both row norms
+    Cosine,
+}
+
+/// How document scores combine into a cluster score
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClusterAggregate {
+    /// Score of the cluster's best document
+    #[default]
+    Max,
+    /// Mean score of the cluster's documents
+    Mean,
+}
+
+/// Which way MaxSim matches tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Each query token takes its best document token (standard ColBERT)
+    #[default]
+    Q2d,
+    /// Each document token takes its best query token; see `d2q_maxsim`
+    D2q,
+    /// Mean of both directions (see `symmetric_maxsim`)
+    Symmetric,
+}
+
+/// Softmax temperature for `logsumexp` when the request gives none
+pub const DEFAULT_LSE_TEMPERATURE: f32 = 0.1;
+
+/// Optional per-request scoring behaviour
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct ScoreOptions {
+    /// Score aggregation; falls back to the server default when omitted
+    pub score_mode: Option<ScoreMode>,
+    /// Softmax temperature for `logsumexp` (default `DEFAULT_LSE_TEMPERATURE`).
+    /// Lower values approach the hard max; higher values approach the mean.
+    pub lse_temperature: Option<f32>,
+    /// Score unnormalized rows with this similarity. When omitted, rows are
+    /// L2-normalized up front and compared by dot product; `cosine` gives
+    /// the same scores without rewriting the rows.
+    pub similarity: Option<Similarity>,
+    /// Check a sample of documents for near-identical embeddings (advisory)
+    pub detect_degenerate: bool,
+    /// Keep only a bounded top-K heap and a reservoir sample of per-doc
+    /// timings instead of every document's result. Percentiles become
+    /// approximate once the corpus exceeds `TIMING_RESERVOIR_SIZE`, and the
+    /// post-scorer only sees the retained top-K.
+    pub low_memory: bool,
+    /// Per-document retriever confidence in [0, 1], multiplied into the
+    /// MaxSim score before ranking
+    pub confidences: Option<Vec<f32>>,
+    /// Per document, a metadata flag for each token (e.g. 1 = heading), for
+    /// `flag_boosts`
+    pub d_token_flags: Option<Vec<Vec<u8>>>,
+    /// Multiplier applied to each dot against a document token carrying the
+    /// flag, before the max; unlisted flags count 1.0. Flags align with the
+    /// unpruned document tokens and follow the tokens pruning keeps.
+    #[serde(deserialize_with = "deserialize_numeric_keys")]
+    pub flag_boosts: Option<HashMap<u8, f32>>,
+    /// Per query token, whether it is scored (`true`) or is a mask/padding
+    /// token (`false`). Masked rows are left out of pruning and of the
+    /// MaxSim sum entirely, as if they had not been sent.
+    pub q_mask: Option<Vec<bool>>,
+    /// Per-query-token importance, aligned with `q_tokens`: each token's max
+    /// is multiplied by its weight before summing. Pruned tokens take their
+    /// weights with them. Not available with `early_exit` or `trace_ops`.
+    pub q_weights: Option<Vec<f32>>,
+    /// Report an estimate of the peak working set in stats
+    pub report_memory: bool,
+    /// Re-score a sample of documents without pruning and count those whose
+    /// score moved by more than `LOSSLESS_TOLERANCE` (eval mode)
+    pub verify_lossless: bool,
+    /// Rank every document again without pruning and report whether the
+    /// returned page differs (`prune_changed_ranking`). Roughly doubles the
+    /// request's cost, and more when pruning was aggressive, since the rerun
+    /// scores every query and document token.
+    pub check_prune_impact: bool,
+    /// Record the dot/max/add sequence for the top document (tiny inputs only,
+    /// capped at `TRACE_MAX_OPS`)
+    pub trace_ops: bool,
+    /// Flag documents whose scoring time exceeds this multiple of the median
+    /// per-doc time (not available in low-memory mode)
+    pub flag_slow_docs: Option<f32>,
+    /// Clamp each per-query-token max dot at zero, so negative similarity
+    /// never subtracts from a score. For unit vectors this only changes
+    /// documents with no token pointing toward a given query token.
+    pub relu_sim: bool,
+    /// Score as the average of query→doc and doc→query MaxSim; see
+    /// `symmetric_maxsim` for the normalization. Shorthand for
+    /// `direction: symmetric`.
+    pub symmetric: bool,
+    /// Which way tokens are matched (default `q2d`); documents much shorter
+    /// than their queries can favour `d2q` or `symmetric`
+    pub direction: Direction,
+    /// Minimum per-query-token max similarity for that token to count as
+    /// covered. Enables the coverage requirement together with `min_coverage`.
+    pub coverage_threshold: Option<f32>,
+    /// Fraction of query tokens that must be covered; documents below it get
+    /// `DISQUALIFIED_SCORE`
+    pub min_coverage: f32,
+    /// Number of top-ranked documents to skip before the returned page
+    pub offset: usize,
+    /// Report `probabilities` alongside the raw scores; `scores` stay raw
+    pub calibration: Option<Calibration>,
+    /// Also return the best K documents for each of these K values, cut from
+    /// the same scoring pass
+    pub topks: Option<Vec<usize>>,
+    /// Per-query-token IDF weights, aligned with `q_tokens`. With `idf_norm`
+    /// query salience becomes `idf × norm`; without them the norm stands in.
+    pub q_idf: Option<Vec<f32>>,
+    /// IDF per vocabulary token id. With `q_token_ids`/`d_token_ids`,
+    /// `idf_norm` salience becomes `idf[id] × norm` (see `idf_from_table`).
+    pub idf: Option<HashMap<u32, f32>>,
+    /// Vocabulary id of each query token, for `idf`
+    pub q_token_ids: Option<Vec<u32>>,
+    /// Per document, the vocabulary id of each token, for `idf`. Ignored for
+    /// documents cut down by `reservoir_sample`.
+    pub d_token_ids: Option<Vec<Vec<u32>>>,
+    /// Report the IDF weight applied to each surviving query token
+    pub return_idf: bool,
+    /// Token norm at or below which `l2_normalize_rows` leaves a row
+    /// unnormalized (default `DEFAULT_NORM_EPS`). Raise it to treat
+    /// near-zero padding tokens as zero; lower it when genuine embeddings
+    /// have very small magnitudes and must still be unit-normalized.
+    pub norm_eps: Option<f32>,
+    /// Approximate MaxSim: only compare each query token against document
+    /// tokens assigned to one of its `centroid_filter` nearest centroids.
+    /// Requires `q_centroids` and `d_centroids`; document pruning is skipped.
+    pub centroid_filter: Option<usize>,
+    /// Per query token, centroid ids ordered nearest first
+    pub q_centroids: Option<Vec<Vec<u32>>>,
+    /// Per document, the centroid id of each token
+    pub d_centroids: Option<Vec<Vec<u32>>>,
+    /// Stable key per query token (e.g. a token id); salience ties during
+    /// pruning go to the lower key instead of the earlier position
+    pub q_tiebreak_keys: Option<Vec<u64>>,
+    /// Per document, a stable key for each token, as `q_tiebreak_keys`.
+    /// Ignored for documents cut down by `reservoir_sample`.
+    pub d_tiebreak_keys: Option<Vec<Vec<u64>>>,
+    /// Report each query token's mean similarity to the other query tokens
+    pub q_redundancy: bool,
+    /// Report a `result_hash` over the returned ranking for change detection
+    pub result_hash: bool,
+    /// Report `log_scores`: `ln(score)` of each raw score, or
+    /// `NON_POSITIVE_LOG_SCORE` when the score is zero or negative
+    pub log_scores: bool,
+    /// Stop scoring a document once even perfect matches on its remaining
+    /// query tokens couldn't lift it into the current top-K. Only available
+    /// in low-memory mode, and not with `symmetric`, `confidences` or
+    /// `coverage_threshold`, which break the per-token upper bound.
+    pub early_exit: bool,
+    /// Two-stage scoring: a `dim × reduced_dim` matrix (e.g. PCA) that
+    /// projects every token for a cheap first pass. Requires `refine_topk`.
+    pub projection: Option<Vec<Vec<f32>>>,
+    /// Documents from the projected pass re-scored at full dimension; see
+    /// `score_docs_two_stage`
+    pub refine_topk: Option<usize>,
+    /// MaxSim top-N handed to the server's registered second-stage scorer,
+    /// which alone ranks the returned documents; see `second_stage`
+    pub second_stage_candidates: Option<usize>,
+    /// Report null percentiles when fewer per-doc timings than this were
+    /// taken (default `DEFAULT_MIN_PERCENTILE_SAMPLES`)
+    pub min_percentile_samples: Option<usize>,
+    /// MMR lambdas to sweep over the top `MMR_POOL_FACTOR × topk` documents;
+    /// each reports the relevance and diversity of its selected top-K
+    pub mmr_sweep: Option<Vec<f32>>,
+    /// Lowest L2 norm expected of input tokens; a sample outside
+    /// `expected_norm_min..=expected_norm_max` rejects the request
+    pub expected_norm_min: Option<f32>,
+    pub expected_norm_max: Option<f32>,
+    /// Report an out-of-range norm sample in stats instead of rejecting
+    pub norm_check_advisory: bool,
+    /// Report, per returned document, the query token with the highest
+    /// max-dot against it
+    pub return_dominant_q_token: bool,
+    /// Report, per returned document, each query token's max-dot as a
+    /// fraction of the query→document MaxSim sum
+    pub contribution_fractions: bool,
+    /// Report the smallest and largest per-query-token max-dot over the
+    /// returned documents, to help pick score thresholds
+    pub return_sim_range: bool,
+    /// Resample the kept query tokens with replacement this many times and
+    /// report each returned document's bootstrap mean and
+    /// `BOOTSTRAP_CONFIDENCE` interval. Costs one more q × d pass per
+    /// returned document, then `bootstrap × q` additions: resamples reuse
+    /// the per-token maxima rather than recomputing dots. MaxSim and
+    /// mean MaxSim in the `q2d` direction only. At most
+    /// `BOOTSTRAP_MAX_RESAMPLES`.
+    pub bootstrap: Option<usize>,
+    /// Cluster id of each document, for `cluster_scores`
+    pub cluster_ids: Option<Vec<usize>>,
+    /// Report every cluster's aggregate MaxSim score and best document,
+    /// over all scored documents rather than just the returned page. Not
+    /// available in low-memory mode or with `projection`.
+    pub cluster_scores: bool,
+    /// How `cluster_scores` combines member scores
+    pub cluster_aggregate: ClusterAggregate,
+    /// Replace NaN and ±Inf token values with 0 before scoring rather than
+    /// rejecting the request. Applied by the server, which records the count
+    /// in `sanitized_values`.
+    pub sanitize: bool,
+    /// Values `sanitize` replaced, reported in stats
+    #[serde(skip)]
+    pub sanitized_values: Option<usize>,
+    /// Incremented once per scored document so callers can observe progress
+    #[serde(skip)]
+    pub progress: Option<Progress>,
+}
+
+/// Counter of scored documents shared with the caller; two counters are
+/// equal only if they are the same counter
+#[derive(Debug, Clone, Default)]
+pub struct Progress(pub Arc<AtomicUsize>);
+
+impl PartialEq for Progress {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl std::ops::Deref for Progress {
+    type Target = AtomicUsize;
+
+    fn deref(&self) -> &AtomicUsize {
+        &self.0
+    }
+}
+
+impl ScoreOptions {
+    /// `direction`, with `symmetric: true` taking precedence
+    pub fn effective_direction(&self) -> Direction {
+        if self.symmetric {
+            Direction::Symmetric
+        } else {
+            self.direction
+        }
+    }
+}
+
+/// Read a JSON object with numeric keys into a map. Flattened fields are
+/// buffered before they are deserialized, and the buffer no longer parses
+/// object keys (always strings in JSON) as numbers, so this does it instead.
+fn deserialize_numeric_keys<'de, D, K>(deserializer: D) -> Result<Option<HashMap<K, f32>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    K: std::str::FromStr + Eq + std::hash::Hash,
+    K::Err: std::fmt::Display,
+{
+    let map: Option<HashMap<String, f32>> = serde::Deserialize::deserialize(deserializer)?;
+    map.map(|map| {
+        map.into_iter()
+            .map(|(key, value)| key.parse().map(|key| (key, value)).map_err(serde::de::Error::custom))
+            .collect()
+    })
+    .transpose()
+}
+
+/// Prune settings as actually applied, after defaults and adaptations
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct EffectivePrune {
+    /// Query token budget after `q_min_ratio` and `adapt_q_to_docs`
+    pub q_max: usize,
+    pub d_max: usize,
+    /// Method used for document tokens
+    pub method: String,
+    /// Method used for query tokens (`query_affinity` falls back to idf_norm)
+    pub query_method: String,
+    pub token_dropout: f32,
+    pub dropout_seed: u64,
+    pub hard_doc_token_cap: Option<usize>,
+    pub reservoir_sample: Option<usize>,
+    pub d_max_ratio: Option<f32>,
+    pub d_min: usize,
+}
+
+impl EffectivePrune {
+    /// `prune_config` as applied with query budget `q_max` and `query_method`
+    pub fn new(prune_config: &PruneConfig, q_max: usize, query_method: &str) -> Self {
+        Self {
+            q_max,
+            d_max: prune_config.d_max,
+            method: prune_config.method.clone(),
+            query_method: query_method.to_string(),
+            token_dropout: prune_config.token_dropout,
+            dropout_seed: prune_config.dropout_seed,
+            hard_doc_token_cap: prune_config.hard_doc_token_cap,
+            reservoir_sample: prune_config.reservoir_sample,
+            d_max_ratio: prune_config.d_max_ratio,
+            d_min: prune_config.d_min,
+        }
+    }
+}
+
+/// Advisory statistics about a scoring run
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ScoreStats {
+    /// Score mode actually used
+    pub score_mode: ScoreMode,
+    /// Prune config actually used
+    pub effective_prune: EffectivePrune,
+    /// Documents sharing the K-th score. Above 1 means the top-K boundary was
+    /// an arbitrary pick among ties, so a larger K may be worth requesting.
+    /// In low-memory mode only the retained top-K are counted.
+    pub cutoff_ties: usize,
+    /// True when documents were scored but every one failed a qualifying
+    /// constraint (e.g. `min_coverage`), as opposed to an empty input
+    pub all_disqualified: bool,
+    /// Set when `detect_degenerate` found near-zero variance across documents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub degenerate_corpus: Option<bool>,
+    /// Total document tokens scored after `token_dropout`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_d_tokens: Option<usize>,
+    /// Estimated peak working-set bytes, when `report_memory` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_memory_bytes: Option<usize>,
+    /// Sampled documents whose pruned score deviated beyond tolerance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lossless_violations: Option<usize>,
+    /// Whether the returned page differs from ranking without pruning, when
+    /// `check_prune_impact` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prune_changed_ranking: Option<bool>,
+    /// Documents cut down by `hard_doc_token_cap`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hard_truncated: Option<Vec<usize>>,
+    /// f32 operation trace for the top document, when `trace_ops` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub op_trace: Option<OpTrace>,
+    /// Documents slower than `flag_slow_docs` × median per-doc time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_docs: Option<Vec<usize>>,
+    /// Hex digest of the returned `(doc_index, rounded score)` pairs, when
+    /// `result_hash` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_hash: Option<String>,
+    /// IDF weight of each kept query token, in kept order, when `return_idf`
+    /// is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_idf: Option<Vec<f32>>,
+    /// Per input query token not masked by `q_mask`, its mean cosine
+    /// similarity to the other unmasked query tokens; values near 1 mark
+    /// redundant tokens. Set by `q_redundancy`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub q_redundancy: Option<Vec<f32>>,
+    /// Documents abandoned by `early_exit` before all query tokens were scored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub early_exit_docs: Option<usize>,
+    /// Fraction of dot products `early_exit` skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dots_skipped_fraction: Option<f32>,
+    /// Covered fraction of query tokens per returned document, when
+    /// `coverage_threshold` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<Vec<f32>>,
+    /// Documents re-scored at full dimension after the projected pass
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refined_docs: Option<usize>,
+    /// Wall time of the prune-and-normalize phase. Not set in low-memory
+    /// mode, which prunes and scores each document in one step.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prune_ms: Option<f32>,
+    /// Part of `prune_ms` spent packing and L2-normalizing the kept rows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalize_ms: Option<f32>,
+    /// Wall time of the scoring phase; per-doc timings cover only this phase
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score_ms: Option<f32>,
+    /// Per-doc times summed over every document: the thread time spent
+    /// scoring. In low-memory mode it also covers pruning.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_ms: Option<f32>,
+    /// Wall time of the parallel pass `cpu_ms` was measured over
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wall_ms: Option<f32>,
+    /// `cpu_ms / wall_ms`, roughly the number of threads kept busy; well
+    /// below the thread count points at under-parallelization
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallelism: Option<f32>,
+    /// One point per `mmr_sweep` lambda, in request order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mmr_sweep: Option<Vec<MmrPoint>>,
+    /// Sampled token norm range, when it fell outside the expected range
+    /// under `norm_check_advisory`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub norm_out_of_range: Option<NormRange>,
+    /// Best-matching query token per returned document, when
+    /// `return_dominant_q_token` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dominant_q_tokens: Option<Vec<DominantToken>>,
+    /// Per returned document, one fraction per request query token (0 for
+    /// pruned tokens) summing to 1; empty for a non-positive MaxSim sum.
+    /// Set by `contribution_fractions`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contribution_fractions: Option<Vec<Vec<f32>>>,
+    /// Range of the per-query-token max-dots summed into the returned
+    /// documents' scores, when `return_sim_range` is set and any were returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sim_range: Option<SimRange>,
+    /// Aggregate score per cluster id, when `cluster_scores` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster_scores: Option<BTreeMap<usize, ClusterScore>>,
+    /// Per returned document, when `bootstrap` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bootstrap: Option<Vec<BootstrapInterval>>,
+    /// Non-finite token values replaced with 0, when `sanitize` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sanitized_values: Option<usize>,
+}
+
+/// Bootstrap estimate of one document's score over resampled query tokens
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct BootstrapInterval {
+    /// Point estimate: the score from the kept query tokens, before
+    /// calibration or confidences
+    pub score: f32,
+    /// Mean of the resampled scores
+    pub mean: f32,
+    /// Bounds of the central `BOOTSTRAP_CONFIDENCE` share of resampled scores
+    pub lower: f32,
+    pub upper: f32,
+}
+
+/// Aggregate score of one document cluster
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct ClusterScore {
+    pub score: f32,
+    /// Highest-scoring member
+    pub best_doc: usize,
+    /// Members that were scored (disqualified documents are left out)
+    pub size: usize,
+}
+
+/// Smallest and largest per-query-token max-dot across returned documents
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct SimRange {
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Query token contributing the most to one document's score
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct DominantToken {
+    /// Index into the request's `q_tokens`
+    pub q_index: usize,
+    /// Its max dot against the document's kept tokens
+    pub value: f32,
+}
+
+/// One f32 operation in a MaxSim computation
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TraceOp {
+    /// Dot product of query row `q` with document row `d`
+    Dot { q: usize, d: usize, value: f32 },
+    /// Max over all dots for query row `q`
+    Max { q: usize, value: f32 },
+    /// Max for query row `q` after clamping at zero (relu_sim)
+    Relu { q: usize, value: f32 },
+    /// Running total after adding the max for query row `q`
+    Add { q: usize, value: f32 },
+    /// Total divided by the number of query rows (mean_maxsim)
+    Mean { n: usize, value: f32 },
+    /// Final score after multiplying by the document's confidence
+    Scale { factor: f32, value: f32 },
+}
+
+/// Replayable operation sequence that produced one document's score
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OpTrace {
+    pub doc_index: usize,
+    pub ops: Vec<TraceOp>,
+}
+
+/// Full result of scoring a set of documents
+#[derive(Debug, Clone)]
+pub struct ScoreOutput {
     pub order: Vec<usize>,
     pub scores: Vec<f32>,
+    pub ranks: Vec<usize>,
     pub perf: PerfStats,
+    pub stats: ScoreStats,
+    /// Calibrated scores, when `calibration` is set
+    pub probabilities: Option<Vec<f32>>,
+    /// Natural log of the raw scores, when `log_scores` is set
+    pub log_scores: Option<Vec<f32>>,
+    /// Top documents per requested K, when `topks` is set
+    pub topks: Option<BTreeMap<usize, RankedPrefix>>,
+    pub prune_stats: PruneStats,
+}
+
+impl From<ScoreOutput> for RerankResponse {
+    fn from(output: ScoreOutput) -> Self {
+        Self {
+            order: output.order,
+            scores: output.scores,
+            ranks: output.ranks,
+            perf: output.perf,
+            stats: output.stats,
+            probabilities: output.probabilities,
+            log_scores: output.log_scores,
+            topks: output.topks,
+            prune_stats: output.prune_stats,
+        }
+    }
+}
+
+/// Pruning facts made available to a post-scorer
+#[derive(Debug, Clone)]
+pub struct ScoringContext {
+    /// Query tokens kept after pruning
+    pub q_tokens_kept: usize,
+    /// Document tokens kept after pruning, indexed by document
+    pub d_tokens_kept: Vec<usize>,
+    /// Embedding dimension
+    pub dim: usize,
 }
 
+/// Custom final re-ranking step that runs after MaxSim
+///
+/// `docs` holds every scored document as `(doc_idx, score)` sorted by
+/// descending MaxSim score. The returned order is taken as the final ranking
+/// before it is truncated to top-K.
+pub trait PostScorer: Send + Sync {
+    fn rescore(&self, docs: &[(usize, f32)], ctx: &ScoringContext) -> Vec<(usize, f32)>;
+
+    /// Whether `rescore` needs every scored document. When false, `docs`
+    /// only holds the documents that can still reach the response, which
+    /// spares sorting the rest.
+    fn needs_full_ranking(&self) -> bool {
+        true
+    }
+}
+
+/// Default post-scorer that keeps the MaxSim ranking unchanged
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopPostScorer;
+
+impl PostScorer for NoopPostScorer {
+    fn rescore(&self, docs: &[(usize, f32)], _ctx: &ScoringContext) -> Vec<(usize, f32)> {
+        docs.to_vec()
+    }
+
+    fn needs_full_ranking(&self) -> bool {
+        false
+    }
+}
+
+/// Default `norm_eps`
+pub const DEFAULT_NORM_EPS: f32 = 1e-8;
+
 /// L2 normalize rows of a matrix
-pub fn l2_normalize_rows(matrix: &mut DMatrix<f32>) {
+///
+/// Rows with norm at or below `eps` are treated as zero vectors and left
+/// unchanged, so they keep their (tiny) raw dot products instead of being
+/// blown up to unit length.
+pub fn l2_normalize_rows(matrix: &mut DMatrix<f32>, eps: f32) {
     for mut row in matrix.row_iter_mut() {
         let norm = row.norm();
-        if norm > 1e-8 {
+        if norm > eps {
             row /= norm;
         }
     }
 }
 
+/// Pack tokens into a row-per-token matrix as given
+pub fn token_matrix(tokens: &[Vec<f32>]) -> DMatrix<f32> {
+    DMatrix::from_row_slice(
+        tokens.len(),
+        tokens[0].len(),
+        &tokens.iter().flatten().cloned().collect::<Vec<_>>(),
+    )
+}
+
+/// Token count from which salience and query matrices are computed in
+/// parallel. Most documents fall below it: they are already pruned in
+/// parallel with each other, and splitting them further only adds overhead.
+pub const PAR_MIN_TOKENS: usize = 256;
+
+/// Pack tokens into a row-per-token matrix with L2-normalized rows
+pub fn normalized_matrix(tokens: &[Vec<f32>], eps: f32) -> DMatrix<f32> {
+    if tokens.len() >= PAR_MIN_TOKENS {
+        // Same arithmetic as `l2_normalize_rows`, one row per task
+        let values: Vec<f32> = tokens
+            .par_iter()
+            .flat_map_iter(|token| {
+                let norm = token.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let scale = if norm > eps { norm } else { 1.0 };
+                token.iter().map(move |x| x / scale)
+            })
+            .collect();
+        return DMatrix::from_row_slice(tokens.len(), tokens[0].len(), &values);
+    }
+    let mut matrix = token_matrix(tokens);
+    l2_normalize_rows(&mut matrix, eps);
+    matrix
+}
+
 /// Compute token salience using IDF * norm (SIGIR 2025 approach)
-pub fn token_salience(tokens: &[Vec<f32>], method: &str) -> Vec<(usize, f32)> {
-    let mut saliences = Vec::new();
-    
-    for (i, token) in tokens.iter().enumerate() {
-        let norm = token.iter().map(|x| x * x).sum::<f32>().sqrt();
+pub fn token_salience<T: AsRef<[f32]> + Sync>(tokens: &[T], method: &str) -> Vec<(usize, f32)> {
+    token_salience_keyed(tokens, method, None)
+}
+
+/// Salience order of two `(index, salience)` pairs: descending salience,
+/// ties to the lower `tiebreak` key when keys are given, then the lower
+/// index. Total over indices, so sorted and selected orders agree.
+fn salience_order(a: &(usize, f32), b: &(usize, f32), tiebreak: Option<&[u64]>) -> Ordering {
+    let by_salience = b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal);
+    let by_key = match tiebreak {
+        Some(keys) => by_salience.then_with(|| keys[a.0].cmp(&keys[b.0])),
+        None => by_salience,
+    };
+    by_key.then_with(|| a.0.cmp(&b.0))
+}
+
+/// Sort `(index, salience)` pairs by `salience_order`
+fn sort_by_salience(saliences: &mut [(usize, f32)], tiebreak: Option<&[u64]>) {
+    saliences.sort_unstable_by(|a, b| salience_order(a, b, tiebreak));
+}
+
+/// Cut `saliences` down to its `n` most salient pairs, sorted. Only the
+/// kept pairs are sorted; the rest are partitioned off in linear time.
+pub fn select_by_salience(saliences: &mut Vec<(usize, f32)>, n: usize, tiebreak: Option<&[u64]>) {
+    if n == 0 {
+        saliences.clear();
+        return;
+    }
+    if n < saliences.len() {
+        saliences.select_nth_unstable_by(n - 1, |a, b| salience_order(a, b, tiebreak));
+        saliences.truncate(n);
+    }
+    sort_by_salience(saliences, tiebreak);
+}
+
+/// Unsorted `(index, salience)` pairs, computed in parallel for long inputs
+fn salience_pairs<T: AsRef<[f32]> + Sync>(tokens: &[T], method: &str) -> Vec<(usize, f32)> {
+    let salience = |(i, token): (usize, &T)| {
+        let norm = token.as_ref().iter().map(|x| x * x).sum::<f32>().sqrt();
         let salience = match method {
             "idf_norm" => {
                 // SIGIR 2025: salience = idf(token) × ||embedding||₂
-                // For demo: use norm as proxy for idf (higher norm = more informative)
+                // Without caller IDF (`q_idf`, or `idf` with token ids) the
+                // norm stands in for idf (higher norm = more informative)
                 norm * norm // Square to emphasize high-norm tokens
             },
             "norm_only" => norm,
             _ => norm,
         };
-        saliences.push((i, salience));
+        (i, salience)
+    };
+    if tokens.len() >= PAR_MIN_TOKENS {
+        tokens.par_iter().enumerate().map(salience).collect()
+    } else {
+        tokens.iter().enumerate().map(salience).collect()
     }
-    
-    saliences.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+}
+
+/// `token_salience` with ties broken by a stable per-token key (e.g. a token
+/// id) rather than by position, so selection doesn't depend on token order
+pub fn token_salience_keyed<T: AsRef<[f32]> + Sync>(tokens: &[T], method: &str, tiebreak: Option<&[u64]>) -> Vec<(usize, f32)> {
+    let mut saliences = salience_pairs(tokens, method);
+    sort_by_salience(&mut saliences, tiebreak);
+    saliences
+}
+
+/// The `n` most salient tokens, as `token_salience_keyed(..).take(n)` but
+/// without sorting the tokens that are dropped
+pub fn top_salient<T: AsRef<[f32]> + Sync>(tokens: &[T], n: usize, method: &str, tiebreak: Option<&[u64]>) -> Vec<(usize, f32)> {
+    let mut saliences = salience_pairs(tokens, method);
+    select_by_salience(&mut saliences, n, tiebreak);
     saliences
 }
 
 /// Prune tokens to keep top-N by salience
 pub fn prune_tokens(tokens: &[Vec<f32>], max_n: usize, method: &str) -> Vec<Vec<f32>> {
+    prune_tokens_keyed(tokens, max_n, method, None)
+}
+
+/// `prune_tokens` with salience ties broken by `tiebreak` keys
+pub fn prune_tokens_keyed(tokens: &[Vec<f32>], max_n: usize, method: &str, tiebreak: Option<&[u64]>) -> Vec<Vec<f32>> {
+    prune_token_indices(tokens, max_n, method, None, tiebreak)
+        .into_iter()
+        .map(|i| tokens[i].clone())
+        .collect()
+}
+
+/// Indices of the tokens `prune_tokens` keeps, optionally with caller IDF
+/// and tiebreak keys
+pub fn prune_token_indices(
+    tokens: &[Vec<f32>],
+    max_n: usize,
+    method: &str,
+    idf: Option<&[f32]>,
+    tiebreak: Option<&[u64]>,
+) -> Vec<usize> {
     if tokens.len() <= max_n {
-        return tokens.to_vec();
+        return (0..tokens.len()).collect();
     }
     
-    let saliences = token_salience(tokens, method);
-    let top_indices: Vec<usize> = saliences
+    let saliences = match idf {
+        Some(idf) if method == "idf_norm" => {
+            let mut saliences: Vec<(usize, f32)> = idf_weights(tokens, Some(idf))
+                .into_iter()
+                .zip(tokens)
+                .map(|(w, token)| w * token.iter().map(|x| x * x).sum::<f32>().sqrt())
+                .enumerate()
+                .collect();
+            select_by_salience(&mut saliences, max_n, tiebreak);
+            saliences
+        }
+        _ => top_salient(tokens, max_n, method, tiebreak),
+    };
+    saliences.iter().map(|(idx, _)| *idx).collect()
+}
+
+/// IDF weight applied to each token by `idf_norm`
+///
+/// Uses the caller-supplied weights when given; otherwise the embedding norm
+/// stands in for IDF, matching `token_salience`'s `norm × norm`.
+pub fn idf_weights(tokens: &[Vec<f32>], idf: Option<&[f32]>) -> Vec<f32> {
+    match idf {
+        Some(idf) => idf.to_vec(),
+        None => tokens.iter().map(|t| t.iter().map(|x| x * x).sum::<f32>().sqrt()).collect(),
+    }
+}
+
+/// IDF of each token id from a document-frequency-derived table. Ids missing
+/// from the table are treated as the rarest seen, taking its largest IDF.
+pub fn idf_from_table(ids: &[u32], table: &HashMap<u32, f32>) -> Vec<f32> {
+    let unseen = table.values().copied().fold(0.0, f32::max);
+    ids.iter().map(|id| table.get(id).copied().unwrap_or(unseen)).collect()
+}
+
+/// Document prune method that ranks tokens by affinity to the current query
+pub const QUERY_AFFINITY: &str = "query_affinity";
+
+/// Every recognised `PruneConfig::method`
+pub const PRUNE_METHODS: [&str; 3] = ["idf_norm", "norm_only", QUERY_AFFINITY];
+
+/// Prune document tokens to the `max_n` with the highest dot against any query token
+///
+/// Unlike salience pruning this is query-dependent, so it runs per document
+/// inside `score_docs` and costs an extra dot product per (query token,
+/// document token) pair. `q_tokens` should already be L2-normalized.
+pub fn prune_by_query_affinity(
+    tokens: &[Vec<f32>],
+    max_n: usize,
+    q_tokens: &[Vec<f32>],
+) -> Vec<Vec<f32>> {
+    query_affinity_indices(tokens, max_n, q_tokens)
+        .into_iter()
+        .map(|i| tokens[i].clone())
+        .collect()
+}
+
+/// Indices of the tokens `prune_by_query_affinity` keeps
+pub fn query_affinity_indices(tokens: &[Vec<f32>], max_n: usize, q_tokens: &[Vec<f32>]) -> Vec<usize> {
+    if tokens.len() <= max_n {
+        return (0..tokens.len()).collect();
+    }
+
+    let dot = dot_kernel();
+    let mut affinities: Vec<(usize, f32)> = tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| {
+            let norm = token.iter().map(|x| x * x).sum::<f32>().sqrt().max(1e-8);
+            let best = q_tokens
+                .iter()
+                .map(|q| dot(q, token) / norm)
+                .fold(f32::NEG_INFINITY, f32::max);
+            (i, best)
+        })
+        .collect();
+
+    select_by_salience(&mut affinities, max_n, None);
+    affinities.iter().map(|(i, _)| *i).collect()
+}
+
+/// Uniformly sample `n` tokens (Algorithm R), preserving their original order
+pub fn reservoir_sample_tokens(tokens: &[Vec<f32>], n: usize, seed: u64) -> Vec<Vec<f32>> {
+    reservoir_sample_indices(tokens.len(), n, seed)
+        .into_iter()
+        .map(|i| tokens[i].clone())
+        .collect()
+}
+
+/// Indices of the tokens `reservoir_sample_tokens` keeps out of `len`, ascending
+pub fn reservoir_sample_indices(len: usize, n: usize, seed: u64) -> Vec<usize> {
+    if len <= n {
+        return (0..len).collect();
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut picked: Vec<usize> = (0..n).collect();
+    for i in n..len {
+        let slot = rng.gen_range(0..=i);
+        if slot < n {
+            picked[slot] = i;
+        }
+    }
+    picked.sort_unstable();
+    picked
+}
+
+/// Randomly drop a fraction of tokens with a seeded RNG
+///
+/// At least one token always survives so the document can still be scored.
+pub fn apply_token_dropout(tokens: Vec<Vec<f32>>, rate: f32, seed: u64) -> Vec<Vec<f32>> {
+    if rate <= 0.0 || tokens.len() <= 1 {
+        return tokens;
+    }
+    let keep = token_dropout_indices(tokens.len(), rate, seed);
+    tokens
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, t)| keep.binary_search(&i).is_ok().then_some(t))
+        .collect()
+}
+
+/// Indices of the tokens `apply_token_dropout` keeps out of `len`, ascending
+pub fn token_dropout_indices(len: usize, rate: f32, seed: u64) -> Vec<usize> {
+    if rate <= 0.0 || len <= 1 {
+        return (0..len).collect();
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let keep: Vec<usize> = (0..len).filter(|_| rng.gen::<f32>() >= rate).collect();
+    if keep.is_empty() {
+        return vec![0];
+    }
+    keep
+}
+
+/// Estimate the peak working set of a scoring run in bytes
+///
+/// Counts the pruned query (token copy plus matrix), the per-document result
+/// buffer and the per-row scratch vectors, plus the documents:
+///
+/// - `low_memory`: one pruned document per worker thread at the largest
+///   kept size (token copy, flattened buffer and matrix).
+/// - otherwise: every pruned document is materialized and then packed into
+///   one buffer for the whole batch, so the token copies and the packed
+///   buffer coexist; while scoring, the packed buffer plus one matrix per
+///   worker thread.
+pub fn estimate_peak_bytes(
+    q_kept: usize,
+    dim: usize,
+    d_tokens: &[Vec<Vec<f32>>],
+    prune_config: &PruneConfig,
+    low_memory: bool,
+    threads: usize,
+    results_len: usize,
+) -> usize {
+    let f32_size = std::mem::size_of::<f32>();
+    let query = 2 * q_kept * dim * f32_size;
+    let largest_doc = d_tokens.iter().map(|doc| prune_config.doc_kept_len(doc.len())).max().unwrap_or(0);
+    let in_flight = threads.min(d_tokens.len());
+    let documents = if low_memory {
+        in_flight * 3 * largest_doc * dim * f32_size
+    } else {
+        let kept_rows: usize = d_tokens.iter().map(|doc| prune_config.doc_kept_len(doc.len())).sum();
+        let token_copies = kept_rows * (dim * f32_size + std::mem::size_of::<Vec<f32>>())
+            + std::mem::size_of_val(d_tokens);
+        let packed = kept_rows * dim * f32_size + (d_tokens.len() + 1) * std::mem::size_of::<usize>();
+        let scoring = in_flight * largest_doc * dim * f32_size;
+        packed + token_copies.max(scoring)
+    };
+    let results = results_len * std::mem::size_of::<(usize, f32, f32, usize)>();
+    let scratch = in_flight * 2 * dim * f32_size;
+    query + documents + results + scratch
+}
+
+/// Documents re-scored without pruning by `verify_lossless`
+pub const LOSSLESS_SAMPLE_SIZE: usize = 32;
+
+/// Maximum absolute score change for pruning to count as lossless
+pub const LOSSLESS_TOLERANCE: f32 = 1e-4;
+
+/// Documents whose time exceeds `multiple` × the median of `times`
+///
+/// `times` holds `(doc_idx, time_ms)` pairs in any order.
+pub fn find_slow_docs(times: &[(usize, f32)], multiple: f32) -> Vec<usize> {
+    if times.is_empty() {
+        return Vec::new();
+    }
+    let mut sorted: Vec<f32> = times.iter().map(|(_, t)| *t).collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+
+    let mut slow: Vec<usize> = times
         .iter()
-        .take(max_n)
+        .filter(|(_, t)| *t > multiple * median)
         .map(|(idx, _)| *idx)
         .collect();
-    
-    top_indices.into_iter().map(|i| tokens[i].clone()).collect()
+    slow.sort_unstable();
+    slow
+}
+
+/// Largest operation trace `trace_ops` will record
+pub const TRACE_MAX_OPS: usize = 4096;
+
+/// Operations `maxsim_trace` records: per query token, one dot per document
+/// token, then a max, an optional relu and an add
+pub fn trace_len(q_rows: usize, d_rows: usize, relu: bool) -> usize {
+    q_rows.saturating_mul(d_rows.saturating_add(if relu { 3 } else { 2 }))
+}
+
+/// Score given to documents that fail the `min_coverage` requirement
+pub const DISQUALIFIED_SCORE: f32 = f32::MIN;
+
+/// `log_scores` entry for a score that is zero or negative. ln is undefined
+/// there, so such documents get the most negative finite f32 instead of
+/// -inf/NaN, which JSON can't carry and which would poison downstream sums.
+pub const NON_POSITIVE_LOG_SCORE: f32 = f32::MIN;
+
+/// Scores are rounded to this many decimal places before `result_hash`, so
+/// run-to-run float jitter doesn't register as a change
+pub const RESULT_HASH_DECIMALS: i32 = 4;
+
+/// Stable FNV-1a hash of an ordered ranking
+///
+/// Each entry contributes its document index and its score rounded to
+/// `RESULT_HASH_DECIMALS` places, so the hash changes when the order or a
+/// score beyond that precision changes.
+pub fn result_hash(order: &[usize], scores: &[f32]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    let scale = 10f64.powi(RESULT_HASH_DECIMALS);
+    let mut hash = FNV_OFFSET;
+    for (&idx, &score) in order.iter().zip(scores) {
+        let rounded = (score as f64 * scale).round() as i64;
+        for byte in (idx as u64).to_le_bytes().into_iter().chain(rounded.to_le_bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash
+}
+
+/// Combine `(doc_idx, score)` pairs by `cluster_ids[doc_idx]`. Documents
+/// scored `DISQUALIFIED_SCORE` count toward no cluster; the best document
+/// of a tie is the lowest index.
+pub fn cluster_scores(
+    scores: &[(usize, f32)],
+    cluster_ids: &[usize],
+    aggregate: ClusterAggregate,
+) -> BTreeMap<usize, ClusterScore> {
+    let mut clusters: BTreeMap<usize, (ClusterScore, f32)> = BTreeMap::new();
+    for &(doc_idx, score) in scores.iter().filter(|(_, score)| *score != DISQUALIFIED_SCORE) {
+        let (cluster, sum) = clusters
+            .entry(cluster_ids[doc_idx])
+            .or_insert((ClusterScore { score, best_doc: doc_idx, size: 0 }, 0.0));
+        if score > cluster.score || (score == cluster.score && doc_idx < cluster.best_doc) {
+            cluster.score = score;
+            cluster.best_doc = doc_idx;
+        }
+        cluster.size += 1;
+        *sum += score;
+    }
+    clusters
+        .into_iter()
+        .map(|(id, (mut cluster, sum))| {
+            if aggregate == ClusterAggregate::Mean {
+                cluster.score = sum / cluster.size as f32;
+            }
+            (id, cluster)
+        })
+        .collect()
+}
+
+/// Natural log of a raw score, with `NON_POSITIVE_LOG_SCORE` below zero
+pub fn log_score(score: f32) -> f32 {
+    if score > 0.0 {
+        score.ln()
+    } else {
+        NON_POSITIVE_LOG_SCORE
+    }
+}
+
+/// Per-doc timings sampled for percentiles in low-memory mode
+pub const TIMING_RESERVOIR_SIZE: usize = 1024;
+
+/// Maximum number of documents inspected by the degenerate-corpus check
+pub const DEGENERATE_SAMPLE_SIZE: usize = 64;
+
+/// Total variance below which sampled documents are considered identical
+pub const DEGENERATE_VARIANCE_EPS: f32 = 1e-6;
+
+/// Check whether a sample of documents has near-zero embedding variance
+///
+/// Each sampled document is reduced to the mean of its L2-normalized tokens,
+/// and the per-dimension variance of those means across documents is summed.
+pub fn is_degenerate_corpus(d_tokens: &[Vec<Vec<f32>>]) -> bool {
+    let step = d_tokens.len().div_ceil(DEGENERATE_SAMPLE_SIZE).max(1);
+    let pooled: Vec<Vec<f32>> = d_tokens
+        .iter()
+        .step_by(step)
+        .filter(|doc| !doc.is_empty())
+        .map(|doc| {
+            let mut mean = vec![0.0; doc[0].len()];
+            for token in doc {
+                let norm = token.iter().map(|x| x * x).sum::<f32>().sqrt().max(1e-8);
+                for (m, x) in mean.iter_mut().zip(token) {
+                    *m += x / norm;
+                }
+            }
+            mean.iter_mut().for_each(|m| *m /= doc.len() as f32);
+            mean
+        })
+        .collect();
+
+    if pooled.len() < 2 {
+        return false;
+    }
+
+    let n = pooled.len() as f32;
+    let dim = pooled[0].len();
+    let total_variance: f32 = (0..dim)
+        .map(|k| {
+            let mean = pooled.iter().map(|p| p[k]).sum::<f32>() / n;
+            pooled.iter().map(|p| (p[k] - mean).powi(2)).sum::<f32>() / n
+        })
+        .sum();
+
+    total_variance < DEGENERATE_VARIANCE_EPS
 }
 
-/// Compute dot product between two vectors (optimized)
+/// Compute dot product between two vectors; scalar, see
+/// `kernels::dot_sim_dispatch` for the SIMD path
 #[inline]
 pub fn dot_sim(a: &[f32], b: &[f32]) -> f32 {
     a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
 }
 
+/// Inner-loop knobs for MaxSim scoring
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaxSimConfig<'a> {
+    /// Clamp each per-query-token max at zero before summing
+    pub relu: bool,
+    /// Which way tokens are matched (see `score_with_mode`)
+    pub direction: Direction,
+    /// Softmax temperature, only read by `ScoreMode::LogSumExp`
+    pub temperature: f32,
+    /// Divide each dot by the product of its row norms
+    pub cosine: bool,
+    /// Multiplier of each query row's max before summing (`q_weights`,
+    /// aligned with the kept query rows); 1.0 when absent
+    pub weights: Option<&'a [f32]>,
+}
+
+impl MaxSimConfig<'_> {
+    /// Weight of query row `i`
+    #[inline]
+    fn row_weight(&self, i: usize) -> f32 {
+        self.weights.map_or(1.0, |weights| weights[i])
+    }
+}
+
+/// Row norms of transposed query and document matrices when `config.cosine`
+/// is set. Zero rows count as norm 1, so their similarity stays 0.
+fn cosine_norms(q_t: &DMatrix<f32>, d_t: &DMatrix<f32>, config: &MaxSimConfig) -> Option<(Vec<f32>, Vec<f32>)> {
+    let norms = |t: &DMatrix<f32>| -> Vec<f32> {
+        token_rows(t)
+            .map(|row| row.iter().map(|x| x * x).sum::<f32>().sqrt())
+            .map(|norm| if norm > 0.0 { norm } else { 1.0 })
+            .collect()
+    };
+    config.cosine.then(|| (norms(q_t), norms(d_t)))
+}
+
+/// Similarity of query row `i` and document row `j` from their dot product
+#[inline]
+fn row_similarity(dot: f32, norms: &Option<(Vec<f32>, Vec<f32>)>, i: usize, j: usize) -> f32 {
+    match norms {
+        Some((q_norms, d_norms)) => dot / (q_norms[i] * d_norms[j]),
+        None => dot,
+    }
+}
+
 /// MaxSim scoring for a single document
 pub fn maxsim_score(q: &DMatrix<f32>, d: &DMatrix<f32>) -> f32 {
+    maxsim_score_with(q, d, &MaxSimConfig::default())
+}
+
+/// Token rows of a transposed matrix as contiguous slices
+///
+/// `DMatrix` is column-major, so a row view is strided. Transposing once per
+/// matrix makes every token a column, which is contiguous, and lets the dot
+/// kernel run on slices without copying each row.
+fn token_rows(transposed: &DMatrix<f32>) -> impl Iterator<Item = &[f32]> + Clone {
+    let (dim, data) = (transposed.nrows(), transposed.as_slice());
+    (0..transposed.ncols()).map(move |j| &data[j * dim..(j + 1) * dim])
+}
+
+/// MaxSim scoring for a single document with inner-loop options
+pub fn maxsim_score_with(q: &DMatrix<f32>, d: &DMatrix<f32>, config: &MaxSimConfig) -> f32 {
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let norms = cosine_norms(&q_t, &d_t, config);
     let mut total_score = 0.0;
     
-    for q_row in q.row_iter() {
+    for (i, q_row) in token_rows(&q_t).enumerate() {
         let mut max_dot = f32::NEG_INFINITY;
         
-        for d_row in d.row_iter() {
-            // Convert row views to vectors for dot product
-            let q_vec: Vec<f32> = q_row.iter().cloned().collect();
-            let d_vec: Vec<f32> = d_row.iter().cloned().collect();
-            let dot = dot_sim(&q_vec, &d_vec);
-            max_dot = max_dot.max(dot);
+        for (j, d_row) in token_rows(&d_t).enumerate() {
+            max_dot = max_dot.max(row_similarity(dot(q_row, d_row), &norms, i, j));
         }
         
-        total_score += max_dot;
+        if config.relu {
+            max_dot = max_dot.max(0.0);
+        }
+        total_score += max_dot * config.row_weight(i);
     }
     
     total_score
 }
 
-/// Score all documents and return top-K
-pub fn score_docs(
-    q_tokens: &[Vec<f32>],
-    d_tokens: &[Vec<Vec<f32>>],
-    topk: usize,
-    prune_config: &PruneConfig,
-) -> (Vec<usize>, Vec<f32>, PerfStats) {
-    let _start_time = std::time::Instant::now();
-    
-    // Prune query tokens (SIGIR 2025: lossless token pruning)
-    let pruned_q = prune_tokens(q_tokens, prune_config.q_max, &prune_config.method);
-    let _q_pruning_ratio = 1.0 - (pruned_q.len() as f32 / q_tokens.len() as f32);
-    
-    let q_matrix = DMatrix::from_row_slice(
-        pruned_q.len(),
-        pruned_q[0].len(),
-        &pruned_q.iter().flatten().cloned().collect::<Vec<_>>(),
-    );
-    let mut q_matrix = q_matrix;
-    l2_normalize_rows(&mut q_matrix);
-    
-    // Process documents in parallel
-    let mut doc_scores: Vec<(usize, f32, f32)> = d_tokens
-        .par_iter()
+/// MaxSim with each hard max replaced by a softmax-weighted average
+///
+/// Query row `i` contributes `Σ_j softmax(dots_i / temperature)_j · dots_ij`,
+/// which never exceeds the hard max and tends to it as the temperature falls.
+pub fn soft_maxsim(q: &DMatrix<f32>, d: &DMatrix<f32>, config: &MaxSimConfig) -> f32 {
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let norms = cosine_norms(&q_t, &d_t, config);
+    let mut total_score = 0.0;
+    
+    for (i, q_row) in token_rows(&q_t).enumerate() {
+        let dots: Vec<f32> = token_rows(&d_t)
+            .enumerate()
+            .map(|(j, d_row)| row_similarity(dot(q_row, d_row), &norms, i, j))
+            .collect();
+        let max_dot = dots.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let (mut weighted, mut norm) = (0.0, 0.0);
+        for &value in &dots {
+            let weight = ((value - max_dot) / config.temperature).exp();
+            weighted += weight * value;
+            norm += weight;
+        }
+        
+        let mut soft_max = weighted / norm;
+        if config.relu {
+            soft_max = soft_max.max(0.0);
+        }
+        total_score += soft_max * config.row_weight(i);
+    }
+    
+    total_score
+}
+
+/// Sum of every query row's similarity to every document row, with no max
+///
+/// Same `q × d` dots as MaxSim, so the same cost. `relu` clamps each dot and
+/// `weights` scale each query row's sum.
+pub fn sum_all_dots(q: &DMatrix<f32>, d: &DMatrix<f32>, config: &MaxSimConfig) -> f32 {
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let norms = cosine_norms(&q_t, &d_t, config);
+    let mut total_score = 0.0;
+    
+    for (i, q_row) in token_rows(&q_t).enumerate() {
+        let mut row_sum = 0.0;
+        for (j, d_row) in token_rows(&d_t).enumerate() {
+            let value = row_similarity(dot(q_row, d_row), &norms, i, j);
+            row_sum += if config.relu { value.max(0.0) } else { value };
+        }
+        total_score += row_sum * config.row_weight(i);
+    }
+    
+    total_score
+}
+
+/// Fraction of query rows whose best document match exceeds `threshold`
+pub fn query_coverage(q: &DMatrix<f32>, d: &DMatrix<f32>, threshold: f32) -> f32 {
+    if q.nrows() == 0 {
+        return 0.0;
+    }
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let mut covered = 0;
+    
+    for q_row in token_rows(&q_t) {
+        let max_dot = token_rows(&d_t).map(|d_row| dot(q_row, d_row)).fold(f32::NEG_INFINITY, f32::max);
+        if max_dot > threshold {
+            covered += 1;
+        }
+    }
+    
+    covered as f32 / q.nrows() as f32
+}
+
+/// Each query row's max similarity against `d`, as summed by MaxSim
+pub fn query_row_maxima(q: &DMatrix<f32>, d: &DMatrix<f32>, config: &MaxSimConfig) -> Vec<f32> {
+    query_row_matches(q, d, config).into_iter().map(|(_, max_dot)| max_dot).collect()
+}
+
+/// Each query row's best-matching document row and their similarity: the
+/// argmax behind `query_row_maxima`. The first document row wins ties.
+pub fn query_row_matches(q: &DMatrix<f32>, d: &DMatrix<f32>, config: &MaxSimConfig) -> Vec<(usize, f32)> {
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let norms = cosine_norms(&q_t, &d_t, config);
+    token_rows(&q_t)
         .enumerate()
-        .map(|(doc_idx, doc_tokens)| {
-            let doc_start = std::time::Instant::now();
-            
-            // Prune document tokens
-            let pruned_d = prune_tokens(doc_tokens, prune_config.d_max, &prune_config.method);
-            let d_matrix = DMatrix::from_row_slice(
-                pruned_d.len(),
-                pruned_d[0].len(),
-                &pruned_d.iter().flatten().cloned().collect::<Vec<_>>(),
-            );
-            let mut d_matrix = d_matrix;
-            l2_normalize_rows(&mut d_matrix);
-            
-            // Compute MaxSim score
-            let score = maxsim_score(&q_matrix, &d_matrix);
-            let doc_time = doc_start.elapsed().as_secs_f32() * 1000.0; // Convert to ms
-            
-            (doc_idx, score, doc_time)
+        .map(|(i, q_row)| {
+            let (best, max_dot) = token_rows(&d_t)
+                .enumerate()
+                .map(|(j, d_row)| (j, row_similarity(dot(q_row, d_row), &norms, i, j)))
+                .fold((0, f32::NEG_INFINITY), |best, (j, value)| if value > best.1 { (j, value) } else { best });
+            (best, if config.relu { max_dot.max(0.0) } else { max_dot })
         })
+        .collect()
+}
+
+/// Query row with the highest max-dot against `d`, and that max. The first
+/// row wins ties.
+pub fn dominant_query_row(q: &DMatrix<f32>, d: &DMatrix<f32>) -> (usize, f32) {
+    query_row_maxima(q, d, &MaxSimConfig::default())
+        .into_iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (row, value)| if value > best.1 { (row, value) } else { best })
+}
+
+/// Coverage of `bootstrap` intervals
+pub const BOOTSTRAP_CONFIDENCE: f32 = 0.95;
+
+/// Most resamples `bootstrap` may ask for
+pub const BOOTSTRAP_MAX_RESAMPLES: usize = 10_000;
+
+/// Fixed so repeated requests report the same intervals
+const BOOTSTRAP_SEED: u64 = 0x5eed;
+
+/// Bootstrap a MaxSim score from its per-query-row contributions (weighted
+/// maxima), resampling rows with replacement. `mean` divides each sum by the
+/// row count, as `mean_maxsim` does.
+pub fn bootstrap_interval(contributions: &[f32], resamples: usize, mean: bool, rng: &mut StdRng) -> BootstrapInterval {
+    let n = contributions.len();
+    let scale = if mean { 1.0 / n as f32 } else { 1.0 };
+    let mut samples: Vec<f32> = (0..resamples)
+        .map(|_| (0..n).map(|_| contributions[rng.gen_range(0..n)]).sum::<f32>() * scale)
         .collect();
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let tail = (1.0 - BOOTSTRAP_CONFIDENCE) / 2.0 * 100.0;
+    let score = contributions.iter().sum::<f32>() * scale;
+    BootstrapInterval {
+        score,
+        mean: samples.iter().sum::<f32>() / resamples as f32,
+        lower: percentile(&samples, tail).unwrap_or(score),
+        upper: percentile(&samples, 100.0 - tail).unwrap_or(score),
+    }
+}
+
+/// Each query row's share of the MaxSim sum. Empty when the sum is not
+/// positive, since shares of a zero or negative total have no meaning.
+pub fn contribution_fractions(maxima: &[f32]) -> Vec<f32> {
+    let total: f32 = maxima.iter().sum();
+    if total > 0.0 {
+        maxima.iter().map(|m| m / total).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Doc→query MaxSim: each document row takes its best query row
+///
+/// The sum runs over document tokens, so it is rescaled by
+/// `q_tokens / d_tokens` onto the query→doc scale. It then counts
+/// per-query-token similarity like `maxsim_score`, and `mean_maxsim` still
+/// divides by the query length. Query weights don't apply; 0 for an empty
+/// document.
+pub fn d2q_maxsim(q: &DMatrix<f32>, d: &DMatrix<f32>, config: &MaxSimConfig) -> f32 {
+    if d.nrows() == 0 {
+        return 0.0;
+    }
+    let unweighted = MaxSimConfig { weights: None, ..*config };
+    maxsim_score_with(d, q, &unweighted) * q.nrows() as f32 / d.nrows() as f32
+}
+
+/// Average of query→doc and doc→query MaxSim (see `d2q_maxsim` for the
+/// rescaling). An empty document keeps its query→doc score.
+pub fn symmetric_maxsim(q: &DMatrix<f32>, d: &DMatrix<f32>, config: &MaxSimConfig) -> f32 {
+    let q_to_d = maxsim_score_with(q, d, config);
+    if d.nrows() == 0 {
+        return q_to_d;
+    }
+    (q_to_d + d2q_maxsim(q, d, config)) / 2.0
+}
+
+/// MaxSim restricted to document tokens in each query token's nearest centroids
+///
+/// Approximate: query row `i` only considers document rows whose centroid id
+/// is in `q_allowed[i]`, and contributes 0 when none are. A true best match
+/// assigned to a farther centroid is missed, so the score can only be lower
+/// than exhaustive MaxSim (or higher where a negative max is replaced by 0).
+pub fn maxsim_centroid_filtered(
+    q: &DMatrix<f32>,
+    d: &DMatrix<f32>,
+    q_allowed: &[&[u32]],
+    d_centroids: &[u32],
+    config: &MaxSimConfig,
+) -> f32 {
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let norms = cosine_norms(&q_t, &d_t, config);
+    let mut total_score = 0.0;
+    
+    for (i, (q_row, allowed)) in token_rows(&q_t).zip(q_allowed).enumerate() {
+        let mut max_dot = f32::NEG_INFINITY;
+        
+        for (j, (d_row, centroid)) in token_rows(&d_t).zip(d_centroids).enumerate() {
+            if !allowed.contains(centroid) {
+                continue;
+            }
+            max_dot = max_dot.max(row_similarity(dot(q_row, d_row), &norms, i, j));
+        }
+        
+        if max_dot == f32::NEG_INFINITY || config.relu {
+            max_dot = max_dot.max(0.0);
+        }
+        total_score += max_dot * config.row_weight(i);
+    }
+    
+    total_score
+}
+
+/// MaxSim with each dot against document row `j` multiplied by `d_boosts[j]`
+/// before taking the max, so boosted tokens (e.g. headings) win more matches
+pub fn maxsim_boosted(q: &DMatrix<f32>, d: &DMatrix<f32>, d_boosts: &[f32], config: &MaxSimConfig) -> f32 {
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let norms = cosine_norms(&q_t, &d_t, config);
+    let mut total_score = 0.0;
+    
+    for (i, q_row) in token_rows(&q_t).enumerate() {
+        let mut max_dot = f32::NEG_INFINITY;
+        
+        for (j, (d_row, boost)) in token_rows(&d_t).zip(d_boosts).enumerate() {
+            max_dot = max_dot.max(row_similarity(dot(q_row, d_row), &norms, i, j) * boost);
+        }
+        
+        if config.relu {
+            max_dot = max_dot.max(0.0);
+        }
+        total_score += max_dot * config.row_weight(i);
+    }
+    
+    total_score
+}
+
+/// Mean dot of each row with every other row (0 for a single row)
+pub fn row_redundancy(rows: &[Vec<f32>]) -> Vec<f32> {
+    let n = rows.len();
+    if n < 2 {
+        return vec![0.0; n];
+    }
+    (0..n)
+        .map(|i| {
+            let total: f32 = (0..n).filter(|&j| j != i).map(|j| dot_sim_dispatch(&rows[i], &rows[j])).sum();
+            total / (n - 1) as f32
+        })
+        .collect()
+}
+
+/// Upper bound on one query token's max dot against unit-length document
+/// tokens, with slack for rounding
+pub const MAX_UNIT_DOT: f32 = 1.0 + 1e-4;
+
+/// MaxSim that gives up once the document can't reach `threshold`
+///
+/// After each query row the remaining rows can add at most `MAX_UNIT_DOT`
+/// apiece, so when `total + remaining * MAX_UNIT_DOT < threshold` the rest is
+/// skipped. Returns the full MaxSim sum, or `Err(dots_skipped)` on early exit.
+/// Both matrices must be L2-normalized.
+pub fn maxsim_bounded(
+    q: &DMatrix<f32>,
+    d: &DMatrix<f32>,
+    config: &MaxSimConfig,
+    threshold: f32,
+) -> Result<f32, usize> {
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let norms = cosine_norms(&q_t, &d_t, config);
+    let mut total_score = 0.0;
+    
+    for (qi, q_row) in token_rows(&q_t).enumerate() {
+        let remaining = q.nrows() - qi;
+        if total_score + remaining as f32 * MAX_UNIT_DOT < threshold {
+            return Err(remaining * d.nrows());
+        }
+        
+        let mut max_dot = f32::NEG_INFINITY;
+        for (j, d_row) in token_rows(&d_t).enumerate() {
+            max_dot = max_dot.max(row_similarity(dot(q_row, d_row), &norms, qi, j));
+        }
+        
+        if config.relu {
+            max_dot = max_dot.max(0.0);
+        }
+        total_score += max_dot;
+    }
+    
+    Ok(total_score)
+}
+
+/// Score a document under the given mode
+pub fn score_with_mode(q: &DMatrix<f32>, d: &DMatrix<f32>, mode: ScoreMode, config: &MaxSimConfig) -> f32 {
+    match mode {
+        ScoreMode::LogSumExp => return soft_maxsim(q, d, config),
+        ScoreMode::SumAll => return sum_all_dots(q, d, config),
+        ScoreMode::MaxSim | ScoreMode::MeanMaxSim => {}
+    }
+    let total = match config.direction {
+        Direction::Q2d => maxsim_score_with(q, d, config),
+        Direction::D2q => d2q_maxsim(q, d, config),
+        Direction::Symmetric => symmetric_maxsim(q, d, config),
+    };
+    match mode {
+        ScoreMode::MaxSim | ScoreMode::LogSumExp | ScoreMode::SumAll => total,
+        ScoreMode::MeanMaxSim => total / q.nrows() as f32,
+    }
+}
+
+/// MaxSim scoring that records every f32 operation in evaluation order
+///
+/// Mirrors `maxsim_score` exactly, so replaying the ops reproduces its result
+/// bit for bit. Returns `None` when the trace would exceed `TRACE_MAX_OPS`.
+pub fn maxsim_trace(
+    q: &DMatrix<f32>,
+    d: &DMatrix<f32>,
+    config: &MaxSimConfig,
+) -> Option<(f32, Vec<TraceOp>)> {
+    let len = trace_len(q.nrows(), d.nrows(), config.relu);
+    if len > TRACE_MAX_OPS {
+        return None;
+    }
+
+    let dot = dot_kernel();
+    let (q_t, d_t) = (q.transpose(), d.transpose());
+    let mut ops = Vec::with_capacity(len);
+    let mut total_score = 0.0;
+    
+    for (qi, q_row) in token_rows(&q_t).enumerate() {
+        let mut max_dot = f32::NEG_INFINITY;
+        
+        for (di, d_row) in token_rows(&d_t).enumerate() {
+            let value = dot(q_row, d_row);
+            ops.push(TraceOp::Dot { q: qi, d: di, value });
+            max_dot = max_dot.max(value);
+        }
+        ops.push(TraceOp::Max { q: qi, value: max_dot });
+        if config.relu {
+            max_dot = max_dot.max(0.0);
+            ops.push(TraceOp::Relu { q: qi, value: max_dot });
+        }
+        
+        total_score += max_dot;
+        ops.push(TraceOp::Add { q: qi, value: total_score });
+    }
+    
+    Some((total_score, ops))
+}
+
+/// Score all documents and return top-K
+pub fn score_docs(
+    q_tokens: &[Vec<f32>],
+    d_tokens: &[Vec<Vec<f32>>],
+    topk: usize,
+    prune_config: &PruneConfig,
+) -> Result<(Vec<usize>, Vec<f32>, PerfStats), ScoreError> {
+    let prune_config = &prune_config.adapted_to_docs(q_tokens.len(), d_tokens);
+    let query = prepare_query(q_tokens, prune_config)?;
+    score_docs_with_query(&query, d_tokens, topk, prune_config)
+}
+
+/// `score_docs` with a query from `prepare_query`, which must have been
+/// prepared with the same `prune_config`
+pub fn score_docs_with_query(
+    query: &PreparedQuery,
+    d_tokens: &[Vec<Vec<f32>>],
+    topk: usize,
+    prune_config: &PruneConfig,
+) -> Result<(Vec<usize>, Vec<f32>, PerfStats), ScoreError> {
+    check_inputs(query.tokens, d_tokens, prune_config)?;
+    let output =
+        score_prepared_query(query, d_tokens, topk, prune_config, &ScoreOptions::default(), &NoopPostScorer, None)?;
+    Ok((output.order, output.scores, output.perf))
+}
+
+/// Score all documents, apply a post-scorer, and return top-K
+pub fn score_docs_with_post_scorer(
+    q_tokens: &[Vec<f32>],
+    d_tokens: &[Vec<Vec<f32>>],
+    topk: usize,
+    prune_config: &PruneConfig,
+    post_scorer: &dyn PostScorer,
+) -> Result<(Vec<usize>, Vec<f32>, PerfStats), ScoreError> {
+    let output = score_docs_with_options(
+        q_tokens,
+        d_tokens,
+        topk,
+        prune_config,
+        &ScoreOptions::default(),
+        post_scorer,
+    )?;
+    Ok((output.order, output.scores, output.perf))
+}
+
+/// Smallest and largest L2 norm seen in a token sample
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct NormRange {
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Most tokens whose norms are checked against the expected range
+pub const NORM_SAMPLE_TOKENS: usize = 256;
+
+/// Norm range over an evenly strided sample of query and document tokens
+pub fn sampled_norm_range(q_tokens: &[Vec<f32>], d_tokens: &[Vec<Vec<f32>>]) -> NormRange {
+    let total = q_tokens.len() + d_tokens.iter().map(|doc| doc.len()).sum::<usize>();
+    let stride = total.div_ceil(NORM_SAMPLE_TOKENS).max(1);
+    q_tokens
+        .iter()
+        .chain(d_tokens.iter().flatten())
+        .step_by(stride)
+        .map(|token| token.iter().map(|x| x * x).sum::<f32>().sqrt())
+        .fold(NormRange { min: f32::INFINITY, max: f32::NEG_INFINITY }, |range, norm| NormRange {
+            min: range.min.min(norm),
+            max: range.max.max(norm),
+        })
+}
+
+/// Why a scoring run could not start
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ScoreError {
+    /// The query has no tokens, or pruning would keep none of them
+    EmptyQuery,
+    /// A document has no tokens, or pruning would keep none of them
+    EmptyDocument { doc_index: usize },
+    /// A document token's width differs from the query's
+    DimMismatch { query_dim: usize, doc_index: usize, doc_dim: usize },
+    /// Sampled token norms fell outside `expected_norm_min..=expected_norm_max`,
+    /// e.g. a model emitting raw logits instead of embeddings
+    NormOutOfRange { observed_min: f32, observed_max: f32 },
+    /// A token holds NaN or ±Inf; `doc_index` is absent for a query token
+    NonFiniteValue { doc_index: Option<usize>, token_index: usize },
+    /// A per-query-token option (e.g. `q_weights`) has `len` entries for
+    /// `expected` query tokens
+    MisalignedOption { option: &'static str, len: usize, expected: usize },
+}
+
+impl std::fmt::Display for ScoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScoreError::EmptyQuery => write!(f, "query has no tokens left to score"),
+            ScoreError::EmptyDocument { doc_index } => {
+                write!(f, "document {} has no tokens left to score", doc_index)
+            }
+            ScoreError::DimMismatch { query_dim, doc_index, doc_dim } => write!(
+                f,
+                "document {} has dimension {}, but the query has dimension {}",
+                doc_index, doc_dim, query_dim
+            ),
+            ScoreError::NormOutOfRange { observed_min, observed_max } => write!(
+                f,
+                "sampled token norms range over [{}, {}], outside the expected range",
+                observed_min, observed_max
+            ),
+            ScoreError::NonFiniteValue { doc_index: None, token_index } => {
+                write!(f, "query token {} has a non-finite value", token_index)
+            }
+            ScoreError::NonFiniteValue { doc_index: Some(doc_index), token_index } => {
+                write!(f, "document {} token {} has a non-finite value", doc_index, token_index)
+            }
+            ScoreError::MisalignedOption { option, len, expected } => {
+                write!(f, "{} has {} entries, expected {}", option, len, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScoreError {}
+
+/// Check that the query and every document keep at least one token, and
+/// that every document token has the query's width
+fn check_inputs(q_tokens: &[Vec<f32>], d_tokens: &[Vec<Vec<f32>>], prune_config: &PruneConfig) -> Result<(), ScoreError> {
+    if prune_config.q_budget(q_tokens.len()).min(q_tokens.len()) == 0 {
+        return Err(ScoreError::EmptyQuery);
+    }
+    if let Some(doc_index) = d_tokens.iter().position(|doc| prune_config.doc_kept_len(doc.len()) == 0) {
+        return Err(ScoreError::EmptyDocument { doc_index });
+    }
+    let query_dim = q_tokens[0].len();
+    for (doc_index, doc) in d_tokens.iter().enumerate() {
+        if let Some(token) = doc.iter().find(|token| token.len() != query_dim) {
+            return Err(ScoreError::DimMismatch { query_dim, doc_index, doc_dim: token.len() });
+        }
+    }
+    check_finite(q_tokens, d_tokens)
+}
+
+/// Reject the first token holding NaN or ±Inf. Left unchecked, a NaN dot
+/// compares equal to everything and silently scrambles the ranking.
+pub fn check_finite(q_tokens: &[Vec<f32>], d_tokens: &[Vec<Vec<f32>>]) -> Result<(), ScoreError> {
+    let non_finite = |tokens: &[Vec<f32>]| tokens.iter().position(|token| token.iter().any(|v| !v.is_finite()));
+    if let Some(token_index) = non_finite(q_tokens) {
+        return Err(ScoreError::NonFiniteValue { doc_index: None, token_index });
+    }
+    for (doc_index, doc) in d_tokens.iter().enumerate() {
+        if let Some(token_index) = non_finite(doc) {
+            return Err(ScoreError::NonFiniteValue { doc_index: Some(doc_index), token_index });
+        }
+    }
+    Ok(())
+}
+
+/// Replace every NaN and ±Inf token value with 0, returning how many were
+/// replaced
+pub fn sanitize_non_finite(q_tokens: &mut [Vec<f32>], d_tokens: &mut [Vec<Vec<f32>>]) -> usize {
+    q_tokens
+        .iter_mut()
+        .chain(d_tokens.iter_mut().flatten())
+        .flatten()
+        .filter(|v| !v.is_finite())
+        .map(|v| *v = 0.0)
+        .count()
+}
+
+/// Compare sampled token norms with the expected range. Returns the observed
+/// range when it is out of bounds in advisory mode.
+fn check_norms(
+    q_tokens: &[Vec<f32>],
+    d_tokens: &[Vec<Vec<f32>>],
+    options: &ScoreOptions,
+) -> Result<Option<NormRange>, ScoreError> {
+    if options.expected_norm_min.is_none() && options.expected_norm_max.is_none() {
+        return Ok(None);
+    }
+    let observed = sampled_norm_range(q_tokens, d_tokens);
+    let too_small = options.expected_norm_min.is_some_and(|min| observed.min < min);
+    let too_large = options.expected_norm_max.is_some_and(|max| observed.max > max);
+    match (too_small || too_large, options.norm_check_advisory) {
+        (false, _) => Ok(None),
+        (true, true) => Ok(Some(observed)),
+        (true, false) => Err(ScoreError::NormOutOfRange { observed_min: observed.min, observed_max: observed.max }),
+    }
+}
+
+/// Indices of the query tokens kept by `prune_config`, using any caller
+/// IDF and tiebreak keys in `options`
+pub fn pruned_query_indices(q_tokens: &[Vec<f32>], prune_config: &PruneConfig, options: &ScoreOptions) -> Vec<usize> {
+    // Query-affinity pruning only applies to documents, so the query falls
+    // back to idf_norm
+    let q_method = if prune_config.method == QUERY_AFFINITY { "idf_norm" } else { &prune_config.method };
+    let q_idf = query_idf(options);
+    if options.q_mask.is_none() {
+        return prune_token_indices(q_tokens, prune_config.q_budget(q_tokens.len()), q_method, q_idf.as_deref(), options.q_tiebreak_keys.as_deref());
+    }
+    // Prune among the unmasked tokens only, then map back to request indices
+    let unmasked = unmasked_query_indices(q_tokens.len(), options);
+    let tokens: Vec<Vec<f32>> = unmasked.iter().map(|&i| q_tokens[i].clone()).collect();
+    let q_idf: Option<Vec<f32>> = q_idf.map(|idf| unmasked.iter().map(|&i| idf[i]).collect());
+    let keys: Option<Vec<u64>> = options.q_tiebreak_keys.as_ref().map(|keys| unmasked.iter().map(|&i| keys[i]).collect());
+    prune_token_indices(&tokens, prune_config.q_budget(tokens.len()), q_method, q_idf.as_deref(), keys.as_deref())
+        .into_iter()
+        .map(|k| unmasked[k])
+        .collect()
+}
+
+/// Indices of the query tokens `q_mask` leaves in play; all of them when
+/// there is no mask
+pub fn unmasked_query_indices(q_len: usize, options: &ScoreOptions) -> Vec<usize> {
+    match &options.q_mask {
+        Some(mask) => (0..q_len).filter(|&i| mask[i]).collect(),
+        None => (0..q_len).collect(),
+    }
+}
+
+/// Caller IDF of each query token: `q_idf`, else looked up from the `idf`
+/// table by `q_token_ids`
+fn query_idf(options: &ScoreOptions) -> Option<Vec<f32>> {
+    match (&options.idf, &options.q_token_ids) {
+        _ if options.q_idf.is_some() => options.q_idf.clone(),
+        (Some(table), Some(ids)) => Some(idf_from_table(ids, table)),
+        _ => None,
+    }
+}
+
+/// Tokens of document `doc_idx` kept by every pruning stage of
+/// `prune_config` except `token_dropout`. `q_rows` are the kept query rows
+/// as scored, read only by `query_affinity`.
+pub fn prune_doc_tokens(
+    doc_idx: usize,
+    doc_tokens: &[Vec<f32>],
+    prune_config: &PruneConfig,
+    options: &ScoreOptions,
+    q_rows: &[Vec<f32>],
+) -> Vec<Vec<f32>> {
+    prune_doc_token_indices(doc_idx, doc_tokens, prune_config, options, q_rows)
+        .into_iter()
+        .map(|i| doc_tokens[i].clone())
+        .collect()
+}
+
+/// Indices into `doc_tokens` of the tokens `prune_doc_tokens` keeps, so
+/// per-token options such as `d_token_flags` can follow them
+pub fn prune_doc_token_indices(
+    doc_idx: usize,
+    doc_tokens: &[Vec<f32>],
+    prune_config: &PruneConfig,
+    options: &ScoreOptions,
+    q_rows: &[Vec<f32>],
+) -> Vec<usize> {
+    let d_budget = prune_config.d_budget(doc_tokens.len());
+    let capped = match prune_config.hard_doc_token_cap {
+        Some(cap) => doc_tokens.len().min(cap),
+        None => doc_tokens.len(),
+    };
+    let sampled = match prune_config.reservoir_sample {
+        Some(n) if capped > n => {
+            Some(reservoir_sample_indices(capped, n, prune_config.dropout_seed.wrapping_add(doc_idx as u64)))
+        }
+        _ => None,
+    };
+    let is_sampled = sampled.is_some();
+    let sampled_tokens: Vec<Vec<f32>>;
+    let tokens = match &sampled {
+        Some(sampled) => {
+            sampled_tokens = sampled.iter().map(|&i| doc_tokens[i].clone()).collect();
+            &sampled_tokens[..]
+        }
+        None => &doc_tokens[..capped],
+    };
+    let kept = if prune_config.method == QUERY_AFFINITY {
+        query_affinity_indices(tokens, d_budget, q_rows)
+    } else {
+        // Sampled tokens no longer line up with their keys and ids
+        let tiebreak = options
+            .d_tiebreak_keys
+            .as_ref()
+            .filter(|_| !is_sampled)
+            .map(|keys| &keys[doc_idx][..tokens.len()]);
+        let doc_idf = match (&options.idf, &options.d_token_ids) {
+            (Some(table), Some(ids)) if !is_sampled => Some(idf_from_table(&ids[doc_idx][..tokens.len()], table)),
+            _ => None,
+        };
+        prune_token_indices(tokens, d_budget, &prune_config.method, doc_idf.as_deref(), tiebreak)
+    };
+    match sampled {
+        Some(sampled) => kept.into_iter().map(|i| sampled[i]).collect(),
+        None => kept,
+    }
+}
+
+/// A query pruned and packed once, so it can be scored against many
+/// document sets (e.g. the shards of a sharded index) without repeating
+/// the work; see `prepare_query`
+#[derive(Debug, Clone)]
+pub struct PreparedQuery<'a> {
+    /// The query as given
+    pub tokens: &'a [Vec<f32>],
+    /// Indices of the tokens kept by pruning, in row order
+    pub kept: Vec<usize>,
+    /// Kept tokens as rows, L2-normalized unless `similarity` is set
+    pub matrix: DMatrix<f32>,
+}
+
+/// Prune and normalize a query the way `score_docs` does
+///
+/// `adapt_q_to_docs` depends on the documents, so it is not applied here;
+/// pass `PruneConfig::adapted_to_docs` to adapt to a known document set.
+pub fn prepare_query<'a>(q_tokens: &'a [Vec<f32>], prune_config: &PruneConfig) -> Result<PreparedQuery<'a>, ScoreError> {
+    check_inputs(q_tokens, &[], prune_config)?;
+    prepare_query_with_options(q_tokens, prune_config, &ScoreOptions::default())
+}
+
+/// Prune query tokens (SIGIR 2025: lossless token pruning) and pack the
+/// kept ones into the matrix scored against every document, honouring the
+/// query options (`q_mask`, caller IDF, tiebreak keys, `norm_eps`,
+/// `similarity`). Per-token options must already line up with the query.
+pub fn prepare_query_with_options<'a>(
+    q_tokens: &'a [Vec<f32>],
+    prune_config: &PruneConfig,
+    options: &ScoreOptions,
+) -> Result<PreparedQuery<'a>, ScoreError> {
+    let kept = pruned_query_indices(q_tokens, prune_config, options);
+    if kept.is_empty() {
+        return Err(ScoreError::EmptyQuery);
+    }
+    let pruned_q: Vec<Vec<f32>> = kept.iter().map(|&i| q_tokens[i].clone()).collect();
+    // With an explicit similarity, rows keep their norms
+    let matrix = if options.similarity.is_none() {
+        normalized_matrix(&pruned_q, options.norm_eps.unwrap_or(DEFAULT_NORM_EPS))
+    } else {
+        token_matrix(&pruned_q)
+    };
+    Ok(PreparedQuery { tokens: q_tokens, kept, matrix })
+}
+
+/// Score all documents with per-request options and return top-K plus stats
+pub fn score_docs_with_options(
+    q_tokens: &[Vec<f32>],
+    d_tokens: &[Vec<Vec<f32>>],
+    topk: usize,
+    prune_config: &PruneConfig,
+    options: &ScoreOptions,
+    post_scorer: &dyn PostScorer,
+) -> Result<ScoreOutput, ScoreError> {
+    check_inputs(q_tokens, d_tokens, prune_config)?;
+    let norm_out_of_range = check_norms(q_tokens, d_tokens, options)?;
+    let q_len = unmasked_query_indices(q_tokens.len(), options).len();
+    let prune_config = &prune_config.adapted_to_docs(q_len, d_tokens);
+    let query = prepare_query_with_options(q_tokens, prune_config, options)?;
+    score_prepared_query(&query, d_tokens, topk, prune_config, options, post_scorer, norm_out_of_range)
+}
+
+/// Score all documents against an already prepared query. `prune_config`
+/// must be the one the query was prepared with.
+fn score_prepared_query(
+    query: &PreparedQuery,
+    d_tokens: &[Vec<Vec<f32>>],
+    topk: usize,
+    prune_config: &PruneConfig,
+    options: &ScoreOptions,
+    post_scorer: &dyn PostScorer,
+    norm_out_of_range: Option<NormRange>,
+) -> Result<ScoreOutput, ScoreError> {
+    let (q_tokens, q_kept, q_matrix) = (query.tokens, &query.kept, &query.matrix);
+    let q_len = unmasked_query_indices(q_tokens.len(), options).len();
+    // Documents that must be ranked to fill the requested page
+    let max_topks = options.topks.iter().flatten().copied().max().unwrap_or(0);
+    let keep = options.offset.saturating_add(topk).max(max_topks);
+    
+    // Query-affinity pruning only applies to documents, so the query falls
+    // back to idf_norm
+    let q_method = if prune_config.method == QUERY_AFFINITY { "idf_norm" } else { &prune_config.method };
+    let q_budget = prune_config.q_budget(q_len);
+    
+    let norm_eps = options.norm_eps.unwrap_or(DEFAULT_NORM_EPS);
+    // With an explicit similarity, rows keep their norms
+    let normalize = options.similarity.is_none();
+    let to_matrix = |tokens: &[Vec<f32>]| {
+        if normalize { normalized_matrix(tokens, norm_eps) } else { token_matrix(tokens) }
+    };
+    let q_rows: Vec<Vec<f32>> = if prune_config.method == QUERY_AFFINITY {
+        q_matrix.row_iter().map(|row| row.iter().cloned().collect()).collect()
+    } else {
+        Vec::new()
+    };
+    
+    let prune_doc = |doc_idx: usize, doc_tokens: &[Vec<f32>]| {
+        prune_doc_tokens(doc_idx, doc_tokens, prune_config, options, &q_rows)
+    };
+    
+    let score_mode = options.score_mode.unwrap_or_default();
+    // Weights follow their query tokens through pruning
+    let kept_weights: Option<Vec<f32>> =
+        options.q_weights.as_ref().map(|weights| q_kept.iter().map(|&i| weights[i]).collect());
+    let maxsim_config = MaxSimConfig {
+        relu: options.relu_sim,
+        direction: options.effective_direction(),
+        temperature: options.lse_temperature.unwrap_or(DEFAULT_LSE_TEMPERATURE),
+        cosine: options.similarity == Some(Similarity::Cosine),
+        weights: kept_weights.as_deref(),
+    };
+    
+    // Prune (and optionally drop out) a document into a normalized matrix
+    // Centroid filtering: each kept query token's allowed document centroids.
+    // Centroid ids align with the unpruned document tokens, so document
+    // pruning is skipped when the filter is on.
+    let q_allowed: Option<Vec<&[u32]>> = match (options.centroid_filter, &options.q_centroids) {
+        (Some(n), Some(q_centroids)) => Some(
+            q_kept
+                .iter()
+                .map(|&i| &q_centroids[i][..n.min(q_centroids[i].len())])
+                .collect(),
+        ),
+        _ => None,
+    };
+    
+    // Flag boosts: each document's kept token indices, so its flags follow
+    // the kept tokens through pruning and dropout
+    let flag_kept: Option<Vec<Vec<usize>>> = options.flag_boosts.as_ref().map(|_| {
+        d_tokens
+            .par_iter()
+            .enumerate()
+            .map(|(doc_idx, doc_tokens)| {
+                let kept = prune_doc_token_indices(doc_idx, doc_tokens, prune_config, options, &q_rows);
+                let seed = prune_config.dropout_seed.wrapping_add(doc_idx as u64);
+                token_dropout_indices(kept.len(), prune_config.token_dropout, seed)
+                    .into_iter()
+                    .map(|i| kept[i])
+                    .collect()
+            })
+            .collect()
+    });
+    
+    // Prune (and optionally drop out) a document's tokens
+    let doc_pruned = |doc_idx: usize, doc_tokens: &[Vec<f32>]| {
+        if q_allowed.is_some() {
+            return doc_tokens.to_vec();
+        }
+        if let Some(flag_kept) = &flag_kept {
+            return flag_kept[doc_idx].iter().map(|&i| doc_tokens[i].clone()).collect();
+        }
+        apply_token_dropout(
+            prune_doc(doc_idx, doc_tokens),
+            prune_config.token_dropout,
+            prune_config.dropout_seed.wrapping_add(doc_idx as u64),
+        )
+    };
+    let doc_matrix = |doc_idx: usize, doc_tokens: &[Vec<f32>]| to_matrix(&doc_pruned(doc_idx, doc_tokens));
+    
+    // Early-exit bookkeeping: the best known K-th score and skip counters
+    let exit_threshold = AtomicScore::new(f32::NEG_INFINITY);
+    let early_exit_docs = AtomicUsize::new(0);
+    let dots_skipped = AtomicUsize::new(0);
+    let dots_total = AtomicUsize::new(0);
+    let early_exit = options.early_exit && options.low_memory;
+    
+    // Score a single document: (score, time_ms, tokens kept). The score is
+    // `None` when early exit proved the document can't make the top-K.
+    let score_matrix = |doc_idx: usize, d_matrix: DMatrix<f32>, doc_start: std::time::Instant| {
+        if early_exit {
+            dots_total.fetch_add(q_matrix.nrows() * d_matrix.nrows(), AtomicOrdering::Relaxed);
+            let mut threshold = exit_threshold.load();
+            if score_mode == ScoreMode::MeanMaxSim {
+                threshold *= q_matrix.nrows() as f32;
+            }
+            if let Err(skipped) = maxsim_bounded(q_matrix, &d_matrix, &maxsim_config, threshold) {
+                early_exit_docs.fetch_add(1, AtomicOrdering::Relaxed);
+                dots_skipped.fetch_add(skipped, AtomicOrdering::Relaxed);
+                if let Some(progress) = &options.progress {
+                    progress.fetch_add(1, AtomicOrdering::Relaxed);
+                }
+                let doc_time = doc_start.elapsed().as_secs_f32() * 1000.0;
+                return (None, doc_time, d_matrix.nrows());
+            }
+        }
+        
+        // Compute MaxSim score, down-weighted by retriever confidence
+        let mut score = match (&q_allowed, &options.d_centroids) {
+            (Some(allowed), Some(d_centroids)) => {
+                let total = maxsim_centroid_filtered(q_matrix, &d_matrix, allowed, &d_centroids[doc_idx], &maxsim_config);
+                // `logsumexp` and `sum_all` are rejected with centroid filtering
+                match score_mode {
+                    ScoreMode::MaxSim | ScoreMode::LogSumExp | ScoreMode::SumAll => total,
+                    ScoreMode::MeanMaxSim => total / q_matrix.nrows() as f32,
+                }
+            }
+            _ => match (&options.flag_boosts, &options.d_token_flags, &flag_kept) {
+                (Some(boosts), Some(flags), Some(flag_kept)) => {
+                    let d_boosts: Vec<f32> = flag_kept[doc_idx]
+                        .iter()
+                        .map(|&i| boosts.get(&flags[doc_idx][i]).copied().unwrap_or(1.0))
+                        .collect();
+                    let total = maxsim_boosted(q_matrix, &d_matrix, &d_boosts, &maxsim_config);
+                    // `logsumexp`, `sum_all` and `symmetric` are rejected with flag boosts
+                    match score_mode {
+                        ScoreMode::MaxSim | ScoreMode::LogSumExp | ScoreMode::SumAll => total,
+                        ScoreMode::MeanMaxSim => total / q_matrix.nrows() as f32,
+                    }
+                }
+                _ => score_with_mode(q_matrix, &d_matrix, score_mode, &maxsim_config),
+            },
+        };
+        if let Some(confidences) = &options.confidences {
+            score *= confidences[doc_idx];
+        }
+        if let Some(threshold) = options.coverage_threshold {
+            if query_coverage(q_matrix, &d_matrix, threshold) < options.min_coverage {
+                score = DISQUALIFIED_SCORE;
+            }
+        }
+        let doc_time = doc_start.elapsed().as_secs_f32() * 1000.0; // Convert to ms
+        
+        if let Some(progress) = &options.progress {
+            progress.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        
+        (Some(score), doc_time, d_matrix.nrows())
+    };
+    let score_doc = |doc_idx: usize, doc_tokens: &Vec<Vec<f32>>| {
+        let doc_start = std::time::Instant::now();
+        score_matrix(doc_idx, doc_matrix(doc_idx, doc_tokens), doc_start)
+    };
+    
+    // Process documents in parallel, either keeping every result or only a
+    // bounded top-K heap plus a timing sample
+    let mut slow_docs = None;
+    let mut clusters = None;
+    let (mut prune_ms, mut normalize_ms, mut score_ms) = (None, None, None);
+    let (cpu_ms, wall_ms);
+    let (ranked, doc_times, d_tokens_kept, total_kept) = if options.low_memory {
+        let pass_start = std::time::Instant::now();
+        let (heap, reservoir, total_kept, time_sum) = d_tokens
+            .par_iter()
+            .enumerate()
+            .fold(
+                || (TopKHeap::new(keep), Reservoir::new(TIMING_RESERVOIR_SIZE), 0, 0.0),
+                |(mut heap, mut reservoir, total, time_sum), (idx, doc_tokens)| {
+                    let (score, time, kept) = score_doc(idx, doc_tokens);
+                    if let Some(score) = score {
+                        heap.push(Ranked { idx, score, kept });
+                        if let (true, Some(kth)) = (early_exit, heap.kth_score()) {
+                            exit_threshold.fetch_max(kth);
+                        }
+                    }
+                    reservoir.push(time, &mut rand::thread_rng());
+                    (heap, reservoir, total + kept, time_sum + time)
+                },
+            )
+            .reduce(
+                || (TopKHeap::new(keep), Reservoir::new(TIMING_RESERVOIR_SIZE), 0, 0.0),
+                |(heap_a, res_a, total_a, time_a), (heap_b, res_b, total_b, time_b)| {
+                    (
+                        heap_a.merge(heap_b),
+                        res_a.merge(res_b, &mut rand::thread_rng()),
+                        total_a + total_b,
+                        time_a + time_b,
+                    )
+                },
+            );
+        wall_ms = pass_start.elapsed().as_secs_f32() * 1000.0;
+        cpu_ms = time_sum;
+        
+        let top = heap.into_sorted_vec();
+        let mut d_tokens_kept = vec![0; d_tokens.len()];
+        for entry in &top {
+            d_tokens_kept[entry.idx] = entry.kept;
+        }
+        let ranked: Vec<(usize, f32)> = top.iter().map(|e| (e.idx, e.score)).collect();
+        (ranked, reservoir.into_samples(), d_tokens_kept, total_kept)
+    } else {
+        // Phase 1: prune every document and L2-normalize all kept rows in one
+        // parallel pass over a packed buffer
+        let prune_start = std::time::Instant::now();
+        let pruned: Vec<Vec<Vec<f32>>> = d_tokens
+            .par_iter()
+            .enumerate()
+            .map(|(doc_idx, doc_tokens)| doc_pruned(doc_idx, doc_tokens))
+            .collect();
+        let normalize_start = std::time::Instant::now();
+        let mut packed = PackedDocs::pack(&pruned, q_matrix.ncols());
+        drop(pruned);
+        if normalize {
+            packed.normalize_rows(norm_eps);
+        }
+        normalize_ms = Some(normalize_start.elapsed().as_secs_f32() * 1000.0);
+        prune_ms = Some(prune_start.elapsed().as_secs_f32() * 1000.0);
+
+        // Phase 2: score the packed matrices
+        let score_start = std::time::Instant::now();
+        let doc_scores: Vec<(usize, f32, f32, usize)> = (0..packed.len())
+            .into_par_iter()
+            .map(|doc_idx| {
+                let doc_start = std::time::Instant::now();
+                let (score, time, kept) = score_matrix(doc_idx, packed.matrix(doc_idx), doc_start);
+                (doc_idx, score.expect("early exit is low-memory only"), time, kept)
+            })
+            .collect();
+        wall_ms = score_start.elapsed().as_secs_f32() * 1000.0;
+        score_ms = Some(wall_ms);
+        cpu_ms = doc_scores.iter().map(|(_, _, time, _)| time).sum();
+        
+        let mut d_tokens_kept = vec![0; d_tokens.len()];
+        for (idx, _, _, kept) in &doc_scores {
+            d_tokens_kept[*idx] = *kept;
+        }
+        let mut ranked: Vec<(usize, f32)> = doc_scores.iter().map(|(idx, score, _, _)| (*idx, *score)).collect();
+        if let (true, Some(cluster_ids)) = (options.cluster_scores, &options.cluster_ids) {
+            clusters = Some(cluster_scores(&ranked, cluster_ids, options.cluster_aggregate));
+        }
+        if post_scorer.needs_full_ranking() {
+            ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        } else {
+            // Only rank the documents the page, `topks` or the MMR pool can use
+            let pool = if options.mmr_sweep.is_some() { topk.saturating_mul(MMR_POOL_FACTOR) } else { 0 };
+            select_top(&mut ranked, keep.max(pool));
+        }
+        let doc_times: Vec<f32> = doc_scores.iter().map(|(_, _, time, _)| *time).collect();
+        let total_kept = d_tokens_kept.iter().sum();
+        slow_docs = options.flag_slow_docs.map(|multiple| {
+            let timed: Vec<(usize, f32)> = doc_scores.iter().map(|(idx, _, time, _)| (*idx, *time)).collect();
+            find_slow_docs(&timed, multiple)
+        });
+        (ranked, doc_times, d_tokens_kept, total_kept)
+    };
     
-    // Sort by score (descending) and take top-K
-    doc_scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    // Run the post-scorer over the MaxSim ranking, then take top-K
+    let ctx = ScoringContext {
+        q_tokens_kept: q_kept.len(),
+        d_tokens_kept,
+        dim: q_matrix.ncols(),
+    };
+    // `ranked` is sorted by score, so a disqualified leader means all are
+    let all_disqualified = topk > 0 && ranked.first().is_some_and(|(_, score)| *score == DISQUALIFIED_SCORE);
+    let rescored = post_scorer.rescore(&ranked, &ctx);
+    let sweep = options.mmr_sweep.as_ref().map(|lambdas| {
+        let pool: Vec<(usize, f32)> = rescored
+            .iter()
+            .copied()
+            .filter(|(_, score)| *score != DISQUALIFIED_SCORE)
+            .take(topk.saturating_mul(MMR_POOL_FACTOR))
+            .collect();
+        let docs: Vec<DMatrix<f32>> = pool.iter().map(|&(idx, _)| doc_matrix(idx, &d_tokens[idx])).collect();
+        let scores: Vec<f32> = pool.iter().map(|(_, score)| *score).collect();
+        mmr_sweep(&scores, &docs, topk, lambdas)
+    });
     
-    let topk = topk.min(doc_scores.len());
-    let order: Vec<usize> = doc_scores.iter().take(topk).map(|(idx, _, _)| *idx).collect();
-    let scores: Vec<f32> = doc_scores.iter().take(topk).map(|(_, score, _)| *score).collect();
+    let end = options.offset.saturating_add(topk).min(rescored.len());
+    let start = options.offset.min(end);
+    let topk = end - start;
+    let cutoff_ties = match end.checked_sub(1).map(|k| rescored[k].1) {
+        Some(cutoff) if topk > 0 => rescored.iter().filter(|(_, score)| *score == cutoff).count(),
+        _ => 0,
+    };
+    let page = &rescored[start..end];
+    let topks = options.topks.as_ref().map(|ks| {
+        ks.iter()
+            .map(|&k| {
+                let prefix = &rescored[..k.min(rescored.len())];
+                let order = prefix.iter().map(|(idx, _)| *idx).collect();
+                let scores = prefix.iter().map(|(_, score)| *score).collect();
+                (k, RankedPrefix { order, scores })
+            })
+            .collect()
+    });
+    let order: Vec<usize> = page.iter().map(|(idx, _)| *idx).collect();
+    let scores: Vec<f32> = page.iter().map(|(_, score)| *score).collect();
+    let ranks: Vec<usize> = (start + 1..=end).collect();
+    let prune_stats = PruneStats {
+        q_tokens_in: q_tokens.len(),
+        q_tokens_kept: q_kept.len(),
+        docs: order.iter().map(|&idx| (d_tokens[idx].len(), ctx.d_tokens_kept[idx])).collect(),
+    };
     
     // Calculate performance statistics
-    let doc_times: Vec<f32> = doc_scores.iter().map(|(_, _, time)| *time).collect();
-    let mut sorted_times = doc_times.clone();
+    let mut sorted_times = doc_times;
     sorted_times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
     
-    let p50_idx = (sorted_times.len() * 50) / 100;
-    let p95_idx = (sorted_times.len() * 95) / 100;
-    
+    let sample_size = sorted_times.len();
+    let min_samples = options.min_percentile_samples.unwrap_or(DEFAULT_MIN_PERCENTILE_SAMPLES).max(1);
+    let report = sample_size >= min_samples;
     let perf = PerfStats {
-        per_doc_ms_p50: if !sorted_times.is_empty() { sorted_times[p50_idx] } else { 0.0 },
-        per_doc_ms_p95: if !sorted_times.is_empty() { sorted_times[p95_idx] } else { 0.0 },
+        per_doc_ms_p50: percentile(&sorted_times, 50.0).filter(|_| report),
+        per_doc_ms_p95: percentile(&sorted_times, 95.0).filter(|_| report),
+        sample_size,
     };
     
     // Log transparency information
     let q_tokens_in = q_tokens.len();
-    let q_tokens_pruned = pruned_q.len();
+    let q_tokens_pruned = q_kept.len();
     let d_tokens_in_avg = if !d_tokens.is_empty() {
         d_tokens.iter().map(|doc| doc.len()).sum::<usize>() as f32 / d_tokens.len() as f32
     } else { 0.0 };
     let d_tokens_pruned_avg = if !d_tokens.is_empty() {
-        d_tokens.iter().map(|doc| {
-            prune_tokens(doc, prune_config.d_max, &prune_config.method).len()
-        }).sum::<usize>() as f32 / d_tokens.len() as f32
+        total_kept as f32 / d_tokens.len() as f32
     } else { 0.0 };
     
-    println!("RERANKER TRANSPARENCY:");
-    println!("  q_tokens_in: {}, q_tokens_pruned: {}", q_tokens_in, q_tokens_pruned);
-    println!("  d_tokens_in_avg: {:.1}, d_tokens_pruned_avg: {:.1}", d_tokens_in_avg, d_tokens_pruned_avg);
-    println!("  dim: {}, threads: {}", pruned_q[0].len(), rayon::current_num_threads());
-    println!("  docs_scored: {}, topk: {}", d_tokens.len(), topk);
-    println!("  rerank_ms_p50: {:.2}, rerank_ms_p95: {:.2}", perf.per_doc_ms_p50, perf.per_doc_ms_p95);
+    info!("RERANKER TRANSPARENCY:");
+    info!("  q_tokens_in: {}, q_tokens_pruned: {}", q_tokens_in, q_tokens_pruned);
+    info!("  d_tokens_in_avg: {:.1}, d_tokens_pruned_avg: {:.1}", d_tokens_in_avg, d_tokens_pruned_avg);
+    info!("  dim: {}, threads: {}", q_matrix.ncols(), rayon::current_num_threads());
+    info!("  docs_scored: {}, topk: {}", d_tokens.len(), topk);
+    info!("  rerank_ms_p50: {:?}, rerank_ms_p95: {:?}", perf.per_doc_ms_p50, perf.per_doc_ms_p95);
     
-    (order, scores, perf)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_dot_sim() {
-        let a = vec![1.0, 2.0, 3.0];
+    let effective_prune = EffectivePrune::new(prune_config, q_budget, q_method);
+    let mut stats = ScoreStats {
+        score_mode,
+        effective_prune,
+        cutoff_ties,
+        all_disqualified,
+        slow_docs,
+        prune_ms,
+        normalize_ms,
+        score_ms,
+        cpu_ms: Some(cpu_ms),
+        wall_ms: Some(wall_ms),
+        parallelism: (wall_ms > 0.0).then(|| cpu_ms / wall_ms),
+        mmr_sweep: sweep,
+        norm_out_of_range,
+        sanitized_values: options.sanitized_values,
+        ..Default::default()
+    };
+    if options.detect_degenerate {
+        stats.degenerate_corpus = Some(is_degenerate_corpus(d_tokens));
+    }
+    if prune_config.token_dropout > 0.0 {
+        stats.effective_d_tokens = Some(total_kept);
+    }
+    if let Some(cap) = prune_config.hard_doc_token_cap {
+        stats.hard_truncated = Some(
+            d_tokens
+                .iter()
+                .enumerate()
+                .filter(|(_, doc)| doc.len() > cap)
+                .map(|(idx, _)| idx)
+                .collect(),
+        );
+    }
+    // Every query token in play, before pruning; `q_mask` drops the rest
+    let unmasked_q = || -> Vec<Vec<f32>> {
+        unmasked_query_indices(q_tokens.len(), options).into_iter().map(|i| q_tokens[i].clone()).collect()
+    };
+    if options.verify_lossless {
+        let full_q = normalized_matrix(&unmasked_q(), norm_eps);
+        let step = d_tokens.len().div_ceil(LOSSLESS_SAMPLE_SIZE).max(1);
+        let violations = d_tokens
+            .par_iter()
+            .enumerate()
+            .step_by(step)
+            .filter(|(doc_idx, doc)| {
+                let pruned = maxsim_score(q_matrix, &normalized_matrix(&prune_doc(*doc_idx, doc), norm_eps));
+                let unpruned = maxsim_score(&full_q, &normalized_matrix(doc, norm_eps));
+                (pruned - unpruned).abs() > LOSSLESS_TOLERANCE
+            })
+            .count();
+        stats.lossless_violations = Some(violations);
+    }
+    if options.check_prune_impact {
+        let longest_doc = d_tokens.iter().map(|doc| doc.len()).max().unwrap_or(0);
+        let unpruned_config = PruneConfig {
+            q_max: q_tokens.len(),
+            d_max: longest_doc,
+            token_dropout: 0.0,
+            q_min_ratio: 0.0,
+            hard_doc_token_cap: None,
+            reservoir_sample: None,
+            d_max_ratio: None,
+            adapt_q_to_docs: false,
+            ..prune_config.clone()
+        };
+        // Only the order is compared, so skip the other diagnostics
+        let rerun_options = ScoreOptions {
+            check_prune_impact: false,
+            verify_lossless: false,
+            trace_ops: false,
+            progress: None,
+            ..options.clone()
+        };
+        let unpruned = score_docs_with_options(q_tokens, d_tokens, topk, &unpruned_config, &rerun_options, post_scorer)?;
+        stats.prune_changed_ranking = Some(unpruned.order != order);
+    }
+    if options.trace_ops {
+        stats.op_trace = order.first().and_then(|&doc_index| {
+            let d_matrix = doc_matrix(doc_index, &d_tokens[doc_index]);
+            let (mut score, mut ops) = maxsim_trace(q_matrix, &d_matrix, &maxsim_config)?;
+            if score_mode == ScoreMode::MeanMaxSim {
+                let n = q_matrix.nrows();
+                score /= n as f32;
+                ops.push(TraceOp::Mean { n, value: score });
+            }
+            if let Some(confidences) = &options.confidences {
+                let factor = confidences[doc_index];
+                ops.push(TraceOp::Scale { factor, value: score * factor });
+            }
+            Some(OpTrace { doc_index, ops })
+        });
+    }
+    if let Some(threshold) = options.coverage_threshold {
+        stats.coverage = Some(
+            order
+                .iter()
+                .map(|&idx| query_coverage(q_matrix, &doc_matrix(idx, &d_tokens[idx]), threshold))
+                .collect(),
+        );
+    }
+    if options.return_dominant_q_token {
+        stats.dominant_q_tokens = Some(
+            order
+                .iter()
+                .map(|&idx| {
+                    let (row, value) = dominant_query_row(q_matrix, &doc_matrix(idx, &d_tokens[idx]));
+                    DominantToken { q_index: q_kept[row], value }
+                })
+                .collect(),
+        );
+    }
+    // Per-query-token maxima of each returned document, shared by the
+    // options reporting on them
+    let top_maxima: Option<Vec<Vec<f32>>> =
+        (options.contribution_fractions || options.return_sim_range || options.bootstrap.is_some()).then(|| {
+            order
+                .iter()
+                .map(|&idx| query_row_maxima(q_matrix, &doc_matrix(idx, &d_tokens[idx]), &maxsim_config))
+                .collect()
+        });
+    if let (true, Some(top_maxima)) = (options.contribution_fractions, &top_maxima) {
+        stats.contribution_fractions = Some(
+            top_maxima
+                .iter()
+                .map(|maxima| {
+                    let kept_fractions = contribution_fractions(maxima);
+                    if kept_fractions.is_empty() {
+                        return kept_fractions;
+                    }
+                    let mut fractions = vec![0.0; q_tokens.len()];
+                    for (&q_index, fraction) in q_kept.iter().zip(kept_fractions) {
+                        fractions[q_index] = fraction;
+                    }
+                    fractions
+                })
+                .collect(),
+        );
+    }
+    if let (true, Some(top_maxima)) = (options.return_sim_range, &top_maxima) {
+        stats.sim_range = top_maxima.iter().flatten().fold(None, |range: Option<SimRange>, &value| {
+            Some(match range {
+                Some(range) => SimRange { min: range.min.min(value), max: range.max.max(value) },
+                None => SimRange { min: value, max: value },
+            })
+        });
+    }
+    if let (Some(resamples), Some(top_maxima)) = (options.bootstrap, &top_maxima) {
+        let mut rng = StdRng::seed_from_u64(BOOTSTRAP_SEED);
+        let mean = score_mode == ScoreMode::MeanMaxSim;
+        stats.bootstrap = Some(
+            top_maxima
+                .iter()
+                .map(|maxima| {
+                    let contributions: Vec<f32> =
+                        maxima.iter().enumerate().map(|(i, max)| max * maxsim_config.row_weight(i)).collect();
+                    bootstrap_interval(&contributions, resamples, mean, &mut rng)
+                })
+                .collect(),
+        );
+    }
+    stats.cluster_scores = clusters;
+    if options.q_redundancy {
+        let full_q = normalized_matrix(&unmasked_q(), norm_eps);
+        let rows: Vec<Vec<f32>> = full_q.row_iter().map(|row| row.iter().cloned().collect()).collect();
+        stats.q_redundancy = Some(row_redundancy(&rows));
+    }
+    if options.return_idf {
+        let weights = idf_weights(q_tokens, query_idf(options).as_deref());
+        stats.query_idf = Some(q_kept.iter().map(|&i| weights[i]).collect());
+    }
+    if early_exit {
+        let total = dots_total.into_inner();
+        stats.early_exit_docs = Some(early_exit_docs.into_inner());
+        stats.dots_skipped_fraction =
+            Some(if total > 0 { dots_skipped.into_inner() as f32 / total as f32 } else { 0.0 });
+    }
+    if options.result_hash {
+        stats.result_hash = Some(format!("{:016x}", result_hash(&order, &scores)));
+    }
+    if options.report_memory {
+        let results_len = if options.low_memory { keep } else { d_tokens.len() };
+        stats.peak_memory_bytes = Some(estimate_peak_bytes(
+            q_kept.len(),
+            q_matrix.ncols(),
+            d_tokens,
+            prune_config,
+            options.low_memory,
+            rayon::current_num_threads(),
+            results_len,
+        ));
+    }
+    
+    let probabilities = options
+        .calibration
+        .map(|calibration| scores.iter().map(|&score| calibration.probability(score)).collect());
+    
+    let log_scores = options
+        .log_scores
+        .then(|| scores.iter().map(|&score| log_score(score)).collect());
+    
+    Ok(ScoreOutput { order, scores, ranks, perf, stats, probabilities, log_scores, topks, prune_stats })
+}
+
+/// Multiply each token by a `dim × reduced_dim` projection matrix
+pub fn project_tokens(tokens: &[Vec<f32>], projection: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let reduced_dim = projection.first().map_or(0, |row| row.len());
+    tokens
+        .iter()
+        .map(|token| {
+            let mut out = vec![0.0; reduced_dim];
+            for (&x, row) in token.iter().zip(projection) {
+                for (o, &p) in out.iter_mut().zip(row) {
+                    *o += x * p;
+                }
+            }
+            out
+        })
+        .collect()
+}
+
+/// `options` with every per-document field cut down to `docs`, in that
+/// order, so it lines up with a subset of the documents
+fn options_for_docs(options: &ScoreOptions, docs: &[usize]) -> ScoreOptions {
+    fn pick<T: Clone>(values: &Option<Vec<T>>, docs: &[usize]) -> Option<Vec<T>> {
+        values.as_ref().map(|values| docs.iter().map(|&i| values[i].clone()).collect())
+    }
+    ScoreOptions {
+        confidences: pick(&options.confidences, docs),
+        d_token_flags: pick(&options.d_token_flags, docs),
+        d_token_ids: pick(&options.d_token_ids, docs),
+        d_centroids: pick(&options.d_centroids, docs),
+        d_tiebreak_keys: pick(&options.d_tiebreak_keys, docs),
+        cluster_ids: pick(&options.cluster_ids, docs),
+        ..options.clone()
+    }
+}
+
+/// Score with a projected first pass, then refine at full dimension
+///
+/// 1. Every query and document token is multiplied by `options.projection`
+///    and all documents are scored in the reduced space, honouring the query
+///    options (`q_mask`, `q_weights`, IDF, tiebreak keys), `similarity` and
+///    `direction`.
+/// 2. The best `options.refine_topk` of those are re-scored from the
+///    original full-dimensional tokens with the remaining options, and the
+///    page is cut from that re-scoring.
+///
+/// Documents the projected pass ranks below `refine_topk` are never returned,
+/// so the result is exact over the refined set, not the whole corpus.
+/// Falls back to `score_docs_with_options` when no projection is set.
+pub fn score_docs_two_stage(
+    q_tokens: &[Vec<f32>],
+    d_tokens: &[Vec<Vec<f32>>],
+    topk: usize,
+    prune_config: &PruneConfig,
+    options: &ScoreOptions,
+) -> Result<ScoreOutput, ScoreError> {
+    check_inputs(q_tokens, d_tokens, prune_config)?;
+    let (Some(projection), Some(refine_topk)) = (&options.projection, options.refine_topk) else {
+        return score_docs_with_options(q_tokens, d_tokens, topk, prune_config, options, &NoopPostScorer);
+    };
+
+    let projected_q = project_tokens(q_tokens, projection);
+    let projected_d: Vec<Vec<Vec<f32>>> = d_tokens.par_iter().map(|doc| project_tokens(doc, projection)).collect();
+    // The candidates are picked with the same query tokens, weights and
+    // similarity as the refinement, so e.g. a masked token can't pick them
+    let first_pass_options = ScoreOptions {
+        score_mode: options.score_mode,
+        lse_temperature: options.lse_temperature,
+        low_memory: options.low_memory,
+        relu_sim: options.relu_sim,
+        norm_eps: options.norm_eps,
+        similarity: options.similarity,
+        direction: options.direction,
+        symmetric: options.symmetric,
+        q_mask: options.q_mask.clone(),
+        q_weights: options.q_weights.clone(),
+        q_idf: options.q_idf.clone(),
+        idf: options.idf.clone(),
+        q_token_ids: options.q_token_ids.clone(),
+        d_token_ids: options.d_token_ids.clone(),
+        q_tiebreak_keys: options.q_tiebreak_keys.clone(),
+        d_tiebreak_keys: options.d_tiebreak_keys.clone(),
+        ..Default::default()
+    };
+    let first_pass =
+        score_docs_with_options(&projected_q, &projected_d, refine_topk, prune_config, &first_pass_options, &NoopPostScorer)?;
+
+    let refined = first_pass.order;
+    let refined_tokens: Vec<Vec<Vec<f32>>> = refined.iter().map(|&idx| d_tokens[idx].clone()).collect();
+    let refine_options = ScoreOptions { projection: None, refine_topk: None, ..options_for_docs(options, &refined) };
+    let mut output =
+        score_docs_with_options(q_tokens, &refined_tokens, topk, prune_config, &refine_options, &NoopPostScorer)?;
+
+    // Map indices into the refined set back to corpus indices
+    let to_corpus = |idx: &mut usize| *idx = refined[*idx];
+    output.order.iter_mut().for_each(to_corpus);
+    for prefix in output.topks.iter_mut().flat_map(|topks| topks.values_mut()) {
+        prefix.order.iter_mut().for_each(to_corpus);
+    }
+    output.stats.hard_truncated.iter_mut().flatten().for_each(to_corpus);
+    output.stats.slow_docs.iter_mut().flatten().for_each(to_corpus);
+    if let Some(trace) = output.stats.op_trace.as_mut() {
+        to_corpus(&mut trace.doc_index);
+    }
+    if options.result_hash {
+        output.stats.result_hash = Some(format!("{:016x}", result_hash(&output.order, &output.scores)));
+    }
+    output.stats.refined_docs = Some(refined.len());
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dot_sim() {
+        let a = vec![1.0, 2.0, 3.0];
         let b = vec![4.0, 5.0, 6.0];
         assert_eq!(dot_sim(&a, &b), 32.0);
     }
@@ -215,4 +2857,1282 @@ mod tests {
         let score = maxsim_score(&q, &d);
         assert!((score - 2.0).abs() < 1e-6);
     }
+
+    struct ReversePostScorer;
+
+    impl PostScorer for ReversePostScorer {
+        fn rescore(&self, docs: &[(usize, f32)], _ctx: &ScoringContext) -> Vec<(usize, f32)> {
+            docs.iter().rev().cloned().collect()
+        }
+    }
+
+    #[test]
+    fn test_prepared_query_scores_shards_like_score_docs() {
+        let mut rng = StdRng::seed_from_u64(29);
+        let mut tokens = |n: usize| -> Vec<Vec<f32>> {
+            (0..n).map(|_| (0..8).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()
+        };
+        // More query tokens than `q_max`, so pruning runs once up front
+        let q_tokens = tokens(24);
+        let shards: Vec<Vec<Vec<Vec<f32>>>> = (0..3).map(|_| (0..5).map(|_| tokens(80)).collect()).collect();
+        let prune = PruneConfig::default();
+
+        let query = prepare_query(&q_tokens, &prune).unwrap();
+        assert_eq!(query.kept.len(), prune.q_max);
+        for shard in &shards {
+            let (order, scores, _) = score_docs_with_query(&query, shard, 3, &prune).unwrap();
+            let (direct_order, direct_scores, _) = score_docs(&q_tokens, shard, 3, &prune).unwrap();
+            let with_options =
+                score_docs_with_options(&q_tokens, shard, 3, &prune, &ScoreOptions::default(), &NoopPostScorer).unwrap();
+            assert_eq!(order, direct_order);
+            assert_eq!(scores, direct_scores);
+            assert_eq!((order, scores), (with_options.order, with_options.scores));
+        }
+        assert_eq!(prepare_query(&[], &prune).unwrap_err(), ScoreError::EmptyQuery);
+    }
+
+    #[test]
+    fn test_custom_post_scorer_reverses_order() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![
+            vec![vec![1.0, 0.0], vec![0.0, 1.0]],
+            vec![vec![1.0, 0.0]],
+            vec![vec![-1.0, 0.0]],
+        ];
+        let prune = PruneConfig::default();
+
+        let (order, _, _) = score_docs(&q_tokens, &d_tokens, 3, &prune).unwrap();
+        assert_eq!(order, vec![0, 1, 2]);
+
+        let (order, scores, _) =
+            score_docs_with_post_scorer(&q_tokens, &d_tokens, 3, &prune, &ReversePostScorer).unwrap();
+        assert_eq!(order, vec![2, 1, 0]);
+        assert!(scores[0] < scores[2]);
+    }
+
+    #[test]
+    fn test_detect_degenerate_corpus() {
+        let q_tokens = vec![vec![1.0, 0.0, 0.0]];
+        let prune = PruneConfig::default();
+        let options = ScoreOptions { detect_degenerate: true, ..Default::default() };
+
+        let identical = vec![vec![vec![0.6, 0.8, 0.0], vec![0.0, 0.0, 1.0]]; 10];
+        let out = score_docs_with_options(&q_tokens, &identical, 5, &prune, &options, &NoopPostScorer).unwrap();
+        assert_eq!(out.stats.degenerate_corpus, Some(true));
+
+        let diverse = vec![
+            vec![vec![1.0, 0.0, 0.0]],
+            vec![vec![0.0, 1.0, 0.0]],
+            vec![vec![0.0, 0.0, 1.0], vec![0.6, 0.8, 0.0]],
+        ];
+        let out = score_docs_with_options(&q_tokens, &diverse, 5, &prune, &options, &NoopPostScorer).unwrap();
+        assert_eq!(out.stats.degenerate_corpus, Some(false));
+
+        let out = score_docs_with_options(&q_tokens, &identical, 5, &prune, &ScoreOptions::default(), &NoopPostScorer).unwrap();
+        assert_eq!(out.stats.degenerate_corpus, None);
+    }
+
+    #[test]
+    fn test_token_dropout_is_reproducible() {
+        let tokens: Vec<Vec<f32>> = (0..20).map(|i| vec![i as f32, 1.0]).collect();
+
+        let a = apply_token_dropout(tokens.clone(), 0.5, 42);
+        let b = apply_token_dropout(tokens.clone(), 0.5, 42);
+        assert_eq!(a, b);
+        assert!(!a.is_empty() && a.len() < tokens.len());
+        assert_ne!(a, apply_token_dropout(tokens.clone(), 0.5, 7));
+
+        let q_tokens = vec![vec![1.0, 0.0]];
+        let d_tokens = vec![tokens.clone(), tokens];
+        let prune = PruneConfig { token_dropout: 0.5, dropout_seed: 42, ..Default::default() };
+        let options = ScoreOptions::default();
+        let first = score_docs_with_options(&q_tokens, &d_tokens, 2, &prune, &options, &NoopPostScorer).unwrap();
+        let second = score_docs_with_options(&q_tokens, &d_tokens, 2, &prune, &options, &NoopPostScorer).unwrap();
+        assert_eq!(first.stats.effective_d_tokens, second.stats.effective_d_tokens);
+        assert!(first.stats.effective_d_tokens.unwrap() < 40);
+    }
+
+    #[test]
+    fn test_low_memory_matches_exact_top_k() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut random_tokens = |n: usize| -> Vec<Vec<f32>> {
+            (0..n).map(|_| (0..16).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()
+        };
+        let q_tokens = random_tokens(8);
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..3000).map(|_| random_tokens(12)).collect();
+        let prune = PruneConfig::default();
+
+        let exact = score_docs_with_options(
+            &q_tokens, &d_tokens, 10, &prune, &ScoreOptions::default(), &NoopPostScorer,
+        ).unwrap();
+        let low_memory = ScoreOptions { low_memory: true, ..Default::default() };
+        let approx = score_docs_with_options(&q_tokens, &d_tokens, 10, &prune, &low_memory, &NoopPostScorer).unwrap();
+
+        assert_eq!(approx.order, exact.order);
+        assert_eq!(approx.scores, exact.scores);
+        let (p50, p95) = (approx.perf.per_doc_ms_p50.unwrap(), approx.perf.per_doc_ms_p95.unwrap());
+        assert!(p95 >= p50);
+        assert!(approx.perf.sample_size > 0 && approx.perf.sample_size <= TIMING_RESERVOIR_SIZE);
+    }
+
+    #[test]
+    fn test_q_min_ratio_overrides_tight_q_max() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.6, 0.8], vec![0.8, 0.6]];
+        let d_tokens = vec![vec![vec![1.0, 0.0], vec![0.0, 1.0]]];
+
+        let tight = PruneConfig { q_max: 1, ..Default::default() };
+        assert_eq!(tight.q_budget(4), 1);
+        let ratio = PruneConfig { q_max: 1, q_min_ratio: 0.5, ..Default::default() };
+        assert_eq!(ratio.q_budget(4), 2);
+        assert_eq!(ratio.q_budget(3), 2);
+
+        let (_, tight_scores, _) = score_docs(&q_tokens, &d_tokens, 1, &tight).unwrap();
+        let (_, ratio_scores, _) = score_docs(&q_tokens, &d_tokens, 1, &ratio).unwrap();
+        assert!((tight_scores[0] - 1.0).abs() < 1e-6);
+        assert!((ratio_scores[0] - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_confidences_demote_low_confidence_doc() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![
+            vec![vec![1.0, 0.0], vec![0.0, 1.0]],
+            vec![vec![1.0, 0.0]],
+        ];
+        let prune = PruneConfig::default();
+
+        let plain = score_docs_with_options(
+            &q_tokens, &d_tokens, 2, &prune, &ScoreOptions::default(), &NoopPostScorer,
+        ).unwrap();
+        assert_eq!(plain.order, vec![0, 1]);
+
+        let options = ScoreOptions { confidences: Some(vec![0.2, 1.0]), ..Default::default() };
+        let weighted = score_docs_with_options(&q_tokens, &d_tokens, 2, &prune, &options, &NoopPostScorer).unwrap();
+        assert_eq!(weighted.order, vec![1, 0]);
+        assert!((weighted.scores[1] - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_peak_memory_scales_with_input() {
+        let options = ScoreOptions { report_memory: true, ..Default::default() };
+        let prune = PruneConfig::default();
+        let peak = |n_docs: usize, dim: usize| {
+            let q_tokens = vec![vec![1.0; dim]; 4];
+            let d_tokens = vec![vec![vec![1.0; dim]; 8]; n_docs];
+            score_docs_with_options(&q_tokens, &d_tokens, 5, &prune, &options, &NoopPostScorer).unwrap()
+                .stats
+                .peak_memory_bytes
+                .unwrap()
+        };
+
+        assert!(peak(10, 16) > 0);
+        assert!(peak(1000, 16) > peak(10, 16));
+        assert!(peak(10, 64) > peak(10, 16));
+
+        // Outside low-memory mode the whole pruned batch is held at once
+        let prune = PruneConfig { d_max: 8, ..Default::default() };
+        let d_tokens = vec![vec![vec![1.0; 16]; 8]; 1000];
+        let batch = 1000 * 8 * 16 * std::mem::size_of::<f32>();
+        assert!(estimate_peak_bytes(4, 16, &d_tokens, &prune, false, 4, 1000) > 2 * batch);
+        assert!(estimate_peak_bytes(4, 16, &d_tokens, &prune, true, 4, 10) < batch);
+    }
+
+    #[test]
+    fn test_query_affinity_pruning_depends_on_query() {
+        let doc = vec![vec![2.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0, 0.0, 1.0]];
+
+        // Salience keeps the high-norm token whatever the query
+        assert_eq!(prune_tokens(&doc, 1, "idf_norm"), vec![vec![2.0, 0.0, 0.0]]);
+
+        let query_y = vec![vec![0.0, 1.0, 0.0]];
+        let query_z = vec![vec![0.0, 0.0, 1.0]];
+        assert_eq!(prune_by_query_affinity(&doc, 1, &query_y), vec![vec![0.0, 1.0, 0.0]]);
+        assert_eq!(prune_by_query_affinity(&doc, 1, &query_z), vec![vec![0.0, 0.0, 1.0]]);
+
+        let prune = PruneConfig { d_max: 1, method: QUERY_AFFINITY.to_string(), ..Default::default() };
+        let docs = vec![doc];
+        let (_, scores, _) = score_docs(&query_y, &docs, 1, &prune).unwrap();
+        assert!((scores[0] - 1.0).abs() < 1e-6);
+        let (_, scores, _) = score_docs(&query_y, &docs, 1, &PruneConfig { d_max: 1, ..Default::default() }).unwrap();
+        assert!(scores[0].abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_verify_lossless_reports_violations() {
+        let q_tokens = vec![vec![2.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![
+            vec![vec![1.0, 0.0], vec![0.0, 1.0]],
+            vec![vec![0.0, 1.0], vec![1.0, 0.0], vec![0.6, 0.8]],
+        ];
+        let options = ScoreOptions { verify_lossless: true, ..Default::default() };
+
+        let lossy = PruneConfig { q_max: 1, d_max: 1, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &lossy, &options, &NoopPostScorer).unwrap();
+        assert_eq!(out.stats.lossless_violations, Some(2));
+
+        let out = score_docs_with_options(
+            &q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer,
+        ).unwrap();
+        assert_eq!(out.stats.lossless_violations, Some(0));
+    }
+
+    #[test]
+    fn test_check_prune_impact_flags_reordering() {
+        let q_tokens = vec![vec![2.0, 0.0], vec![0.0, 1.0]];
+        // Unpruned, document 1 leads on both query tokens (1.8 vs 1.0); with
+        // only the first query token kept, document 0 leads (1.0 vs 0.8)
+        let d_tokens = vec![vec![vec![1.0, 0.0]], vec![vec![0.8, 0.6], vec![0.0, 1.0]]];
+        let options = ScoreOptions { check_prune_impact: true, ..Default::default() };
+
+        let lossy = PruneConfig { q_max: 1, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &lossy, &options, &NoopPostScorer).unwrap();
+        assert_eq!(out.order, vec![0, 1]);
+        assert_eq!(out.stats.prune_changed_ranking, Some(true));
+
+        let out = score_docs_with_options(
+            &q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer,
+        ).unwrap();
+        assert_eq!(out.stats.prune_changed_ranking, Some(false));
+    }
+
+    #[test]
+    fn test_hard_doc_token_cap_applies_before_salience() {
+        let q_tokens = vec![vec![0.0, 1.0]];
+        let mut huge = vec![vec![1.0, 0.0]; 10_000];
+        // The most salient token sits beyond the cap
+        huge[5_000] = vec![0.0, 10.0];
+        let d_tokens = vec![huge, vec![vec![0.0, 1.0]]];
+        let options = ScoreOptions::default();
+
+        let uncapped = PruneConfig { d_max: 10, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &uncapped, &options, &NoopPostScorer).unwrap();
+        assert!((out.scores[0] - 1.0).abs() < 1e-6 && (out.scores[1] - 1.0).abs() < 1e-6);
+        assert_eq!(out.stats.hard_truncated, None);
+
+        let capped = PruneConfig { d_max: 10, hard_doc_token_cap: Some(100), ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &capped, &options, &NoopPostScorer).unwrap();
+        assert_eq!(out.order, vec![1, 0]);
+        assert!(out.scores[1].abs() < 1e-6);
+        assert_eq!(out.stats.hard_truncated, Some(vec![0]));
+    }
+
+    #[test]
+    fn test_d_max_ratio_scales_budget_with_length() {
+        let prune = PruneConfig { d_max: 64, d_max_ratio: Some(0.25), d_min: 4, ..Default::default() };
+        assert_eq!(prune.d_budget(200), 50);
+        assert_eq!(prune.d_budget(40), 10);
+        assert_eq!(prune.d_budget(8), 4);
+        assert_eq!(prune.d_budget(1000), 64);
+
+        // 20 salient distractors, a mid-salience match, low-salience filler:
+        // only the long document's budget reaches the match
+        let q_tokens = vec![vec![1.0, 0.0]];
+        let doc = |n: usize| {
+            let mut tokens = vec![vec![0.0, 0.1]; n];
+            tokens[..20].fill(vec![0.0, 2.0]);
+            tokens[n - 1] = vec![1.0, 0.0];
+            tokens
+        };
+        let d_tokens = vec![doc(40), doc(200)];
+        let prune = PruneConfig { d_max: 64, d_max_ratio: Some(0.25), ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &prune, &ScoreOptions::default(), &NoopPostScorer).unwrap();
+        assert_eq!(out.order, vec![1, 0]);
+        assert!((out.scores[0] - 1.0).abs() < 1e-6 && out.scores[1].abs() < 1e-6);
+        assert_eq!(out.stats.effective_prune.d_max_ratio, Some(0.25));
+    }
+
+    #[test]
+    fn test_two_stage_refines_at_full_dimension() {
+        // Keep only the first dimension in the projected pass
+        let projection = vec![vec![1.0], vec![0.0], vec![0.0]];
+        let q_tokens = vec![vec![0.6, 0.8, 0.0]];
+        let d_tokens = vec![
+            vec![vec![1.0, 0.0, 0.0]],
+            vec![vec![0.6, 0.8, 0.0]],
+            vec![vec![0.5, 0.0, 0.8]],
+            vec![vec![0.0, 1.0, 0.0]],
+        ];
+        let prune = PruneConfig::default();
+        let options = ScoreOptions { projection: Some(projection), refine_topk: Some(3), ..Default::default() };
+        let out = score_docs_two_stage(&q_tokens, &d_tokens, 2, &prune, &options).unwrap();
+
+        // Doc 3 has no first-dimension weight, so the projected pass drops it;
+        // the refined ranking matches exact scoring of docs 0..3
+        let refined: Vec<Vec<Vec<f32>>> = d_tokens[..3].to_vec();
+        let exact = score_docs_with_options(&q_tokens, &refined, 2, &prune, &ScoreOptions::default(), &NoopPostScorer).unwrap();
+        assert_eq!(out.order, exact.order);
+        assert_eq!(out.scores, exact.scores);
+        assert_eq!(out.order, vec![1, 0]);
+        assert_eq!(out.stats.refined_docs, Some(3));
+    }
+
+    #[test]
+    fn test_two_stage_remaps_per_document_options() {
+        let projection = vec![vec![1.0], vec![0.0]];
+        let q_tokens = vec![vec![1.0, 0.0]];
+        let d_tokens = vec![
+            vec![vec![0.0, 1.0]],
+            vec![vec![0.6, 0.8], vec![1.0, 0.0], vec![0.8, 0.6]],
+            vec![vec![0.8, 0.6], vec![0.0, 1.0]],
+        ];
+        // Only one token per document survives, chosen by tiebreak key among
+        // the equal-norm tokens; doc 0's single key would panic if indexed by
+        // the refined position of doc 1
+        let prune = PruneConfig { d_max: 1, ..Default::default() };
+        let options = ScoreOptions {
+            projection: Some(projection),
+            refine_topk: Some(2),
+            d_tiebreak_keys: Some(vec![vec![0], vec![2, 0, 1], vec![0, 1]]),
+            confidences: Some(vec![1.0, 1.0, 0.5]),
+            ..Default::default()
+        };
+        let out = score_docs_two_stage(&q_tokens, &d_tokens, 2, &prune, &options).unwrap();
+
+        assert_eq!(out.order, vec![1, 2]);
+        assert!((out.scores[0] - 1.0).abs() < 1e-6);
+        assert!((out.scores[1] - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_two_stage_first_pass_honours_q_mask() {
+        let projection = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        // The padding token would pick document 0 as the only candidate
+        let q_tokens = vec![vec![1.0, 0.0], vec![-30.0, 40.0]];
+        let d_tokens = vec![vec![vec![-0.6, 0.8]], vec![vec![0.6, -0.8]]];
+        let options = ScoreOptions {
+            projection: Some(projection),
+            refine_topk: Some(1),
+            ..Default::default()
+        };
+        let unmasked = score_docs_two_stage(&q_tokens, &d_tokens, 1, &PruneConfig::default(), &options).unwrap();
+        assert_eq!(unmasked.order, vec![0]);
+
+        let masked = ScoreOptions { q_mask: Some(vec![true, false]), ..options };
+        let out = score_docs_two_stage(&q_tokens, &d_tokens, 1, &PruneConfig::default(), &masked).unwrap();
+        assert_eq!(out.order, vec![1]);
+        assert!((out.scores[0] - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_aggregation_modes_on_two_token_query() {
+        let q = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.0, 1.0]);
+        let d = q.clone();
+        let config = MaxSimConfig { temperature: 1.0, ..Default::default() };
+        assert_eq!(score_with_mode(&q, &d, ScoreMode::MaxSim, &config), 2.0);
+        assert_eq!(score_with_mode(&q, &d, ScoreMode::MeanMaxSim, &config), 1.0);
+        // Each query token weighs dots [1, 0] by softmax([1, 0])
+        let soft = std::f32::consts::E / (std::f32::consts::E + 1.0);
+        assert!((score_with_mode(&q, &d, ScoreMode::LogSumExp, &config) - 2.0 * soft).abs() < 1e-6);
+        let sharp = MaxSimConfig { temperature: 0.01, ..Default::default() };
+        assert!((score_with_mode(&q, &d, ScoreMode::LogSumExp, &sharp) - 2.0).abs() < 1e-4);
+
+        assert_eq!("sum".parse::<ScoreMode>(), Ok(ScoreMode::MaxSim));
+        assert_eq!("mean".parse::<ScoreMode>(), Ok(ScoreMode::MeanMaxSim));
+        assert_eq!("logsumexp".parse::<ScoreMode>(), Ok(ScoreMode::LogSumExp));
+    }
+
+    #[test]
+    fn test_sum_all_adds_every_dot() {
+        let q = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.0, 1.0]);
+        let d = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.6, 0.8]);
+        let config = MaxSimConfig::default();
+        // MaxSim keeps max(1.0, 0.6) + max(0.0, 0.8); SumAll adds all four
+        assert!((score_with_mode(&q, &d, ScoreMode::MaxSim, &config) - 1.8).abs() < 1e-6);
+        assert!((score_with_mode(&q, &d, ScoreMode::SumAll, &config) - 2.4).abs() < 1e-6);
+        assert_eq!("sum_all".parse::<ScoreMode>(), Ok(ScoreMode::SumAll));
+    }
+
+    #[test]
+    fn test_aggregation_modes_score_identical_docs_equally() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let doc = vec![vec![0.6, 0.8], vec![1.0, 0.0]];
+        let d_tokens = vec![doc.clone(), doc];
+        for mode in ScoreMode::ALL {
+            let options = ScoreOptions { score_mode: Some(mode), ..Default::default() };
+            let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+            assert_eq!(out.scores[0], out.scores[1], "{}", mode.as_str());
+            assert_eq!(out.stats.score_mode, mode);
+        }
+        let sum: ScoreMode = serde_json::from_str("\"sum\"").unwrap();
+        assert_eq!(sum, ScoreMode::MaxSim);
+    }
+
+    #[test]
+    fn test_empty_inputs_are_errors() {
+        let q_tokens = vec![vec![1.0, 0.0]];
+        let prune = PruneConfig::default();
+        assert_eq!(score_docs(&[], &[vec![vec![1.0, 0.0]]], 1, &prune).unwrap_err(), ScoreError::EmptyQuery);
+        let d_tokens = vec![vec![vec![1.0, 0.0]], vec![]];
+        assert_eq!(score_docs(&q_tokens, &d_tokens, 2, &prune).unwrap_err(), ScoreError::EmptyDocument { doc_index: 1 });
+
+        // A length-proportional budget can round a short document down to nothing
+        let ratio = PruneConfig { d_max_ratio: Some(0.25), ..Default::default() };
+        let d_tokens = vec![vec![vec![1.0, 0.0]; 8], vec![vec![1.0, 0.0]]];
+        assert_eq!(score_docs(&q_tokens, &d_tokens, 2, &ratio).unwrap_err(), ScoreError::EmptyDocument { doc_index: 1 });
+    }
+
+    #[test]
+    fn test_dimension_mismatch_is_error() {
+        let q_tokens = vec![vec![0.1; 128]; 4];
+        let d_tokens = vec![vec![vec![0.1; 128]; 3], vec![vec![0.1; 64]; 3]];
+        let err = score_docs(&q_tokens, &d_tokens, 2, &PruneConfig::default()).unwrap_err();
+        assert_eq!(err, ScoreError::DimMismatch { query_dim: 128, doc_index: 1, doc_dim: 64 });
+    }
+
+    #[test]
+    fn test_nearest_rank_percentile() {
+        assert_eq!(percentile(&[], 50.0), None);
+        assert_eq!(percentile(&[4.0], 50.0), Some(4.0));
+        assert_eq!(percentile(&[4.0], 95.0), Some(4.0));
+        assert_eq!((percentile(&[1.0, 2.0], 50.0), percentile(&[1.0, 2.0], 95.0)), (Some(1.0), Some(2.0)));
+        let three = [1.0, 2.0, 3.0];
+        assert_eq!((percentile(&three, 50.0), percentile(&three, 95.0)), (Some(2.0), Some(3.0)));
+        assert_eq!((percentile(&three, 0.0), percentile(&three, 100.0)), (Some(1.0), Some(3.0)));
+    }
+
+    #[test]
+    fn test_percentiles_for_few_documents() {
+        let q_tokens = vec![vec![1.0, 0.0]];
+        for n in 1..=3 {
+            let d_tokens: Vec<Vec<Vec<f32>>> = (0..n).map(|i| vec![vec![1.0, i as f32]]).collect();
+            let (_, _, perf) = score_docs(&q_tokens, &d_tokens, n, &PruneConfig::default()).unwrap();
+            assert_eq!(perf.sample_size, n);
+            let (p50, p95) = (perf.per_doc_ms_p50.unwrap(), perf.per_doc_ms_p95.unwrap());
+            assert!(p50 >= 0.0 && p95 >= p50);
+        }
+    }
+
+    #[test]
+    fn test_single_document_percentiles() {
+        let q_tokens = vec![vec![1.0, 0.0]];
+        let d_tokens = vec![vec![vec![1.0, 0.0]]];
+        let prune = PruneConfig::default();
+
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 1, &prune, &ScoreOptions::default(), &NoopPostScorer).unwrap();
+        assert_eq!(out.perf.sample_size, 1);
+        assert!(out.perf.per_doc_ms_p50.is_some());
+        assert_eq!(out.perf.per_doc_ms_p50, out.perf.per_doc_ms_p95);
+
+        let options = ScoreOptions { min_percentile_samples: Some(2), ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 1, &prune, &options, &NoopPostScorer).unwrap();
+        assert_eq!(out.perf.sample_size, 1);
+        assert_eq!((out.perf.per_doc_ms_p50, out.perf.per_doc_ms_p95), (None, None));
+    }
+
+    #[test]
+    fn test_op_trace_replays_to_final_score() {
+        let q_tokens = vec![vec![0.3, 0.9, 0.1], vec![0.7, -0.2, 0.4]];
+        let d_tokens = vec![
+            vec![vec![0.1, 0.8, 0.3], vec![0.9, 0.1, -0.2]],
+            vec![vec![-0.5, 0.2, 0.6]],
+        ];
+        let options = ScoreOptions { trace_ops: true, ..Default::default() };
+        let out = score_docs_with_options(
+            &q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer,
+        ).unwrap();
+        let trace = out.stats.op_trace.expect("trace recorded");
+        assert_eq!(trace.doc_index, out.order[0]);
+        assert_eq!(trace.ops.len(), trace_len(2, 2, false));
+
+        let mut total = 0.0f32;
+        let mut max_dot = f32::NEG_INFINITY;
+        for op in &trace.ops {
+            match *op {
+                TraceOp::Dot { value, .. } => max_dot = max_dot.max(value),
+                TraceOp::Max { value, .. } => {
+                    assert_eq!(value, max_dot);
+                    total += max_dot;
+                    max_dot = f32::NEG_INFINITY;
+                }
+                TraceOp::Relu { .. } => unreachable!("relu_sim not enabled"),
+                TraceOp::Add { value, .. } => assert_eq!(value, total),
+                TraceOp::Mean { n, value } => {
+                    total /= n as f32;
+                    assert_eq!(value, total);
+                }
+                TraceOp::Scale { factor, value } => {
+                    total *= factor;
+                    assert_eq!(value, total);
+                }
+            }
+        }
+        assert_eq!(total.to_bits(), out.scores[0].to_bits());
+
+        // The shared bound is exact: a trace of TRACE_MAX_OPS ops is recorded
+        let relu = MaxSimConfig { relu: true, ..Default::default() };
+        let q = DMatrix::from_row_slice(1, 2, &[0.6, 0.8]);
+        let d = DMatrix::from_fn(TRACE_MAX_OPS - 3, 2, |i, j| ((i * 7 + j) % 5) as f32 - 2.0);
+        let (score, ops) = maxsim_trace(&q, &d, &relu).expect("trace at the limit");
+        assert_eq!(ops.len(), TRACE_MAX_OPS);
+        assert_eq!(score.to_bits(), maxsim_score_with(&q, &d, &relu).to_bits());
+        let d = d.insert_row(0, 0.0);
+        assert!(maxsim_trace(&q, &d, &relu).is_none());
+    }
+
+    #[test]
+    fn test_mean_maxsim_divides_by_query_tokens() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![vec![vec![1.0, 0.0], vec![0.0, 1.0]]];
+        let options = ScoreOptions { score_mode: Some(ScoreMode::MeanMaxSim), ..Default::default() };
+        let out = score_docs_with_options(
+            &q_tokens, &d_tokens, 1, &PruneConfig::default(), &options, &NoopPostScorer,
+        ).unwrap();
+        assert!((out.scores[0] - 1.0).abs() < 1e-6);
+        assert_eq!(out.stats.score_mode, ScoreMode::MeanMaxSim);
+        assert_eq!("mean_maxsim".parse::<ScoreMode>(), Ok(ScoreMode::MeanMaxSim));
+        assert!("meanmaxsim".parse::<ScoreMode>().is_err());
+    }
+
+    #[test]
+    fn test_flag_slow_docs() {
+        assert_eq!(find_slow_docs(&[(0, 1.0), (1, 1.2), (2, 9.0), (3, 0.9)], 5.0), vec![2]);
+
+        let q_tokens = vec![vec![0.5; 64]; 4];
+        let mut d_tokens = vec![vec![vec![0.5; 64]; 2]; 20];
+        d_tokens[7] = vec![vec![0.5; 64]; 4000];
+        let prune = PruneConfig { d_max: 10_000, ..Default::default() };
+        let options = ScoreOptions { flag_slow_docs: Some(5.0), ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 5, &prune, &options, &NoopPostScorer).unwrap();
+        assert!(out.stats.slow_docs.unwrap().contains(&7));
+    }
+
+    #[test]
+    fn test_relu_sim_zeroes_negative_similarity() {
+        let q = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.0, 1.0]);
+        let opposite = DMatrix::from_row_slice(1, 2, &[-0.6, -0.8]);
+        assert!(maxsim_score(&q, &opposite) < 0.0);
+
+        let relu = MaxSimConfig { relu: true, ..Default::default() };
+        assert_eq!(maxsim_score_with(&q, &opposite, &relu), 0.0);
+
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![vec![vec![-0.6, -0.8]], vec![vec![1.0, 0.0]]];
+        let options = ScoreOptions { relu_sim: true, ..Default::default() };
+        let out = score_docs_with_options(
+            &q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer,
+        ).unwrap();
+        assert_eq!(out.order, vec![1, 0]);
+        assert_eq!(out.scores[1], 0.0);
+    }
+
+    #[test]
+    fn test_cutoff_ties_counts_documents_at_kth_score() {
+        let q_tokens = vec![vec![1.0, 0.0]];
+        let d_tokens = vec![
+            vec![vec![0.6, 0.8]],
+            vec![vec![1.0, 0.0]],
+            vec![vec![0.6, 0.8]],
+            vec![vec![0.0, 1.0]],
+            vec![vec![0.6, 0.8]],
+        ];
+        let run = |topk| {
+            score_docs_with_options(
+                &q_tokens, &d_tokens, topk, &PruneConfig::default(), &ScoreOptions::default(), &NoopPostScorer,
+            ).unwrap()
+        };
+
+        let out = run(2);
+        assert_eq!(out.order, vec![1, 0]);
+        assert_eq!(out.stats.cutoff_ties, 3);
+        assert_eq!(run(1).stats.cutoff_ties, 1);
+        assert_eq!(run(0).stats.cutoff_ties, 0);
+    }
+
+    #[test]
+    fn test_min_coverage_disqualifies_narrow_match() {
+        let q_tokens = vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0, 0.0, 1.0]];
+        // Doc 0 matches two query tokens perfectly, doc 1 matches all three moderately
+        let d_tokens = vec![
+            vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]],
+            vec![vec![1.0, 1.0, 1.0]],
+        ];
+        let run = |options: &ScoreOptions| {
+            score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), options, &NoopPostScorer).unwrap()
+        };
+
+        assert_eq!(run(&ScoreOptions::default()).order, vec![0, 1]);
+
+        let options = ScoreOptions { coverage_threshold: Some(0.5), min_coverage: 1.0, ..Default::default() };
+        let out = run(&options);
+        assert_eq!(out.order, vec![1, 0]);
+        assert_eq!(out.scores[1], DISQUALIFIED_SCORE);
+        let coverage = out.stats.coverage.unwrap();
+        assert_eq!(coverage[0], 1.0);
+        assert!((coverage[1] - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_log_scores_match_ln_of_positive_scores() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![vec![vec![0.6, 0.8]], vec![vec![1.0, 0.0]], vec![vec![-1.0, 0.0]]];
+        let options = ScoreOptions { log_scores: true, ..Default::default() };
+        let out = score_docs_with_options(
+            &q_tokens, &d_tokens, 3, &PruneConfig::default(), &options, &NoopPostScorer,
+        ).unwrap();
+
+        let log_scores = out.log_scores.unwrap();
+        for (score, log) in out.scores.iter().zip(&log_scores) {
+            if *score > 0.0 {
+                assert!((log.exp() - score).abs() < 1e-5);
+            } else {
+                assert_eq!(*log, NON_POSITIVE_LOG_SCORE);
+            }
+        }
+        assert_eq!(log_scores[2], NON_POSITIVE_LOG_SCORE);
+    }
+
+    #[test]
+    fn test_effective_prune_reports_adapted_budget() {
+        let q_tokens: Vec<Vec<f32>> = (0..10).map(|i| vec![1.0, i as f32]).collect();
+        let d_tokens = vec![vec![vec![1.0, 0.0]]];
+        let prune = PruneConfig {
+            q_max: 2,
+            q_min_ratio: 0.5,
+            method: QUERY_AFFINITY.to_string(),
+            ..Default::default()
+        };
+        let out = score_docs_with_options(
+            &q_tokens, &d_tokens, 1, &prune, &ScoreOptions::default(), &NoopPostScorer,
+        ).unwrap();
+
+        let effective = out.stats.effective_prune;
+        assert_eq!(effective.q_max, 5);
+        assert_ne!(effective.q_max, prune.q_max);
+        assert_eq!(effective.method, QUERY_AFFINITY);
+        assert_eq!(effective.query_method, "idf_norm");
+    }
+
+    #[test]
+    fn test_adapt_q_to_docs_caps_query_budget_at_tiny_doc() {
+        let q_tokens: Vec<Vec<f32>> = (0..8).map(|i| vec![1.0, i as f32]).collect();
+        // The second document has only two tokens
+        let d_tokens = vec![vec![vec![1.0, 0.0]; 5], vec![vec![0.0, 1.0], vec![1.0, 1.0]]];
+        let prune = PruneConfig { q_max: 6, q_min_ratio: 1.0, adapt_q_to_docs: true, ..Default::default() };
+        let out = score_docs_with_options(
+            &q_tokens, &d_tokens, 2, &prune, &ScoreOptions::default(), &NoopPostScorer,
+        ).unwrap();
+        assert_eq!(out.stats.effective_prune.q_max, 2);
+        assert_eq!(out.prune_stats.q_tokens_kept, 2);
+
+        let fixed = PruneConfig { adapt_q_to_docs: false, ..prune };
+        assert_eq!(fixed.adapted_to_docs(8, &d_tokens).q_max, 6);
+        assert_eq!(fixed.q_budget_for_docs(8, Some(2)), 8);
+    }
+
+    #[test]
+    fn test_symmetric_differs_from_standard_maxsim() {
+        // The doc's extra off-topic token doesn't hurt query→doc MaxSim but
+        // does pull down the doc→query direction
+        let q = DMatrix::from_row_slice(1, 2, &[1.0, 0.0]);
+        let d = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.0, 1.0]);
+        let standard = MaxSimConfig::default();
+        let symmetric = MaxSimConfig { direction: Direction::Symmetric, ..Default::default() };
+
+        assert_eq!(score_with_mode(&q, &d, ScoreMode::MaxSim, &standard), 1.0);
+        // d→q = (1 + 0) * 1/2 = 0.5, averaged with 1.0
+        assert_eq!(score_with_mode(&q, &d, ScoreMode::MaxSim, &symmetric), 0.75);
+        // Identical token sets score the same either way
+        assert_eq!(symmetric_maxsim(&d, &d, &symmetric), maxsim_score(&d, &d));
+    }
+
+    #[test]
+    fn test_direction_d2q_differs_and_symmetric_is_mean() {
+        // A short document matching one of two query tokens
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![vec![vec![1.0, 0.0]]];
+        let score = |options: ScoreOptions| {
+            score_docs_with_options(&q_tokens, &d_tokens, 1, &PruneConfig::default(), &options, &NoopPostScorer).unwrap().scores[0]
+        };
+        let q2d = score(ScoreOptions::default());
+        let d2q = score(ScoreOptions { direction: Direction::D2q, ..Default::default() });
+        let symmetric = score(ScoreOptions { direction: Direction::Symmetric, ..Default::default() });
+
+        assert!((q2d - 1.0).abs() < 1e-6);
+        // One document token, rescaled by 2 query tokens / 1 document token
+        assert!((d2q - 2.0).abs() < 1e-6);
+        assert!((symmetric - (q2d + d2q) / 2.0).abs() < 1e-6);
+        assert_eq!(score(ScoreOptions { symmetric: true, ..Default::default() }), symmetric);
+    }
+
+    #[test]
+    fn test_result_hash_tracks_order_and_scores() {
+        let base = result_hash(&[2, 0, 1], &[0.9, 0.5, 0.1]);
+        assert_eq!(base, result_hash(&[2, 0, 1], &[0.9, 0.5, 0.1]));
+        // Jitter below the rounding precision is ignored
+        assert_eq!(base, result_hash(&[2, 0, 1], &[0.900001, 0.5, 0.1]));
+        assert_ne!(base, result_hash(&[0, 2, 1], &[0.9, 0.5, 0.1]));
+        assert_ne!(base, result_hash(&[2, 0, 1], &[0.9, 0.4, 0.1]));
+
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![vec![vec![0.6, 0.8]], vec![vec![1.0, 0.0]]];
+        let options = ScoreOptions { result_hash: true, ..Default::default() };
+        let run = || {
+            score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer).unwrap()
+                .stats
+                .result_hash
+                .unwrap()
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_reservoir_sample_then_prune() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let huge: Vec<Vec<f32>> = (0..5000).map(|_| vec![rng.gen_range(0.1..1.0), rng.gen_range(-1.0..1.0)]).collect();
+
+        let sample = reservoir_sample_tokens(&huge, 200, 7);
+        assert_eq!(sample.len(), 200);
+        assert_eq!(sample, reservoir_sample_tokens(&huge, 200, 7));
+        assert_ne!(sample, reservoir_sample_tokens(&huge, 200, 8));
+
+        // Record how many tokens survive both stages
+        struct KeptRecorder(std::sync::Mutex<Vec<usize>>);
+        impl PostScorer for KeptRecorder {
+            fn rescore(&self, docs: &[(usize, f32)], ctx: &ScoringContext) -> Vec<(usize, f32)> {
+                *self.0.lock().unwrap() = ctx.d_tokens_kept.clone();
+                docs.to_vec()
+            }
+        }
+
+        let q_tokens = vec![vec![1.0, 0.0]];
+        let d_tokens = vec![huge];
+        let prune = PruneConfig { d_max: 32, reservoir_sample: Some(200), ..Default::default() };
+        let recorder = KeptRecorder(Default::default());
+        let run = || score_docs_with_options(&q_tokens, &d_tokens, 1, &prune, &ScoreOptions::default(), &recorder).unwrap();
+        let first = run();
+        assert_eq!(*recorder.0.lock().unwrap(), vec![32]);
+        assert_eq!(first.scores, run().scores);
+        assert_eq!(first.stats.effective_prune.reservoir_sample, Some(200));
+    }
+
+    #[test]
+    fn test_topks_are_prefixes_of_largest_k() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..12)
+            .map(|i| vec![vec![1.0, i as f32 * 0.1], vec![(i % 5) as f32, 1.0]])
+            .collect();
+        let options = ScoreOptions { topks: Some(vec![1, 5, 10, 100]), ..Default::default() };
+
+        for low_memory in [false, true] {
+            let options = ScoreOptions { low_memory, ..options.clone() };
+            let out = score_docs_with_options(
+                &q_tokens, &d_tokens, 3, &PruneConfig::default(), &options, &NoopPostScorer,
+            ).unwrap();
+            let topks = out.topks.unwrap();
+            let largest = &topks[&100];
+            assert_eq!(largest.order.len(), 12);
+            for k in [1, 5, 10] {
+                assert_eq!(topks[&k].order, largest.order[..k]);
+                assert_eq!(topks[&k].scores, largest.scores[..k]);
+            }
+            assert_eq!(out.order, largest.order[..3]);
+        }
+    }
+
+    #[test]
+    fn test_early_exit_skips_hopeless_documents() {
+        // A few strong matches followed by many documents orthogonal to the
+        // query; once the heap fills, the rest can be abandoned after one token
+        let q_tokens = vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0, 0.0, 1.0]];
+        let mut d_tokens = vec![vec![vec![1.0, 1.0, 1.0]]; 2];
+        d_tokens.extend((0..400).map(|_| vec![vec![-1.0, -1.0, -1.0]]));
+        let options = ScoreOptions { low_memory: true, early_exit: true, ..Default::default() };
+        let run = |options: &ScoreOptions| {
+            score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), options, &NoopPostScorer).unwrap()
+        };
+
+        let fast = run(&options);
+        let exact = run(&ScoreOptions { early_exit: false, ..options.clone() });
+        assert_eq!(fast.order, exact.order);
+        assert_eq!(fast.scores, exact.scores);
+        assert!(fast.stats.early_exit_docs.unwrap() > 0);
+        assert!(fast.stats.dots_skipped_fraction.unwrap() > 0.0);
+        assert!(exact.stats.early_exit_docs.is_none());
+    }
+
+    #[test]
+    fn test_return_idf_matches_supplied_weights() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.6, 0.8], vec![0.8, 0.6]];
+        let d_tokens = vec![vec![vec![1.0, 0.0]]];
+        let prune = PruneConfig { q_max: 2, ..Default::default() };
+        let options = ScoreOptions {
+            q_idf: Some(vec![0.5, 3.0, 1.0, 2.0]),
+            return_idf: true,
+            ..Default::default()
+        };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 1, &prune, &options, &NoopPostScorer).unwrap();
+
+        // Equal norms, so the two highest-IDF tokens survive
+        assert_eq!(out.stats.query_idf.unwrap(), vec![3.0, 2.0]);
+
+        // Without supplied weights the norm proxy is reported
+        let options = ScoreOptions { return_idf: true, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 1, &prune, &options, &NoopPostScorer).unwrap();
+        assert!(out.stats.query_idf.unwrap().iter().all(|w| (w - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_all_disqualified_when_coverage_rejects_every_doc() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let d_tokens = vec![vec![vec![1.0, 0.0]], vec![vec![0.0, 1.0]]];
+        let strict = ScoreOptions { coverage_threshold: Some(0.9), min_coverage: 1.0, ..Default::default() };
+        for low_memory in [false, true] {
+            let options = ScoreOptions { low_memory, ..strict.clone() };
+            let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+            assert!(out.stats.all_disqualified);
+            assert!(out.scores.iter().all(|s| *s == DISQUALIFIED_SCORE));
+        }
+
+        let lenient = ScoreOptions { min_coverage: 0.5, ..strict };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), &lenient, &NoopPostScorer).unwrap();
+        assert!(!out.stats.all_disqualified);
+    }
+
+    #[test]
+    fn test_norm_eps_controls_small_vector_normalization() {
+        let tokens = vec![vec![3e-9, 4e-9]];
+        let untouched = normalized_matrix(&tokens, DEFAULT_NORM_EPS);
+        assert_eq!(untouched[(0, 0)], 3e-9);
+
+        let normalized = normalized_matrix(&tokens, 1e-10);
+        assert!((normalized[(0, 0)] - 0.6).abs() < 1e-6);
+        assert!((normalized[(0, 1)] - 0.8).abs() < 1e-6);
+
+        let q_tokens = vec![vec![1.0, 0.0]];
+        let d_tokens = vec![tokens];
+        let run = |norm_eps| {
+            let options = ScoreOptions { norm_eps, ..Default::default() };
+            score_docs_with_options(&q_tokens, &d_tokens, 1, &PruneConfig::default(), &options, &NoopPostScorer).unwrap().scores[0]
+        };
+        assert!(run(None) < 1e-6);
+        assert!((run(Some(1e-10)) - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_centroid_filter_approximates_exhaustive_maxsim() {
+        // Four orthogonal centroids; tokens are small perturbations of them
+        let mut rng = StdRng::seed_from_u64(21);
+        let centroid = |c: usize| -> Vec<f32> { (0..4).map(|k| if k == c { 1.0 } else { 0.0 }).collect() };
+        let mut noisy = |c: usize| -> Vec<f32> {
+            centroid(c).iter().map(|x| x + rng.gen_range(-0.05..0.05)).collect()
+        };
+
+        let q_tokens = vec![noisy(0), noisy(2)];
+        let q_centroids = vec![vec![0, 1, 2, 3], vec![2, 3, 0, 1]];
+        let mut d_tokens = Vec::new();
+        let mut d_centroids = Vec::new();
+        for doc in 0..20usize {
+            let ids: Vec<u32> = (0..6).map(|t| ((doc + t) % 4) as u32).collect();
+            d_tokens.push(ids.iter().map(|&c| noisy(c as usize)).collect::<Vec<_>>());
+            d_centroids.push(ids);
+        }
+
+        let run = |centroid_filter| {
+            let options = ScoreOptions {
+                centroid_filter,
+                q_centroids: Some(q_centroids.clone()),
+                d_centroids: Some(d_centroids.clone()),
+                ..Default::default()
+            };
+            score_docs_with_options(&q_tokens, &d_tokens, 20, &PruneConfig::default(), &options, &NoopPostScorer).unwrap()
+        };
+        let exhaustive = run(None);
+        let filtered = run(Some(1));
+
+        assert_eq!(filtered.order, exhaustive.order);
+        for (f, e) in filtered.scores.iter().zip(&exhaustive.scores) {
+            assert!(f <= e);
+            assert!((f - e).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_packed_normalize_matches_per_document() {
+        let mut rng = StdRng::seed_from_u64(17);
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..50)
+            .map(|i| (0..1 + i % 7).map(|_| (0..5).map(|_| rng.gen_range(-2.0..2.0)).collect()).collect())
+            .collect();
+
+        let mut packed = PackedDocs::pack(&d_tokens, 5);
+        packed.normalize_rows(DEFAULT_NORM_EPS);
+        for (idx, doc) in d_tokens.iter().enumerate() {
+            assert_eq!(packed.matrix(idx), normalized_matrix(doc, DEFAULT_NORM_EPS));
+        }
+    }
+
+    #[test]
+    fn test_phase_split_matches_interleaved_scoring() {
+        let mut rng = StdRng::seed_from_u64(23);
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..40)
+            .map(|i| (0..2 + i % 9).map(|_| (0..6).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect())
+            .collect();
+        let q_tokens: Vec<Vec<f32>> = (0..4).map(|_| (0..6).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect();
+        let prune = PruneConfig { d_max: 5, ..Default::default() };
+
+        // Low-memory mode still prunes and scores each document in one step
+        let run = |low_memory| {
+            let options = ScoreOptions { low_memory, ..Default::default() };
+            score_docs_with_options(&q_tokens, &d_tokens, 40, &prune, &options, &NoopPostScorer).unwrap()
+        };
+        let (split, interleaved) = (run(false), run(true));
+        assert_eq!(split.order, interleaved.order);
+        assert_eq!(split.scores, interleaved.scores);
+        assert!(split.stats.prune_ms.is_some() && split.stats.score_ms.is_some());
+        assert!(interleaved.stats.prune_ms.is_none() && interleaved.stats.score_ms.is_none());
+    }
+
+    #[test]
+    fn test_mmr_sweep_trades_relevance_for_diversity() {
+        let q_tokens = vec![vec![1.0, 0.0, 0.0]];
+        // Three near-duplicate strong matches and three weaker, varied ones
+        let d_tokens = vec![
+            vec![vec![1.0, 0.05, 0.0]],
+            vec![vec![1.0, 0.0, 0.05]],
+            vec![vec![1.0, 0.04, 0.04]],
+            vec![vec![0.8, 0.6, 0.0]],
+            vec![vec![0.8, 0.0, 0.6]],
+            vec![vec![0.8, -0.6, 0.0]],
+        ];
+        let options = ScoreOptions { mmr_sweep: Some(vec![0.0, 1.0]), ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 3, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+
+        let sweep = out.stats.mmr_sweep.unwrap();
+        assert_eq!(sweep.iter().map(|p| p.lambda).collect::<Vec<_>>(), vec![0.0, 1.0]);
+        assert!(sweep[1].mean_relevance > sweep[0].mean_relevance);
+        assert!(sweep[1].mean_diversity < sweep[0].mean_diversity);
+    }
+
+    #[test]
+    fn test_dominant_q_token_per_document() {
+        // Query token 1 matches doc 0 exactly; token 2 matches doc 1 best
+        let q_tokens = vec![vec![0.0, 0.0, 1.0], vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]];
+        let d_tokens = vec![vec![vec![1.0, 0.0, 0.2]], vec![vec![0.0, 0.8, 0.6]]];
+        let options = ScoreOptions { return_dominant_q_token: true, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+
+        let dominant = out.stats.dominant_q_tokens.unwrap();
+        let by_doc: Vec<(usize, DominantToken)> = out.order.iter().copied().zip(dominant).collect();
+        let doc0 = by_doc.iter().find(|(idx, _)| *idx == 0).unwrap().1;
+        let doc1 = by_doc.iter().find(|(idx, _)| *idx == 1).unwrap().1;
+        assert_eq!(doc0.q_index, 1);
+        assert!((doc0.value - 1.0 / 1.04f32.sqrt()).abs() < 1e-6);
+        assert_eq!(doc1.q_index, 2);
+        assert!((doc1.value - 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cosine_similarity_matches_normalized_dot() {
+        let mut rng = StdRng::seed_from_u64(29);
+        // Norms between 0.5 and 10, as from a model encoding salience in length
+        let token = |rng: &mut StdRng| -> Vec<f32> {
+            let scale = rng.gen_range(0.5..10.0);
+            (0..8).map(|_| scale * rng.gen_range(-1.0f32..1.0)).collect()
+        };
+        let q_tokens: Vec<Vec<f32>> = (0..4).map(|_| token(&mut rng)).collect();
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..12).map(|i| (0..1 + i % 5).map(|_| token(&mut rng)).collect()).collect();
+        let run = |similarity| {
+            let options = ScoreOptions { similarity, ..Default::default() };
+            score_docs_with_options(&q_tokens, &d_tokens, 12, &PruneConfig::default(), &options, &NoopPostScorer).unwrap()
+        };
+
+        let (normalized, cosine, dot) = (run(None), run(Some(Similarity::Cosine)), run(Some(Similarity::Dot)));
+        assert_eq!(cosine.order, normalized.order);
+        for (c, n) in cosine.scores.iter().zip(&normalized.scores) {
+            assert!((c - n).abs() < 1e-5, "{} vs {}", c, n);
+        }
+        // Raw dots keep the norms, so they leave the [-1, 1] per-token range
+        assert!(dot.scores[0] > q_tokens.len() as f32);
+    }
+
+    #[test]
+    fn test_prune_stats_match_pruned_counts() {
+        let q_tokens: Vec<Vec<f32>> = (0..6).map(|i| vec![1.0, i as f32 * 0.1]).collect();
+        let d_tokens = vec![vec![vec![1.0, 0.0]; 10], vec![vec![0.0, 1.0]; 3], vec![vec![1.0, 1.0]; 5]];
+        let prune = PruneConfig { q_max: 4, d_max: 4, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 3, &prune, &ScoreOptions::default(), &NoopPostScorer).unwrap();
+
+        assert_eq!((out.prune_stats.q_tokens_in, out.prune_stats.q_tokens_kept), (6, 4));
+        let expected: Vec<(usize, usize)> = out.order.iter().map(|&idx| (d_tokens[idx].len(), d_tokens[idx].len().min(4))).collect();
+        assert_eq!(out.prune_stats.docs, expected);
+    }
+
+    #[test]
+    fn test_tiebreak_keys_order_equal_salience() {
+        let tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![-1.0, 0.0], vec![0.0, -1.0]];
+        assert_eq!(prune_token_indices(&tokens, 2, "idf_norm", None, None), vec![0, 1]);
+        let keys = [40, 10, 30, 20];
+        assert_eq!(prune_token_indices(&tokens, 2, "idf_norm", None, Some(&keys)), vec![1, 3]);
+
+        // Reordering the tokens with their keys selects the same tokens
+        let reordered = vec![tokens[3].clone(), tokens[2].clone(), tokens[1].clone(), tokens[0].clone()];
+        assert_eq!(prune_tokens_keyed(&reordered, 2, "norm_only", Some(&[20, 30, 10, 40])), vec![tokens[1].clone(), tokens[3].clone()]);
+    }
+
+    #[test]
+    fn test_partial_selection_matches_full_sort() {
+        let mut rng = StdRng::seed_from_u64(53);
+        // Coarse values so many tokens tie on salience
+        let tokens: Vec<Vec<f32>> =
+            (0..500).map(|_| (0..4).map(|_| rng.gen_range(0..4) as f32).collect()).collect();
+        let keys: Vec<u64> = (0..500).map(|_| rng.gen_range(0..50)).collect();
+        for tiebreak in [None, Some(keys.as_slice())] {
+            let sorted: Vec<usize> = token_salience_keyed(&tokens, "norm_only", tiebreak).into_iter().map(|(i, _)| i).collect();
+            for n in [0, 1, 32, 250, 499] {
+                assert_eq!(prune_token_indices(&tokens, n, "norm_only", None, tiebreak), sorted[..n]);
+            }
+        }
+
+        // Long inputs take the parallel path with the same arithmetic
+        let mut sequential = token_matrix(&tokens);
+        l2_normalize_rows(&mut sequential, DEFAULT_NORM_EPS);
+        assert_eq!(normalized_matrix(&tokens, DEFAULT_NORM_EPS), sequential);
+    }
+
+    #[test]
+    fn test_idf_table_prunes_high_norm_stopword() {
+        // Token 0 is a long-norm stopword; tokens 1 and 2 carry the content
+        let q_tokens = vec![vec![1.0, 0.0, 0.0]];
+        let d_tokens = vec![vec![vec![5.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0, 0.0, 0.9]]];
+        let prune = PruneConfig { d_max: 2, ..Default::default() };
+        let idf = HashMap::from([(1, 0.01), (2, 3.0), (3, 2.0)]);
+        let run = |idf: Option<HashMap<u32, f32>>| {
+            let options = ScoreOptions { idf, d_token_ids: Some(vec![vec![1, 2, 3]]), ..Default::default() };
+            score_docs_with_options(&q_tokens, &d_tokens, 1, &prune, &options, &NoopPostScorer).unwrap()
+        };
+
+        // Norm-squared salience keeps the stopword, which matches the query
+        assert!((run(None).scores[0] - 1.0).abs() < 1e-6);
+        assert!(run(Some(idf.clone())).scores[0].abs() < 1e-6);
+
+        assert_eq!(idf_from_table(&[2, 9], &idf), vec![3.0, 3.0]);
+    }
+
+    #[test]
+    fn test_contribution_fractions_sum_to_one() {
+        let mut rng = StdRng::seed_from_u64(37);
+        let q_tokens: Vec<Vec<f32>> = (0..5).map(|_| (0..4).map(|_| rng.gen_range(0.0..1.0)).collect()).collect();
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..6).map(|_| (0..3).map(|_| (0..4).map(|_| rng.gen_range(0.0..1.0)).collect()).collect()).collect();
+        let options = ScoreOptions { contribution_fractions: true, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 6, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+
+        for fractions in out.stats.contribution_fractions.unwrap() {
+            assert_eq!(fractions.len(), 5);
+            assert!((fractions.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+        assert!(contribution_fractions(&[0.5, -0.5]).is_empty());
+    }
+
+    #[test]
+    fn test_bootstrap_interval_brackets_score() {
+        let mut rng = StdRng::seed_from_u64(41);
+        let q_tokens: Vec<Vec<f32>> = (0..8).map(|_| (0..4).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect();
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..5).map(|_| (0..6).map(|_| (0..4).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()).collect();
+        let options = ScoreOptions { bootstrap: Some(200), ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 3, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+
+        let intervals = out.stats.bootstrap.unwrap();
+        assert_eq!(intervals.len(), 3);
+        for (interval, score) in intervals.iter().zip(&out.scores) {
+            assert!((interval.score - score).abs() < 1e-5);
+            assert!(interval.lower < interval.upper);
+            assert!(interval.lower <= interval.score && interval.score <= interval.upper);
+            assert!(interval.lower <= interval.mean && interval.mean <= interval.upper);
+        }
+    }
+
+    #[test]
+    fn test_flag_boost_lifts_heading_match() {
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        // Document 1's first token is a heading that half-matches the query
+        let d_tokens = vec![vec![vec![0.8, 0.6]], vec![vec![0.6, 0.8], vec![0.0, -1.0]]];
+        let mut options = ScoreOptions { d_token_flags: Some(vec![vec![0], vec![1, 0]]), ..Default::default() };
+        let plain = score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+        assert_eq!(plain.order, vec![0, 1]);
+        assert!((plain.scores[1] - 1.4).abs() < 1e-6);
+
+        options.flag_boosts = Some(HashMap::from([(1, 1.5)]));
+        let boosted = score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+        assert_eq!(boosted.order, vec![1, 0]);
+        assert!((boosted.scores[0] - 2.1).abs() < 1e-6);
+        // Unflagged documents score as before
+        assert_eq!(boosted.scores[1], plain.scores[0]);
+    }
+
+    #[test]
+    fn test_flag_boosts_follow_pruned_document_tokens() {
+        let q_tokens = vec![vec![0.0, 1.0]];
+        // Pruning to one token keeps the heading, the second, larger token
+        let d_tokens = vec![vec![vec![0.1, 0.0], vec![0.0, 2.0]], vec![vec![0.0, 1.0]]];
+        let options = ScoreOptions {
+            d_token_flags: Some(vec![vec![0, 1], vec![0]]),
+            flag_boosts: Some(HashMap::from([(1, 1.5)])),
+            ..Default::default()
+        };
+        let prune = PruneConfig { d_max: 1, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &prune, &options, &NoopPostScorer).unwrap();
+        assert_eq!(out.order, vec![0, 1]);
+        assert!((out.scores[0] - 1.5).abs() < 1e-6);
+        assert_eq!(out.prune_stats.docs[0], (2, 1));
+    }
+
+    #[test]
+    fn test_masked_query_token_is_not_scored() {
+        let real = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        // A huge padding token would win salience pruning and match document 1
+        let mut padded = real.clone();
+        padded.insert(1, vec![-30.0, 40.0]);
+        let d_tokens = vec![vec![vec![0.6, 0.8]], vec![vec![-0.6, 0.8]]];
+        let prune = PruneConfig { q_max: 2, ..Default::default() };
+        let unmasked = score_docs_with_options(&real, &d_tokens, 2, &prune, &ScoreOptions::default(), &NoopPostScorer).unwrap();
+
+        let options = ScoreOptions { q_mask: Some(vec![true, false, true]), ..Default::default() };
+        let masked = score_docs_with_options(&padded, &d_tokens, 2, &prune, &options, &NoopPostScorer).unwrap();
+        assert_eq!(masked.order, unmasked.order);
+        assert_eq!(masked.scores, unmasked.scores);
+        assert_eq!(masked.prune_stats.q_tokens_kept, 2);
+
+        let all_masked = ScoreOptions { q_mask: Some(vec![false; 3]), ..Default::default() };
+        let err = score_docs_with_options(&padded, &d_tokens, 2, &prune, &all_masked, &NoopPostScorer).unwrap_err();
+        assert_eq!(err, ScoreError::EmptyQuery);
+    }
+
+    #[test]
+    fn test_non_finite_values_are_rejected_or_zeroed() {
+        let mut q_tokens = vec![vec![1.0, 0.0], vec![f32::NAN, 1.0]];
+        let mut d_tokens = vec![vec![vec![1.0, 0.0]], vec![vec![0.0, 1.0], vec![f32::INFINITY, f32::NEG_INFINITY]]];
+        let err = score_docs(&q_tokens, &d_tokens, 2, &PruneConfig::default()).unwrap_err();
+        assert_eq!(err, ScoreError::NonFiniteValue { doc_index: None, token_index: 1 });
+
+        q_tokens[1][0] = 0.0;
+        let err = score_docs(&q_tokens, &d_tokens, 2, &PruneConfig::default()).unwrap_err();
+        assert_eq!(err, ScoreError::NonFiniteValue { doc_index: Some(1), token_index: 1 });
+
+        q_tokens[1][0] = f32::NAN;
+        assert_eq!(sanitize_non_finite(&mut q_tokens, &mut d_tokens), 3);
+        assert_eq!(d_tokens[1][1], vec![0.0, 0.0]);
+        assert!(check_finite(&q_tokens, &d_tokens).is_ok());
+    }
+
+    #[test]
+    fn test_q_weights_scale_token_contributions() {
+        let q = normalized_matrix(&[vec![1.0, 0.0], vec![0.0, 1.0]], DEFAULT_NORM_EPS);
+        let d = normalized_matrix(&[vec![0.6, 0.8]], DEFAULT_NORM_EPS);
+        let score = |weights: &[f32]| maxsim_score_with(&q, &d, &MaxSimConfig { weights: Some(weights), ..Default::default() });
+        assert!((maxsim_score(&q, &d) - 1.4).abs() < 1e-6);
+        assert_eq!(score(&[1.0, 1.0]), maxsim_score(&q, &d));
+        // A zero weight drops the token; a doubled weight doubles its 0.8
+        assert!((score(&[0.0, 1.0]) - 0.8).abs() < 1e-6);
+        assert!((score(&[1.0, 2.0]) - 2.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_q_weights_follow_pruned_query_tokens() {
+        // norm_only keeps the two longest tokens, 0 and 2
+        let q_tokens = vec![vec![3.0, 0.0], vec![0.0, 1.0], vec![0.0, 2.0]];
+        let d_tokens = vec![vec![vec![1.0, 0.0]], vec![vec![0.0, 1.0]]];
+        let prune = PruneConfig { q_max: 2, method: "norm_only".to_string(), ..Default::default() };
+        let options = ScoreOptions { q_weights: Some(vec![0.0, 5.0, 1.0]), ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &prune, &options, &NoopPostScorer).unwrap();
+        // Token 0 is zeroed, so only token 2 (weight 1, not token 1's 5) counts
+        assert_eq!(out.order, vec![1, 0]);
+        assert!((out.scores[0] - 1.0).abs() < 1e-6);
+        assert!(out.scores[1].abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cluster_scores_reflect_members() {
+        let q_tokens = vec![vec![1.0, 0.0]];
+        // Cluster 7 holds documents 0 and 2, cluster 3 holds document 1
+        let d_tokens = vec![vec![vec![1.0, 0.0]], vec![vec![0.6, 0.8]], vec![vec![0.0, 1.0]]];
+        let mut options = ScoreOptions { cluster_ids: Some(vec![7, 3, 7]), cluster_scores: true, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 1, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+        let clusters = out.stats.cluster_scores.unwrap();
+        assert_eq!(clusters.keys().copied().collect::<Vec<_>>(), vec![3, 7]);
+        assert_eq!((clusters[&7].best_doc, clusters[&7].size), (0, 2));
+        assert!((clusters[&7].score - 1.0).abs() < 1e-6);
+        assert!((clusters[&3].score - 0.6).abs() < 1e-6);
+
+        options.cluster_aggregate = ClusterAggregate::Mean;
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 1, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+        let clusters = out.stats.cluster_scores.unwrap();
+        assert!((clusters[&7].score - 0.5).abs() < 1e-6);
+        assert_eq!(clusters[&7].best_doc, 0);
+    }
+
+    #[test]
+    fn test_sim_range_bounds_per_token_maxima() {
+        let mut rng = StdRng::seed_from_u64(41);
+        let q_tokens: Vec<Vec<f32>> = (0..4).map(|_| (0..6).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect();
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..8).map(|_| (0..5).map(|_| (0..6).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()).collect();
+        let options = ScoreOptions { return_sim_range: true, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 3, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+
+        let range = out.stats.sim_range.unwrap();
+        let q = normalized_matrix(&q_tokens, DEFAULT_NORM_EPS);
+        let maxima: Vec<f32> = out
+            .order
+            .iter()
+            .flat_map(|&idx| query_row_maxima(&q, &normalized_matrix(&d_tokens[idx], DEFAULT_NORM_EPS), &MaxSimConfig::default()))
+            .collect();
+        assert!(maxima.iter().all(|&m| range.min <= m && m <= range.max));
+        assert!(maxima.contains(&range.min) && maxima.contains(&range.max));
+        // The range only covers the returned documents
+        assert_eq!(out.order.len(), 3);
+    }
+
+    #[test]
+    fn test_cpu_time_exceeds_wall_time_when_parallel() {
+        let mut rng = StdRng::seed_from_u64(43);
+        let mut random_tokens = |n: usize| -> Vec<Vec<f32>> {
+            (0..n).map(|_| (0..128).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()
+        };
+        let q_tokens = random_tokens(32);
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..128).map(|_| random_tokens(64)).collect();
+        let prune = PruneConfig { q_max: 32, d_max: 64, ..Default::default() };
+        // Threads only overlap with more than one core to run on
+        let parallel = std::thread::available_parallelism().is_ok_and(|n| n.get() > 1);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for low_memory in [false, true] {
+            let options = ScoreOptions { low_memory, ..Default::default() };
+            let out = pool.install(|| {
+                score_docs_with_options(&q_tokens, &d_tokens, 10, &prune, &options, &NoopPostScorer).unwrap()
+            });
+            let (cpu, wall) = (out.stats.cpu_ms.unwrap(), out.stats.wall_ms.unwrap());
+            assert!(!parallel || cpu > wall, "cpu {}ms vs wall {}ms", cpu, wall);
+            assert!((out.stats.parallelism.unwrap() - cpu / wall).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_q_redundancy_flags_duplicated_token() {
+        let q_tokens = vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0, 0.0, 1.0], vec![2.0, 0.0, 0.0]];
+        let d_tokens = vec![vec![vec![1.0, 0.0, 0.0]]];
+        let options = ScoreOptions { q_redundancy: true, ..Default::default() };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 1, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+
+        // Tokens 0 and 3 point the same way, so each matches one of three others
+        let redundancy = out.stats.q_redundancy.unwrap();
+        assert!((redundancy[0] - 1.0 / 3.0).abs() < 1e-6);
+        assert!((redundancy[3] - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(redundancy[1], 0.0);
+        assert_eq!(redundancy[2], 0.0);
+    }
+
+    #[test]
+    fn test_masked_query_tokens_left_out_of_lossless_check_and_redundancy() {
+        // Token 2 is padding: it would add 1.0 to the unpruned score and
+        // duplicate token 0 in the redundancy report
+        let q_tokens = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![1.0, 0.0]];
+        let d_tokens = vec![vec![vec![1.0, 0.0], vec![0.0, 1.0]], vec![vec![0.6, 0.8]]];
+        let options = ScoreOptions {
+            q_mask: Some(vec![true, true, false]),
+            verify_lossless: true,
+            q_redundancy: true,
+            ..Default::default()
+        };
+        let out = score_docs_with_options(&q_tokens, &d_tokens, 2, &PruneConfig::default(), &options, &NoopPostScorer).unwrap();
+
+        assert_eq!(out.stats.lossless_violations, Some(0));
+        assert_eq!(out.stats.q_redundancy, Some(vec![0.0, 0.0]));
+    }
 }
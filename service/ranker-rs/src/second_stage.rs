@@ -0,0 +1,130 @@
+use std::cmp::Ordering;
+use std::fmt::Debug;
+
+use crate::scoring::{
+    normalized_matrix, prune_doc_tokens, pruned_query_indices, score_docs_with_options, unmasked_query_indices,
+    PostScorer, PruneConfig, ScoreError, ScoreOptions, ScoreOutput, ScoringContext, DEFAULT_NORM_EPS,
+    DISQUALIFIED_SCORE,
+};
+
+/// A first-stage candidate handed to the second stage
+#[derive(Debug, Clone)]
+pub struct SecondStageCandidate {
+    /// Index into the request's documents
+    pub doc_idx: usize,
+    /// MaxSim score that placed it in the top-N
+    pub first_stage_score: f32,
+    /// Document tokens kept after pruning, as given (not normalized)
+    pub tokens: Vec<Vec<f32>>,
+}
+
+/// Expensive rescoring (e.g. a cross-encoder) of the MaxSim top-N
+///
+/// Implementations must return one score per candidate, in candidate order;
+/// higher is better. The final top-K is ranked by these scores alone.
+pub trait SecondStageScorer: Send + Sync + Debug {
+    /// `q_tokens` are the query tokens kept after pruning, as given
+    fn score(&self, q_tokens: &[Vec<f32>], candidates: &[SecondStageCandidate]) -> Vec<f32>;
+}
+
+/// Default second stage that keeps the first-stage scores
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopSecondStage;
+
+impl SecondStageScorer for NoopSecondStage {
+    fn score(&self, _q_tokens: &[Vec<f32>], candidates: &[SecondStageCandidate]) -> Vec<f32> {
+        candidates.iter().map(|c| c.first_stage_score).collect()
+    }
+}
+
+/// Post-scorer that hands the first `candidates` documents of the MaxSim
+/// ranking to a second stage and ranks them by its scores
+struct SecondStagePostScorer<'a> {
+    scorer: &'a dyn SecondStageScorer,
+    candidates: usize,
+    q_tokens: &'a [Vec<f32>],
+    d_tokens: &'a [Vec<Vec<f32>>],
+    prune_config: &'a PruneConfig,
+    options: &'a ScoreOptions,
+}
+
+impl PostScorer for SecondStagePostScorer<'_> {
+    fn rescore(&self, docs: &[(usize, f32)], _ctx: &ScoringContext) -> Vec<(usize, f32)> {
+        let pruned_q: Vec<Vec<f32>> = pruned_query_indices(self.q_tokens, self.prune_config, self.options)
+            .into_iter()
+            .map(|i| self.q_tokens[i].clone())
+            .collect();
+        // Query-affinity pruning compares documents against the scored rows
+        let q_rows: Vec<Vec<f32>> = if self.options.similarity.is_none() {
+            let eps = self.options.norm_eps.unwrap_or(DEFAULT_NORM_EPS);
+            normalized_matrix(&pruned_q, eps).row_iter().map(|row| row.iter().cloned().collect()).collect()
+        } else {
+            pruned_q.clone()
+        };
+
+        let candidates: Vec<SecondStageCandidate> = docs
+            .iter()
+            .filter(|(_, score)| *score != DISQUALIFIED_SCORE)
+            .take(self.candidates)
+            .map(|&(doc_idx, first_stage_score)| SecondStageCandidate {
+                doc_idx,
+                first_stage_score,
+                tokens: prune_doc_tokens(doc_idx, &self.d_tokens[doc_idx], self.prune_config, self.options, &q_rows),
+            })
+            .collect();
+        let scores = self.scorer.score(&pruned_q, &candidates);
+        let mut rescored: Vec<(usize, f32)> = candidates.iter().map(|c| c.doc_idx).zip(scores).collect();
+        rescored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        rescored
+    }
+}
+
+/// Rank by MaxSim, rescore the top `candidates` with `scorer`, and return
+/// the top-K by second-stage score. Documents outside the first-stage
+/// top-N are never returned.
+pub fn score_docs_second_stage(
+    q_tokens: &[Vec<f32>],
+    d_tokens: &[Vec<Vec<f32>>],
+    topk: usize,
+    prune_config: &PruneConfig,
+    options: &ScoreOptions,
+    candidates: usize,
+    scorer: &dyn SecondStageScorer,
+) -> Result<ScoreOutput, ScoreError> {
+    // Adapt up front so the candidates see the query as it was scored
+    let q_len = unmasked_query_indices(q_tokens.len(), options).len();
+    let prune_config = &prune_config.adapted_to_docs(q_len, d_tokens);
+    let post_scorer = SecondStagePostScorer { scorer, candidates, q_tokens, d_tokens, prune_config, options };
+    score_docs_with_options(q_tokens, d_tokens, topk, prune_config, options, &post_scorer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ranks the candidates in reverse first-stage order
+    #[derive(Debug)]
+    struct Inverting;
+
+    impl SecondStageScorer for Inverting {
+        fn score(&self, _q_tokens: &[Vec<f32>], candidates: &[SecondStageCandidate]) -> Vec<f32> {
+            candidates.iter().map(|c| -c.first_stage_score).collect()
+        }
+    }
+
+    #[test]
+    fn test_second_stage_reranks_first_stage_candidates() {
+        let q_tokens = vec![vec![1.0, 0.0]];
+        // First-stage scores fall with the document index
+        let d_tokens: Vec<Vec<Vec<f32>>> = (0..6).map(|i| vec![vec![1.0, i as f32 * 0.3]]).collect();
+        let prune = PruneConfig::default();
+        let options = ScoreOptions::default();
+
+        let first = score_docs_second_stage(&q_tokens, &d_tokens, 3, &prune, &options, 4, &NoopSecondStage).unwrap();
+        assert_eq!(first.order, vec![0, 1, 2]);
+        let inverted = score_docs_second_stage(&q_tokens, &d_tokens, 3, &prune, &options, 4, &Inverting).unwrap();
+        // Only the top 4 reach the second stage, so documents 4 and 5 never return
+        assert_eq!(inverted.order, vec![3, 2, 1]);
+        assert!(inverted.scores.iter().all(|s| *s < 0.0));
+    }
+}
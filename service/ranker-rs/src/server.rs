@@ -0,0 +1,3143 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
+    routing::{get, post},
+    Router,
+};
+use crate::coalesce::{Coalescer, Fingerprint, KeyHasher};
+use crate::compare::{compare_configs, CompareRequest};
+use crate::contrastive::{score_contrastive, ContrastiveRequest};
+use crate::corpus::{
+    dedup_ids, resolve_tenant, Corpus, PromoteError, RerankByIdRequest, RerankByIdResponse, StandbyReport,
+    UploadError, UploadRequest, UploadResponse, ValidateStandbyRequest, TENANT_HEADER,
+};
+use crate::doc_cache::{
+    prepare_doc, prune_key, score_cached_docs, CacheUploadRequest, CacheUploadResponse, DocCache, CACHED_OPTIONS,
+};
+use crate::embeddings::{WireRequest, WireToken};
+use crate::explain::{explain_pair, unexplainable_prune, ExplainRequest, ExplainResponse};
+use crate::jobs::{JobRequest, JobState, JobStore};
+use crate::kernels::{kernel_name, set_kernel, CpuCaps, KernelKind};
+use crate::metrics::{Endpoint, Metrics, METRICS_CONTENT_TYPE};
+use crate::models::ModelRegistry;
+use crate::proto::{encode_response, PROTOBUF_CONTENT_TYPE};
+use crate::quant::{score_quantized_docs, QuantizedRows, QUANT_OPTIONS};
+use crate::scoring::{
+    RerankRequest, RerankResponse, result_hash, score_docs_two_stage, score_docs_with_options, NoopPostScorer,
+    Layout, Progress, PruneConfig, ScoreError, ScoreOutput, ScoreMode, ScoreOptions, Similarity, Direction, check_finite, sanitize_non_finite, BOOTSTRAP_MAX_RESAMPLES, PRUNE_METHODS, QUERY_AFFINITY, TRACE_MAX_OPS, trace_len,
+};
+use crate::second_stage::{score_docs_second_stage, NoopSecondStage, SecondStageScorer};
+use crate::sessions::{SessionPostScorer, SessionStore, DEFAULT_SMOOTHING};
+use crate::snapshots::{DeltaResponse, SnapshotResponse, SnapshotStore};
+use serde::Deserialize;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tower::ServiceBuilder;
+use tower_http::cors::CorsLayer;
+use tracing::{info, error, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Environment variable selecting the score mode used when a request omits one
+pub const DEFAULT_SCORE_MODE_ENV: &str = "RERANKER_DEFAULT_SCORE_MODE";
+
+/// Environment variable capping the documents each tenant may upload
+pub const MAX_TENANT_DOCS_ENV: &str = "RERANKER_MAX_TENANT_DOCS";
+
+/// Environment variable overriding where batch job results are written
+pub const JOBS_DIR_ENV: &str = "RERANKER_JOBS_DIR";
+
+/// Environment variable with how many seconds finished batch jobs are kept
+pub const JOBS_RETENTION_SECS_ENV: &str = "RERANKER_JOBS_RETENTION_SECS";
+
+/// Environment variable enabling coalescing of identical `/rerank` requests
+pub const COALESCE_ENV: &str = "RERANKER_COALESCE";
+
+/// Environment variable capping the entries held by the document cache
+pub const DOC_CACHE_CAPACITY_ENV: &str = "RERANKER_DOC_CACHE_CAPACITY";
+
+/// Environment variable with the IP address the listener binds to
+pub const BIND_ADDR_ENV: &str = "BIND_ADDR";
+
+/// Environment variable with the port the listener binds to
+pub const PORT_ENV: &str = "PORT";
+
+/// Listen address when neither `--bind` nor the environment sets one
+pub const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8088);
+
+/// Resolve the listen address: a `--bind host:port` argument wins over
+/// `BIND_ADDR`/`PORT`, each of which falls back to `DEFAULT_BIND_ADDR`
+pub fn resolve_bind_addr(
+    bind_addr: Option<&str>,
+    port: Option<&str>,
+    bind_arg: Option<&str>,
+) -> Result<SocketAddr, String> {
+    if let Some(bind) = bind_arg {
+        return bind.parse().map_err(|e| format!("--bind {}: {}", bind, e));
+    }
+    let ip = match bind_addr {
+        Some(ip) => ip.parse().map_err(|e| format!("{} {}: {}", BIND_ADDR_ENV, ip, e))?,
+        None => DEFAULT_BIND_ADDR.ip(),
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|e| format!("{} {}: {}", PORT_ENV, port, e))?,
+        None => DEFAULT_BIND_ADDR.port(),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Outcome of one `/rerank` scoring run, shared by coalesced requests
+type RerankOutcome = Result<Result<ScoreOutput, ScoreError>, StatusCode>;
+
+/// A `/rerank` request as scored, after defaults and validation
+type RerankJob = (RerankRequest, PruneConfig);
+
+/// Coalescing key of a `/rerank` request: tokens are hashed by bit pattern,
+/// the remaining settings by their `Debug` form. A hit is confirmed by
+/// comparing the requests, so the hash need not cover every field.
+fn rerank_fingerprint(job: Arc<RerankJob>) -> Fingerprint<RerankJob> {
+    let (payload, prune) = &*job;
+    let mut hasher = KeyHasher::default();
+    for token in payload.q_tokens.iter().chain(payload.d_tokens.iter().flatten()) {
+        hasher.floats(token);
+    }
+    hasher.debug(&(payload.topk, &payload.options, prune));
+    Fingerprint::new(hasher.finish(), job)
+}
+
+/// Server-wide settings shared by all handlers
+#[derive(Debug, Clone, Default)]
+pub struct AppState {
+    pub default_score_mode: ScoreMode,
+    /// Per-model defaults selected by a request's `model` field
+    pub models: ModelRegistry,
+    /// Background batch jobs submitted to `/jobs`
+    pub jobs: JobStore,
+    /// Documents uploaded for `/rerank_by_id`
+    pub corpus: Corpus,
+    /// Rankings saved by `/rerank` for delta responses
+    pub snapshots: SnapshotStore,
+    /// Blended scores of `/rerank` sessions; see `sessions`
+    pub sessions: SessionStore,
+    /// Share one scoring run among concurrent identical `/rerank` requests;
+    /// see `coalesce` for the tradeoffs
+    pub coalesce: bool,
+    pub coalescer: Coalescer<RerankJob, RerankOutcome>,
+    /// Pruned, normalized documents uploaded to `/cache/upload`
+    pub doc_cache: DocCache,
+    /// Request counters and latencies served by `/metrics`
+    pub metrics: Metrics,
+    /// Rescores `second_stage_candidates`; `NoopSecondStage` when unset
+    pub second_stage: Option<Arc<dyn SecondStageScorer>>,
+}
+
+impl AppState {
+    /// Load settings from the environment, rejecting unknown values
+    pub fn from_env() -> Result<Self, String> {
+        let default_score_mode = match std::env::var(DEFAULT_SCORE_MODE_ENV) {
+            Ok(value) => value
+                .parse()
+                .map_err(|e| format!("{}: {}", DEFAULT_SCORE_MODE_ENV, e))?,
+            Err(_) => ScoreMode::default(),
+        };
+        let jobs_dir = match std::env::var(JOBS_DIR_ENV) {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => JobStore::default().dir().to_path_buf(),
+        };
+        let jobs = match std::env::var(JOBS_RETENTION_SECS_ENV) {
+            Ok(value) => JobStore::with_retention(
+                jobs_dir,
+                Duration::from_secs(
+                    value
+                        .parse()
+                        .map_err(|e| format!("{}: {}", JOBS_RETENTION_SECS_ENV, e))?,
+                ),
+            ),
+            Err(_) => JobStore::new(jobs_dir),
+        };
+        let corpus = match std::env::var(MAX_TENANT_DOCS_ENV) {
+            Ok(value) => Corpus::with_tenant_limit(
+                value
+                    .parse()
+                    .map_err(|e| format!("{}: {}", MAX_TENANT_DOCS_ENV, e))?,
+            ),
+            Err(_) => Corpus::default(),
+        };
+        let coalesce = match std::env::var(COALESCE_ENV) {
+            Ok(value) => value
+                .parse()
+                .map_err(|e| format!("{}: {}", COALESCE_ENV, e))?,
+            Err(_) => false,
+        };
+        let doc_cache = match std::env::var(DOC_CACHE_CAPACITY_ENV) {
+            Ok(value) => DocCache::with_capacity(
+                value
+                    .parse()
+                    .map_err(|e| format!("{}: {}", DOC_CACHE_CAPACITY_ENV, e))?,
+            ),
+            Err(_) => DocCache::default(),
+        };
+        Ok(Self { default_score_mode, jobs, corpus, coalesce, doc_cache, ..Default::default() })
+    }
+
+    /// Fill omitted settings from the named model's defaults and check its dimension
+    fn apply_model(&self, payload: &mut RerankRequest) -> Result<(), RerankError> {
+        let Some(name) = &payload.model else {
+            return Ok(());
+        };
+        let Some(model) = self.models.get(name) else {
+            return Err(RerankError::InvalidRequest(format!("Unknown model '{}'", name)));
+        };
+        let dim = payload.q_tokens[0].len();
+        if dim != model.dim {
+            return Err(RerankError::DimMismatch(format!("Model '{}' expects {} dims, got {}", name, model.dim, dim)));
+        }
+        if payload.prune.is_none() {
+            payload.prune = model.prune.clone();
+        }
+        if payload.options.score_mode.is_none() {
+            payload.options.score_mode = model.score_mode;
+        }
+        if payload.options.calibration.is_none() {
+            payload.options.calibration = model.calibration;
+        }
+        Ok(())
+    }
+
+    /// Fill in server defaults for anything the request left unset
+    fn apply_defaults(&self, options: &mut ScoreOptions) -> Result<(), RerankError> {
+        let score_mode = *options.score_mode.get_or_insert(self.default_score_mode);
+        // Checked here rather than in `validate_options` so the server default counts too
+        if score_mode == ScoreMode::LogSumExp
+            && (options.effective_direction() != Direction::Q2d
+                || options.trace_ops
+                || options.centroid_filter.is_some()
+                || options.flag_boosts.is_some()
+                || options.bootstrap.is_some())
+        {
+            return Err(RerankError::InvalidRequest(
+                "logsumexp does not support d2q/symmetric direction, trace_ops, centroid_filter, flag_boosts or bootstrap"
+                    .into(),
+            ));
+        }
+        // Early exit bounds each query row by its max, which sum_all exceeds
+        if score_mode == ScoreMode::SumAll
+            && (options.effective_direction() != Direction::Q2d
+                || options.trace_ops
+                || options.centroid_filter.is_some()
+                || options.flag_boosts.is_some()
+                || options.bootstrap.is_some()
+                || options.early_exit)
+        {
+            return Err(RerankError::InvalidRequest(
+                "sum_all does not support d2q/symmetric direction, trace_ops, centroid_filter, flag_boosts, bootstrap or early_exit"
+                    .into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Normalize, validate and fill defaults for a rerank request, returning
+    /// the prune config to score it with
+    fn prepare(&self, payload: &mut RerankRequest) -> Result<PruneConfig, RerankError> {
+        payload.normalize_layout().map_err(|e| RerankError::InvalidRequest(format!("Invalid col_major input: {}", e)))?;
+        if payload.d_ids.is_some() {
+            return Err(RerankError::InvalidRequest("d_ids is only supported by /rerank".into()));
+        }
+        if payload.d_quant.is_some() {
+            return Err(RerankError::InvalidRequest("d_quant is only supported by /rerank".into()));
+        }
+        validate_tokens(&payload.q_tokens, &payload.d_tokens, payload.allow_empty_candidates)?;
+        if payload.options.sanitize {
+            let replaced = sanitize_non_finite(&mut payload.q_tokens, &mut payload.d_tokens);
+            payload.options.sanitized_values = Some(replaced);
+        }
+        check_finite(&payload.q_tokens, &payload.d_tokens)?;
+        self.apply_model(payload)?;
+        let prune = payload.prune.take().unwrap_or_default();
+        validate_prune(&prune)?;
+        validate_options(&payload.options, &payload.q_tokens, &payload.d_tokens)?;
+        self.apply_defaults(&mut payload.options)?;
+        Ok(prune)
+    }
+}
+
+/// Build the reranker HTTP router with default settings
+pub fn router() -> Router {
+    router_with_state(AppState::default())
+}
+
+/// Build the reranker HTTP router
+pub fn router_with_state(state: AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(handle_healthz))
+        .route("/rerank", post(handle_rerank))
+        .route("/rerank_batch", post(handle_rerank_batch))
+        .route("/rerank_progress", post(handle_rerank_progress))
+        .route("/compare", post(handle_compare))
+        .route("/contrastive", post(handle_contrastive))
+        .route("/explain", post(handle_explain))
+        .route("/cache/upload", post(handle_cache_upload))
+        .route("/corpus/upload", post(handle_corpus_upload))
+        .route("/corpus/stage", post(handle_corpus_stage))
+        .route("/corpus/validate_standby", post(handle_validate_standby))
+        .route("/corpus/promote", post(handle_promote_standby))
+        .route("/rerank_by_id", post(handle_rerank_by_id))
+        .route("/check_compat", post(handle_check_compat))
+        .route("/jobs", post(handle_submit_job))
+        .route("/jobs/:id", get(handle_job_status))
+        .route("/bench", get(handle_bench))
+        .route("/metrics", get(handle_metrics))
+        .route("/info", get(handle_info))
+        .route("/capabilities", get(handle_capabilities))
+        .route("/config/kernel", post(handle_set_kernel))
+        .layer(
+            ServiceBuilder::new()
+                .layer(CorsLayer::permissive())
+        )
+        .with_state(Arc::new(state))
+}
+
+/// Whether the client listed `media_type` in the Accept header
+fn accepts(headers: &HeaderMap, media_type: &str) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').any(|t| t.trim().starts_with(media_type)))
+        .unwrap_or(false)
+}
+
+/// Render a rerank response as `rank,doc_index,score` rows, plus a
+/// `doc_id` column when the returned documents' ids are given (best first)
+pub fn response_to_csv(response: &RerankResponse, doc_ids: Option<&[String]>) -> String {
+    let mut csv = String::from(if doc_ids.is_some() { "rank,doc_index,doc_id,score\n" } else { "rank,doc_index,score\n" });
+    for (i, ((rank, doc_index), score)) in response.ranks.iter().zip(&response.order).zip(&response.scores).enumerate() {
+        match doc_ids {
+            Some(ids) => {
+                let _ = writeln!(csv, "{},{},{},{}", rank, doc_index, csv_field(&ids[i]), score);
+            }
+            None => {
+                let _ = writeln!(csv, "{},{},{}", rank, doc_index, score);
+            }
+        }
+    }
+    csv
+}
+
+/// Quote a CSV field if it contains a delimiter, quote or line break
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
+
+/// Answer with a rerank response in the format the client `Accept`s: CSV,
+/// protobuf, or the JSON rendered by `json`. `doc_ids` are the returned
+/// documents' ids, best first, when the request named documents by id.
+fn negotiate_response(
+    headers: &HeaderMap,
+    response: RerankResponse,
+    doc_ids: Option<&[String]>,
+    json: impl FnOnce(RerankResponse) -> Result<Response, RerankError>,
+) -> Result<Response, RerankError> {
+    if accepts(headers, "text/csv") {
+        return Ok(([(header::CONTENT_TYPE, "text/csv")], response_to_csv(&response, doc_ids)).into_response());
+    }
+    if accepts(headers, PROTOBUF_CONTENT_TYPE) {
+        return Ok(([(header::CONTENT_TYPE, PROTOBUF_CONTENT_TYPE)], encode_response(&response, doc_ids)).into_response());
+    }
+    json(response)
+}
+
+/// JSON body whose token arrays may be half precision (see `embeddings`).
+/// Parsed in one pass, as with `Json`, then tokens are widened to f32.
+struct EmbeddingsJson<T>(T);
+
+/// Whether the request declares a JSON body, as `Json` requires
+fn json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence == "application/json" || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+#[axum::async_trait]
+impl<T: WireRequest, S: Send + Sync> FromRequest<S> for EmbeddingsJson<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !json_content_type(req.headers()) {
+            return Err(RerankError::Status(StatusCode::UNSUPPORTED_MEDIA_TYPE).into_response());
+        }
+        let bytes = Bytes::from_request(req, state).await.map_err(IntoResponse::into_response)?;
+        let Json(wire) = Json::<T::Wire>::from_bytes(&bytes).map_err(IntoResponse::into_response)?;
+        T::from_wire(wire).map(Self).map_err(|e| {
+            RerankError::InvalidRequest(format!("Invalid half-precision tokens: {}", e)).into_response()
+        })
+    }
+}
+
+/// Reject empty inputs and dimension mismatches between query and documents
+fn validate_tokens(q_tokens: &[Vec<f32>], d_tokens: &[Vec<Vec<f32>>], allow_empty_docs: bool) -> Result<(), RerankError> {
+    if q_tokens.is_empty() || (d_tokens.is_empty() && !allow_empty_docs) {
+        return Err(RerankError::EmptyInput("Empty query tokens or document tokens".into()));
+    }
+
+    if q_tokens[0].is_empty() {
+        return Err(RerankError::EmptyInput("Empty query token vectors".into()));
+    }
+
+    let expected_dim = q_tokens[0].len();
+    if let Some(i) = q_tokens.iter().position(|token| token.len() != expected_dim) {
+        return Err(RerankError::DimMismatch(format!(
+            "Dimension mismatch: query token {} has {} dims, expected {}",
+            i, q_tokens[i].len(), expected_dim
+        )));
+    }
+
+    // Validate all document tokens have same dimension
+    for (i, doc_tokens) in d_tokens.iter().enumerate() {
+        for (j, token) in doc_tokens.iter().enumerate() {
+            if token.len() != expected_dim {
+                return Err(RerankError::DimMismatch(format!(
+                    "Dimension mismatch: doc {} token {} has {} dims, expected {}",
+                    i, j, token.len(), expected_dim
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reject prune settings outside their documented ranges
+fn validate_prune(prune: &PruneConfig) -> Result<(), RerankError> {
+    if !PRUNE_METHODS.contains(&prune.method.as_str()) {
+        return Err(RerankError::InvalidPrune(format!("unknown prune method '{}', expected one of {:?}", prune.method, PRUNE_METHODS)));
+    }
+
+    if !(0.0..1.0).contains(&prune.token_dropout) {
+        return Err(RerankError::InvalidPrune(format!("token_dropout {} outside [0, 1)", prune.token_dropout)));
+    }
+
+    if !(0.0..=1.0).contains(&prune.q_min_ratio) {
+        return Err(RerankError::InvalidPrune(format!("q_min_ratio {} outside [0, 1]", prune.q_min_ratio)));
+    }
+
+    if prune.reservoir_sample == Some(0) {
+        return Err(RerankError::InvalidPrune("reservoir_sample must be at least 1".into()));
+    }
+
+    if let Some(ratio) = prune.d_max_ratio {
+        if !(ratio > 0.0 && ratio <= 1.0) {
+            return Err(RerankError::InvalidPrune(format!("d_max_ratio {} outside (0, 1]", ratio)));
+        }
+    }
+
+    if prune.hard_doc_token_cap == Some(0) {
+        return Err(RerankError::InvalidPrune("hard_doc_token_cap must be at least 1".into()));
+    }
+
+    Ok(())
+}
+
+/// Reject per-request options that don't line up with the documents
+fn validate_options(
+    options: &ScoreOptions,
+    q_tokens: &[Vec<f32>],
+    d_tokens: &[Vec<Vec<f32>>],
+) -> Result<(), RerankError> {
+    let n_docs = d_tokens.len();
+    // Directions that match document tokens against the query
+    let reverse_direction = options.effective_direction() != Direction::Q2d;
+    if let Some(confidences) = &options.confidences {
+        if confidences.len() != n_docs {
+            return Err(RerankError::InvalidRequest(format!("confidences has {} entries, expected {}", confidences.len(), n_docs)));
+        }
+        if let Some(bad) = confidences.iter().find(|c| !(0.0..=1.0).contains(*c)) {
+            return Err(RerankError::InvalidRequest(format!("confidence {} outside [0, 1]", bad)));
+        }
+    }
+
+    if let Some(q_idf) = &options.q_idf {
+        if q_idf.len() != q_tokens.len() {
+            return Err(RerankError::InvalidRequest(format!("q_idf has {} entries, expected {}", q_idf.len(), q_tokens.len())));
+        }
+        if let Some(bad) = q_idf.iter().find(|w| !w.is_finite() || **w < 0.0) {
+            return Err(RerankError::InvalidRequest(format!("q_idf weight {} must be finite and non-negative", bad)));
+        }
+    }
+
+    if let Some(eps) = options.norm_eps {
+        if !(0.0..=1.0).contains(&eps) {
+            return Err(RerankError::InvalidRequest(format!("norm_eps {} outside [0, 1]", eps)));
+        }
+    }
+
+    if let Some(n) = options.centroid_filter {
+        let (Some(q_centroids), Some(d_centroids)) = (&options.q_centroids, &options.d_centroids) else {
+            return Err(RerankError::InvalidRequest("centroid_filter requires q_centroids and d_centroids".into()));
+        };
+        if n == 0 || reverse_direction || options.early_exit || options.trace_ops {
+            return Err(RerankError::InvalidRequest("centroid_filter must be positive and excludes d2q/symmetric direction, early_exit and trace_ops".into()));
+        }
+        let aligned = q_centroids.len() == q_tokens.len()
+            && d_centroids.len() == n_docs
+            && d_centroids.iter().zip(d_tokens).all(|(ids, doc)| ids.len() == doc.len());
+        if !aligned {
+            return Err(RerankError::InvalidRequest("q_centroids/d_centroids do not line up with the tokens".into()));
+        }
+    }
+
+    if let Some(boosts) = &options.flag_boosts {
+        let Some(flags) = &options.d_token_flags else {
+            return Err(RerankError::InvalidRequest("flag_boosts requires d_token_flags".into()));
+        };
+        let aligned = flags.len() == n_docs && flags.iter().zip(d_tokens).all(|(flags, doc)| flags.len() == doc.len());
+        if !aligned {
+            return Err(RerankError::InvalidRequest("d_token_flags do not line up with the document tokens".into()));
+        }
+        if let Some(bad) = boosts.values().find(|b| !(b.is_finite() && **b >= 0.0)) {
+            return Err(RerankError::InvalidRequest(format!("flag boost {} must be finite and non-negative", bad)));
+        }
+        if reverse_direction
+            || options.early_exit
+            || options.trace_ops
+            || options.centroid_filter.is_some()
+            || options.projection.is_some()
+        {
+            return Err(RerankError::InvalidRequest("flag_boosts excludes d2q/symmetric direction, early_exit, trace_ops, centroid_filter and projection".into()));
+        }
+    }
+
+    if let Some(table) = &options.idf {
+        if let Some(bad) = table.values().find(|w| !w.is_finite() || **w < 0.0) {
+            return Err(RerankError::InvalidRequest(format!("idf weight {} must be finite and non-negative", bad)));
+        }
+    }
+    if options.q_idf.is_some() && options.q_token_ids.is_some() {
+        return Err(RerankError::InvalidRequest("q_idf and q_token_ids are alternatives; send one".into()));
+    }
+    let ids_aligned = options.q_token_ids.as_ref().is_none_or(|ids| ids.len() == q_tokens.len())
+        && options.d_token_ids.as_ref().is_none_or(|ids| {
+            ids.len() == n_docs && ids.iter().zip(d_tokens).all(|(ids, doc)| ids.len() == doc.len())
+        });
+    if !ids_aligned {
+        return Err(RerankError::InvalidRequest("q_token_ids/d_token_ids do not line up with the tokens".into()));
+    }
+
+    let keys_aligned = options.q_tiebreak_keys.as_ref().is_none_or(|keys| keys.len() == q_tokens.len())
+        && options.d_tiebreak_keys.as_ref().is_none_or(|keys| {
+            keys.len() == n_docs && keys.iter().zip(d_tokens).all(|(keys, doc)| keys.len() == doc.len())
+        });
+    if !keys_aligned {
+        return Err(RerankError::InvalidRequest("q_tiebreak_keys/d_tiebreak_keys do not line up with the tokens".into()));
+    }
+
+    if let Some(multiple) = options.flag_slow_docs {
+        if multiple <= 0.0 {
+            return Err(RerankError::InvalidRequest(format!("flag_slow_docs must be positive, got {}", multiple)));
+        }
+    }
+
+    if options.coverage_threshold.is_some() && !(0.0..=1.0).contains(&options.min_coverage) {
+        return Err(RerankError::InvalidRequest(format!("min_coverage {} outside [0, 1]", options.min_coverage)));
+    }
+
+    if let Some(mask) = &options.q_mask {
+        if mask.len() != q_tokens.len() {
+            return Err(RerankError::InvalidRequest(format!("q_mask has {} entries, expected {}", mask.len(), q_tokens.len())));
+        }
+        if !mask.contains(&true) {
+            return Err(RerankError::InvalidRequest("q_mask leaves no query token to score".into()));
+        }
+    }
+
+    if let Some(weights) = &options.q_weights {
+        if weights.len() != q_tokens.len() {
+            return Err(RerankError::InvalidRequest(format!("q_weights has {} entries, expected {}", weights.len(), q_tokens.len())));
+        }
+        if let Some(bad) = weights.iter().find(|w| !(w.is_finite() && **w >= 0.0)) {
+            return Err(RerankError::InvalidRequest(format!("q_weight {} must be finite and non-negative", bad)));
+        }
+        if options.early_exit || options.trace_ops {
+            return Err(RerankError::InvalidRequest("q_weights excludes early_exit and trace_ops".into()));
+        }
+    }
+
+    if options.cluster_scores {
+        let Some(cluster_ids) = &options.cluster_ids else {
+            return Err(RerankError::InvalidRequest("cluster_scores requires cluster_ids".into()));
+        };
+        if cluster_ids.len() != n_docs {
+            return Err(RerankError::InvalidRequest(format!("cluster_ids has {} entries, expected {}", cluster_ids.len(), n_docs)));
+        }
+        if options.low_memory || options.projection.is_some() {
+            return Err(RerankError::InvalidRequest("cluster_scores excludes low_memory and projection".into()));
+        }
+    }
+
+    if let Some(resamples) = options.bootstrap {
+        if resamples == 0 || resamples > BOOTSTRAP_MAX_RESAMPLES {
+            return Err(RerankError::InvalidRequest(format!(
+                "bootstrap must be between 1 and {}",
+                BOOTSTRAP_MAX_RESAMPLES
+            )));
+        }
+        if reverse_direction
+            || options.centroid_filter.is_some()
+            || options.flag_boosts.is_some()
+            || options.projection.is_some()
+        {
+            return Err(RerankError::InvalidRequest(
+                "bootstrap excludes d2q/symmetric direction, centroid_filter, flag_boosts and projection".into(),
+            ));
+        }
+    }
+
+    if let Some(calibration) = &options.calibration {
+        if !calibration.scale.is_finite() || !calibration.bias.is_finite() {
+            return Err(RerankError::InvalidRequest("calibration must be finite".into()));
+        }
+    }
+
+    if options.early_exit
+        && (!options.low_memory
+            || reverse_direction
+            || options.confidences.is_some()
+            || options.coverage_threshold.is_some())
+    {
+        return Err(RerankError::InvalidRequest("early_exit requires low_memory and excludes d2q/symmetric direction, confidences and coverage_threshold".into()));
+    }
+
+    if options.projection.is_some() != options.refine_topk.is_some() {
+        return Err(RerankError::InvalidRequest("projection and refine_topk must be given together".into()));
+    }
+
+    if let Some(candidates) = options.second_stage_candidates {
+        if candidates == 0 || options.low_memory || options.projection.is_some() {
+            return Err(RerankError::InvalidRequest("second_stage_candidates must be positive and excludes low_memory and projection".into()));
+        }
+    }
+
+    if let Some(projection) = &options.projection {
+        let dim = q_tokens[0].len();
+        let reduced_dim = projection.first().map_or(0, |row| row.len());
+        if projection.len() != dim || reduced_dim == 0 || projection.iter().any(|row| row.len() != reduced_dim) {
+            return Err(RerankError::InvalidRequest(format!("projection must be a {} x reduced_dim matrix", dim)));
+        }
+        if options.refine_topk.unwrap_or(0) <= options.offset {
+            return Err(RerankError::InvalidRequest("refine_topk must cover at least offset + 1 documents".into()));
+        }
+        if options.confidences.is_some() || options.centroid_filter.is_some() {
+            return Err(RerankError::InvalidRequest("projection does not support per-document confidences or centroid_filter".into()));
+        }
+    }
+
+    let norm_bounds = [options.expected_norm_min, options.expected_norm_max];
+    if norm_bounds.iter().flatten().any(|bound| !(bound.is_finite() && *bound >= 0.0)) {
+        return Err(RerankError::InvalidRequest("expected_norm_min/expected_norm_max must be finite and non-negative".into()));
+    }
+    if let [Some(min), Some(max)] = norm_bounds {
+        if min > max {
+            return Err(RerankError::InvalidRequest(format!("expected_norm_min {} exceeds expected_norm_max {}", min, max)));
+        }
+    }
+
+    if options.similarity.is_some()
+        && (options.coverage_threshold.is_some()
+            || options.trace_ops
+            || options.verify_lossless
+            || options.return_dominant_q_token
+            || options.mmr_sweep.is_some())
+    {
+        return Err(RerankError::InvalidRequest("similarity excludes coverage_threshold, trace_ops, verify_lossless, return_dominant_q_token and mmr_sweep".into()));
+    }
+    if options.similarity == Some(Similarity::Dot) && options.early_exit {
+        return Err(RerankError::InvalidRequest("early_exit bounds assume unit rows and does not support similarity 'dot'".into()));
+    }
+
+    if let Some(lambdas) = &options.mmr_sweep {
+        if lambdas.is_empty() || lambdas.iter().any(|lambda| !(0.0..=1.0).contains(lambda)) {
+            return Err(RerankError::InvalidRequest("mmr_sweep lambdas must be a non-empty list within [0, 1]".into()));
+        }
+    }
+
+    if let Some(temperature) = options.lse_temperature {
+        if !(temperature.is_finite() && temperature > 0.0) {
+            return Err(RerankError::InvalidRequest(format!("lse_temperature {} must be positive", temperature)));
+        }
+    }
+
+    if options.trace_ops && reverse_direction {
+        return Err(RerankError::InvalidRequest("trace_ops does not support d2q/symmetric direction".into()));
+    }
+
+    if options.trace_ops {
+        let longest_doc = d_tokens.iter().map(|doc| doc.len()).max().unwrap_or(0);
+        if trace_len(q_tokens.len(), longest_doc, options.relu_sim) > TRACE_MAX_OPS {
+            return Err(RerankError::InvalidRequest(format!("trace_ops input too large: {} query tokens x {} doc tokens", q_tokens.len(), longest_doc)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Request field names of the per-request options `options` sets
+fn set_options(options: &ScoreOptions) -> Vec<&'static str> {
+    [
+        ("bootstrap", options.bootstrap.is_some()),
+        ("calibration", options.calibration.is_some()),
+        ("centroid_filter", options.centroid_filter.is_some()),
+        ("check_prune_impact", options.check_prune_impact),
+        ("cluster_scores", options.cluster_scores),
+        ("confidences", options.confidences.is_some()),
+        ("contribution_fractions", options.contribution_fractions),
+        ("coverage_threshold", options.coverage_threshold.is_some()),
+        ("d_tiebreak_keys", options.d_tiebreak_keys.is_some()),
+        ("d_token_ids", options.d_token_ids.is_some()),
+        ("detect_degenerate", options.detect_degenerate),
+        ("direction", options.direction != Direction::Q2d),
+        ("early_exit", options.early_exit),
+        ("expected_norm_max", options.expected_norm_max.is_some()),
+        ("expected_norm_min", options.expected_norm_min.is_some()),
+        ("flag_boosts", options.flag_boosts.is_some()),
+        ("flag_slow_docs", options.flag_slow_docs.is_some()),
+        ("idf", options.idf.is_some()),
+        ("log_scores", options.log_scores),
+        ("low_memory", options.low_memory),
+        ("lse_temperature", options.lse_temperature.is_some()),
+        ("min_percentile_samples", options.min_percentile_samples.is_some()),
+        ("mmr_sweep", options.mmr_sweep.is_some()),
+        ("norm_eps", options.norm_eps.is_some()),
+        ("offset", options.offset > 0),
+        ("projection", options.projection.is_some()),
+        ("q_idf", options.q_idf.is_some()),
+        ("q_mask", options.q_mask.is_some()),
+        ("q_redundancy", options.q_redundancy),
+        ("q_tiebreak_keys", options.q_tiebreak_keys.is_some()),
+        ("q_token_ids", options.q_token_ids.is_some()),
+        ("q_weights", options.q_weights.is_some()),
+        ("refine_topk", options.refine_topk.is_some()),
+        ("relu_sim", options.relu_sim),
+        ("report_memory", options.report_memory),
+        ("result_hash", options.result_hash),
+        ("return_dominant_q_token", options.return_dominant_q_token),
+        ("return_idf", options.return_idf),
+        ("return_sim_range", options.return_sim_range),
+        ("second_stage_candidates", options.second_stage_candidates.is_some()),
+        ("similarity", options.similarity.is_some()),
+        ("symmetric", options.symmetric),
+        ("topks", options.topks.is_some()),
+        ("trace_ops", options.trace_ops),
+        ("verify_lossless", options.verify_lossless),
+    ]
+    .into_iter()
+    .filter_map(|(name, set)| set.then_some(name))
+    .collect()
+}
+
+/// Reject options a scoring path without the raw document tokens doesn't
+/// implement, rather than silently dropping them
+fn reject_unsupported(options: &ScoreOptions, source: &str, supported: &[&str]) -> Result<(), RerankError> {
+    let unsupported: Vec<&str> = set_options(options).into_iter().filter(|name| !supported.contains(name)).collect();
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    Err(RerankError::InvalidRequest(format!("{} does not support {}", source, unsupported.join(", "))))
+}
+
+/// Check `session_id` and `smoothing`. Sessions blend every document's
+/// score, so they exclude options that score only some documents.
+fn validate_session(payload: &RerankRequest) -> Result<(), RerankError> {
+    if let Some(smoothing) = payload.smoothing {
+        if payload.session_id.is_none() {
+            return Err(RerankError::InvalidRequest("smoothing requires session_id".into()));
+        }
+        if !(smoothing > 0.0 && smoothing <= 1.0) {
+            return Err(RerankError::InvalidRequest(format!("smoothing {} must be in (0, 1]", smoothing)));
+        }
+    }
+    let options = &payload.options;
+    if payload.session_id.is_some()
+        && (options.low_memory || options.refine_topk.is_some() || options.second_stage_candidates.is_some())
+    {
+        return Err(RerankError::InvalidRequest("session_id excludes low_memory, refine_topk and second_stage_candidates".into()));
+    }
+    Ok(())
+}
+
+async fn handle_rerank(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    EmbeddingsJson(payload): EmbeddingsJson<RerankRequest>,
+) -> Result<Response, RerankError> {
+    let start = std::time::Instant::now();
+    let response = rerank(&state, headers, payload).await;
+    let failed = response.as_ref().map_or(true, |r| !r.status().is_success());
+    state.metrics.observe_request(Endpoint::Rerank, start.elapsed(), failed);
+    response
+}
+
+async fn rerank(state: &AppState, headers: HeaderMap, mut payload: RerankRequest) -> Result<Response, RerankError> {
+    info!("Received rerank request: {} query tokens, {} documents, topk={}", 
+          payload.q_tokens.len(), payload.d_tokens.len(), payload.topk);
+
+    if let Some(d_ids) = payload.d_ids.take() {
+        return rerank_cached(state, &headers, payload, d_ids).await;
+    }
+    if let Some(d_quant) = payload.d_quant.take() {
+        return rerank_quantized(state, &headers, payload, d_quant).await;
+    }
+
+    let prune = state.prepare(&mut payload)?;
+    info!("SIGIR 2025: Lossless token pruning enabled (q_max={}, d_max={})", 
+          prune.q_max, prune.d_max);
+    validate_session(&payload)?;
+    let session = match payload.session_id.take() {
+        Some(id) => {
+            let smoothing = payload.smoothing.unwrap_or(DEFAULT_SMOOTHING);
+            let session = SessionPostScorer::new(&state.sessions, &id, payload.d_tokens.len(), smoothing);
+            Some(session.map_err(RerankError::InvalidRequest)?)
+        }
+        None => None,
+    };
+
+    let start_time = std::time::Instant::now();
+
+    // Perform reranking
+    let (prev_snapshot_id, snapshot) = (payload.prev_snapshot_id.take(), payload.snapshot);
+    payload.snapshot = false;
+    let n_docs = payload.d_tokens.len();
+    let job = Arc::new((payload, prune));
+    // Session requests update shared state, so each must run on its own
+    let key = (state.coalesce && session.is_none()).then(|| rerank_fingerprint(job.clone()));
+    let second_stage = state.second_stage.clone();
+    let score = move || {
+        run_blocking(move || {
+            let (payload, prune) = &*job;
+            match (session, payload.options.second_stage_candidates) {
+                (Some(session), _) => score_docs_with_options(
+                    &payload.q_tokens,
+                    &payload.d_tokens,
+                    payload.topk,
+                    prune,
+                    &payload.options,
+                    &session,
+                ),
+                (None, Some(candidates)) => score_docs_second_stage(
+                    &payload.q_tokens,
+                    &payload.d_tokens,
+                    payload.topk,
+                    prune,
+                    &payload.options,
+                    candidates,
+                    second_stage.as_deref().unwrap_or(&NoopSecondStage),
+                ),
+                (None, None) => score_docs_two_stage(&payload.q_tokens, &payload.d_tokens, payload.topk, prune, &payload.options),
+            }
+        })
+    };
+    let scored = match key {
+        Some(key) => state.coalescer.run(key, score).await,
+        None => score().await,
+    }?;
+    let output = match scored {
+        Ok(output) => output,
+        Err(e) => return Err(e.into()),
+    };
+    state.metrics.observe_scoring(Endpoint::Rerank, n_docs, &output.perf);
+
+    let total_time = start_time.elapsed().as_secs_f32() * 1000.0;
+    info!("Reranking completed in {:.2}ms, p50: {:?}ms, p95: {:?}ms", 
+          total_time, output.perf.per_doc_ms_p50, output.perf.per_doc_ms_p95);
+
+    let response = RerankResponse::from(output);
+
+    if let Some(prev_snapshot_id) = prev_snapshot_id {
+        let changes = state.snapshots.delta(&prev_snapshot_id, &response).ok_or_else(|| {
+            RerankError::NotFound(format!("Unknown snapshot '{}'", prev_snapshot_id))
+        })?;
+        let snapshot_id = state.snapshots.save(&response);
+        let delta = DeltaResponse { snapshot_id, prev_snapshot_id, changes, perf: response.perf };
+        return json_with_serialize_ms(&delta);
+    }
+
+    if snapshot {
+        let snapshot_id = state.snapshots.save(&response);
+        return json_with_serialize_ms(&SnapshotResponse { snapshot_id, response });
+    }
+
+    negotiate_response(&headers, response, None, |response| json_with_serialize_ms(&response))
+}
+
+/// Rerank documents from the document cache, named by `d_ids`
+async fn rerank_cached(
+    state: &AppState,
+    headers: &HeaderMap,
+    mut payload: RerankRequest,
+    d_ids: Vec<String>,
+) -> Result<Response, RerankError> {
+    if !payload.d_tokens.is_empty() || payload.snapshot || payload.prev_snapshot_id.is_some() || payload.session_id.is_some() {
+        return Err(RerankError::InvalidRequest("d_ids excludes d_tokens, snapshot, prev_snapshot_id and session_id".into()));
+    }
+    payload.normalize_layout().map_err(|e| RerankError::InvalidRequest(format!("Invalid col_major input: {}", e)))?;
+    if d_ids.is_empty() && !payload.allow_empty_candidates {
+        return Err(RerankError::EmptyInput("Empty query tokens or d_ids".into()));
+    }
+    // Cached documents were checked on upload
+    validate_tokens(&payload.q_tokens, &[], true)?;
+    if payload.options.sanitize {
+        payload.options.sanitized_values = Some(sanitize_non_finite(&mut payload.q_tokens, &mut []));
+    }
+    check_finite(&payload.q_tokens, &[])?;
+    state.apply_model(&mut payload)?;
+    reject_unsupported(&payload.options, "d_ids", CACHED_OPTIONS)?;
+    let prune = payload.prune.take().unwrap_or_default();
+    validate_prune(&prune)?;
+    validate_options(&payload.options, &payload.q_tokens, &[])?;
+    state.apply_defaults(&mut payload.options)?;
+
+    let docs = state.doc_cache.get(prune_key(&prune), &d_ids).map_err(|id| {
+        RerankError::NotFound(format!("Document '{}' is not cached for this prune config", id))
+    })?;
+    let n_docs = docs.len();
+    let scored = run_blocking(move || {
+        score_cached_docs(&payload.q_tokens, &docs, payload.topk, &prune, &payload.options)
+    })
+    .await?;
+    match scored {
+        Ok(output) => {
+            state.metrics.observe_scoring(Endpoint::Rerank, n_docs, &output.perf);
+            let response = RerankResponse::from(output);
+            let ids: Vec<String> = response.order.iter().map(|&idx| d_ids[idx].clone()).collect();
+            negotiate_response(headers, response, Some(&ids), |response| json_with_serialize_ms(&response))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Rerank int8 documents sent as `d_quant` and `d_scales`; see `quant`
+async fn rerank_quantized(
+    state: &AppState,
+    headers: &HeaderMap,
+    mut payload: RerankRequest,
+    d_quant: Vec<Vec<Vec<i8>>>,
+) -> Result<Response, RerankError> {
+    if !payload.d_tokens.is_empty() || payload.snapshot || payload.prev_snapshot_id.is_some() || payload.session_id.is_some() {
+        return Err(RerankError::InvalidRequest("d_quant excludes d_tokens, snapshot, prev_snapshot_id and session_id".into()));
+    }
+    if payload.layout != Layout::RowMajor {
+        return Err(RerankError::InvalidRequest("d_quant must use row_major layout".into()));
+    }
+    let d_scales = payload.d_scales.take().unwrap_or_default();
+    if d_scales.len() != d_quant.len() {
+        return Err(RerankError::InvalidRequest(format!("{} d_quant documents but {} d_scales", d_quant.len(), d_scales.len())));
+    }
+    if d_quant.is_empty() && !payload.allow_empty_candidates {
+        return Err(RerankError::EmptyInput("Empty query tokens or d_quant".into()));
+    }
+    // Document widths are checked against the query by `score_quantized_docs`
+    validate_tokens(&payload.q_tokens, &[], true)?;
+    if payload.options.sanitize {
+        payload.options.sanitized_values = Some(sanitize_non_finite(&mut payload.q_tokens, &mut []));
+    }
+    check_finite(&payload.q_tokens, &[])?;
+    let docs = d_quant
+        .iter()
+        .zip(&d_scales)
+        .enumerate()
+        .map(|(i, (rows, scales))| {
+            QuantizedRows::from_parts(rows, scales).map_err(|e| RerankError::InvalidRequest(format!("d_quant[{}]: {}", i, e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    state.apply_model(&mut payload)?;
+    reject_unsupported(&payload.options, "d_quant", QUANT_OPTIONS)?;
+    let prune = payload.prune.take().unwrap_or_default();
+    validate_prune(&prune)?;
+    if prune.method == QUERY_AFFINITY || prune.token_dropout > 0.0 || prune.reservoir_sample.is_some() {
+        return Err(RerankError::InvalidPrune("d_quant cannot use query_affinity, token_dropout or reservoir_sample".into()));
+    }
+    validate_options(&payload.options, &payload.q_tokens, &[])?;
+    state.apply_defaults(&mut payload.options)?;
+    let score_mode = payload.options.score_mode.unwrap_or_default();
+    if !matches!(score_mode, ScoreMode::MaxSim | ScoreMode::MeanMaxSim) || payload.options.effective_direction() != Direction::Q2d {
+        return Err(RerankError::InvalidRequest("d_quant supports only the maxsim and mean_maxsim score modes in the q2d direction".into()));
+    }
+
+    let n_docs = docs.len();
+    let scored = run_blocking(move || {
+        score_quantized_docs(&payload.q_tokens, &docs, payload.topk, &prune, &payload.options)
+    })
+    .await?;
+    match scored {
+        Ok(output) => {
+            state.metrics.observe_scoring(Endpoint::Rerank, n_docs, &output.perf);
+            negotiate_response(headers, RerankResponse::from(output), None, |response| json_with_serialize_ms(&response))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Several rerank requests answered in one round trip
+#[derive(Debug, Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+struct BatchRerankRequest<T = Vec<f32>> {
+    queries: Vec<RerankRequest<T>>,
+    /// Every query ranks the first query's documents. Later queries leave
+    /// `d_tokens` empty, so the documents are sent and validated once.
+    #[serde(default)]
+    shared_docs: bool,
+}
+
+impl WireRequest for BatchRerankRequest {
+    type Wire = BatchRerankRequest<WireToken>;
+
+    fn from_wire(wire: Self::Wire) -> Result<Self, String> {
+        let queries = wire
+            .queries
+            .into_iter()
+            .enumerate()
+            .map(|(i, query)| query.widen().map_err(|e| format!("queries[{}].{}", i, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { queries, shared_docs: wire.shared_docs })
+    }
+}
+
+/// Rerank several queries in parallel, answering in request order
+async fn handle_rerank_batch(
+    State(state): State<Arc<AppState>>,
+    EmbeddingsJson(payload): EmbeddingsJson<BatchRerankRequest>,
+) -> Result<Response, RerankError> {
+    info!("Received rerank batch: {} queries, shared_docs={}", payload.queries.len(), payload.shared_docs);
+
+    let mut queries = payload.queries;
+    let mut shared = Vec::new();
+    let mut work = Vec::with_capacity(queries.len());
+    for (i, query) in queries.iter_mut().enumerate() {
+        if query.snapshot || query.prev_snapshot_id.is_some() || query.session_id.is_some() {
+            return Err(RerankError::InvalidRequest("rerank_batch does not support snapshots or sessions".into()));
+        }
+        if payload.shared_docs && i > 0 {
+            if !query.d_tokens.is_empty() || query.layout != Layout::RowMajor {
+                return Err(RerankError::InvalidRequest(format!("shared_docs query {} must send no d_tokens and use row_major layout", i)));
+            }
+            // Validate against the shared documents without copying them
+            std::mem::swap(&mut query.d_tokens, &mut shared);
+            let prune = state.prepare(query);
+            std::mem::swap(&mut query.d_tokens, &mut shared);
+            work.push(prune?);
+        } else {
+            work.push(state.prepare(query)?);
+            if payload.shared_docs {
+                shared = std::mem::take(&mut query.d_tokens);
+            }
+        }
+    }
+
+    let scored = run_blocking(move || {
+        use rayon::prelude::*;
+        queries
+            .par_iter()
+            .zip(&work)
+            .map(|(query, prune)| {
+                let d_tokens = if payload.shared_docs { &shared } else { &query.d_tokens };
+                score_docs_two_stage(&query.q_tokens, d_tokens, query.topk, prune, &query.options)
+                    .map(RerankResponse::from)
+            })
+            .collect::<Result<Vec<_>, ScoreError>>()
+    })
+    .await?;
+    match scored {
+        Ok(responses) => Ok(Json(responses).into_response()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Request metrics in the Prometheus text format
+async fn handle_metrics(State(state): State<Arc<AppState>>) -> Response {
+    ([(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)], state.metrics.render()).into_response()
+}
+
+/// Liveness probe; answers even while scoring saturates the CPU
+async fn handle_healthz() -> &'static str {
+    "ok"
+}
+
+/// Run CPU-bound scoring on the blocking pool so the async workers stay
+/// free for I/O such as `/healthz`
+async fn run_blocking<T, F>(work: F) -> Result<T, StatusCode>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(work).await.map_err(|e| {
+        error!("Scoring task failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Why a request was rejected. Answered as `{"error": <class>, "detail": <message>}`
+/// so integrators can tell the failure classes apart; scoring failures also
+/// carry the `ScoreError` fields.
+#[derive(Debug)]
+pub enum RerankError {
+    /// No query tokens, no documents, or zero-width token vectors
+    EmptyInput(String),
+    /// Token widths disagree with each other or with the named model
+    DimMismatch(String),
+    /// Prune settings outside their documented ranges
+    InvalidPrune(String),
+    /// Any other field or option the request got wrong
+    InvalidRequest(String),
+    /// A snapshot, cached document or corpus id the server doesn't hold
+    NotFound(String),
+    /// Scoring could not start, e.g. nothing was left after pruning
+    Score(ScoreError),
+    /// Failure with nothing to add beyond its status, e.g. a panicked task
+    Status(StatusCode),
+}
+
+impl RerankError {
+    /// Failure class reported in the `error` field
+    pub fn class(&self) -> &'static str {
+        match self {
+            RerankError::EmptyInput(_) => "empty_input",
+            RerankError::DimMismatch(_) | RerankError::Score(ScoreError::DimMismatch { .. }) => "dim_mismatch",
+            RerankError::InvalidPrune(_) => "invalid_prune_config",
+            RerankError::InvalidRequest(_) | RerankError::Score(ScoreError::MisalignedOption { .. }) => "invalid_request",
+            RerankError::NotFound(_) => "not_found",
+            RerankError::Score(ScoreError::EmptyQuery | ScoreError::EmptyDocument { .. }) => "empty_after_prune",
+            RerankError::Score(ScoreError::NormOutOfRange { .. }) => "norm_out_of_range",
+            RerankError::Score(ScoreError::NonFiniteValue { .. }) => "non_finite_input",
+            RerankError::Status(StatusCode::NOT_FOUND) => "not_found",
+            RerankError::Status(StatusCode::CONFLICT) => "conflict",
+            RerankError::Status(StatusCode::PAYLOAD_TOO_LARGE) => "payload_too_large",
+            RerankError::Status(StatusCode::UNSUPPORTED_MEDIA_TYPE) => "unsupported_media_type",
+            RerankError::Status(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RerankError::NotFound(_) => StatusCode::NOT_FOUND,
+            RerankError::Status(status) => *status,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl std::fmt::Display for RerankError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RerankError::EmptyInput(detail)
+            | RerankError::DimMismatch(detail)
+            | RerankError::InvalidPrune(detail)
+            | RerankError::InvalidRequest(detail)
+            | RerankError::NotFound(detail) => f.write_str(detail),
+            RerankError::Score(e) => e.fmt(f),
+            RerankError::Status(status) => status.fmt(f),
+        }
+    }
+}
+
+impl From<ScoreError> for RerankError {
+    fn from(e: ScoreError) -> Self {
+        RerankError::Score(e)
+    }
+}
+
+impl From<StatusCode> for RerankError {
+    fn from(status: StatusCode) -> Self {
+        RerankError::Status(status)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    detail: String,
+    #[serde(flatten)]
+    score: Option<&'a ScoreError>,
+}
+
+impl IntoResponse for RerankError {
+    fn into_response(self) -> Response {
+        // Helpers returning a bare status have already logged the cause
+        if !matches!(self, RerankError::Status(_)) {
+            error!("Request rejected: {}", self);
+        }
+        let score = match &self {
+            RerankError::Score(e) => Some(e),
+            _ => None,
+        };
+        let body = ErrorBody { error: self.class(), detail: self.to_string(), score };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+/// Serialize a response to JSON, adding a top-level `serialize_ms` with the
+/// time spent encoding it. `PerfStats` only covers scoring; for a large top-K
+/// (or options like `topks` that repeat the ranking) serialization can
+/// dominate end-to-end latency. The field is spliced in after timing, so it
+/// excludes its own few bytes.
+fn json_with_serialize_ms<T: serde::Serialize>(value: &T) -> Result<Response, RerankError> {
+    let start = std::time::Instant::now();
+    let mut body = serde_json::to_vec(value).map_err(|e| {
+        error!("Failed to serialize response: {}", e);
+        RerankError::Status(StatusCode::INTERNAL_SERVER_ERROR)
+    })?;
+    let serialize_ms = start.elapsed().as_secs_f32() * 1000.0;
+
+    // Replace the closing brace of the top-level object
+    body.pop();
+    body.extend_from_slice(format!(",\"serialize_ms\":{}}}", serialize_ms).as_bytes());
+    Ok(([(header::CONTENT_TYPE, "application/json")], body).into_response())
+}
+
+/// Tenant named by the `x-tenant-id` header and/or the request body
+fn request_tenant(headers: &HeaderMap, field: Option<&str>) -> Result<String, RerankError> {
+    let header = headers.get(TENANT_HEADER).and_then(|v| v.to_str().ok());
+    resolve_tenant(header, field).map_err(RerankError::InvalidRequest)
+}
+
+/// Prune, normalize and cache documents for `/rerank` requests sending `d_ids`
+async fn handle_cache_upload(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<CacheUploadRequest>,
+) -> Result<Json<CacheUploadResponse>, RerankError> {
+    if payload.doc_ids.len() != payload.d_tokens.len() {
+        return Err(RerankError::InvalidRequest(format!("{} doc_ids for {} documents", payload.doc_ids.len(), payload.d_tokens.len())));
+    }
+    if let Some(i) = payload.d_tokens.iter().position(|doc| doc.is_empty()) {
+        return Err(RerankError::EmptyInput(format!("Document {} has no tokens", i)));
+    }
+    if let Some(first) = payload.d_tokens.first() {
+        validate_tokens(first, &payload.d_tokens, false)?;
+    }
+    check_finite(&[], &payload.d_tokens)?;
+    let prune = payload.prune.unwrap_or_default();
+    validate_prune(&prune)?;
+    if prune.method == QUERY_AFFINITY || prune.token_dropout > 0.0 || prune.reservoir_sample.is_some() {
+        return Err(RerankError::InvalidPrune("Cached documents cannot use query_affinity, token_dropout or reservoir_sample".into()));
+    }
+
+    let key = prune_key(&prune);
+    let (doc_ids, d_tokens) = (payload.doc_ids, payload.d_tokens);
+    let docs = run_blocking(move || {
+        use rayon::prelude::*;
+        let prepared: Vec<_> = d_tokens.par_iter().map(|doc| prepare_doc(doc, &prune)).collect();
+        doc_ids.into_iter().zip(prepared).collect::<Vec<_>>()
+    })
+    .await?;
+    let (stored, evicted) = state.doc_cache.insert(key, docs);
+    let cached = state.doc_cache.len();
+    info!("Document cache upload: {} stored, {} evicted, {} cached", stored, evicted, cached);
+    Ok(Json(CacheUploadResponse { stored, evicted, cached }))
+}
+
+async fn handle_corpus_upload(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<UploadRequest>,
+) -> Result<Json<UploadResponse>, RerankError> {
+    let tenant = request_tenant(&headers, payload.tenant_id.as_deref())?;
+    let stored = payload.docs.len();
+    let total = state.corpus.insert(&tenant, payload.docs).map_err(|e| match e {
+        UploadError::TenantFull { limit, requested } => {
+            error!("Tenant '{}' upload would hold {} documents, limit {}", tenant, requested, limit);
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+    })?;
+    info!("Corpus upload for tenant '{}': {} documents stored, {} total", tenant, stored, total);
+    Ok(Json(UploadResponse { stored, total }))
+}
+
+/// Load documents into the standby corpus; the active corpus keeps serving
+async fn handle_corpus_stage(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<UploadRequest>,
+) -> Result<Json<UploadResponse>, RerankError> {
+    let tenant = request_tenant(&headers, payload.tenant_id.as_deref())?;
+    let stored = payload.docs.len();
+    let total = state.corpus.stage(&tenant, payload.docs).map_err(|e| match e {
+        UploadError::TenantFull { limit, requested } => {
+            error!("Tenant '{}' standby would hold {} documents, limit {}", tenant, requested, limit);
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+    })?;
+    info!("Corpus stage for tenant '{}': {} documents staged, {} total", tenant, stored, total);
+    Ok(Json(UploadResponse { stored, total }))
+}
+
+async fn handle_validate_standby(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<ValidateStandbyRequest>,
+) -> Result<Json<StandbyReport>, RerankError> {
+    let report = state.corpus.validate_standby(&payload).ok_or_else(|| {
+        RerankError::NotFound("No standby corpus to validate".into())
+    })?;
+    if report.valid {
+        info!("Standby corpus validated: {} documents", report.docs);
+    } else {
+        warn!("Standby corpus failed validation: {}", report.problems.join("; "));
+    }
+    Ok(Json(report))
+}
+
+#[derive(Debug, serde::Serialize)]
+struct PromoteResponse {
+    /// Documents in the newly active corpus
+    docs: usize,
+}
+
+/// Swap the validated standby in as the active corpus
+async fn handle_promote_standby(State(state): State<Arc<AppState>>) -> Result<Json<PromoteResponse>, RerankError> {
+    let docs = state.corpus.promote().map_err(|e| {
+        error!("Cannot promote standby corpus: {:?}", e);
+        match e {
+            PromoteError::NoStandby => StatusCode::NOT_FOUND,
+            PromoteError::NotValidated => StatusCode::CONFLICT,
+        }
+    })?;
+    info!("Promoted standby corpus: {} documents active", docs);
+    Ok(Json(PromoteResponse { docs }))
+}
+
+/// Query shape to check against a model and tenant corpus before a job
+#[derive(Debug, Deserialize)]
+struct CheckCompatRequest {
+    /// Query token dimension
+    dim: usize,
+    #[serde(default)]
+    model: Option<String>,
+    /// Tenant namespace; may also be sent as the `x-tenant-id` header
+    #[serde(default)]
+    tenant_id: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct CheckCompatResponse {
+    compatible: bool,
+    /// The named model's dimension, or else the corpus's when it has one
+    expected_dim: Option<usize>,
+    /// Distinct token dimensions in the tenant's corpus
+    corpus_dims: Vec<usize>,
+    /// Token encodings requests may use; all are scored as f32
+    dtypes: &'static [&'static str],
+    problems: Vec<String>,
+}
+
+/// Dry run: check a query dimension against the model and corpus without scoring
+async fn handle_check_compat(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CheckCompatRequest>,
+) -> Result<Json<CheckCompatResponse>, RerankError> {
+    let tenant = request_tenant(&headers, payload.tenant_id.as_deref())?;
+    let mut problems = Vec::new();
+    if payload.dim == 0 {
+        problems.push("dim must be positive".to_string());
+    }
+    let model_dim = match &payload.model {
+        Some(name) => match state.models.get(name) {
+            Some(model) => Some(model.dim),
+            None => {
+                problems.push(format!("unknown model '{}'", name));
+                None
+            }
+        },
+        None => None,
+    };
+    if let Some(model_dim) = model_dim.filter(|&model_dim| model_dim != payload.dim) {
+        problems.push(format!("model expects {} dims, got {}", model_dim, payload.dim));
+    }
+    let corpus_dims = state.corpus.tenant_dims(&tenant);
+    if let Some(&other) = corpus_dims.iter().find(|&&dim| dim != payload.dim) {
+        problems.push(format!("tenant '{}' corpus holds {}-dim tokens, got {}", tenant, other, payload.dim));
+    }
+
+    let expected_dim = model_dim.or(match corpus_dims.as_slice() {
+        [dim] => Some(*dim),
+        _ => None,
+    });
+    info!("Compatibility check for tenant '{}': dim {}, {} problems", tenant, payload.dim, problems.len());
+    Ok(Json(CheckCompatResponse { compatible: problems.is_empty(), expected_dim, corpus_dims, dtypes: DTYPES, problems }))
+}
+
+/// Rerank corpus documents by id; see `RerankByIdRequest::dedup_candidates`
+/// for how repeated ids are handled
+async fn handle_rerank_by_id(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<RerankByIdRequest>,
+) -> Result<Response, RerankError> {
+    let tenant = request_tenant(&headers, payload.tenant_id.as_deref())?;
+    info!("Received rerank_by_id request for tenant '{}': {} query tokens, {} candidates, topk={}",
+          tenant, payload.q_tokens.len(), payload.candidate_ids.len(), payload.topk);
+
+    let (ids, dropped) = dedup_ids(&payload.candidate_ids);
+    if dropped > 0 {
+        if !payload.dedup_candidates {
+            return Err(RerankError::InvalidRequest(format!("candidate_ids contains {} duplicates", dropped)));
+        }
+        warn!("Dropped {} duplicate candidate ids", dropped);
+    }
+
+    let d_tokens = state.corpus.fetch(&tenant, &ids).map_err(|id| {
+        RerankError::NotFound(format!("Unknown candidate id {} for tenant '{}'", id, tenant))
+    })?;
+    let mut request = payload.into_rerank(d_tokens);
+    let prune = state.prepare(&mut request)?;
+
+    let scored = run_blocking(move || {
+        score_docs_with_options(
+            &request.q_tokens,
+            &request.d_tokens,
+            request.topk,
+            &prune,
+            &request.options,
+            &NoopPostScorer,
+        )
+    })
+    .await?;
+    let output = match scored {
+        Ok(output) => output,
+        Err(e) => return Err(e.into()),
+    };
+    let response = RerankResponse::from(output);
+    let ids: Vec<u64> = response.order.iter().map(|&idx| ids[idx]).collect();
+    let doc_ids: Vec<String> = ids.iter().map(u64::to_string).collect();
+    negotiate_response(&headers, response, Some(&doc_ids), |response| {
+        Ok(Json(RerankByIdResponse { ids, response }).into_response())
+    })
+}
+
+/// How often `/rerank_progress` reports the scored-document count
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(serde::Serialize)]
+struct ProgressEvent {
+    scored: usize,
+    total: usize,
+}
+
+/// Rerank while streaming `progress` events, then a final `result` event
+async fn handle_rerank_progress(
+    State(state): State<Arc<AppState>>,
+    EmbeddingsJson(mut payload): EmbeddingsJson<RerankRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, RerankError> {
+    info!("Received streaming rerank request: {} query tokens, {} documents, topk={}",
+          payload.q_tokens.len(), payload.d_tokens.len(), payload.topk);
+
+    let prune = state.prepare(&mut payload)?;
+
+    let total = payload.d_tokens.len();
+    let scored = Arc::new(AtomicUsize::new(0));
+    payload.options.progress = Some(Progress(scored.clone()));
+
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let progress = |scored: usize| {
+            Event::default().event("progress").json_data(ProgressEvent { scored, total })
+        };
+
+        let mut task = tokio::task::spawn_blocking(move || {
+            score_docs_with_options(
+                &payload.q_tokens,
+                &payload.d_tokens,
+                payload.topk,
+                &prune,
+                &payload.options,
+                &NoopPostScorer,
+            )
+        });
+
+        let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+        let result = loop {
+            tokio::select! {
+                result = &mut task => break result,
+                _ = ticker.tick() => {
+                    if tx.send(progress(scored.load(Ordering::Relaxed))).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        };
+
+        let _ = tx.send(progress(scored.load(Ordering::Relaxed))).await;
+        let event = match result {
+            Ok(Ok(output)) => Event::default().event("result").json_data(RerankResponse::from(output)),
+            Ok(Err(e)) => {
+                error!("Streaming rerank rejected: {}", e);
+                Ok(Event::default().event("error").data(e.to_string()))
+            }
+            Err(e) => {
+                error!("Streaming rerank failed: {}", e);
+                Ok(Event::default().event("error").data("rerank failed"))
+            }
+        };
+        let _ = tx.send(event).await;
+    });
+
+    Ok(Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default()))
+}
+
+async fn handle_compare(
+    State(state): State<Arc<AppState>>,
+    Json(mut payload): Json<CompareRequest>,
+) -> Result<Response, RerankError> {
+    info!("Received compare request: {} query tokens, {} documents, topk={}",
+          payload.q_tokens.len(), payload.d_tokens.len(), payload.topk);
+
+    validate_tokens(&payload.q_tokens, &payload.d_tokens, false)?;
+    validate_prune(&payload.a.prune)?;
+    validate_prune(&payload.b.prune)?;
+    validate_options(&payload.a.options, &payload.q_tokens, &payload.d_tokens)?;
+    validate_options(&payload.b.options, &payload.q_tokens, &payload.d_tokens)?;
+    state.apply_defaults(&mut payload.a.options)?;
+    state.apply_defaults(&mut payload.b.options)?;
+
+    let response = match run_blocking(move || compare_configs(&payload)).await? {
+        Ok(response) => response,
+        Err(e) => return Err(e.into()),
+    };
+    info!("Compare completed: kendall_tau={:.3}, topk_overlap={:.3}",
+          response.kendall_tau, response.topk_overlap);
+
+    Ok(Json(response).into_response())
+}
+
+/// Score each query against its positive plus the shared in-batch negatives
+async fn handle_contrastive(
+    Json(mut payload): Json<ContrastiveRequest>,
+) -> Result<Response, RerankError> {
+    info!("Received contrastive request: {} queries, {} negatives",
+          payload.queries.len(), payload.negatives.len());
+
+    if payload.queries.len() != payload.positives.len() {
+        return Err(RerankError::InvalidRequest(format!("{} queries but {} positives", payload.queries.len(), payload.positives.len())));
+    }
+    if !(payload.temperature.is_finite() && payload.temperature > 0.0) {
+        return Err(RerankError::InvalidRequest(format!("temperature {} must be positive", payload.temperature)));
+    }
+    for (query, positive) in payload.queries.iter().zip(&payload.positives) {
+        validate_tokens(query, std::slice::from_ref(positive), false)?;
+        if !payload.negatives.is_empty() {
+            validate_tokens(query, &payload.negatives, false)?;
+        }
+    }
+    let prune = payload.prune.take().unwrap_or_default();
+    validate_prune(&prune)?;
+
+    let response = match run_blocking(move || score_contrastive(&payload, &prune)).await? {
+        Ok(response) => response,
+        Err(e) => return Err(e.into()),
+    };
+    info!("Contrastive completed: mrr={:.3}", response.mean_reciprocal_rank);
+    Ok(Json(response).into_response())
+}
+
+/// Align one query with one document, reporting each query token's best match
+async fn handle_explain(Json(mut payload): Json<ExplainRequest>) -> Result<Json<ExplainResponse>, RerankError> {
+    info!("Received explain request: {} query tokens, {} document tokens",
+          payload.q_tokens.len(), payload.d_tokens.len());
+
+    validate_tokens(&payload.q_tokens, std::slice::from_ref(&payload.d_tokens), false)?;
+    check_finite(&payload.q_tokens, std::slice::from_ref(&payload.d_tokens))?;
+    let prune = payload.prune.take().unwrap_or_default();
+    validate_prune(&prune)?;
+    if let Some(stage) = unexplainable_prune(&prune) {
+        return Err(RerankError::InvalidPrune(format!("{} can't be explained token by token", stage)));
+    }
+
+    let response = run_blocking(move || explain_pair(&payload, &prune)).await??;
+    info!("Explain completed: score={:.4}, {} query tokens matched", response.score, response.matches.len());
+    Ok(Json(response))
+}
+
+#[derive(serde::Serialize)]
+struct JobSubmitted {
+    job_id: String,
+}
+
+/// Validate a batch up front, then score it in the background
+async fn handle_submit_job(
+    State(state): State<Arc<AppState>>,
+    Json(mut payload): Json<JobRequest>,
+) -> Result<Json<JobSubmitted>, RerankError> {
+    info!("Received batch job: {} requests", payload.requests.len());
+
+    let mut work = Vec::with_capacity(payload.requests.len());
+    for mut request in payload.requests.drain(..) {
+        let prune = state.prepare(&mut request)?;
+        work.push((request, prune));
+    }
+
+    let job_id = state.jobs.create();
+    let jobs = state.jobs.clone();
+    let id = job_id.clone();
+    tokio::spawn(async move {
+        jobs.set(&id, JobState::Running);
+        let task_jobs = jobs.clone();
+        let task_id = id.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let results: Result<Vec<RerankResponse>, ScoreError> = work
+                .iter()
+                .map(|(request, prune)| {
+                    score_docs_with_options(
+                        &request.q_tokens,
+                        &request.d_tokens,
+                        request.topk,
+                        prune,
+                        &request.options,
+                        &NoopPostScorer,
+                    )
+                    .map(RerankResponse::from)
+                })
+                .collect();
+            match results {
+                Ok(results) => task_jobs.finish(&task_id, &results),
+                Err(e) => task_jobs.set(&task_id, JobState::Failed { error: e.to_string() }),
+            }
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Batch job {} failed: {}", id, e);
+            jobs.set(&id, JobState::Failed { error: "rerank failed".to_string() });
+        }
+    });
+
+    info!("Batch job {} queued", job_id);
+    Ok(Json(JobSubmitted { job_id }))
+}
+
+#[derive(serde::Serialize)]
+struct JobStatusResponse {
+    job_id: String,
+    #[serde(flatten)]
+    state: JobState,
+    /// One rerank response per submitted request, once the job is done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results: Option<serde_json::Value>,
+}
+
+async fn handle_job_status(
+    State(state): State<Arc<AppState>>,
+    Path(job_id): Path<String>,
+) -> Result<Json<JobStatusResponse>, RerankError> {
+    let job_state = state.jobs.get(&job_id).ok_or_else(|| RerankError::NotFound(format!("Unknown job '{}'", job_id)))?;
+    let results = match job_state {
+        JobState::Done => Some(state.jobs.read_results(&job_id).map_err(|e| {
+            error!("Failed to read results for job {}: {}", job_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?),
+        _ => None,
+    };
+    Ok(Json(JobStatusResponse { job_id, state: job_state, results }))
+}
+
+#[derive(serde::Serialize)]
+struct InfoResponse {
+    kernel: &'static str,
+    cpu_flags: &'static str,
+    threads: usize,
+    default_score_mode: ScoreMode,
+}
+
+/// Report the active kernel and server settings
+async fn handle_info(State(state): State<Arc<AppState>>) -> Json<InfoResponse> {
+    Json(InfoResponse {
+        kernel: kernel_name(),
+        cpu_flags: CpuCaps::detect().label(),
+        threads: rayon::current_num_threads(),
+        default_score_mode: state.default_score_mode,
+    })
+}
+
+/// Token encodings accepted in request bodies (see `embeddings`)
+pub const DTYPES: &[&str] = &["f32", "f16", "bf16"];
+
+/// Optional request features this build understands
+pub const FEATURES: &[&str] = &[
+    "adapt_q_to_docs",
+    "batch",
+    "bootstrap",
+    "calibration",
+    "centroid_filter",
+    "check_compat",
+    "cluster_scores",
+    "compare",
+    "contrastive",
+    "contribution_fractions",
+    "corpus",
+    "corpus_standby",
+    "coverage",
+    "csv",
+    "direction",
+    "doc_cache",
+    "dominant_q_token",
+    "early_exit",
+    "empty_candidates",
+    "explain",
+    "flag_boosts",
+    "half_precision",
+    "idf_table",
+    "int8_docs",
+    "jobs",
+    "log_scores",
+    "low_memory",
+    "metrics",
+    "mmr_sweep",
+    "models",
+    "norm_check",
+    "pagination",
+    "progress_sse",
+    "projection",
+    "protobuf",
+    "prune_impact",
+    "q_idf",
+    "q_mask",
+    "q_weights",
+    "relu_sim",
+    "result_hash",
+    "sanitize",
+    "second_stage",
+    "seeded_bench",
+    "sessions",
+    "sim_range",
+    "similarity",
+    "snapshots",
+    "symmetric",
+    "tenants",
+    "tiebreak_keys",
+    "topks",
+];
+
+#[derive(serde::Serialize)]
+struct CapabilitiesResponse {
+    version: &'static str,
+    score_modes: Vec<&'static str>,
+    prune_methods: Vec<&'static str>,
+    dtypes: Vec<&'static str>,
+    layouts: Vec<&'static str>,
+    /// Kernels `/config/kernel` accepts on this machine
+    kernels: Vec<&'static str>,
+    features: Vec<&'static str>,
+}
+
+/// Describe what this server build supports so clients can adapt
+async fn handle_capabilities() -> Json<CapabilitiesResponse> {
+    let caps = CpuCaps::detect();
+    Json(CapabilitiesResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        score_modes: ScoreMode::ALL.iter().map(|mode| mode.as_str()).collect(),
+        prune_methods: PRUNE_METHODS.to_vec(),
+        dtypes: DTYPES.to_vec(),
+        layouts: vec!["row_major", "col_major"],
+        kernels: [KernelKind::Scalar, KernelKind::Avx2, KernelKind::Avx512]
+            .into_iter()
+            .filter(|kind| kind.supported(caps))
+            .map(|kind| kind.name())
+            .collect(),
+        features: FEATURES.to_vec(),
+    })
+}
+
+#[derive(Deserialize, serde::Serialize)]
+struct KernelConfig {
+    kernel: String,
+}
+
+/// Admin: force the dot-product kernel for all subsequent scoring
+async fn handle_set_kernel(Json(payload): Json<KernelConfig>) -> Result<Json<KernelConfig>, RerankError> {
+    let kind: KernelKind = payload.kernel.parse().map_err(RerankError::InvalidRequest)?;
+    set_kernel(kind).map_err(RerankError::InvalidRequest)?;
+    info!("Dot-product kernel switched to {}", kind.name());
+    Ok(Json(KernelConfig { kernel: kind.name().to_string() }))
+}
+
+#[derive(Deserialize)]
+struct BenchParams {
+    n_docs: Option<usize>,
+    td: Option<usize>,
+    d: Option<usize>,
+    prune: Option<String>,
+    /// Seed for the generated matrices, so runs can score the same workload
+    seed: Option<u64>,
+    /// Also report per-document averages of each scoring phase
+    #[serde(default)]
+    detailed: bool,
+}
+
+/// Phase wall times averaged over the benchmark's documents
+#[derive(serde::Serialize)]
+struct BenchPhases {
+    /// Choosing each document's kept tokens
+    prune_ms: f32,
+    /// Packing and L2-normalizing the kept rows
+    normalize_ms: f32,
+    /// The MaxSim dot-product loop
+    score_ms: f32,
+    /// `total_ms / n_docs`; the phases account for nearly all of it
+    per_doc_ms: f32,
+}
+
+#[derive(serde::Serialize)]
+struct BenchResponse {
+    n_docs: usize,
+    td: usize,
+    d: usize,
+    p50_ms: f32,
+    p95_ms: f32,
+    threads: usize,
+    cpu_flags: String,
+    /// Seed the matrices were generated from; pass it back to repeat the run
+    seed: u64,
+    /// `result_hash` of the ranking, equal across runs with the same seed
+    result_hash: String,
+    /// Wall time of the scoring call, all documents included
+    total_ms: f32,
+    /// `n_docs` over `total_ms`
+    docs_per_sec: f32,
+    /// Queries per second, counting the run as one query
+    qps: f32,
+    /// Set when `detailed` was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phases: Option<BenchPhases>,
+}
+
+async fn handle_bench(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BenchParams>,
+) -> Result<Json<BenchResponse>, RerankError> {
+    let start = std::time::Instant::now();
+    let bench_state = state.clone();
+    let response = match run_blocking(move || run_bench(&bench_state, params)).await {
+        Ok(response) => response,
+        Err(status) => Err(status.into()),
+    };
+    state.metrics.observe_request(Endpoint::Bench, start.elapsed(), response.is_err());
+    response
+}
+
+fn run_bench(state: &AppState, params: BenchParams) -> Result<Json<BenchResponse>, RerankError> {
+    let n_docs = params.n_docs.unwrap_or(100);
+    let td = params.td.unwrap_or(64);
+    let d = params.d.unwrap_or(128);
+    let prune_setting = params.prune.unwrap_or_else(|| "16/64".to_string());
+    
+    info!("Running microbench: n_docs={}, td={}, d={}, prune={}", n_docs, td, d, prune_setting);
+    
+    // Parse prune setting
+    let (q_max, d_max) = if prune_setting == "none" {
+        (td, d)
+    } else if prune_setting.contains("/") {
+        let parts: Vec<&str> = prune_setting.split("/").collect();
+        if parts.len() == 2 {
+            (parts[0].parse().unwrap_or(td), parts[1].parse().unwrap_or(d))
+        } else {
+            (td, d)
+        }
+    } else {
+        (td, d)
+    };
+    
+    // Generate random unit-norm matrices
+    let seed = params.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    let mut rng = StdRng::seed_from_u64(seed);
+    
+    // Generate query tokens
+    let mut q_tokens = Vec::new();
+    for _ in 0..td {
+        let mut token = vec![0.0; d];
+        for v in token.iter_mut() {
+            *v = rng.gen_range(-1.0..1.0);
+        }
+        // Normalize to unit length
+        let norm: f32 = token.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            for v in token.iter_mut() {
+                *v /= norm;
+            }
+        }
+        q_tokens.push(token);
+    }
+    
+    // Generate document tokens
+    let mut d_tokens = Vec::new();
+    for _ in 0..n_docs {
+        let mut doc = Vec::new();
+        for _ in 0..td {
+            let mut token = vec![0.0; d];
+            for v in token.iter_mut() {
+                *v = rng.gen_range(-1.0..1.0);
+            }
+            // Normalize to unit length
+            let norm: f32 = token.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                for v in token.iter_mut() {
+                    *v /= norm;
+                }
+            }
+            doc.push(token);
+        }
+        d_tokens.push(doc);
+    }
+    
+    // Configure pruning
+    let prune_config = PruneConfig {
+        q_max,
+        d_max,
+        method: "idf_norm".to_string(),
+        ..Default::default()
+    };
+    
+    // Run benchmark
+    let start_time = std::time::Instant::now();
+    let output = score_docs_with_options(
+        &q_tokens,
+        &d_tokens,
+        n_docs,
+        &prune_config,
+        &ScoreOptions::default(),
+        &NoopPostScorer,
+    )?;
+    let total_time = start_time.elapsed().as_secs_f32() * 1000.0;
+    let ScoreOutput { order, scores, perf, stats, .. } = output;
+    state.metrics.observe_scoring(Endpoint::Bench, n_docs, &perf);
+    
+    let cpu_flags = CpuCaps::detect().label();
+    
+    let threads = rayon::current_num_threads();
+    
+    // Every benchmark doc is timed, so the percentiles are always reported
+    let p50_ms = perf.per_doc_ms_p50.unwrap_or_default();
+    let p95_ms = perf.per_doc_ms_p95.unwrap_or_default();
+    info!("Microbench completed: {:.2}ms total, p50: {:.2}ms, p95: {:.2}ms", 
+          total_time, p50_ms, p95_ms);
+    
+    // A run too fast for the clock reports zero rather than infinity
+    let per_sec = |count: f32| if total_time > 0.0 { count * 1000.0 / total_time } else { 0.0 };
+    let per_doc = |ms: Option<f32>| ms.unwrap_or_default() / n_docs.max(1) as f32;
+    let phases = params.detailed.then(|| BenchPhases {
+        prune_ms: per_doc(stats.prune_ms) - per_doc(stats.normalize_ms),
+        normalize_ms: per_doc(stats.normalize_ms),
+        score_ms: per_doc(stats.score_ms),
+        per_doc_ms: per_doc(Some(total_time)),
+    });
+    let response = BenchResponse {
+        n_docs,
+        td,
+        d,
+        p50_ms,
+        p95_ms,
+        threads,
+        cpu_flags: cpu_flags.to_string(),
+        seed,
+        result_hash: format!("{:016x}", result_hash(&order, &scores)),
+        total_ms: total_time,
+        docs_per_sec: per_sec(n_docs as f32),
+        qps: per_sec(1.0),
+        phases,
+    };
+    
+    Ok(Json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::{CorpusDoc, DEFAULT_TENANT};
+    use crate::proto::RerankResponseProto;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn rerank_body() -> String {
+        serde_json::json!({
+            "q_tokens": [[1.0, 0.0], [0.0, 1.0]],
+            "d_tokens": [
+                [[1.0, 0.0]],
+                [[1.0, 0.0], [0.0, 1.0]],
+                [[-1.0, 0.0]]
+            ],
+            "topk": 3,
+            "prune": { "q_max": 16, "d_max": 64, "method": "idf_norm" }
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_rerank_returns_csv_when_requested() {
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::ACCEPT, "text/csv")
+            .body(Body::from(rerank_body()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let csv = String::from_utf8(body.to_vec()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines, vec!["rank,doc_index,score", "1,1,2", "2,0,1", "3,2,-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_defaults_to_json() {
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(rerank_body()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["order"], serde_json::json!([1, 0, 2]));
+    }
+
+    #[tokio::test]
+    async fn test_rerank_empty_candidates_opt_in() {
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body["d_tokens"] = serde_json::json!([]);
+        let send = |body: &serde_json::Value| {
+            Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = router().oneshot(send(&body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        body["allow_empty_candidates"] = serde_json::json!(true);
+        let response = router().oneshot(send(&body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["order"], serde_json::json!([]));
+        assert_eq!(json["scores"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_rerank_checks_token_flag_alignment() {
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body["flag_boosts"] = serde_json::json!({ "1": 2.0 });
+        body["d_token_flags"] = serde_json::json!([[1], [0], [0]]);
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        // Document 1 has two tokens but one flag
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        body["d_token_flags"] = serde_json::json!([[1], [0, 0], [0]]);
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        assert_eq!(router().oneshot(request).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_caps_bootstrap_resamples() {
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        let send = |body: &serde_json::Value| {
+            Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        body["bootstrap"] = serde_json::json!(BOOTSTRAP_MAX_RESAMPLES + 1);
+        assert_eq!(router().oneshot(send(&body)).await.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        body["bootstrap"] = serde_json::json!(BOOTSTRAP_MAX_RESAMPLES);
+        assert_eq!(router().oneshot(send(&body)).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_rejects_or_sanitizes_non_finite_values() {
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        // Overflows f32 to +Inf on parse
+        body["d_tokens"][1][1][0] = serde_json::json!(1e39);
+        let send = |body: &serde_json::Value| {
+            Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = router().oneshot(send(&body)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["error"], "non_finite_input");
+        assert_eq!((json["doc_index"].as_u64(), json["token_index"].as_u64()), (Some(1), Some(1)));
+
+        body["sanitize"] = serde_json::json!(true);
+        let response = router().oneshot(send(&body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["stats"]["sanitized_values"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_rejects_misaligned_cluster_ids() {
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body["cluster_scores"] = serde_json::json!(true);
+        body["cluster_ids"] = serde_json::json!([0]);
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_rejects_misaligned_confidences() {
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body["confidences"] = serde_json::json!([0.5, 1.0]);
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_progress_streams_progress_then_result() {
+        let request = Request::post("/rerank_progress")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(rerank_body()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let stream = String::from_utf8(body.to_vec()).unwrap();
+
+        let first_progress = stream.find("event: progress").expect("progress event");
+        let result = stream.find("event: result").expect("result event");
+        assert!(first_progress < result);
+        assert!(stream.contains(r#"{"scored":3,"total":3}"#));
+        assert!(stream[result..].contains(r#""order":[1,0,2]"#));
+    }
+
+    #[tokio::test]
+    async fn test_default_score_mode_from_env() {
+        // Both cases share one test so they can't race on the variable
+        std::env::set_var(DEFAULT_SCORE_MODE_ENV, "meanmaxsim");
+        assert!(AppState::from_env().is_err());
+        std::env::set_var(DEFAULT_SCORE_MODE_ENV, "mean_maxsim");
+        let state = AppState::from_env();
+        std::env::remove_var(DEFAULT_SCORE_MODE_ENV);
+        let state = state.unwrap();
+        assert_eq!(state.default_score_mode, ScoreMode::MeanMaxSim);
+
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(rerank_body()))
+            .unwrap();
+        let response = router_with_state(state).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["stats"]["score_mode"], "mean_maxsim");
+        assert_eq!(json["scores"][0], 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_col_major_layout_matches_row_major() {
+        let row_major = serde_json::json!({
+            "q_tokens": [[0.3, 0.9, 0.1], [0.7, -0.2, 0.4]],
+            "d_tokens": [
+                [[0.1, 0.8, 0.3], [0.9, 0.1, -0.2]],
+                [[-0.5, 0.2, 0.6]]
+            ],
+            "topk": 2,
+            "prune": { "q_max": 16, "d_max": 64, "method": "idf_norm" }
+        });
+        let col_major = serde_json::json!({
+            "q_tokens": [[0.3, 0.7], [0.9, -0.2], [0.1, 0.4]],
+            "d_tokens": [
+                [[0.1, 0.9], [0.8, 0.1], [0.3, -0.2]],
+                [[-0.5], [0.2], [0.6]]
+            ],
+            "topk": 2,
+            "prune": { "q_max": 16, "d_max": 64, "method": "idf_norm" },
+            "layout": "col_major"
+        });
+
+        let mut results = Vec::new();
+        for body in [row_major, col_major] {
+            let request = Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = router().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            results.push((json["order"].clone(), json["scores"].clone()));
+        }
+        assert_eq!(results[0], results[1]);
+
+        // Ragged col-major input can't be transposed
+        let ragged = serde_json::json!({
+            "q_tokens": [[0.3, 0.7], [0.9]],
+            "d_tokens": [[[0.1], [0.8]]],
+            "topk": 1,
+            "prune": { "q_max": 16, "d_max": 64, "method": "idf_norm" },
+            "layout": "col_major"
+        });
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(ragged.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_model_defaults_apply_when_omitted() {
+        let models = ModelRegistry::from_toml_str(
+            r#"
+            [models.small]
+            dim = 2
+            score_mode = "mean_maxsim"
+            calibration = { scale = 2.0, bias = -1.0 }
+
+            [models.large]
+            dim = 4
+            prune = { q_max = 8, d_max = 32, method = "norm_only" }
+            "#,
+        )
+        .unwrap();
+        let state = AppState { models, ..Default::default() };
+
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body.as_object_mut().unwrap().remove("prune");
+        body["model"] = "small".into();
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router_with_state(state.clone()).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["stats"]["score_mode"], "mean_maxsim");
+        assert_eq!(json["scores"][0], 1.0);
+        let p = json["probabilities"][0].as_f64().unwrap();
+        assert!((p - 1.0 / (1.0 + (-1.0f64).exp())).abs() < 1e-6);
+
+        // The 2-dim query doesn't fit the 4-dim model
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body["model"] = "large".into();
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router_with_state(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_ranks_continue_across_pages() {
+        let mut pages = Vec::new();
+        for offset in [0, 2] {
+            let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+            body["topk"] = 2.into();
+            body["offset"] = offset.into();
+            let request = Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = router().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            pages.push(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+        }
+
+        assert_eq!(pages[0]["order"], serde_json::json!([1, 0]));
+        assert_eq!(pages[0]["ranks"], serde_json::json!([1, 2]));
+        assert_eq!(pages[1]["order"], serde_json::json!([2]));
+        assert_eq!(pages[1]["ranks"], serde_json::json!([3]));
+    }
+
+    #[tokio::test]
+    async fn test_batch_job_runs_to_completion() {
+        let dir = std::env::temp_dir().join(format!("ranker-jobs-test-{}", std::process::id()));
+        let state = AppState { jobs: JobStore::new(&dir), ..Default::default() };
+        let app = router_with_state(state);
+
+        let rerank: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        let body = serde_json::json!({ "requests": [rerank.clone(), rerank] });
+        let request = Request::post("/jobs")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let job_id = json["job_id"].as_str().unwrap().to_string();
+
+        let mut status = serde_json::Value::Null;
+        for _ in 0..100 {
+            let request = Request::get(format!("/jobs/{}", job_id)).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            status = serde_json::from_slice(&body).unwrap();
+            if status["status"] == "done" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(status["status"], "done");
+        assert_eq!(status["results"].as_array().unwrap().len(), 2);
+        assert_eq!(status["results"][1]["order"], serde_json::json!([1, 0, 2]));
+        assert!(dir.join(format!("{}.json", job_id)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let request = Request::get("/jobs/missing").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_explain_endpoint_aligns_and_rejects_affinity_pruning() {
+        let app = router();
+        let explain = |prune: serde_json::Value| {
+            let body = serde_json::json!({
+                "q_tokens": [[1.0, 0.0], [0.0, 1.0]],
+                "d_tokens": [[0.0, 1.0], [1.0, 0.1]],
+                "prune": prune,
+            });
+            Request::post("/explain")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(explain(serde_json::Value::Null)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["matches"][0]["d_index"], 1);
+        assert_eq!(json["matches"][1]["d_index"], 0);
+        assert!(json["score"].as_f64().unwrap() > 1.9);
+
+        let affinity = serde_json::json!({ "q_max": 2, "d_max": 2, "method": "query_affinity" });
+        let response = app.oneshot(explain(affinity)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_session_blends_partial_queries() {
+        let app = router();
+        let rerank = |q_tokens: serde_json::Value, d_tokens: serde_json::Value| {
+            let body = serde_json::json!({
+                "q_tokens": q_tokens, "d_tokens": d_tokens, "topk": 2, "session_id": "voice-1", "smoothing": 0.25
+            });
+            Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let d_tokens = serde_json::json!([[[1.0, 0.0]], [[0.0, 1.0]]]);
+
+        let response = app.clone().oneshot(rerank(serde_json::json!([[1.0, 0.0]]), d_tokens.clone())).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let first: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(first["scores"], serde_json::json!([1.0, 0.0]));
+
+        // The second partial query alone would rank document 1 first
+        let response = app.clone().oneshot(rerank(serde_json::json!([[0.0, 1.0]]), d_tokens)).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let second: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(second["order"], serde_json::json!([0, 1]));
+        assert_eq!(second["scores"], serde_json::json!([0.75, 0.25]));
+
+        let response = app.oneshot(rerank(serde_json::json!([[1.0, 0.0]]), serde_json::json!([[[1.0, 0.0]]]))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_int8_docs_with_scales() {
+        let app = router();
+        let rerank = |body: serde_json::Value| {
+            Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let body = serde_json::json!({
+            "q_tokens": [[0.0, 1.0]],
+            "d_quant": [[[127, 0]], [[0, 127], [127, 0]]],
+            "d_scales": [[0.01], [0.02, 0.01]],
+            "topk": 2
+        });
+
+        let response = app.clone().oneshot(rerank(body.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(json["order"], serde_json::json!([1, 0]));
+        assert!((json["scores"][0].as_f64().unwrap() - 1.0).abs() < 1e-6);
+
+        let mut missing_scale = body.clone();
+        missing_scale["d_scales"] = serde_json::json!([[0.01], [0.02]]);
+        let response = app.clone().oneshot(rerank(missing_scale)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Query options are validated as on the f32 path, and options the
+        // int8 path doesn't implement are refused rather than ignored
+        let cases = [
+            ("q_weights", serde_json::json!([1.0, 1.0])),
+            ("q_mask", serde_json::json!([true, false])),
+            ("q_tokens", serde_json::json!([[0.0, 1.0], [1.0]])),
+            ("offset", serde_json::json!(1)),
+            ("similarity", serde_json::json!("dot")),
+        ];
+        for (field, value) in cases {
+            let mut bad = body.clone();
+            bad[field] = value;
+            let response = app.clone().oneshot(rerank(bad)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", field);
+        }
+
+        let mut masked = body;
+        masked["q_tokens"] = serde_json::json!([[0.0, 1.0], [1.0, 0.0]]);
+        masked["q_mask"] = serde_json::json!([true, false]);
+        let response = app.oneshot(rerank(masked)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(json["order"], serde_json::json!([1, 0]));
+    }
+
+    #[tokio::test]
+    async fn test_check_compat_reports_dimension_mismatch() {
+        let models = ModelRegistry::from_toml_str("[models.small]\ndim = 2\n").unwrap();
+        let state = AppState { models, ..Default::default() };
+        state.corpus.insert(DEFAULT_TENANT, vec![CorpusDoc { id: 1, tokens: vec![vec![1.0, 0.0]] }]).unwrap();
+        let app = router_with_state(state);
+        let check = |dim: usize| {
+            Request::post("/check_compat")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::json!({ "dim": dim, "model": "small" }).to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(check(2)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["compatible"], true);
+        assert_eq!(json["expected_dim"], 2);
+        assert_eq!(json["corpus_dims"], serde_json::json!([2]));
+        assert!(json["dtypes"].as_array().unwrap().contains(&"f16".into()));
+
+        let response = app.oneshot(check(3)).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["compatible"], false);
+        assert_eq!(json["expected_dim"], 2);
+        // Both the model and the corpus disagree
+        assert_eq!(json["problems"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_by_id_duplicate_policy() {
+        let state = AppState::default();
+        state.corpus.insert(DEFAULT_TENANT, vec![
+            CorpusDoc { id: 10, tokens: vec![vec![1.0, 0.0]] },
+            CorpusDoc { id: 20, tokens: vec![vec![1.0, 0.0], vec![0.0, 1.0]] },
+            CorpusDoc { id: 30, tokens: vec![vec![-1.0, 0.0]] },
+        ]).unwrap();
+        let app = router_with_state(state);
+        let body = |dedup: bool| {
+            serde_json::json!({
+                "q_tokens": [[1.0, 0.0], [0.0, 1.0]],
+                "candidate_ids": [30, 10, 30, 20, 10],
+                "topk": 5,
+                "dedup_candidates": dedup
+            })
+            .to_string()
+        };
+
+        let request = Request::post("/rerank_by_id")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body(true)))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body_bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body_bytes).unwrap();
+        assert_eq!(json["ids"], serde_json::json!([20, 10, 30]));
+        assert_eq!(json["order"], serde_json::json!([2, 1, 0]));
+
+        let request = Request::post("/rerank_by_id")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body(false)))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_corpora_are_isolated() {
+        let state = AppState { corpus: Corpus::with_tenant_limit(2), ..Default::default() };
+        let app = router_with_state(state);
+        let send = |uri: &str, tenant: &str, body: serde_json::Value| {
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(TENANT_HEADER, tenant)
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let upload_a = serde_json::json!({ "docs": [{ "id": 1, "tokens": [[1.0, 0.0]] }] });
+        let upload_b = serde_json::json!({ "docs": [{ "id": 2, "tokens": [[0.0, 1.0]] }] });
+        for (tenant, body) in [("a", upload_a), ("b", upload_b)] {
+            let response = app.clone().oneshot(send("/corpus/upload", tenant, body)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let rerank = |ids: serde_json::Value| {
+            serde_json::json!({ "q_tokens": [[1.0, 0.0]], "candidate_ids": ids, "topk": 2 })
+        };
+        let response = app.clone().oneshot(send("/rerank_by_id", "a", rerank(serde_json::json!([1])))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["ids"], serde_json::json!([1]));
+
+        let mut request = send("/rerank_by_id", "a", rerank(serde_json::json!([1])));
+        request.headers_mut().insert(header::ACCEPT, "text/csv".parse().unwrap());
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let csv = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(csv.lines().collect::<Vec<_>>(), vec!["rank,doc_index,doc_id,score", "1,0,1,1"]);
+
+        // Tenant a can't reach tenant b's document
+        let response = app.clone().oneshot(send("/rerank_by_id", "a", rerank(serde_json::json!([1, 2])))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Header and body must agree on the tenant
+        let mut body = rerank(serde_json::json!([1]));
+        body["tenant_id"] = "b".into();
+        let response = app.clone().oneshot(send("/rerank_by_id", "a", body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Each tenant has its own size limit
+        let too_many = serde_json::json!({ "docs": [
+            { "id": 3, "tokens": [[1.0, 0.0]] },
+            { "id": 4, "tokens": [[1.0, 0.0]] }
+        ] });
+        let response = app.oneshot(send("/corpus/upload", "b", too_many)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "payload_too_large");
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_list_core_features() {
+        let request = Request::get("/capabilities").body(Body::empty()).unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let has = |key: &str, value: &str| json[key].as_array().unwrap().iter().any(|v| v == value);
+        assert!(has("score_modes", "maxsim") && has("score_modes", "mean_maxsim"));
+        assert!(has("prune_methods", "idf_norm") && has("prune_methods", "query_affinity"));
+        assert!(has("dtypes", "f32"));
+        assert!(has("kernels", "scalar"));
+        assert!(has("features", "low_memory") && has("features", "csv"));
+    }
+
+    #[tokio::test]
+    async fn test_calibration_returns_raw_and_calibrated_scores() {
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body["calibration"] = serde_json::json!({ "scale": 1.5, "bias": -0.5 });
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["scores"], serde_json::json!([2.0, 1.0, -1.0]));
+        let probabilities = json["probabilities"].as_array().unwrap();
+        assert_eq!(probabilities.len(), 3);
+        for (score, p) in json["scores"].as_array().unwrap().iter().zip(probabilities) {
+            let expected = 1.0 / (1.0 + (-(1.5 * score.as_f64().unwrap() - 0.5)).exp());
+            assert!((p.as_f64().unwrap() - expected).abs() < 1e-6);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rerank_protobuf_matches_json() {
+        let send = |accept: &'static str| {
+            let request = Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::ACCEPT, accept)
+                .body(Body::from(rerank_body()))
+                .unwrap();
+            router().oneshot(request)
+        };
+        let response = send("application/json").await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let response = send(PROTOBUF_CONTENT_TYPE).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], PROTOBUF_CONTENT_TYPE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let decoded = <RerankResponseProto as prost::Message>::decode(body).unwrap();
+
+        assert_eq!(serde_json::json!(decoded.order), json["order"]);
+        assert_eq!(serde_json::json!(decoded.scores), json["scores"]);
+        assert_eq!(serde_json::json!(decoded.ranks), json["ranks"]);
+        // Phase timings differ between the two runs
+        let without_timings = |mut stats: serde_json::Value| {
+            let stats_map = stats.as_object_mut().unwrap();
+            for timing in ["prune_ms", "normalize_ms", "score_ms", "cpu_ms", "wall_ms", "parallelism"] {
+                stats_map.remove(timing);
+            }
+            stats
+        };
+        let stats: serde_json::Value = serde_json::from_str(&decoded.stats_json).unwrap();
+        assert_eq!(without_timings(stats), without_timings(json["stats"].clone()));
+        assert!(decoded.probabilities.is_empty() && decoded.perf.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_standby_corpus_stage_validate_promote() {
+        let app = router();
+        let send = |uri: &str, body: serde_json::Value| {
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let rerank = serde_json::json!({ "q_tokens": [[1.0, 0.0]], "candidate_ids": [1], "topk": 1 });
+        let score_of_doc_1 = |app: Router| {
+            let request = send("/rerank_by_id", rerank.clone());
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                json["scores"][0].as_f64().unwrap()
+            }
+        };
+
+        let old = serde_json::json!({ "docs": [{ "id": 1, "tokens": [[1.0, 0.0]] }] });
+        let response = app.clone().oneshot(send("/corpus/upload", old)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Staged documents aren't served, and can't be promoted unvalidated
+        let new = serde_json::json!({ "docs": [
+            { "id": 1, "tokens": [[0.0, 1.0]] },
+            { "id": 2, "tokens": [[1.0, 0.0], [0.0, 1.0]] }
+        ] });
+        let response = app.clone().oneshot(send("/corpus/stage", new)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(score_of_doc_1(app.clone()).await, 1.0);
+        let response = app.clone().oneshot(send("/corpus/promote", serde_json::json!(null))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // Wrong dimension fails validation; the right one passes
+        let response = app.clone()
+            .oneshot(send("/corpus/validate_standby", serde_json::json!({ "dim": 3 })))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["valid"], false);
+        let response = app.clone()
+            .oneshot(send("/corpus/validate_standby", serde_json::json!({ "dim": 2, "min_docs": 2 })))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["valid"], true);
+        assert_eq!(score_of_doc_1(app.clone()).await, 1.0);
+
+        let response = app.clone().oneshot(send("/corpus/promote", serde_json::json!(null))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(score_of_doc_1(app.clone()).await, 0.0);
+        let response = app.oneshot(send("/corpus/promote", serde_json::json!(null))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn test_rerank_reports_serialize_ms() {
+        let n_docs = 2000;
+        let body = serde_json::json!({
+            "q_tokens": [[1.0, 0.0], [0.0, 1.0]],
+            "d_tokens": (0..n_docs).map(|i| vec![vec![1.0, i as f32 / n_docs as f32]]).collect::<Vec<_>>(),
+            "topk": n_docs
+        });
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["order"].as_array().unwrap().len(), n_docs);
+        let serialize_ms = json["serialize_ms"].as_f64().unwrap();
+        assert!(serialize_ms > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_delta_against_snapshot() {
+        let app = router();
+        let send = |body: serde_json::Value| {
+            let request = Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+        let d_tokens = serde_json::json!([
+            [[1.0, 0.0]],
+            [[0.8, 0.6]],
+            [[0.6, 0.8]],
+            [[0.0, 1.0]]
+        ]);
+
+        let first = send(serde_json::json!({
+            "q_tokens": [[0.95, 0.31]], "d_tokens": d_tokens, "topk": 4, "snapshot": true
+        })).await;
+        assert_eq!(first["order"], serde_json::json!([0, 1, 2, 3]));
+        let snapshot_id = first["snapshot_id"].as_str().unwrap();
+
+        // Tilting the query slightly toward the second axis swaps docs 0 and 1
+        let delta = send(serde_json::json!({
+            "q_tokens": [[0.93, 0.37]], "d_tokens": d_tokens, "topk": 4, "prev_snapshot_id": snapshot_id
+        })).await;
+        assert!(delta.get("order").is_none());
+        assert_ne!(delta["snapshot_id"], first["snapshot_id"]);
+        assert_eq!(delta["changes"], serde_json::json!([
+            { "doc_index": 1, "old_rank": 2, "new_rank": 1 },
+            { "doc_index": 0, "old_rank": 1, "new_rank": 2 }
+        ]));
+
+        let unknown = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({
+                "q_tokens": [[1.0, 0.0]], "d_tokens": d_tokens, "topk": 1, "prev_snapshot_id": "missing"
+            }).to_string()))
+            .unwrap();
+        assert_eq!(app.clone().oneshot(unknown).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_rejects_unknown_prune_method() {
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body["prune"]["method"] = "idfnorm".into();
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_empty_document_is_bad_request() {
+        let body = serde_json::json!({
+            "q_tokens": [[1.0, 0.0]],
+            "d_tokens": [[[1.0, 0.0]], []],
+            "topk": 2
+        });
+        let request = Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "empty_after_prune");
+        assert_eq!(json["kind"], "empty_document");
+        assert_eq!(json["doc_index"], 1);
+        assert!(json["detail"].as_str().unwrap().contains("document 1"));
+    }
+
+    #[tokio::test]
+    async fn test_rerank_errors_name_their_class() {
+        let cases = [
+            (serde_json::json!({"topk": 1, "q_tokens": [], "d_tokens": [[[1.0, 0.0]]]}), StatusCode::BAD_REQUEST, "empty_input"),
+            (
+                serde_json::json!({"topk": 1, "q_tokens": [[1.0, 0.0]], "d_tokens": [[[1.0, 0.0, 0.0]]]}),
+                StatusCode::BAD_REQUEST,
+                "dim_mismatch",
+            ),
+            (
+                serde_json::json!({"topk": 1, "q_tokens": [[1.0, 0.0]], "d_tokens": [[[1.0, 0.0]]], "prune": {"q_max": 4, "d_max": 4, "method": "bogus"}}),
+                StatusCode::BAD_REQUEST,
+                "invalid_prune_config",
+            ),
+            (
+                serde_json::json!({"topk": 1, "q_tokens": [[1.0, 0.0]], "d_tokens": [[[1.0, 0.0]]], "prune": {"q_max": 0, "d_max": 4, "method": "norm_only"}}),
+                StatusCode::BAD_REQUEST,
+                "empty_after_prune",
+            ),
+            (
+                serde_json::json!({"topk": 1, "q_tokens": [[1.0, 0.0]], "d_tokens": [[[1.0, 0.0]]], "confidences": [0.5, 0.5]}),
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                serde_json::json!({"topk": 1, "q_tokens": [[1.0, 0.0]], "d_tokens": [[[1.0, 0.0]]], "prev_snapshot_id": "missing"}),
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+        ];
+        for (body, status, class) in cases {
+            let request = Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = router().oneshot(request).await.unwrap();
+
+            assert_eq!(response.status(), status, "{}", class);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["error"], class);
+            assert!(!json["detail"].as_str().unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rerank_rejects_out_of_range_norms() {
+        // Unnormalized logits rather than unit-scale embeddings
+        let send = |advisory: bool| {
+            let body = serde_json::json!({
+                "q_tokens": [[1.0, 0.0]],
+                "d_tokens": [[[30.0, 40.0]], [[0.6, 0.8]]],
+                "topk": 2,
+                "expected_norm_min": 0.5,
+                "expected_norm_max": 2.0,
+                "norm_check_advisory": advisory
+            });
+            let request = Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            router().oneshot(request)
+        };
+
+        let response = send(false).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["kind"], "norm_out_of_range");
+        assert_eq!((json["observed_min"].as_f64(), json["observed_max"].as_f64()), (Some(1.0), Some(50.0)));
+
+        let response = send(true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["stats"]["norm_out_of_range"], serde_json::json!({ "min": 1.0, "max": 50.0 }));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_healthz_responds_under_scoring_load() {
+        // More scoring jobs than async workers, each held until healthz answers
+        let gate = Arc::new(tokio::sync::RwLock::new(()));
+        let held = gate.write().await;
+        let load: Vec<_> = (0..8)
+            .map(|_| {
+                let gate = gate.clone();
+                tokio::spawn(run_blocking(move || drop(gate.blocking_read())))
+            })
+            .collect();
+
+        let app = router();
+        let probe = app.oneshot(Request::get("/healthz").body(Body::empty()).unwrap());
+        let response = tokio::time::timeout(Duration::from_secs(30), probe).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(&to_bytes(response.into_body(), usize::MAX).await.unwrap()[..], b"ok");
+        assert!(load.iter().all(|task| !task.is_finished()));
+
+        drop(held);
+        for task in load {
+            task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rerank_batch_shared_docs() {
+        let body = serde_json::json!({
+            "shared_docs": true,
+            "queries": [
+                { "q_tokens": [[1.0, 0.0]], "d_tokens": [[[1.0, 0.0]], [[0.6, 0.8]], [[0.0, 1.0]]], "topk": 3 },
+                { "q_tokens": [[0.0, 1.0]], "d_tokens": [], "topk": 3 }
+            ]
+        });
+        let request = Request::post("/rerank_batch")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json[0]["order"], serde_json::json!([0, 1, 2]));
+        assert_eq!(json[1]["order"], serde_json::json!([2, 1, 0]));
+    }
+
+    /// Second stage that blocks scoring until the test releases its gate
+    #[derive(Debug)]
+    struct GatedSecondStage(Arc<tokio::sync::RwLock<()>>);
+
+    impl SecondStageScorer for GatedSecondStage {
+        fn score(&self, q_tokens: &[Vec<f32>], candidates: &[crate::second_stage::SecondStageCandidate]) -> Vec<f32> {
+            let _open = self.0.blocking_read();
+            NoopSecondStage.score(q_tokens, candidates)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_coalesced_reranks_share_results() {
+        let gate = Arc::new(tokio::sync::RwLock::new(()));
+        let held = gate.write().await;
+        let second_stage: Arc<dyn SecondStageScorer> = Arc::new(GatedSecondStage(gate.clone()));
+        let state = AppState { coalesce: true, second_stage: Some(second_stage), ..Default::default() };
+        let coalescer = state.coalescer.clone();
+        let app = router_with_state(state);
+        let mut body: serde_json::Value = serde_json::from_str(&rerank_body()).unwrap();
+        body["second_stage_candidates"] = 3.into();
+        let requests: Vec<_> = (0..8)
+            .map(|_| {
+                let request = Request::post("/rerank")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+                tokio::spawn(app.clone().oneshot(request))
+            })
+            .collect();
+
+        // The leader is held in its second stage until every request joins
+        while coalescer.waiting() < 8 {
+            tokio::task::yield_now().await;
+        }
+        drop(held);
+        for request in requests {
+            let response = request.await.unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["order"], serde_json::json!([1, 0, 2]));
+        }
+        assert_eq!(coalescer.computations(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_scores_cached_documents_by_id() {
+        let app = router();
+        let post = |uri: &str, body: serde_json::Value| {
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let upload = serde_json::json!({
+            "doc_ids": ["a", "b", "c"],
+            "d_tokens": [[[1.0, 0.0]], [[1.0, 0.0], [0.0, 1.0]], [[-1.0, 0.0]]],
+            "prune": { "q_max": 16, "d_max": 64, "method": "idf_norm" }
+        });
+        // A zero-capacity cache keeps nothing and says so
+        let disabled = router_with_state(AppState { doc_cache: DocCache::with_capacity(0), ..Default::default() });
+        let response = disabled.oneshot(post("/cache/upload", upload.clone())).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!((json["stored"].as_u64(), json["cached"].as_u64()), (Some(0), Some(0)));
+
+        let response = app.clone().oneshot(post("/cache/upload", upload)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["stored"], 3);
+
+        let rerank = |ids: serde_json::Value| {
+            serde_json::json!({
+                "q_tokens": [[1.0, 0.0], [0.0, 1.0]],
+                "d_ids": ids,
+                "topk": 3,
+                "prune": { "q_max": 16, "d_max": 64, "method": "idf_norm" }
+            })
+        };
+        let response = app.clone().oneshot(post("/rerank", rerank(serde_json::json!(["a", "b", "c"])))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // Same ranking as sending the tokens inline (see `rerank_body`)
+        assert_eq!(json["order"], serde_json::json!([1, 0, 2]));
+
+        assert_eq!(json["stats"]["effective_prune"]["q_max"], 16);
+        assert_eq!(json["stats"]["effective_prune"]["d_max"], 64);
+
+        // CSV and protobuf responses name the cached documents
+        let mut request = post("/rerank", rerank(serde_json::json!(["a", "b", "c"])));
+        request.headers_mut().insert(header::ACCEPT, "text/csv".parse().unwrap());
+        let body = to_bytes(app.clone().oneshot(request).await.unwrap().into_body(), usize::MAX).await.unwrap();
+        let csv = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(csv.lines().collect::<Vec<_>>(), vec!["rank,doc_index,doc_id,score", "1,1,b,2", "2,0,a,1", "3,2,c,-1"]);
+        let mut request = post("/rerank", rerank(serde_json::json!(["a", "b", "c"])));
+        request.headers_mut().insert(header::ACCEPT, PROTOBUF_CONTENT_TYPE.parse().unwrap());
+        let body = to_bytes(app.clone().oneshot(request).await.unwrap().into_body(), usize::MAX).await.unwrap();
+        let decoded = <RerankResponseProto as prost::Message>::decode(body).unwrap();
+        assert_eq!(decoded.doc_ids, vec!["b", "a", "c"]);
+
+        let response = app.clone().oneshot(post("/rerank", rerank(serde_json::json!(["a", "missing"])))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Options the cached path can't honour are rejected, not dropped
+        for (option, value) in [("offset", serde_json::json!(1)), ("q_mask", serde_json::json!([true, false]))] {
+            let mut body = rerank(serde_json::json!(["a", "b", "c"]));
+            body[option] = value;
+            let response = app.clone().oneshot(post("/rerank", body)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", option);
+        }
+
+        // One weight for two query tokens
+        let mut short_weights = rerank(serde_json::json!(["a", "b", "c"]));
+        short_weights["q_weights"] = serde_json::json!([1.0]);
+        let response = app.oneshot(post("/rerank", short_weights)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_rerank_accepts_f16_tokens() {
+        let f16 = |token: &[f32]| crate::embeddings::encode_token(token, crate::embeddings::Dtype::F16);
+        let body = serde_json::json!({
+            "dtype": "f16",
+            "q_tokens": [f16(&[1.0, 0.0]), f16(&[0.0, 1.0])],
+            "d_tokens": [[f16(&[1.0, 0.0])], [f16(&[1.0, 0.0]), f16(&[0.0, 1.0])], [f16(&[-1.0, 0.0])]],
+            "topk": 3
+        });
+        let post = |body: serde_json::Value| {
+            Request::post("/rerank")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = router().oneshot(post(body.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        // Same ranking and scores as the f32 `rerank_body`
+        assert_eq!(json["order"], serde_json::json!([1, 0, 2]));
+        assert_eq!(json["scores"], serde_json::json!([2.0, 1.0, -1.0]));
+
+        let mut truncated = body;
+        truncated["q_tokens"][0] = serde_json::json!("AAA=");
+        let response = router().oneshot(post(truncated)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_resolve_bind_addr_precedence() {
+        assert_eq!(resolve_bind_addr(None, None, None).unwrap(), DEFAULT_BIND_ADDR);
+        assert_eq!(resolve_bind_addr(None, Some("9000"), None).unwrap().to_string(), "0.0.0.0:9000");
+        assert_eq!(resolve_bind_addr(Some("127.0.0.1"), None, None).unwrap().to_string(), "127.0.0.1:8088");
+        assert_eq!(resolve_bind_addr(Some("::1"), Some("9000"), None).unwrap().to_string(), "[::1]:9000");
+        // --bind overrides both variables, even invalid ones
+        let bind = resolve_bind_addr(Some("bogus"), Some("x"), Some("10.0.0.2:7000")).unwrap();
+        assert_eq!(bind.to_string(), "10.0.0.2:7000");
+        assert!(resolve_bind_addr(None, Some("70000"), None).unwrap_err().starts_with(PORT_ENV));
+        assert!(resolve_bind_addr(None, None, Some("127.0.0.1")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_seeded_bench_repeats_workload() {
+        let bench = |query: &str| {
+            let request = Request::get(format!("/bench?n_docs=8&td=8&d=16{}", query)).body(Body::empty()).unwrap();
+            async move {
+                let response = router().oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+        let (first, second) = (bench("&seed=42").await, bench("&seed=42").await);
+        assert_eq!(first["seed"], 42);
+        let (total_ms, docs_per_sec) = (first["total_ms"].as_f64().unwrap(), first["docs_per_sec"].as_f64().unwrap());
+        assert!((docs_per_sec - 8.0 * first["qps"].as_f64().unwrap()).abs() < 1e-2 * docs_per_sec.max(1.0));
+        assert!(total_ms == 0.0 || (docs_per_sec * total_ms / 1000.0 - 8.0).abs() < 1e-2);
+        assert_eq!(first["result_hash"], second["result_hash"]);
+        assert_ne!(bench("&seed=43").await["result_hash"], first["result_hash"]);
+        // An unseeded run reports the seed it drew
+        assert!(bench("").await["seed"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_detailed_bench_splits_per_doc_time() {
+        let request = Request::get("/bench?n_docs=64&td=32&d=64&seed=5&detailed=true").body(Body::empty()).unwrap();
+        let response = router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let phase = |name: &str| json["phases"][name].as_f64().unwrap();
+        assert!(["prune_ms", "normalize_ms", "score_ms", "per_doc_ms"].iter().all(|name| phase(name) >= 0.0));
+        // Scoring's prune_ms includes normalizing; the bench reports them
+        // apart, so the phases are disjoint parts of the wall time
+        let phases = phase("prune_ms") + phase("normalize_ms") + phase("score_ms");
+        assert!(phases <= phase("per_doc_ms") + 1e-3);
+    }
+}
@@ -0,0 +1,150 @@
+//! Score smoothing across successive `/rerank` requests of a session
+//!
+//! A streaming client (e.g. voice search) re-sends the same candidates as
+//! its query grows and names a `session_id`. Each request's score for
+//! document `i` is blended with the session's previous one by exponential
+//! smoothing, `smoothing * score + (1 - smoothing) * previous`, and the
+//! blend ranks the response and becomes the next request's `previous`.
+//! A session's first request is returned unblended.
+//!
+//! Sessions live in memory only. One left unused for `SESSION_TTL` expires
+//! and the next request naming it starts afresh; past `MAX_SESSIONS`, the
+//! least recently used session is dropped. Concurrent requests on one
+//! session each blend against the scores saved before they started, and
+//! the last to finish wins.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::scoring::{PostScorer, ScoringContext, DISQUALIFIED_SCORE};
+
+/// Idle time after which a session's scores are forgotten
+pub const SESSION_TTL: Duration = Duration::from_secs(300);
+
+/// Sessions kept before the least recently used is dropped
+pub const MAX_SESSIONS: usize = 4096;
+
+/// Weight of the newest request's scores when `smoothing` is omitted
+pub const DEFAULT_SMOOTHING: f32 = 0.5;
+
+#[derive(Debug)]
+struct Session {
+    /// Blended score of every document, by request index
+    scores: Vec<f32>,
+    last_used: Instant,
+}
+
+/// Blended scores of live sessions, by session id
+#[derive(Debug, Clone, Default)]
+pub struct SessionStore {
+    inner: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl SessionStore {
+    /// Session `id`'s blended scores, or `None` when it is new or expired.
+    /// Fails when the session was scoring a different number of documents.
+    pub fn previous(&self, id: &str, n_docs: usize) -> Result<Option<Vec<f32>>, String> {
+        let mut sessions = self.inner.lock().unwrap();
+        sessions.retain(|_, session| session.last_used.elapsed() < SESSION_TTL);
+        match sessions.get(id) {
+            Some(session) if session.scores.len() != n_docs => Err(format!(
+                "session '{}' holds {} documents, request has {}",
+                id,
+                session.scores.len(),
+                n_docs
+            )),
+            Some(session) => Ok(Some(session.scores.clone())),
+            None => Ok(None),
+        }
+    }
+
+    /// Store `scores` as session `id`'s latest blend
+    pub fn save(&self, id: &str, scores: Vec<f32>) {
+        let mut sessions = self.inner.lock().unwrap();
+        if !sessions.contains_key(id) && sessions.len() >= MAX_SESSIONS {
+            let oldest = sessions.iter().min_by_key(|(_, session)| session.last_used).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(id.to_string(), Session { scores, last_used: Instant::now() });
+    }
+
+    /// Live sessions, including expired ones not yet swept
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// `smoothing * score + (1 - smoothing) * previous`. Disqualified documents
+/// stay disqualified and are not blended.
+pub fn smooth(previous: f32, score: f32, smoothing: f32) -> f32 {
+    if score == DISQUALIFIED_SCORE || previous == DISQUALIFIED_SCORE {
+        return score;
+    }
+    smoothing * score + (1.0 - smoothing) * previous
+}
+
+/// Post-scorer that blends every document's score with the session's
+/// previous one, ranks by the blend and saves it for the next request
+pub struct SessionPostScorer {
+    sessions: SessionStore,
+    id: String,
+    previous: Option<Vec<f32>>,
+    smoothing: f32,
+    /// Set by the first rescore, so later ones (e.g. the `check_prune_impact`
+    /// rerun) don't overwrite the saved blend
+    saved: AtomicBool,
+}
+
+impl SessionPostScorer {
+    /// Blend against session `id`'s current scores; see `SessionStore::previous`
+    pub fn new(sessions: &SessionStore, id: &str, n_docs: usize, smoothing: f32) -> Result<Self, String> {
+        let previous = sessions.previous(id, n_docs)?;
+        Ok(Self { sessions: sessions.clone(), id: id.to_string(), previous, smoothing, saved: AtomicBool::new(false) })
+    }
+}
+
+impl PostScorer for SessionPostScorer {
+    fn rescore(&self, docs: &[(usize, f32)], ctx: &ScoringContext) -> Vec<(usize, f32)> {
+        let mut rescored: Vec<(usize, f32)> = docs
+            .iter()
+            .map(|&(idx, score)| match &self.previous {
+                Some(previous) => (idx, smooth(previous[idx], score, self.smoothing)),
+                None => (idx, score),
+            })
+            .collect();
+        if !self.saved.swap(true, AtomicOrdering::Relaxed) {
+            let mut blended = vec![DISQUALIFIED_SCORE; ctx.d_tokens_kept.len()];
+            for &(idx, score) in &rescored {
+                blended[idx] = score;
+            }
+            self.sessions.save(&self.id, blended);
+        }
+        rescored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        rescored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_rejects_changed_document_count() {
+        let sessions = SessionStore::default();
+        assert_eq!(sessions.previous("s", 2).unwrap(), None);
+        sessions.save("s", vec![1.0, 2.0]);
+        assert_eq!(sessions.previous("s", 2).unwrap(), Some(vec![1.0, 2.0]));
+        assert!(sessions.previous("s", 3).is_err());
+        assert_eq!(smooth(1.0, 3.0, 0.25), 1.5);
+        assert_eq!(smooth(1.0, DISQUALIFIED_SCORE, 0.25), DISQUALIFIED_SCORE);
+    }
+}
@@ -0,0 +1,113 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::scoring::{PerfStats, RerankResponse};
+
+/// Snapshots kept before the oldest is evicted
+pub const MAX_SNAPSHOTS: usize = 1024;
+
+/// A stored ranking: `(doc_index, rank)` pairs, best first
+type Ranking = Vec<(usize, usize)>;
+
+/// Rankings returned by earlier `/rerank` calls, for delta responses
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotStore {
+    next_id: Arc<AtomicU64>,
+    inner: Arc<Mutex<Snapshots>>,
+}
+
+#[derive(Debug, Default)]
+struct Snapshots {
+    rankings: HashMap<String, Ranking>,
+    /// Insertion order, oldest first
+    ids: VecDeque<String>,
+}
+
+impl SnapshotStore {
+    /// Store the ranking of `response` and return its snapshot id
+    pub fn save(&self, response: &RerankResponse) -> String {
+        let id = format!("snap-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let ranking = response.order.iter().copied().zip(response.ranks.iter().copied()).collect();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.ids.len() >= MAX_SNAPSHOTS {
+            if let Some(oldest) = inner.ids.pop_front() {
+                inner.rankings.remove(&oldest);
+            }
+        }
+        inner.rankings.insert(id.clone(), ranking);
+        inner.ids.push_back(id.clone());
+        id
+    }
+
+    /// Rank changes from snapshot `id` to `response`, or `None` if unknown
+    pub fn delta(&self, id: &str, response: &RerankResponse) -> Option<Vec<RankChange>> {
+        let inner = self.inner.lock().unwrap();
+        let prev = inner.rankings.get(id)?;
+        let next: Ranking = response.order.iter().copied().zip(response.ranks.iter().copied()).collect();
+        Some(rank_delta(prev, &next))
+    }
+}
+
+/// A document whose rank differs between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RankChange {
+    pub doc_index: usize,
+    /// `None` when the document wasn't in the previous result
+    pub old_rank: Option<usize>,
+    /// `None` when the document dropped out of the result
+    pub new_rank: Option<usize>,
+}
+
+/// Documents whose rank changed, in new-rank order followed by dropouts
+pub fn rank_delta(prev: &[(usize, usize)], next: &[(usize, usize)]) -> Vec<RankChange> {
+    let old_ranks: HashMap<usize, usize> = prev.iter().copied().collect();
+    let new_ranks: HashMap<usize, usize> = next.iter().copied().collect();
+    let moved = next.iter().filter_map(|&(doc_index, rank)| {
+        let old_rank = old_ranks.get(&doc_index).copied();
+        (old_rank != Some(rank)).then_some(RankChange { doc_index, old_rank, new_rank: Some(rank) })
+    });
+    let dropped = prev
+        .iter()
+        .filter(|(doc_index, _)| !new_ranks.contains_key(doc_index))
+        .map(|&(doc_index, rank)| RankChange { doc_index, old_rank: Some(rank), new_rank: None });
+    moved.chain(dropped).collect()
+}
+
+/// `/rerank` reply when `prev_snapshot_id` is set: only what changed
+#[derive(Debug, Serialize)]
+pub struct DeltaResponse {
+    /// Snapshot of this result, usable as the next `prev_snapshot_id`
+    pub snapshot_id: String,
+    pub prev_snapshot_id: String,
+    pub changes: Vec<RankChange>,
+    pub perf: PerfStats,
+}
+
+/// Full `/rerank` reply with the id of its stored snapshot
+#[derive(Debug, Serialize)]
+pub struct SnapshotResponse {
+    pub snapshot_id: String,
+    #[serde(flatten)]
+    pub response: RerankResponse,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rank_delta_reports_moves_entries_and_dropouts() {
+        let prev = vec![(3, 1), (1, 2), (4, 3)];
+        let next = vec![(1, 1), (3, 2), (5, 3)];
+        let changes = rank_delta(&prev, &next);
+        assert_eq!(changes, vec![
+            RankChange { doc_index: 1, old_rank: Some(2), new_rank: Some(1) },
+            RankChange { doc_index: 3, old_rank: Some(1), new_rank: Some(2) },
+            RankChange { doc_index: 5, old_rank: None, new_rank: Some(3) },
+            RankChange { doc_index: 4, old_rank: Some(3), new_rank: None },
+        ]);
+        assert!(rank_delta(&next, &next).is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
+
+/// A scored document; greater means better ranked
+///
+/// Higher scores rank first and equal scores fall back to the lower document
+/// index, matching a stable descending sort over documents in input order.
+#[derive(Debug, Clone, Copy)]
+pub struct Ranked {
+    pub idx: usize,
+    pub score: f32,
+    /// Document tokens kept after pruning
+    pub kept: usize,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.idx.cmp(&self.idx))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+/// Bounded min-heap keeping the best `k` documents seen so far
+#[derive(Debug, Clone)]
+pub struct TopKHeap {
+    k: usize,
+    heap: BinaryHeap<Reverse<Ranked>>,
+}
+
+impl TopKHeap {
+    pub fn new(k: usize) -> Self {
+        Self {
+            k,
+            heap: BinaryHeap::with_capacity(k + 1),
+        }
+    }
+
+    /// Offer a document, evicting the current worst if over capacity
+    pub fn push(&mut self, entry: Ranked) {
+        if self.k == 0 {
+            return;
+        }
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(entry));
+        } else if let Some(Reverse(worst)) = self.heap.peek() {
+            if entry > *worst {
+                self.heap.pop();
+                self.heap.push(Reverse(entry));
+            }
+        }
+    }
+
+    /// Score of the worst retained document once `k` are held
+    pub fn kth_score(&self) -> Option<f32> {
+        if self.k == 0 || self.heap.len() < self.k {
+            return None;
+        }
+        self.heap.peek().map(|Reverse(worst)| worst.score)
+    }
+
+    /// Combine two heaps built over disjoint documents
+    pub fn merge(mut self, other: TopKHeap) -> Self {
+        for Reverse(entry) in other.heap {
+            self.push(entry);
+        }
+        self
+    }
+
+    /// Retained documents, best first
+    pub fn into_sorted_vec(self) -> Vec<Ranked> {
+        let mut entries: Vec<Ranked> = self.heap.into_iter().map(|Reverse(e)| e).collect();
+        entries.sort_by(|a, b| b.cmp(a));
+        entries
+    }
+}
+
+/// Monotonically rising f32 shared across threads
+///
+/// Stores the float's bits remapped so unsigned integer order matches
+/// `f32::total_cmp`, which makes `fetch_max` a single atomic op.
+#[derive(Debug)]
+pub struct AtomicScore(AtomicU32);
+
+impl AtomicScore {
+    pub fn new(value: f32) -> Self {
+        Self(AtomicU32::new(Self::encode(value)))
+    }
+
+    fn encode(value: f32) -> u32 {
+        let bits = value.to_bits();
+        if bits & 0x8000_0000 != 0 { !bits } else { bits | 0x8000_0000 }
+    }
+
+    fn decode(bits: u32) -> f32 {
+        f32::from_bits(if bits & 0x8000_0000 != 0 { bits & 0x7fff_ffff } else { !bits })
+    }
+
+    pub fn load(&self) -> f32 {
+        Self::decode(self.0.load(AtomicOrdering::Relaxed))
+    }
+
+    /// Raise the stored value to `value` if it is larger
+    pub fn fetch_max(&self, value: f32) {
+        self.0.fetch_max(Self::encode(value), AtomicOrdering::Relaxed);
+    }
+}
+
+/// Fixed-capacity uniform sample of a stream of values (Algorithm R)
+#[derive(Debug, Clone)]
+pub struct Reservoir {
+    capacity: usize,
+    seen: usize,
+    samples: Vec<f32>,
+}
+
+impl Reservoir {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: 0,
+            samples: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn push<R: Rng>(&mut self, value: f32, rng: &mut R) {
+        self.seen += 1;
+        if self.samples.len() < self.capacity {
+            self.samples.push(value);
+        } else {
+            let slot = rng.gen_range(0..self.seen);
+            if slot < self.capacity {
+                self.samples[slot] = value;
+            }
+        }
+    }
+
+    /// Combine two reservoirs, drawing from each in proportion to what it saw
+    pub fn merge<R: Rng>(mut self, mut other: Reservoir, rng: &mut R) -> Self {
+        let seen = self.seen + other.seen;
+        if self.samples.len() + other.samples.len() <= self.capacity {
+            self.samples.append(&mut other.samples);
+            self.seen = seen;
+            return self;
+        }
+
+        let weight_a = self.seen as f64 / self.samples.len().max(1) as f64;
+        let weight_b = other.seen as f64 / other.samples.len().max(1) as f64;
+        self.samples.shuffle(rng);
+        other.samples.shuffle(rng);
+
+        let mut merged = Vec::with_capacity(self.capacity);
+        while merged.len() < self.capacity {
+            let remaining_a = self.samples.len() as f64 * weight_a;
+            let remaining_b = other.samples.len() as f64 * weight_b;
+            let from_a = rng.gen::<f64>() * (remaining_a + remaining_b) < remaining_a;
+            let next = if from_a { self.samples.pop() } else { other.samples.pop() };
+            match next.or_else(|| self.samples.pop()).or_else(|| other.samples.pop()) {
+                Some(value) => merged.push(value),
+                None => break,
+            }
+        }
+
+        Self {
+            capacity: self.capacity,
+            seen,
+            samples: merged,
+        }
+    }
+
+    /// Number of values offered to the reservoir
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    pub fn into_samples(self) -> Vec<f32> {
+        self.samples
+    }
+}
+
+/// Best-first order of `(doc_idx, score)` pairs: descending score, then
+/// ascending index, as a stable descending sort of documents in input order
+fn best_first(a: &(usize, f32), b: &(usize, f32)) -> Ordering {
+    b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal).then_with(|| a.0.cmp(&b.0))
+}
+
+/// Reduce `ranked` to its best `k` entries plus any others tying the k-th
+/// score, sorted best first
+///
+/// Keeps the same prefix as a full stable sort, so tie counts at the cutoff
+/// are unaffected, but only the kept entries are sorted: O(n + k log k)
+/// instead of O(n log n).
+pub fn select_top(ranked: &mut Vec<(usize, f32)>, k: usize) {
+    if k == 0 {
+        ranked.clear();
+        return;
+    }
+    if k < ranked.len() {
+        ranked.select_nth_unstable_by(k - 1, best_first);
+        let kth = ranked[k - 1].1;
+        let mut kept = k;
+        for i in k..ranked.len() {
+            if ranked[i].1 == kth {
+                ranked.swap(kept, i);
+                kept += 1;
+            }
+        }
+        ranked.truncate(kept);
+    }
+    ranked.sort_unstable_by(best_first);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_select_top_matches_stable_sort_prefix() {
+        let mut rng = StdRng::seed_from_u64(3);
+        // Coarse scores so ties straddle the cutoff
+        let ranked: Vec<(usize, f32)> = (0..500).map(|idx| (idx, rng.gen_range(0..20) as f32)).collect();
+        let mut sorted = ranked.clone();
+        sorted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+        for k in [0, 1, 10, 37, 500, 800] {
+            let mut top = ranked.clone();
+            select_top(&mut top, k);
+            let k = k.min(ranked.len());
+            assert_eq!(top[..k], sorted[..k]);
+            let cutoff = k.checked_sub(1).map(|i| sorted[i].1);
+            let ties = sorted.iter().filter(|(_, score)| Some(*score) == cutoff).count();
+            assert_eq!(top.iter().filter(|(_, score)| Some(*score) == cutoff).count(), ties);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Switches the process-wide kernel, so it lives in its own test binary
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request, StatusCode};
+use ranker_rs::kernels::{CpuCaps, KernelKind};
+use ranker_rs::server::router;
+use tower::ServiceExt;
+
+async fn post(uri: &str, body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+    let request = Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = router().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+}
+
+#[tokio::test]
+async fn test_switching_kernels_keeps_results() {
+    let rerank = serde_json::json!({
+        "q_tokens": [[0.3, 0.9, 0.1, 0.2, 0.5, 0.1, 0.7, 0.3, 0.2, 0.4, 0.6, 0.8, 0.1, 0.9, 0.3, 0.2, 0.5],
+                     [0.7, 0.2, 0.4, 0.9, 0.1, 0.3, 0.2, 0.8, 0.6, 0.1, 0.4, 0.2, 0.7, 0.3, 0.5, 0.9, 0.1]],
+        "d_tokens": [
+            [[0.1, 0.8, 0.3, 0.2, 0.4, 0.1, 0.6, 0.2, 0.3, 0.5, 0.7, 0.9, 0.2, 0.8, 0.1, 0.3, 0.4]],
+            [[0.9, 0.1, 0.5, 0.8, 0.2, 0.4, 0.1, 0.7, 0.5, 0.2, 0.3, 0.1, 0.8, 0.2, 0.6, 0.8, 0.2]],
+            [[-0.5, 0.2, 0.6, -0.1, 0.3, 0.2, -0.4, 0.1, 0.9, -0.3, 0.2, 0.5, -0.2, 0.1, 0.4, -0.6, 0.3]]
+        ],
+        "topk": 3
+    });
+
+    let caps = CpuCaps::detect();
+    let mut baseline: Option<(serde_json::Value, Vec<f64>)> = None;
+    for kind in [KernelKind::Scalar, KernelKind::Avx2, KernelKind::Avx512] {
+        let (status, _) = post("/config/kernel", serde_json::json!({ "kernel": kind.name() })).await;
+        if !kind.supported(caps) {
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            continue;
+        }
+        assert_eq!(status, StatusCode::OK);
+
+        let request = Request::get("/info").body(Body::empty()).unwrap();
+        let response = router().oneshot(request).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["kernel"], kind.name());
+
+        let (status, json) = post("/rerank", rerank.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let scores: Vec<f64> = json["scores"].as_array().unwrap().iter().map(|s| s.as_f64().unwrap()).collect();
+        match &baseline {
+            None => baseline = Some((json["order"].clone(), scores)),
+            Some((order, expected)) => {
+                assert_eq!(&json["order"], order);
+                for (got, want) in scores.iter().zip(expected) {
+                    assert!((got - want).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    let (status, _) = post("/config/kernel", serde_json::json!({ "kernel": "neon" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
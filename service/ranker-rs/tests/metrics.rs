@@ -0,0 +1,55 @@
+//! Scrapes `/metrics` after real requests through the router
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request, StatusCode};
+use axum::Router;
+use ranker_rs::server::router;
+use tower::ServiceExt;
+
+async fn send(app: &Router, request: Request<Body>) -> (StatusCode, String) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+fn metric(text: &str, series: &str) -> f64 {
+    let line = text
+        .lines()
+        .find(|line| line.starts_with(series) && line[series.len()..].starts_with(' '))
+        .unwrap_or_else(|| panic!("no series {}", series));
+    line[series.len()..].trim().parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_metrics_count_reranks_and_bench_runs() {
+    let app = router();
+    let rerank = |body: serde_json::Value| {
+        Request::post("/rerank")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let good = serde_json::json!({
+        "q_tokens": [[1.0, 0.0], [0.0, 1.0]],
+        "d_tokens": [[[1.0, 0.0]], [[0.0, 1.0]], [[-1.0, 0.0]]],
+        "topk": 2
+    });
+    for _ in 0..3 {
+        assert_eq!(send(&app, rerank(good.clone())).await.0, StatusCode::OK);
+    }
+    let empty = serde_json::json!({ "q_tokens": [], "d_tokens": [], "topk": 2 });
+    assert_eq!(send(&app, rerank(empty)).await.0, StatusCode::BAD_REQUEST);
+    let bench = Request::get("/bench?n_docs=4&td=4&d=8").body(Body::empty()).unwrap();
+    assert_eq!(send(&app, bench).await.0, StatusCode::OK);
+
+    let (status, text) = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(metric(&text, "reranker_requests_total{endpoint=\"rerank\"}"), 4.0);
+    assert_eq!(metric(&text, "reranker_request_errors_total{endpoint=\"rerank\"}"), 1.0);
+    assert_eq!(metric(&text, "reranker_documents_scored_total{endpoint=\"rerank\"}"), 9.0);
+    assert_eq!(metric(&text, "reranker_request_duration_seconds_count{endpoint=\"rerank\"}"), 4.0);
+    assert_eq!(metric(&text, "reranker_requests_total{endpoint=\"bench\"}"), 1.0);
+    assert_eq!(metric(&text, "reranker_documents_scored_total{endpoint=\"bench\"}"), 4.0);
+    assert!(metric(&text, "reranker_per_doc_ms{endpoint=\"rerank\",quantile=\"0.95\"}") >= 0.0);
+}